@@ -0,0 +1,7 @@
+// generates the message types and the service from proto/draw_rust.proto,
+// with a bundled protoc so no protobuf install is needed
+fn main(){
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    println!("cargo:rerun-if-changed=proto/draw_rust.proto");
+    tonic_build::compile_protos("proto/draw_rust.proto").unwrap();
+}
@@ -0,0 +1,329 @@
+// `field: field` like the draw-rust crate, and tonic::Status is the error
+// type of every handler however large it is
+#![allow(clippy::redundant_field_names, clippy::result_large_err)]
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use draw_rust::canvas::Canvas;
+use draw_rust::server::ModelStore;
+use draw_rust::{imagefile, model, preprocess, CNN};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status, Streaming};
+
+use proto::classifier_server::{Classifier, ClassifierServer};
+
+// # gRPC service
+// The classifier of proto/draw_rust.proto on top of the draw-rust library.
+// Predict and PredictBatch take preprocessed 784 pixel images or PNG/JPEG
+// files like the HTTP server, StreamPredict keeps a canvas per stream that
+// the client draws on with strokes and answers every update with the
+// probabilities of the drawing so far. The model file is watched and
+// reloaded like in `draw-rust-cli serve`.
+pub mod proto {
+    tonic::include_proto!("draw_rust");
+}
+
+// size of a streamed canvas until the client resizes it, the same as the window
+pub const CANVAS_SIZE: usize = 540;
+pub const BRUSH_RADIUS: f32 = 22.0;
+// largest canvas side a stream may ask for
+const MAX_CANVAS_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone)]
+pub struct Config{
+    pub addr: String,
+    pub model: PathBuf,
+    // how often the model file is checked for changes
+    pub reload_interval: Duration
+}
+
+#[derive(Clone)]
+pub struct Service{
+    store: Arc<ModelStore>
+}
+
+const USAGE: &str = "usage: draw-rust-grpc [--addr 127.0.0.1:50051] [--model model.json]";
+
+impl Default for Config{
+    fn default() -> Config{
+        Config{
+            addr: "127.0.0.1:50051".to_owned(),
+            model: PathBuf::from("./src/assets/model.json"),
+            reload_interval: Duration::from_millis(500)
+        }
+    }
+}
+
+fn predict(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>) -> proto::Prediction{
+    let probabilities = cnn.probabilities(input);
+    proto::Prediction{
+        class: model::argmax(&probabilities) as u32,
+        probabilities: probabilities
+    }
+}
+
+// the network input of an image message
+pub fn image_input(image: &proto::Image) -> Result<Vec<Vec<Vec<f32>>>, Status>{
+    match image.data {
+        Some(proto::image::Data::Pixels(ref pixels)) => {
+            if pixels.values.len() != 784{
+                return Err(Status::invalid_argument(format!("expected 784 pixels, got {}", pixels.values.len())));
+            }
+            let image: Vec<Vec<f32>> = pixels.values.chunks(28).map(|row| row.to_vec()).collect();
+            Ok(preprocess::to_input(&image))
+        },
+        Some(proto::image::Data::Encoded(ref bytes)) => {
+            let image = imagefile::decode(bytes).map_err(|e| Status::invalid_argument(e.to_string()))?;
+            Ok(preprocess::to_input(&preprocess::mnist(&image)))
+        },
+        None => Err(Status::invalid_argument("the image has no data"))
+    }
+}
+
+fn canvas_size(width: u32, height: u32) -> Result<(usize, usize), Status>{
+    if width == 0 || height == 0 || width > MAX_CANVAS_SIZE || height > MAX_CANVAS_SIZE{
+        return Err(Status::invalid_argument(format!("a canvas of {}x{} is not supported", width, height)));
+    }
+    Ok((width as usize, height as usize))
+}
+
+// draws one update of a stream onto its canvas
+pub fn apply_update(canvas: &mut Canvas, update: proto::CanvasUpdate) -> Result<(), Status>{
+    match update.update {
+        Some(proto::canvas_update::Update::Resize(size)) => {
+            let (width, height) = canvas_size(size.width, size.height)?;
+            *canvas = Canvas::new(width, height, canvas.brush_radius);
+        },
+        Some(proto::canvas_update::Update::Clear(_)) => canvas.clear(),
+        Some(proto::canvas_update::Update::Stroke(stroke)) => {
+            if stroke.brush_radius > 0.0{
+                canvas.set_brush_radius(stroke.brush_radius);
+            }
+            // the number of brush stamps grows with the distance between
+            // points, so far away and non-finite points are rejected before
+            // any is drawn
+            let margin = canvas.brush_radius;
+            for point in stroke.points.iter(){
+                let inside = point.x >= -margin && point.x <= canvas.width() as f32 + margin
+                    && point.y >= -margin && point.y <= canvas.height() as f32 + margin;
+                if !inside{
+                    return Err(Status::invalid_argument(format!("the point ({}, {}) is outside the {}x{} canvas",
+                        point.x, point.y, canvas.width(), canvas.height())));
+                }
+            }
+            for point in stroke.points.iter(){
+                canvas.stroke_to(point.x, point.y, stroke.erase);
+            }
+            canvas.end_stroke();
+        },
+        Some(proto::canvas_update::Update::Pixels(pixels)) => {
+            let (width, height) = canvas_size(pixels.width, pixels.height)?;
+            if pixels.values.len() != width * height{
+                return Err(Status::invalid_argument(format!("expected {} values for a {}x{} canvas, got {}",
+                    width * height, width, height, pixels.values.len())));
+            }
+            let image: Vec<Vec<f32>> = pixels.values.chunks(width).map(|row| row.to_vec()).collect();
+            *canvas = Canvas::new(width, height, canvas.brush_radius);
+            canvas.set_pixels(&image);
+        },
+        None => return Err(Status::invalid_argument("the update is empty"))
+    }
+    Ok(())
+}
+
+// what the window shows for the canvas, nothing while it is empty
+pub fn canvas_prediction(cnn: &CNN, canvas: &Canvas) -> proto::Prediction{
+    let image = preprocess::mnist(&canvas.downsample(28, 28));
+    if preprocess::bounding_box(&image).is_none(){
+        return proto::Prediction::default();
+    }
+    predict(cnn, &preprocess::to_input(&image))
+}
+
+fn model_info(store: &ModelStore) -> proto::ModelInfo{
+    let json = store.to_json();
+    let shape = |value: &serde_json::Value| -> Vec<u32> {
+        value.as_array().map_or(Vec::new(), |dims| dims.iter().filter_map(|d| d.as_u64()).map(|d| d as u32).collect())
+    };
+    let layers = json["layers"].as_array().map_or(Vec::new(), |layers| layers.iter().map(|layer| proto::Layer{
+        name: layer["name"].as_str().unwrap_or("").to_owned(),
+        r#type: layer["type"].as_str().unwrap_or("").to_owned(),
+        weight: shape(&layer["weight"]),
+        bias: shape(&layer["bias"])
+    }).collect());
+    let info = store.info();
+    proto::ModelInfo{
+        path: store.path().to_string_lossy().into_owned(),
+        hash: info.hash,
+        reloads: info.reloads as u64,
+        input: shape(&json["input"]),
+        layers: layers
+    }
+}
+
+impl Service{
+    pub fn new(store: Arc<ModelStore>) -> Service{
+        Service{
+            store: store
+        }
+    }
+
+    pub fn store(&self) -> Arc<ModelStore>{
+        self.store.clone()
+    }
+}
+
+#[tonic::async_trait]
+impl Classifier for Service{
+    async fn predict(&self, request: Request<proto::Image>) -> Result<Response<proto::Prediction>, Status>{
+        // decoding and the forward pass would hold up the other requests on
+        // this worker, like in predict_batch
+        let cnn = self.store.current();
+        let image = request.into_inner();
+        let prediction = tokio::task::spawn_blocking(move || {
+            image_input(&image).map(|input| predict(&cnn, &input))
+        }).await.map_err(|e| Status::internal(e.to_string()))??;
+        Ok(Response::new(prediction))
+    }
+
+    async fn predict_batch(&self, request: Request<proto::ImageBatch>) -> Result<Response<proto::PredictionBatch>, Status>{
+        // a large batch would hold up the other requests on this worker
+        let cnn = self.store.current();
+        let images = request.into_inner().images;
+        let predictions = tokio::task::spawn_blocking(move || {
+            images.iter().map(|image| image_input(image).map(|input| predict(&cnn, &input))).collect::<Result<Vec<_>, Status>>()
+        }).await.map_err(|e| Status::internal(e.to_string()))??;
+        Ok(Response::new(proto::PredictionBatch{
+            predictions: predictions
+        }))
+    }
+
+    type StreamPredictStream = ReceiverStream<Result<proto::Prediction, Status>>;
+
+    async fn stream_predict(&self, request: Request<Streaming<proto::CanvasUpdate>>) -> Result<Response<Self::StreamPredictStream>, Status>{
+        let mut updates = request.into_inner();
+        let (sender, receiver) = mpsc::channel(16);
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            let mut canvas = Canvas::new(CANVAS_SIZE, CANVAS_SIZE, BRUSH_RADIUS);
+            loop {
+                let reply = match updates.message().await {
+                    Ok(Some(update)) => {
+                        // drawing a long stroke on a large canvas would hold
+                        // up the other requests on this worker
+                        let cnn = store.current();
+                        let drawn = tokio::task::spawn_blocking(move || {
+                            let reply = apply_update(&mut canvas, update).map(|()| canvas_prediction(&cnn, &canvas));
+                            (canvas, reply)
+                        }).await;
+                        match drawn {
+                            Ok((drawn, reply)) => {
+                                canvas = drawn;
+                                reply
+                            },
+                            Err(e) => {
+                                let _ = sender.send(Err(Status::internal(e.to_string()))).await;
+                                break;
+                            }
+                        }
+                    },
+                    Ok(None) => break,
+                    Err(status) => Err(status)
+                };
+                // an invalid update ends the stream with its error
+                let failed = reply.is_err();
+                if sender.send(reply).await.is_err() || failed{
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn get_model_info(&self, _request: Request<proto::ModelInfoRequest>) -> Result<Response<proto::ModelInfo>, Status>{
+        Ok(Response::new(model_info(&self.store)))
+    }
+}
+
+// checks the model file every `interval` until the runtime shuts down
+fn watch(store: Arc<ModelStore>, interval: Duration){
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let reload = store.clone();
+            match tokio::task::spawn_blocking(move || reload.reload_if_changed()).await {
+                Ok(Ok(true)) => println!("Reloaded {}", store.path().display()),
+                Ok(Ok(false)) => {},
+                Ok(Err(e)) => eprintln!("Error: {}: {}", store.path().display(), e),
+                Err(_) => break
+            }
+        }
+    });
+}
+
+// serves on an already bound listener until `shutdown` completes, which
+// lets tests pick a free port first
+pub async fn serve<F>(listener: tokio::net::TcpListener, config: Config, shutdown: F) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where F: std::future::Future<Output = ()> + Send + 'static{
+    let store = Arc::new(ModelStore::open(&config.model)?);
+    watch(store.clone(), config.reload_interval);
+    tonic::transport::Server::builder()
+        .add_service(ClassifierServer::new(Service::new(store)))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown)
+        .await?;
+    Ok(())
+}
+
+pub async fn bind(addr: &str) -> std::io::Result<(tokio::net::TcpListener, SocketAddr)>{
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    Ok((listener, addr))
+}
+
+// command line arguments without the program name, returns the exit code
+pub async fn run(args: &[String]) -> i32{
+    let mut config = Config::default();
+    let mut i = 0;
+    while i < args.len(){
+        match args[i].as_str() {
+            "--addr" | "--model" if i + 1 >= args.len() => {
+                eprintln!("{} needs a value\n{}", args[i], USAGE);
+                return 2;
+            },
+            "--addr" => config.addr = args[i + 1].clone(),
+            "--model" => config.model = PathBuf::from(&args[i + 1]),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            other => {
+                eprintln!("unknown argument {}\n{}", other, USAGE);
+                return 2;
+            }
+        }
+        i += 2;
+    }
+    let (listener, addr) = match bind(&config.addr).await {
+        Ok(bound) => bound,
+        Err(e) => {
+            eprintln!("Error: {}: {}", config.addr, e);
+            return 1;
+        }
+    };
+    println!("Listening on {} (gRPC), model {}", addr, config.model.display());
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    match serve(listener, config, shutdown).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
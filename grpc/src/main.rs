@@ -0,0 +1,5 @@
+#[tokio::main]
+async fn main(){
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(draw_rust_grpc::run(&args).await);
+}
@@ -0,0 +1,211 @@
+// written like the library: `field: field` and index loops
+#![allow(clippy::redundant_field_names, clippy::needless_range_loop)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use draw_rust::imagefile;
+use draw_rust_grpc::proto::classifier_client::ClassifierClient;
+use draw_rust_grpc::proto::{self, canvas_update, image};
+use draw_rust_grpc::Config;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+
+fn model_path() -> PathBuf{
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../src/assets/model.json")
+}
+
+// a server on a free port and a client connected to it, the server stops
+// when the sender is dropped
+async fn start(config: Config) -> (ClassifierClient<Channel>, oneshot::Sender<()>){
+    let (listener, addr) = draw_rust_grpc::bind("127.0.0.1:0").await.unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    tokio::spawn(draw_rust_grpc::serve(listener, config, async {
+        let _ = stopped.await;
+    }));
+    let client = ClassifierClient::connect(format!("http://{}", addr)).await.unwrap();
+    (client, stop)
+}
+
+fn config(model: PathBuf) -> Config{
+    Config{
+        model: model,
+        ..Config::default()
+    }
+}
+
+// a dark 1 on white paper as a PNG file
+fn one_png(name: &str) -> Vec<u8>{
+    let mut pixels = vec![vec![1.0; 60]; 80];
+    for i in 10..70{
+        for j in 27..33{
+            pixels[i][j] = 0.0;
+        }
+    }
+    let path = std::env::temp_dir().join(format!("draw-rust-grpc-{}-{}.png", name, std::process::id()));
+    imagefile::save_png(&pixels, &path).unwrap();
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    bytes
+}
+
+fn encoded(bytes: Vec<u8>) -> proto::Image{
+    proto::Image{
+        data: Some(image::Data::Encoded(bytes))
+    }
+}
+
+fn pixels(values: Vec<f32>) -> proto::Image{
+    proto::Image{
+        data: Some(image::Data::Pixels(proto::Pixels{values: values}))
+    }
+}
+
+fn update(update: canvas_update::Update) -> proto::CanvasUpdate{
+    proto::CanvasUpdate{
+        update: Some(update)
+    }
+}
+
+#[tokio::test]
+async fn predict_test(){
+    let (mut client, _stop) = start(config(model_path())).await;
+    let prediction = client.predict(encoded(one_png("predict"))).await.unwrap().into_inner();
+    assert_eq!(prediction.class, 1, "Sample: {:?}", prediction.probabilities);
+    assert_eq!(prediction.probabilities.len(), 10);
+    let sum: f32 = prediction.probabilities.iter().sum();
+    assert!((sum - 1.0).abs() < 1e-4, "Sample: {}", sum);
+
+    let cnn = draw_rust::load_cnn(&model_path()).unwrap();
+    let blank = client.predict(pixels(vec![0.0; 784])).await.unwrap().into_inner();
+    let expected = cnn.probabilities(&draw_rust::preprocess::to_input(&vec![vec![0.0; 28]; 28]));
+    assert_eq!(blank.probabilities, expected);
+    assert_eq!(blank.class, cnn.forward(&draw_rust::preprocess::to_input(&vec![vec![0.0; 28]; 28])));
+
+    let status = client.predict(pixels(vec![0.0; 100])).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    let status = client.predict(encoded(b"not an image".to_vec())).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn predict_batch_test(){
+    let (mut client, _stop) = start(config(model_path())).await;
+    let batch = proto::ImageBatch{
+        images: vec![encoded(one_png("batch")), pixels(vec![0.0; 784]), encoded(one_png("batch"))]
+    };
+    let predictions = client.predict_batch(batch).await.unwrap().into_inner().predictions;
+    assert_eq!(predictions.len(), 3);
+    assert_eq!(predictions[0], predictions[2]);
+    assert_eq!(predictions[0].class, 1);
+
+    let empty = client.predict_batch(proto::ImageBatch{images: Vec::new()}).await.unwrap().into_inner();
+    assert!(empty.predictions.is_empty());
+    let status = client.predict_batch(proto::ImageBatch{images: vec![proto::Image{data: None}]}).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn stream_predict_test(){
+    let (mut client, _stop) = start(config(model_path())).await;
+    let (updates, receiver) = mpsc::channel(4);
+    let mut predictions = client.stream_predict(ReceiverStream::new(receiver)).await.unwrap().into_inner();
+
+    // every update is answered before the next one is sent
+    updates.send(update(canvas_update::Update::Resize(proto::CanvasSize{width: 200, height: 200}))).await.unwrap();
+    let prediction = predictions.message().await.unwrap().unwrap();
+    assert!(prediction.probabilities.is_empty());
+
+    let stroke = proto::Stroke{
+        points: (0..8).map(|i| proto::Point{x: 100.0, y: 40.0 + i as f32 * 17.0}).collect(),
+        brush_radius: 10.0,
+        erase: false
+    };
+    updates.send(update(canvas_update::Update::Stroke(stroke))).await.unwrap();
+    let prediction = predictions.message().await.unwrap().unwrap();
+    assert_eq!(prediction.class, 1, "Sample: {:?}", prediction.probabilities);
+
+    updates.send(update(canvas_update::Update::Clear(true))).await.unwrap();
+    assert!(predictions.message().await.unwrap().unwrap().probabilities.is_empty());
+
+    let mut canvas = vec![0.0; 40 * 40];
+    for i in 6..34{
+        canvas[i * 40 + 20] = 1.0;
+        canvas[i * 40 + 21] = 1.0;
+    }
+    updates.send(update(canvas_update::Update::Pixels(proto::Canvas{width: 40, height: 40, values: canvas}))).await.unwrap();
+    assert_eq!(predictions.message().await.unwrap().unwrap().class, 1);
+
+    // a bad update ends the stream with an error
+    updates.send(update(canvas_update::Update::Resize(proto::CanvasSize{width: 0, height: 10}))).await.unwrap();
+    let status = predictions.message().await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn model_info_test(){
+    // a copy of the model that can be changed while the server runs
+    let path = std::env::temp_dir().join(format!("draw-rust-grpc-model-{}.json", std::process::id()));
+    fs::copy(model_path(), &path).unwrap();
+    let config = Config{
+        reload_interval: Duration::from_millis(20),
+        ..config(path.clone())
+    };
+    let (mut client, _stop) = start(config).await;
+    let info = client.get_model_info(proto::ModelInfoRequest{}).await.unwrap().into_inner();
+    assert_eq!(info.input, vec![1, 28, 28]);
+    assert_eq!(info.layers.len(), 9);
+    assert_eq!((info.layers[0].name.as_str(), info.layers[0].r#type.as_str()), ("conv1", "Conv2D"));
+    assert_eq!(info.layers[0].weight, vec![4, 1, 3, 3]);
+    assert_eq!(info.layers[7].weight, vec![10, 200]);
+    assert_eq!(info.reloads, 0);
+
+    let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    json["fc1.bias"][7] = serde_json::json!(50.0);
+    fs::write(&path, serde_json::to_string(&json).unwrap()).unwrap();
+    let mut reloaded = info.clone();
+    for _ in 0..100{
+        reloaded = client.get_model_info(proto::ModelInfoRequest{}).await.unwrap().into_inner();
+        if reloaded.reloads > 0{
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(reloaded.reloads, 1);
+    assert!(reloaded.hash != info.hash);
+    let prediction = client.predict(pixels(vec![0.0; 784])).await.unwrap().into_inner();
+    assert_eq!(prediction.class, 7, "Sample: {:?}", prediction.probabilities);
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn stream_predict_invalid_points_test(){
+    let (mut client, _stop) = start(config(model_path())).await;
+    let far = [(f32::INFINITY, 100.0), (100.0, f32::NAN), (1e12, 100.0), (100.0, -40.0)];
+    for &(x, y) in far.iter(){
+        let (updates, receiver) = mpsc::channel(4);
+        let mut predictions = client.stream_predict(ReceiverStream::new(receiver)).await.unwrap().into_inner();
+        updates.send(update(canvas_update::Update::Resize(proto::CanvasSize{width: 200, height: 200}))).await.unwrap();
+        predictions.message().await.unwrap().unwrap();
+
+        // a point just outside the canvas is still under the brush
+        let stroke = proto::Stroke{
+            points: vec![proto::Point{x: -5.0, y: 100.0}, proto::Point{x: 100.0, y: 205.0}],
+            brush_radius: 10.0,
+            erase: false
+        };
+        updates.send(update(canvas_update::Update::Stroke(stroke))).await.unwrap();
+        predictions.message().await.unwrap().unwrap();
+
+        let stroke = proto::Stroke{
+            points: vec![proto::Point{x: 100.0, y: 100.0}, proto::Point{x: x, y: y}],
+            brush_radius: 10.0,
+            erase: false
+        };
+        updates.send(update(canvas_update::Update::Stroke(stroke))).await.unwrap();
+        let status = predictions.message().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "Sample: ({}, {})", x, y);
+    }
+}
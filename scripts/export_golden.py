@@ -0,0 +1,98 @@
+"""Export golden forward-pass outputs for the Rust parity tests.
+
+Builds the PyTorch model that model.json was exported from, runs a fixed set
+of inputs through it and writes the input, every intermediate activation and
+the logits to src/assets/golden/<name>.json.
+
+    python scripts/export_golden.py [--model src/assets/model.json] [--out src/assets/golden]
+
+Needs torch: the point of the goldens is to catch where the Rust layers
+diverge from PyTorch, which a reimplementation with the same assumptions
+would not. Every file records "generator": "torch".
+"""
+import argparse
+import json
+import math
+import os
+
+
+def golden_inputs():
+    # every input is a 28x28 canvas in [0, 1], normalized like the app does
+    blank = [[0.0] * 28 for _ in range(28)]
+
+    one = [[0.0] * 28 for _ in range(28)]
+    for row in range(5, 23):
+        for col in range(12, 16):
+            one[row][col] = 1.0
+
+    zero = [[0.0] * 28 for _ in range(28)]
+    for row in range(28):
+        for col in range(28):
+            r = math.hypot(row - 13.5, col - 13.5)
+            if 5.5 <= r <= 8.5:
+                zero[row][col] = 1.0
+
+    noise = [[0.0] * 28 for _ in range(28)]
+    state = 12345
+    for row in range(28):
+        for col in range(28):
+            state = (1103515245 * state + 12345) % (1 << 31)
+            noise[row][col] = state / float(1 << 31)
+
+    images = {"blank": blank, "one": one, "zero": zero, "noise": noise}
+    return {
+        name: [[[(v - 0.5) / 0.5 for v in row] for row in image]]
+        for name, image in images.items()
+    }
+
+
+def torch_forward(weights, image):
+    import torch
+    import torch.nn as nn
+    import torch.nn.functional as F
+
+    conv1 = nn.Conv2d(1, 4, 3)
+    conv2 = nn.Conv2d(4, 8, 3)
+    fc1 = nn.Linear(200, 10)
+    with torch.no_grad():
+        for name, layer in (("conv1", conv1), ("conv2", conv2), ("fc1", fc1)):
+            layer.weight.copy_(torch.tensor(weights[name + ".weight"]))
+            layer.bias.copy_(torch.tensor(weights[name + ".bias"]))
+
+        x = torch.tensor([image], dtype=torch.float32)
+        acts = {}
+        x = conv1(x); acts["conv1"] = x
+        x = F.relu(x); acts["relu1"] = x
+        x = F.max_pool2d(x, 2); acts["pool1"] = x
+        x = conv2(x); acts["conv2"] = x
+        x = F.relu(x); acts["relu2"] = x
+        x = F.max_pool2d(x, 2); acts["pool2"] = x
+        x = torch.flatten(x, 1); acts["flatten"] = x
+        x = fc1(x); acts["logits"] = x
+    return {name: value[0].tolist() for name, value in acts.items()}
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--model", default="src/assets/model.json")
+    parser.add_argument("--out", default="src/assets/golden")
+    args = parser.parse_args()
+
+    with open(args.model) as f:
+        weights = json.load(f)
+
+    os.makedirs(args.out, exist_ok=True)
+    for name, image in golden_inputs().items():
+        golden = {
+            "name": name,
+            "generator": "torch",
+            "input": image,
+            "activations": torch_forward(weights, image),
+        }
+        with open(os.path.join(args.out, name + ".json"), "w") as f:
+            json.dump(golden, f)
+        print("wrote", name, golden["activations"]["logits"])
+
+
+if __name__ == "__main__":
+    main()
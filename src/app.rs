@@ -0,0 +1,808 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use canvas::Canvas;
+use dataset::Dataset;
+use explain::{self, Method};
+use features;
+use finetune::{FineTune, Progress};
+use history::{Edit, History, Stroke};
+use idx;
+use imagefile;
+use model;
+use preprocess;
+use toolbar::Control;
+use CNN;
+
+// seconds between strokes when replaying a drawing
+const REPLAY_INTERVAL: f64 = 0.4;
+
+// # App
+// Everything the drawing window shows, independent of piston. The toolbar
+// and the keyboard shortcuts both end up in `apply`, so they always behave
+// the same.
+pub struct App{
+    pub canvas: Canvas,
+    pub erase: bool,
+    pub drawing: bool,
+    // the canvas texture has to be uploaded again
+    pub dirty: bool,
+    // the prediction has to be redone
+    stale: bool,
+    pub show_preview: bool,
+    // what the network saw for the last prediction
+    pub preview: Option<Vec<Vec<f32>>>,
+    pub probabilities: Option<Vec<f32>>,
+    // heatmap of the predicted class, canvas sized, while explaining
+    pub explain: Option<Method>,
+    pub heatmap: Option<Vec<Vec<f32>>>,
+    // the heatmap texture has to be uploaded again
+    pub heatmap_dirty: bool,
+    history: History,
+    // the stroke the mouse is drawing right now, added to the history when it ends
+    current: Option<Stroke>,
+    // (edits shown so far, seconds since the last one) while replaying
+    replay: Option<(usize, f64)>,
+    // number keys store the drawing in the dataset with that label
+    pub labeling: bool,
+    dataset: Option<Dataset>,
+    // index of the dataset sample shown on the canvas
+    review: Option<usize>,
+    // fine-tuning only trains fc1
+    pub freeze_conv: bool,
+    // progress of the fine-tuning thread and where the result is saved
+    finetune: Option<(mpsc::Receiver<Progress>, PathBuf)>,
+    // last fine-tuning message, shown in the window
+    pub status: Option<String>,
+    models: Vec<PathBuf>,
+    model_index: usize,
+    cnn: CNN
+}
+
+// model files next to model.json, e.g. model.json and model-finetuned.json
+pub fn list_models(dir: &Path) -> Vec<PathBuf>{
+    let mut models: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok().map(|e| e.path())).collect(),
+        Err(_) => Vec::new()
+    };
+    models.retain(|path| {
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        name.starts_with("model") && name.ends_with(".json")
+    });
+    models.sort();
+    models
+}
+
+impl App{
+    pub fn new(canvas: Canvas, cnn: CNN, models: Vec<PathBuf>, model_index: usize) -> App{
+        App{
+            canvas: canvas,
+            erase: false,
+            drawing: false,
+            dirty: true,
+            stale: false,
+            show_preview: true,
+            preview: None,
+            probabilities: None,
+            explain: None,
+            heatmap: None,
+            heatmap_dirty: false,
+            history: History::new(),
+            current: None,
+            replay: None,
+            labeling: false,
+            dataset: None,
+            review: None,
+            freeze_conv: false,
+            finetune: None,
+            status: None,
+            models: models,
+            model_index: model_index,
+            cnn: cnn
+        }
+    }
+
+    pub fn model_name(&self) -> String{
+        self.models.get(self.model_index)
+            .and_then(|path| path.file_stem())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or("model".to_owned())
+    }
+
+    pub fn can_undo(&self) -> bool{
+        self.history.can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool{
+        self.history.can_redo()
+    }
+
+    fn changed(&mut self){
+        self.dirty = true;
+        self.stale = true;
+    }
+
+    // draws the canvas again from the history, after undo or redo
+    fn render(&mut self){
+        let count = self.history.edits().len();
+        self.history.render(&mut self.canvas, count);
+        self.changed();
+    }
+
+    pub fn apply(&mut self, control: Control){
+        if control != Control::Predict && control != Control::Brush{
+            self.stop_replay();
+        }
+        match control {
+            Control::Draw | Control::Erase | Control::Clear | Control::Undo | Control::Redo => self.stop_review(),
+            _ => {}
+        }
+        match control {
+            Control::Draw => self.erase = false,
+            Control::Erase => self.erase = true,
+            Control::Clear => {
+                // clear everything, back to draw
+                self.history.push(Edit::Clear);
+                self.canvas.clear();
+                self.erase = false;
+                self.changed();
+            },
+            Control::Predict => self.stale = true,
+            Control::Undo => {
+                if self.history.undo(){
+                    self.render();
+                }
+            },
+            Control::Redo => {
+                if self.history.redo(){
+                    self.render();
+                }
+            },
+            Control::Brush => {},
+            Control::Model => self.next_model(),
+            Control::Tune => {
+                let path = self.tuned_model_path();
+                if let Err(e) = self.start_finetune(&path){
+                    self.status = Some(format!("Error: {}", e));
+                }
+            },
+            Control::Label => self.labeling = !self.labeling,
+            Control::Review => {
+                if self.review.is_some(){
+                    self.stop_review();
+                }
+                else{
+                    self.start_review();
+                }
+            }
+        }
+    }
+
+    pub fn set_brush_radius(&mut self, radius: f32){
+        self.canvas.set_brush_radius(radius);
+    }
+
+    pub fn next_model(&mut self){
+        if self.models.is_empty(){
+            return;
+        }
+        let index = (self.model_index + 1) % self.models.len();
+        match ::load_cnn(&self.models[index]) {
+            Ok(cnn) => {
+                self.cnn = cnn;
+                self.model_index = index;
+                self.stale = true;
+            },
+            Err(e) => println!("Error: {}", e)
+        }
+    }
+
+    // off, then every explanation method in turn
+    pub fn toggle_explain(&mut self) -> Option<Method>{
+        self.explain = Method::next(self.explain);
+        self.stale = true;
+        self.explain
+    }
+
+    pub fn begin_stroke(&mut self, x: f32, y: f32){
+        self.stop_replay();
+        self.stop_review();
+        self.current = Some(Stroke::new(self.erase, self.canvas.brush_radius));
+        self.drawing = true;
+        self.continue_stroke(x, y);
+    }
+
+    pub fn continue_stroke(&mut self, x: f32, y: f32){
+        if self.drawing{
+            if let Some(ref mut stroke) = self.current{
+                stroke.points.push((x, y));
+            }
+            let erase = self.erase;
+            self.canvas.stroke_to(x, y, erase);
+            self.changed();
+        }
+    }
+
+    pub fn end_stroke(&mut self){
+        // the explanation was skipped while drawing, it catches up now
+        if self.drawing && self.explain.is_some(){
+            self.stale = true;
+        }
+        self.drawing = false;
+        self.canvas.end_stroke();
+        if let Some(stroke) = self.current.take(){
+            self.history.push(Edit::Stroke(stroke));
+        }
+    }
+
+    pub fn replaying(&self) -> bool{
+        self.replay.is_some()
+    }
+
+    // clears the canvas and draws the current drawing again one stroke at a time
+    pub fn start_replay(&mut self){
+        self.stop_review();
+        self.end_stroke();
+        self.canvas.clear();
+        self.replay = Some((0, 0.0));
+        self.changed();
+    }
+
+    pub fn stop_replay(&mut self){
+        if self.replay.take().is_some(){
+            self.render();
+        }
+    }
+
+    // advances the replay, dt is the time since the last call in seconds
+    pub fn step_replay(&mut self, dt: f64){
+        let (shown, elapsed) = match self.replay {
+            Some(replay) => replay,
+            None => return
+        };
+        let elapsed = elapsed + dt;
+        if elapsed < REPLAY_INTERVAL{
+            self.replay = Some((shown, elapsed));
+            return;
+        }
+        match self.history.edits().get(shown).cloned() {
+            Some(edit) => {
+                edit.apply(&mut self.canvas);
+                self.replay = Some((shown + 1, 0.0));
+                self.changed();
+            },
+            None => self.replay = None
+        }
+    }
+
+    pub fn load_history(&mut self, path: &Path) -> io::Result<()>{
+        let file = fs::File::open(path)?;
+        let json: serde_json::Value = serde_json::from_reader(file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.history = History::from_json(&json)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a drawing history"))?;
+        self.render();
+        Ok(())
+    }
+
+    pub fn save_history(&self, path: &Path) -> io::Result<()>{
+        if let Some(dir) = path.parent(){
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.history.to_json().to_string())
+    }
+
+    // loads a PNG or JPEG into the canvas as one edit, so it can be undone
+    // and drawn over like anything else
+    pub fn load_image(&mut self, path: &Path) -> io::Result<()>{
+        let pixels = imagefile::fit(&imagefile::load(path)?, self.canvas.height(), self.canvas.width());
+        self.stop_replay();
+        self.end_stroke();
+        self.history.push(Edit::Image(pixels));
+        self.render();
+        Ok(())
+    }
+
+    // writes `<stem>-28.png`, the 28x28 image the network gets before
+    // preprocessing, and `<stem>-full.png` at canvas resolution into `dir`
+    pub fn save_png(&self, dir: &Path, stem: &str) -> io::Result<Vec<PathBuf>>{
+        let small = dir.join(format!("{}-28.png", stem));
+        let full = dir.join(format!("{}-full.png", stem));
+        imagefile::save_png(&self.canvas.downsample(28, 28), &small)?;
+        imagefile::save_png(self.canvas.pixels(), &full)?;
+        Ok(vec![small, full])
+    }
+
+    // the kernels of the current model and what its layers make of the
+    // drawing, as one image in `dir`
+    pub fn save_features(&self, dir: &Path, stem: &str) -> io::Result<PathBuf>{
+        let path = dir.join(format!("{}.png", stem));
+        let image = self.mnist_image();
+        let drawn = preprocess::bounding_box(&image).is_some();
+        features::save(&features::sheet(&self.cnn, if drawn {Some(&image)} else {None}), &path)?;
+        Ok(path)
+    }
+
+    // the drawing preprocessed like MNIST, what gets stored in a dataset
+    pub fn mnist_image(&self) -> Vec<Vec<f32>>{
+        preprocess::mnist(&self.canvas.downsample(28, 28))
+    }
+
+    // appends the drawing with `label` to images-idx3-ubyte and
+    // labels-idx1-ubyte in `dir`, returns how many images the file holds now
+    pub fn append_idx(&self, dir: &Path, label: u8) -> io::Result<usize>{
+        let image = self.mnist_image();
+        if preprocess::bounding_box(&image).is_none(){
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the canvas is empty"));
+        }
+        fs::create_dir_all(dir)?;
+        idx::append(&dir.join("images-idx3-ubyte"), &dir.join("labels-idx1-ubyte"), &image, label)
+    }
+
+    // opens the dataset the number keys add to when labeling
+    pub fn open_dataset(&mut self, dir: &Path) -> io::Result<()>{
+        self.dataset = Some(Dataset::open(dir)?);
+        Ok(())
+    }
+
+    pub fn dataset(&self) -> Option<&Dataset>{
+        self.dataset.as_ref()
+    }
+
+    // index of the sample being reviewed
+    pub fn reviewing(&self) -> Option<usize>{
+        self.review
+    }
+
+    // a number key while labeling or reviewing: stores the drawing with that
+    // label and clears the canvas for the next one, or relabels the sample
+    // under review. Returns what happened for the console.
+    pub fn label(&mut self, label: u8) -> io::Result<String>{
+        self.update_prediction();
+        let predicted = self.probabilities.as_ref().map(|p| model::argmax(p) as u8);
+        let image = self.mnist_image();
+        let review = self.review;
+        let dataset = match self.dataset {
+            Some(ref mut dataset) => dataset,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no dataset is open"))
+        };
+        if let Some(index) = review{
+            dataset.relabel(index, label)?;
+            return Ok(format!("Sample {} is now a {}", index + 1, label));
+        }
+        if preprocess::bounding_box(&image).is_none(){
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the canvas is empty"));
+        }
+        let index = dataset.add(&image, label, predicted)?;
+        let message = match predicted {
+            Some(p) if p != label => format!("Sample {} stored as a {}, corrected from {}", index + 1, label, p),
+            _ => format!("Sample {} stored as a {}", index + 1, label)
+        };
+        self.apply(Control::Clear);
+        Ok(message)
+    }
+
+    // shows the latest sample on the canvas, the drawing comes back when
+    // the review ends
+    pub fn start_review(&mut self){
+        let count = self.dataset.as_ref().map_or(0, |dataset| dataset.len());
+        if count == 0{
+            return;
+        }
+        self.stop_replay();
+        self.end_stroke();
+        self.review = Some(count - 1);
+        self.show_sample();
+    }
+
+    pub fn stop_review(&mut self){
+        if self.review.take().is_some(){
+            self.render();
+        }
+    }
+
+    fn show_sample(&mut self){
+        let (index, dataset) = match (self.review, self.dataset.as_ref()) {
+            (Some(index), Some(dataset)) => (index, dataset),
+            _ => return
+        };
+        match dataset.image(index) {
+            Ok(image) => {
+                let pixels = imagefile::fit(&image, self.canvas.height(), self.canvas.width());
+                self.canvas.set_pixels(&pixels);
+            },
+            Err(e) => {
+                println!("Error: {}", e);
+                self.canvas.clear();
+            }
+        }
+        self.changed();
+    }
+
+    // moves through the samples, e.g. -1 for the previous one
+    pub fn review_step(&mut self, delta: isize){
+        let count = self.dataset.as_ref().map_or(0, |dataset| dataset.len()) as isize;
+        if let Some(index) = self.review{
+            self.review = Some((index as isize + delta).max(0).min(count - 1) as usize);
+            self.show_sample();
+        }
+    }
+
+    pub fn delete_sample(&mut self) -> io::Result<String>{
+        let index = match self.review {
+            Some(index) => index,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no sample is being reviewed"))
+        };
+        let remaining = match self.dataset {
+            Some(ref mut dataset) => {
+                dataset.remove(index)?;
+                dataset.len()
+            },
+            None => 0
+        };
+        if remaining == 0{
+            self.stop_review();
+        }
+        else{
+            self.review = Some(index.min(remaining - 1));
+            self.show_sample();
+        }
+        Ok(format!("Deleted sample {}, {} left", index + 1, remaining))
+    }
+
+    // next to the current model, so the model selector picks it up
+    fn tuned_model_path(&self) -> PathBuf{
+        let dir = self.models.get(self.model_index)
+            .and_then(|path| path.parent())
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or(PathBuf::from("./src/assets"));
+        dir.join(format!("model-finetuned-{}.json", ::timestamp()))
+    }
+
+    pub fn fine_tuning(&self) -> bool{
+        self.finetune.is_some()
+    }
+
+    // trains a copy of the current model on the dataset in the background,
+    // `poll_finetune` picks up the result and saves it to `path`. The
+    // checkpoints of every epoch go next to it, into <name>.checkpoints.
+    pub fn start_finetune(&mut self, path: &Path) -> io::Result<()>{
+        if self.finetune.is_some(){
+            return Err(io::Error::other("fine-tuning is already running"));
+        }
+        let (images, labels) = match self.dataset {
+            Some(ref dataset) => dataset.load_all()?,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no dataset is open"))
+        };
+        if images.len() < 5{
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "label at least 5 drawings first"));
+        }
+        let config = FineTune{
+            freeze_conv: self.freeze_conv,
+            checkpoints: Some(path.with_extension("checkpoints")),
+            ..FineTune::default()
+        };
+        self.status = Some(format!("Fine-tuning on {} samples", images.len()));
+        self.finetune = Some((config.spawn(self.cnn.clone(), images, labels), path.to_path_buf()));
+        Ok(())
+    }
+
+    // called every frame, switches to the tuned model once it is done and
+    // returns the final message
+    pub fn poll_finetune(&mut self) -> Option<String>{
+        let mut finished = None;
+        if let Some((ref receiver, ref path)) = self.finetune{
+            loop {
+                match receiver.try_recv() {
+                    Ok(Progress::Epoch(epoch, epochs, loss)) => {
+                        self.status = Some(format!("Fine-tuning epoch {}/{}, loss {:.3}", epoch, epochs, loss));
+                    },
+                    Ok(Progress::Done(cnn, report)) => {
+                        finished = Some((Ok((cnn, report)), path.clone()));
+                        break;
+                    },
+                    Ok(Progress::Failed(e)) => {
+                        finished = Some((Err(e), path.clone()));
+                        break;
+                    },
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        finished = Some((Err("fine-tuning stopped unexpectedly".to_owned()), path.clone()));
+                        break;
+                    }
+                }
+            }
+        }
+        let (result, path) = finished?;
+        self.finetune = None;
+        let message = match result {
+            Ok((cnn, report)) => {
+                let saved = path.parent().map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| fs::write(&path, cnn.to_json().to_string()));
+                let accuracy = format!("held-out accuracy {:.0}% -> {:.0}% on {} samples",
+                    report.before * 100.0, report.after * 100.0, report.holdout_samples);
+                self.cnn = cnn;
+                self.stale = true;
+                match saved {
+                    Ok(()) => {
+                        self.models.push(path.clone());
+                        self.model_index = self.models.len() - 1;
+                        format!("Fine-tuned, {}, saved {}", accuracy, path.display())
+                    },
+                    Err(e) => format!("Fine-tuned, {}, but saving failed: {}", accuracy, e)
+                }
+            },
+            Err(e) => format!("Error: {}", e)
+        };
+        self.status = Some(message.clone());
+        Some(message)
+    }
+
+    // redoes the prediction if anything changed since the last one
+    pub fn update_prediction(&mut self){
+        if !self.stale{
+            return;
+        }
+        self.stale = false;
+        self.heatmap_dirty = self.heatmap.is_some();
+        self.heatmap = None;
+        let small = self.canvas.downsample(28, 28);
+        let processed = preprocess::mnist(&small);
+        let placement = match preprocess::placement(&small) {
+            Some(placement) => placement,
+            None => {
+                self.preview = None;
+                self.probabilities = None;
+                return;
+            }
+        };
+        let input = preprocess::to_input(&processed);
+        let probabilities = self.cnn.probabilities(&input);
+        // an explanation costs up to 32 backward passes, too many for every
+        // frame of a stroke, so it waits for the stroke to end
+        if let (Some(method), false) = (self.explain, self.drawing){
+            let heatmap = explain::explain(&self.cnn, &input, model::argmax(&probabilities), method);
+            self.heatmap = Some(explain::project(&heatmap, &placement, 28, 28, self.canvas.height(), self.canvas.width()));
+            self.heatmap_dirty = true;
+        }
+        self.probabilities = Some(probabilities);
+        self.preview = Some(processed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    fn app() -> App{
+        let cnn = testutil::model();
+        App::new(Canvas::new(100, 100, 5.0), cnn, Vec::new(), 0)
+    }
+
+    #[test]
+    fn undo_redo_test(){
+        let mut app = app();
+        assert!(!app.can_undo() && !app.can_redo());
+        app.begin_stroke(50.0, 20.0);
+        app.continue_stroke(50.0, 80.0);
+        app.end_stroke();
+        let drawn = app.canvas.pixels().clone();
+
+        app.apply(Control::Clear);
+        assert!(app.canvas.pixels().iter().all(|row| row.iter().all(|&v| v == 0.0)));
+        app.apply(Control::Undo);
+        assert_eq!(app.canvas.pixels(), &drawn);
+        app.apply(Control::Undo);
+        assert!(!app.can_undo());
+        app.set_brush_radius(9.0);
+        app.apply(Control::Redo);
+        assert_eq!(app.canvas.pixels(), &drawn);
+        app.apply(Control::Redo);
+        assert!(app.canvas.pixels().iter().all(|row| row.iter().all(|&v| v == 0.0)));
+        assert!(!app.can_redo());
+        assert_eq!(app.canvas.brush_radius, 9.0);
+    }
+
+    #[test]
+    fn replay_test(){
+        let mut app = app();
+        app.begin_stroke(50.0, 20.0);
+        app.continue_stroke(50.0, 80.0);
+        app.end_stroke();
+        app.begin_stroke(20.0, 50.0);
+        app.continue_stroke(80.0, 50.0);
+        app.end_stroke();
+        let drawn = app.canvas.pixels().clone();
+
+        app.start_replay();
+        assert!(app.replaying());
+        assert!(app.canvas.pixels().iter().all(|row| row.iter().all(|&v| v == 0.0)));
+        app.step_replay(REPLAY_INTERVAL);
+        assert!(app.canvas.pixels()[50][50] > 0.0 && app.canvas.pixels()[50][25] == 0.0);
+        app.step_replay(REPLAY_INTERVAL);
+        assert_eq!(app.canvas.pixels(), &drawn);
+        app.step_replay(REPLAY_INTERVAL);
+        assert!(!app.replaying());
+    }
+
+    #[test]
+    fn image_files_test(){
+        let dir = std::env::temp_dir().join(format!("draw-rust-app-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut app = app();
+        assert!(app.append_idx(&dir, 1).is_err());
+        app.begin_stroke(50.0, 20.0);
+        app.continue_stroke(50.0, 80.0);
+        app.end_stroke();
+        let drawn = app.canvas.pixels().clone();
+
+        let paths = app.save_png(&dir, "one").unwrap();
+        assert_eq!(imagefile::load(&paths[0]).unwrap().len(), 28);
+        app.apply(Control::Clear);
+        app.load_image(&paths[1]).unwrap();
+        for i in 0..100{
+            for j in 0..100{
+                assert!((app.canvas.pixels()[i][j] - drawn[i][j]).abs() < 1.0 / 255.0, "pixel {} {}", i, j);
+            }
+        }
+        app.apply(Control::Undo);
+        assert!(app.canvas.pixels().iter().all(|row| row.iter().all(|&v| v == 0.0)));
+        app.apply(Control::Redo);
+
+        assert_eq!(app.append_idx(&dir, 1).unwrap(), 1);
+        assert_eq!(app.append_idx(&dir, 7).unwrap(), 2);
+        assert_eq!(idx::read_labels(&dir.join("labels-idx1-ubyte")).unwrap(), vec![1, 7]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn labeling_test(){
+        let dir = std::env::temp_dir().join(format!("draw-rust-labeling-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut app = app();
+        app.open_dataset(&dir).unwrap();
+        app.apply(Control::Label);
+        assert!(app.labeling);
+        assert!(app.label(1).is_err());
+
+        app.begin_stroke(50.0, 20.0);
+        app.continue_stroke(50.0, 80.0);
+        app.end_stroke();
+        app.label(1).unwrap();
+        // the canvas is cleared for the next drawing
+        assert!(app.canvas.pixels().iter().all(|row| row.iter().all(|&v| v == 0.0)));
+        app.begin_stroke(20.0, 50.0);
+        app.continue_stroke(80.0, 50.0);
+        app.end_stroke();
+        app.label(7).unwrap();
+        app.begin_stroke(30.0, 30.0);
+        app.continue_stroke(70.0, 70.0);
+        app.end_stroke();
+        assert_eq!(app.dataset().unwrap().counts(), vec![0, 1, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert!(app.dataset().unwrap().samples()[0].predicted.is_some());
+
+        // review starts at the newest sample and shows it on the canvas
+        let drawing = app.canvas.pixels().clone();
+        app.apply(Control::Review);
+        assert_eq!(app.reviewing(), Some(1));
+        assert!(app.canvas.pixels()[50][25] > 0.5 && app.canvas.pixels()[25][50] == 0.0);
+        app.review_step(-5);
+        assert_eq!(app.reviewing(), Some(0));
+        app.label(4).unwrap();
+        assert_eq!(app.dataset().unwrap().counts(), vec![0, 0, 0, 0, 1, 0, 0, 1, 0, 0]);
+        app.delete_sample().unwrap();
+        assert_eq!(app.reviewing(), Some(0));
+        assert_eq!(app.dataset().unwrap().samples()[0].label, 7);
+        app.apply(Control::Review);
+        assert_eq!(app.reviewing(), None);
+        assert_eq!(app.canvas.pixels(), &drawing);
+
+        app.apply(Control::Review);
+        app.delete_sample().unwrap();
+        assert_eq!(app.reviewing(), None);
+        assert!(Dataset::open(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finetune_test(){
+        let dir = std::env::temp_dir().join(format!("draw-rust-finetune-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut app = app();
+        app.open_dataset(&dir.join("dataset")).unwrap();
+        assert!(app.start_finetune(&dir.join("model-tuned.json")).is_err());
+        for i in 0..6{
+            let x = 30.0 + 8.0 * i as f32;
+            app.begin_stroke(x, 20.0);
+            app.continue_stroke(x, 80.0);
+            app.end_stroke();
+            app.label(1).unwrap();
+        }
+
+        app.freeze_conv = true;
+        app.start_finetune(&dir.join("model-tuned.json")).unwrap();
+        assert!(app.fine_tuning());
+        assert!(app.start_finetune(&dir.join("model-tuned.json")).is_err());
+        let message = loop {
+            if let Some(message) = app.poll_finetune(){
+                break message;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        };
+        assert!(message.starts_with("Fine-tuned"), "Sample: {}", message);
+        assert!(!app.fine_tuning());
+        assert_eq!(app.model_name(), "model-tuned");
+        assert!(fs::read_dir(dir.join("model-tuned.checkpoints")).unwrap().count() > 0);
+        let tuned = ::load_cnn(&dir.join("model-tuned.json")).unwrap();
+        assert_eq!(tuned.conv1.filter(), app.cnn.conv1.filter());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prediction_test(){
+        let mut app = app();
+        app.apply(Control::Predict);
+        app.update_prediction();
+        assert!(app.probabilities.is_none());
+
+        app.begin_stroke(50.0, 20.0);
+        app.continue_stroke(50.0, 80.0);
+        app.end_stroke();
+        app.update_prediction();
+        let probabilities = app.probabilities.clone().unwrap();
+        assert_eq!(probabilities.len(), 10);
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+        assert!(app.preview.is_some());
+    }
+
+    #[test]
+    fn save_features_test(){
+        let dir = std::env::temp_dir().join(format!("draw-rust-features-{}", std::process::id()));
+        let mut app = app();
+        let empty = image::open(app.save_features(&dir, "empty").unwrap()).unwrap();
+        app.begin_stroke(50.0, 20.0);
+        app.continue_stroke(50.0, 80.0);
+        app.end_stroke();
+        let drawn = image::open(app.save_features(&dir, "drawn").unwrap()).unwrap();
+        // the activations come below the kernels
+        assert_eq!(drawn.width(), empty.width().max(drawn.width()));
+        assert!(drawn.height() > empty.height());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn explain_test(){
+        let mut app = app();
+        app.begin_stroke(50.0, 20.0);
+        app.continue_stroke(50.0, 80.0);
+        app.end_stroke();
+        app.update_prediction();
+        assert!(app.heatmap.is_none());
+
+        assert_eq!(app.toggle_explain(), Some(Method::GradCam));
+        app.update_prediction();
+        let heatmap = app.heatmap.clone().unwrap();
+        assert!(app.heatmap_dirty);
+        assert_eq!((heatmap.len(), heatmap[0].len()), (100, 100));
+        // the heat is on the stroke, the far corner is outside the network input
+        assert!(heatmap[50].iter().any(|&v| v > 0.0));
+        assert_eq!(heatmap[5][95], 0.0);
+
+        // only the prediction follows the pen, the heatmap waits for the stroke
+        app.begin_stroke(30.0, 20.0);
+        app.continue_stroke(30.0, 80.0);
+        app.update_prediction();
+        assert!(app.heatmap.is_none() && app.probabilities.is_some());
+        app.end_stroke();
+        app.update_prediction();
+        assert!(app.heatmap.is_some());
+
+        while app.toggle_explain().is_some(){}
+        app.update_prediction();
+        assert!(app.heatmap.is_none());
+    }
+}
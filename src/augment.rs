@@ -0,0 +1,354 @@
+use rng::Rng;
+
+// # Augment
+// Random distortions that make MNIST digits look more like the ones drawn
+// on the canvas. Images are (rows, cols) grids in [0, 1], as returned by
+// the idx loader. Every random choice comes from the Rng that is passed in,
+// so the same seed always gives the same images.
+#[derive(Debug, Clone)]
+pub struct Augment{
+    // max rotation in degrees, either direction
+    pub rotation: f32,
+    // the digit is scaled by a factor in [1 - scale, 1 + scale]
+    pub scale: f32,
+    // max horizontal shear factor, either direction
+    pub shear: f32,
+    // max translation in pixels, either direction
+    pub translate: f32,
+    // elastic distortion strength and smoothness, alpha 0 disables it
+    pub elastic_alpha: f32,
+    pub elastic_sigma: f32,
+    // chance of thickening and thinning the strokes
+    pub thicken: f32,
+    pub thin: f32,
+    // standard deviation of the added gaussian noise
+    pub noise: f32,
+    // chance of erasing a random rectangle
+    pub erase: f32
+}
+
+impl Default for Augment{
+    fn default() -> Augment{
+        Augment{
+            rotation: 15.0,
+            scale: 0.15,
+            shear: 0.2,
+            translate: 3.0,
+            elastic_alpha: 8.0,
+            elastic_sigma: 3.0,
+            thicken: 0.3,
+            thin: 0.1,
+            noise: 0.05,
+            erase: 0.1
+        }
+    }
+}
+
+impl Augment{
+    // no distortion at all, useful as a starting point
+    pub fn none() -> Augment{
+        Augment{
+            rotation: 0.0,
+            scale: 0.0,
+            shear: 0.0,
+            translate: 0.0,
+            elastic_alpha: 0.0,
+            elastic_sigma: 0.0,
+            thicken: 0.0,
+            thin: 0.0,
+            noise: 0.0,
+            erase: 0.0
+        }
+    }
+
+    pub fn apply(&self, image: &Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Vec<f32>>{
+        // the per-image parameters are always drawn, even for disabled steps,
+        // so changing one of the ranges does not shift the others
+        let rotation = rng.uniform(-self.rotation, self.rotation).to_radians();
+        let scale = 1.0 + rng.uniform(-self.scale, self.scale);
+        let shear = rng.uniform(-self.shear, self.shear);
+        let tx = rng.uniform(-self.translate, self.translate);
+        let ty = rng.uniform(-self.translate, self.translate);
+        let morphology = rng.next_f32();
+        let erase = rng.next_f32();
+
+        let mut output = affine(image, rotation, scale, shear, tx, ty);
+        if self.elastic_alpha > 0.0{
+            output = elastic(&output, self.elastic_alpha, self.elastic_sigma, rng);
+        }
+        if morphology < self.thicken{
+            output = dilate(&output);
+        }
+        else if morphology < self.thicken + self.thin{
+            output = erode(&output);
+        }
+        if self.noise > 0.0{
+            output = gaussian_noise(&output, self.noise, rng);
+        }
+        if erase < self.erase{
+            output = random_erase(&output, 0.02, 0.15, rng);
+        }
+        output
+    }
+}
+
+// bilinear sample, everything outside the image is background
+fn sample(image: &Vec<Vec<f32>>, row: f32, col: f32) -> f32{
+    let r0 = row.floor();
+    let c0 = col.floor();
+    let dr = row - r0;
+    let dc = col - c0;
+    let pixel = |r: f32, c: f32| -> f32 {
+        if r < 0.0 || c < 0.0 || r >= image.len() as f32 || c >= image[0].len() as f32{
+            0.0
+        }
+        else{
+            image[r as usize][c as usize]
+        }
+    };
+    pixel(r0, c0) * (1.0 - dr) * (1.0 - dc)
+        + pixel(r0, c0 + 1.0) * (1.0 - dr) * dc
+        + pixel(r0 + 1.0, c0) * dr * (1.0 - dc)
+        + pixel(r0 + 1.0, c0 + 1.0) * dr * dc
+}
+
+// rotation (radians), scale and shear around the image center, followed by
+// a translation of (tx, ty) pixels to the right and down
+pub fn affine(image: &Vec<Vec<f32>>, rotation: f32, scale: f32, shear: f32, tx: f32, ty: f32) -> Vec<Vec<f32>>{
+    let rows = image.len();
+    let cols = image[0].len();
+    let center_row = (rows as f32 - 1.0) / 2.0;
+    let center_col = (cols as f32 - 1.0) / 2.0;
+
+    // forward transform is [x, y] -> scale * R * S * [x, y] + t, so each output
+    // pixel is mapped back through the inverse to find where it comes from
+    let (sin, cos) = rotation.sin_cos();
+    let mut output = vec![vec![0.0; cols]; rows];
+    for i in 0..rows{
+        for j in 0..cols{
+            let x = (j as f32 - center_col - tx) / scale;
+            let y = (i as f32 - center_row - ty) / scale;
+            // undo the rotation
+            let xr = cos * x + sin * y;
+            let yr = -sin * x + cos * y;
+            // undo the shear
+            let xs = xr - shear * yr;
+            output[i][j] = sample(image, yr + center_row, xs + center_col);
+        }
+    }
+    output
+}
+
+fn gaussian_kernel(sigma: f32) -> Vec<f32>{
+    // no blur at all, also for a NaN sigma
+    if sigma.is_nan() || sigma <= 0.0{
+        return vec![1.0];
+    }
+    let radius = (sigma * 3.0).ceil().max(1.0) as i32;
+    let mut kernel: Vec<f32> = (-radius..radius + 1)
+        .map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    for value in kernel.iter_mut(){
+        *value /= sum;
+    }
+    kernel
+}
+
+// separable gaussian blur with clamped borders
+pub fn gaussian_blur(image: &Vec<Vec<f32>>, sigma: f32) -> Vec<Vec<f32>>{
+    let kernel = gaussian_kernel(sigma);
+    let radius = (kernel.len() / 2) as i32;
+    let rows = image.len() as i32;
+    let cols = image[0].len() as i32;
+
+    let mut horizontal = vec![vec![0.0; cols as usize]; rows as usize];
+    for i in 0..rows{
+        for j in 0..cols{
+            for k in 0..kernel.len() as i32{
+                let c = (j + k - radius).max(0).min(cols - 1);
+                horizontal[i as usize][j as usize] += image[i as usize][c as usize] * kernel[k as usize];
+            }
+        }
+    }
+    let mut output = vec![vec![0.0; cols as usize]; rows as usize];
+    for i in 0..rows{
+        for j in 0..cols{
+            for k in 0..kernel.len() as i32{
+                let r = (i + k - radius).max(0).min(rows - 1);
+                output[i as usize][j as usize] += horizontal[r as usize][j as usize] * kernel[k as usize];
+            }
+        }
+    }
+    output
+}
+
+// elastic distortion (Simard et al. 2003): random displacement fields are
+// smoothed with a gaussian of width sigma and scaled by alpha
+pub fn elastic(image: &Vec<Vec<f32>>, alpha: f32, sigma: f32, rng: &mut Rng) -> Vec<Vec<f32>>{
+    let rows = image.len();
+    let cols = image[0].len();
+    let mut dx = vec![vec![0.0; cols]; rows];
+    let mut dy = vec![vec![0.0; cols]; rows];
+    for i in 0..rows{
+        for j in 0..cols{
+            dx[i][j] = rng.uniform(-1.0, 1.0);
+            dy[i][j] = rng.uniform(-1.0, 1.0);
+        }
+    }
+    let dx = gaussian_blur(&dx, sigma);
+    let dy = gaussian_blur(&dy, sigma);
+
+    let mut output = vec![vec![0.0; cols]; rows];
+    for i in 0..rows{
+        for j in 0..cols{
+            output[i][j] = sample(image, i as f32 + alpha * dy[i][j], j as f32 + alpha * dx[i][j]);
+        }
+    }
+    output
+}
+
+// 3x3 grayscale morphology, max over the neighbourhood to dilate and min to erode
+fn morphology(image: &Vec<Vec<f32>>, dilate: bool) -> Vec<Vec<f32>>{
+    let rows = image.len() as i32;
+    let cols = image[0].len() as i32;
+    let mut output = vec![vec![0.0; cols as usize]; rows as usize];
+    for i in 0..rows{
+        for j in 0..cols{
+            let mut value = image[i as usize][j as usize];
+            for di in -1..2{
+                for dj in -1..2{
+                    let (r, c) = (i + di, j + dj);
+                    // outside of the image counts as background
+                    let neighbour = if r < 0 || c < 0 || r >= rows || c >= cols {0.0} else {image[r as usize][c as usize]};
+                    value = if dilate {value.max(neighbour)} else {value.min(neighbour)};
+                }
+            }
+            output[i as usize][j as usize] = value;
+        }
+    }
+    output
+}
+
+pub fn dilate(image: &Vec<Vec<f32>>) -> Vec<Vec<f32>>{
+    morphology(image, true)
+}
+
+pub fn erode(image: &Vec<Vec<f32>>) -> Vec<Vec<f32>>{
+    morphology(image, false)
+}
+
+pub fn gaussian_noise(image: &Vec<Vec<f32>>, std: f32, rng: &mut Rng) -> Vec<Vec<f32>>{
+    let mut output = image.clone();
+    for i in 0..output.len(){
+        for j in 0..output[i].len(){
+            output[i][j] = (output[i][j] + std * rng.normal()).clamp(0.0, 1.0);
+        }
+    }
+    output
+}
+
+// clears a random rectangle covering between min_area and max_area of the image
+pub fn random_erase(image: &Vec<Vec<f32>>, min_area: f32, max_area: f32, rng: &mut Rng) -> Vec<Vec<f32>>{
+    let rows = image.len();
+    let cols = image[0].len();
+    let area = rng.uniform(min_area, max_area) * (rows * cols) as f32;
+    let aspect = rng.uniform(0.3, 1.0 / 0.3);
+    let height = ((area * aspect).sqrt().round() as usize).max(1).min(rows);
+    let width = ((area / aspect).sqrt().round() as usize).max(1).min(cols);
+    let top = rng.below(rows - height + 1);
+    let left = rng.below(cols - width + 1);
+
+    let mut output = image.clone();
+    for i in top..top + height{
+        for j in left..left + width{
+            output[i][j] = 0.0;
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn digit() -> Vec<Vec<f32>>{
+        let mut image = vec![vec![0.0; 28]; 28];
+        for i in 6..22{
+            for j in 12..16{
+                image[i][j] = 1.0;
+            }
+        }
+        image
+    }
+
+    fn mass(image: &Vec<Vec<f32>>) -> f32{
+        image.iter().map(|row| row.iter().sum::<f32>()).sum()
+    }
+
+    #[test]
+    fn affine_identity_test(){
+        let image = digit();
+        assert_eq!(affine(&image, 0.0, 1.0, 0.0, 0.0, 0.0), image);
+    }
+
+    #[test]
+    fn gaussian_blur_test(){
+        let image = digit();
+        assert_eq!(gaussian_blur(&image, 0.0), image);
+        assert_eq!(gaussian_blur(&image, -1.0), image);
+        let blurred = gaussian_blur(&image, 1.0);
+        assert!(blurred.iter().all(|row| row.iter().all(|v| v.is_finite())));
+        assert!((mass(&blurred) - mass(&image)).abs() < 1e-3, "Sample: {} {}", mass(&blurred), mass(&image));
+    }
+
+    #[test]
+    fn affine_translate_test(){
+        let image = digit();
+        let output = affine(&image, 0.0, 1.0, 0.0, 2.0, -1.0);
+        assert_eq!(output[5][14], 1.0);
+        assert_eq!(output[20][17], 1.0);
+        assert_eq!(output[21][14], 0.0);
+        assert_eq!(output[10][13], 0.0);
+    }
+
+    #[test]
+    fn affine_rotation_test(){
+        // a quarter turn makes the vertical bar horizontal
+        let image = digit();
+        let output = affine(&image, std::f32::consts::FRAC_PI_2, 1.0, 0.0, 0.0, 0.0);
+        assert!(output[13][8] > 0.99 && output[13][19] > 0.99, "Sample: {:?}", output[13]);
+        assert!(output[8][13] < 0.01, "Sample: {:?}", output[8]);
+    }
+
+    #[test]
+    fn morphology_test(){
+        let image = digit();
+        assert!(mass(&dilate(&image)) > mass(&image));
+        assert!(mass(&erode(&image)) < mass(&image));
+        assert_eq!(erode(&dilate(&image)), image);
+    }
+
+    #[test]
+    fn augment_deterministic_test(){
+        let image = digit();
+        let augment = Augment::default();
+        let a = augment.apply(&image, &mut Rng::new(3));
+        let b = augment.apply(&image, &mut Rng::new(3));
+        let c = augment.apply(&image, &mut Rng::new(4));
+        assert_eq!(a, b);
+        assert!(a != c);
+        for row in a.iter(){
+            for &value in row.iter(){
+                assert!((0.0..=1.0).contains(&value), "Sample: {}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn augment_none_test(){
+        let image = digit();
+        assert_eq!(Augment::none().apply(&image, &mut Rng::new(1)), image);
+    }
+}
@@ -0,0 +1,25 @@
+extern crate draw_rust;
+
+// headless binary, works without the `gui` feature and without a display:
+// `draw-rust-cli predict <files or dirs>`, `serve`, `quantize`, `qat`, `half`, `prune`, `distill`, `explain`, `features`, `activations` or `embed`
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let code = match draw_rust::dispatch(&args) {
+        Some(code) => code,
+        None => {
+            eprintln!("usage: draw-rust-cli predict [--format text|json|csv] [--model model.json] <files or dirs>");
+            eprintln!("       draw-rust-cli serve [--addr 127.0.0.1:8080] [--model model.json]");
+            eprintln!("       draw-rust-cli quantize --images <idx> --labels <idx> [--model model.json] [--calibration 500] [--output model-int8.bin]");
+            eprintln!("       draw-rust-cli qat --images <idx> --labels <idx> [--model model.json] [--epochs 3] [--lr 0.001] [--batch-size 32] [--output model-int8.bin]");
+            eprintln!("       draw-rust-cli half [--precision f16|bf16] [--model model.json] [--output model-f16.bin] [--images <idx> --labels <idx> [--epochs 0]]");
+            eprintln!("       draw-rust-cli prune --images <idx> --labels <idx> [--model model.json] [--fc-sparsity 0.8] [--conv1-filters 1] [--conv2-filters 2] [--steps 4] [--epochs 1] [--output model-pruned.json]");
+            eprintln!("       draw-rust-cli distill --images <idx> --labels <idx> [--teacher model.json] [--conv1 2] [--conv2 4] [--temperature 4] [--alpha 0.7] [--epochs 5] [--output model-student.json]");
+            eprintln!("       draw-rust-cli explain [--method grad-cam|saliency|integrated-gradients] [--class 0-9] [--model model.json] [--scale 10] [--output heatmap.png] <images>");
+            eprintln!("       draw-rust-cli features [--model model.json] [--output features.png] [<image>]");
+            eprintln!("       draw-rust-cli activations [--model model.json] [--images <idx>] [<images or dirs>]");
+            eprintln!("       draw-rust-cli embed --images <idx> --labels <idx> [--model model.json] [--method pca|tsne] [--count 1000] [--perplexity 30] [--iterations 1000] [--output embedding.png|.csv] [--embeddings embeddings.csv]");
+            2
+        }
+    };
+    std::process::exit(code);
+}
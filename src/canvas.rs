@@ -0,0 +1,162 @@
+use preprocess;
+
+// # Canvas
+// Grayscale drawing surface at window resolution. The brush is a disc with
+// an anti-aliased edge, and the drawing is only reduced to 28x28 (with area
+// averaging) when it is handed to the network, so strokes end up with the
+// same soft edges as the MNIST digits.
+//
+// pixels are (rows, cols) in [0, 1], 1 is ink.
+#[derive(Debug, Clone)]
+pub struct Canvas{
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec<f32>>,
+    pub brush_radius: f32,
+    last: Option<(f32, f32)>
+}
+
+pub const MIN_BRUSH_RADIUS: f32 = 2.0;
+pub const MAX_BRUSH_RADIUS: f32 = 60.0;
+
+// coverage of a pixel whose center is `dist` away from the brush center,
+// the outer `soft` pixels of the disc fade out smoothly
+pub fn brush_coverage(dist: f32, radius: f32) -> f32{
+    let soft = (radius * 0.3).max(1.5);
+    let t = ((radius - dist) / soft).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl Canvas{
+    pub fn new(width: usize, height: usize, brush_radius: f32) -> Canvas{
+        Canvas{
+            width: width,
+            height: height,
+            pixels: vec![vec![0.0; width]; height],
+            brush_radius: brush_radius,
+            last: None
+        }
+    }
+
+    pub fn width(&self) -> usize{
+        self.width
+    }
+
+    pub fn height(&self) -> usize{
+        self.height
+    }
+
+    pub fn pixels(&self) -> &Vec<Vec<f32>>{
+        &self.pixels
+    }
+
+    pub fn clear(&mut self){
+        self.pixels = vec![vec![0.0; self.width]; self.height];
+        self.last = None;
+    }
+
+    // replaces the drawing, e.g. with a loaded image already fitted to the canvas size
+    pub fn set_pixels(&mut self, pixels: &Vec<Vec<f32>>){
+        for i in 0..self.height{
+            for j in 0..self.width{
+                self.pixels[i][j] = pixels.get(i).and_then(|row| row.get(j)).cloned().unwrap_or(0.0);
+            }
+        }
+        self.last = None;
+    }
+
+    pub fn set_brush_radius(&mut self, radius: f32){
+        self.brush_radius = radius.clamp(MIN_BRUSH_RADIUS, MAX_BRUSH_RADIUS);
+    }
+
+    // stamps the brush once at (x, y) window coordinates
+    pub fn dab(&mut self, x: f32, y: f32, erase: bool){
+        let radius = self.brush_radius;
+        let top = ((y - radius).floor().max(0.0) as usize).min(self.height);
+        let bottom = ((y + radius).ceil().max(0.0) as usize + 1).min(self.height);
+        let left = ((x - radius).floor().max(0.0) as usize).min(self.width);
+        let right = ((x + radius).ceil().max(0.0) as usize + 1).min(self.width);
+        for i in top..bottom{
+            for j in left..right{
+                // distance from the pixel center
+                let dx = j as f32 + 0.5 - x;
+                let dy = i as f32 + 0.5 - y;
+                let coverage = brush_coverage((dx * dx + dy * dy).sqrt(), radius);
+                if erase{
+                    self.pixels[i][j] = self.pixels[i][j].min(1.0 - coverage);
+                }
+                else{
+                    self.pixels[i][j] = self.pixels[i][j].max(coverage);
+                }
+            }
+        }
+    }
+
+    // continues the current stroke to (x, y), filling in the gap since the
+    // last mouse event so fast movements still give a solid line
+    pub fn stroke_to(&mut self, x: f32, y: f32, erase: bool){
+        let (x0, y0) = self.last.unwrap_or((x, y));
+        let length = ((x - x0) * (x - x0) + (y - y0) * (y - y0)).sqrt();
+        let steps = (length / (self.brush_radius * 0.25).max(1.0)).ceil().max(1.0) as usize;
+        for step in 1..steps + 1{
+            let t = step as f32 / steps as f32;
+            self.dab(x0 + (x - x0) * t, y0 + (y - y0) * t, erase);
+        }
+        self.last = Some((x, y));
+    }
+
+    pub fn end_stroke(&mut self){
+        self.last = None;
+    }
+
+    // (rows, cols) grid for the network, e.g. downsample(28, 28)
+    pub fn downsample(&self, rows: usize, cols: usize) -> Vec<Vec<f32>>{
+        preprocess::resize_area(&self.pixels, rows, cols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn brush_coverage_test(){
+        assert_eq!(brush_coverage(0.0, 10.0), 1.0);
+        assert_eq!(brush_coverage(10.0, 10.0), 0.0);
+        let edge = brush_coverage(8.5, 10.0);
+        assert!(edge > 0.0 && edge < 1.0, "Sample: {}", edge);
+    }
+
+    #[test]
+    fn stroke_test(){
+        let mut canvas = Canvas::new(100, 100, 5.0);
+        canvas.stroke_to(10.0, 50.0, false);
+        canvas.stroke_to(90.0, 50.0, false);
+        canvas.end_stroke();
+        // the whole line is covered, not just the two end points
+        for x in 10..90{
+            assert_eq!(canvas.pixels()[49][x], 1.0, "gap at {}", x);
+        }
+        assert_eq!(canvas.pixels()[30][50], 0.0);
+
+        canvas.stroke_to(50.0, 50.0, true);
+        canvas.end_stroke();
+        assert_eq!(canvas.pixels()[49][50], 0.0);
+        assert_eq!(canvas.pixels()[49][20], 1.0);
+    }
+
+    #[test]
+    fn downsample_test(){
+        let mut canvas = Canvas::new(280, 280, 12.0);
+        canvas.stroke_to(140.0, 40.0, false);
+        canvas.stroke_to(140.0, 240.0, false);
+        let image = canvas.downsample(28, 28);
+        assert_eq!(image.len(), 28);
+        assert_eq!(image[0].len(), 28);
+        assert!(image[14][13] > 0.9 || image[14][14] > 0.9, "Sample: {:?}", image[14]);
+        assert_eq!(image[14][0], 0.0);
+        // anti-aliased edges leave partially covered pixels
+        assert!(image[14].iter().any(|&v| v > 0.0 && v < 1.0), "Sample: {:?}", image[14]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rng::Rng;
+use train::{Sgd, StepLR};
+
+// # Checkpoint
+// Everything needed to resume a training run exactly where it stopped.
+// `weights` uses the same layout and names as model.json, so a checkpoint
+// can be loaded with the same code as the exported model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint{
+    pub epoch: u32,
+    pub step: u64,
+    pub weights: serde_json::Value,
+    pub optimizer: Sgd,
+    pub scheduler: StepLR,
+    pub rng: Rng,
+    pub val_accuracy: Option<f32>
+}
+
+// # CheckpointManager
+// Writes checkpoints into `dir` as checkpoint-<step>.json and removes old
+// ones. The newest `keep_last` checkpoints are always kept, as well as the
+// `keep_best` checkpoints with the highest validation accuracy.
+#[derive(Debug)]
+pub struct CheckpointManager{
+    dir: PathBuf,
+    keep_last: usize,
+    keep_best: usize
+}
+
+fn invalid_data(message: &str) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+impl Checkpoint{
+    pub fn to_json(&self) -> serde_json::Value{
+        json!({
+            "epoch": self.epoch,
+            "step": self.step,
+            "weights": self.weights,
+            "optimizer": self.optimizer.to_json(),
+            "scheduler": self.scheduler.to_json(),
+            // u64 is stored as a string, JSON readers are not required to keep it exact
+            "rng": self.rng.state().to_string(),
+            "val_accuracy": self.val_accuracy
+        })
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> io::Result<Checkpoint>{
+        let rng_state: u64 = json["rng"].as_str()
+            .and_then(|state| state.parse().ok())
+            .ok_or_else(|| invalid_data("checkpoint is missing the rng state"))?;
+        Ok(Checkpoint{
+            epoch: json["epoch"].as_u64()
+                .ok_or_else(|| invalid_data("checkpoint is missing the epoch"))? as u32,
+            step: json["step"].as_u64()
+                .ok_or_else(|| invalid_data("checkpoint is missing the step"))?,
+            weights: json["weights"].clone(),
+            optimizer: Sgd::from_json(&json["optimizer"])
+                .ok_or_else(|| invalid_data("checkpoint has an invalid optimizer state"))?,
+            scheduler: StepLR::from_json(&json["scheduler"])
+                .ok_or_else(|| invalid_data("checkpoint has an invalid scheduler state"))?,
+            rng: Rng::from_state(rng_state),
+            val_accuracy: json["val_accuracy"].as_f64().map(|acc| acc as f32)
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()>{
+        // write to a temporary file first so a crash never leaves half a checkpoint
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, self.to_json().to_string())?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Checkpoint>{
+        let file = fs::File::open(path)?;
+        let json: serde_json::Value = serde_json::from_reader(file)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        Checkpoint::from_json(&json)
+    }
+}
+
+impl CheckpointManager{
+    pub fn new(dir: &Path, keep_last: usize, keep_best: usize) -> io::Result<CheckpointManager>{
+        fs::create_dir_all(dir)?;
+        Ok(CheckpointManager{
+            dir: dir.to_path_buf(),
+            keep_last: keep_last,
+            keep_best: keep_best
+        })
+    }
+
+    pub fn path_for(&self, step: u64) -> PathBuf{
+        self.dir.join(format!("checkpoint-{:010}.json", step))
+    }
+
+    pub fn save(&self, checkpoint: &Checkpoint) -> io::Result<PathBuf>{
+        let path = self.path_for(checkpoint.step);
+        checkpoint.save(&path)?;
+        self.prune()?;
+        Ok(path)
+    }
+
+    // (step, val_accuracy, path) for every checkpoint in the directory, oldest first
+    pub fn list(&self) -> io::Result<Vec<(u64, Option<f32>, PathBuf)>>{
+        let mut checkpoints = Vec::new();
+        for entry in fs::read_dir(&self.dir)?{
+            let path = entry?.path();
+            let step = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if name.starts_with("checkpoint-") && name.ends_with(".json") => {
+                    match name["checkpoint-".len()..name.len() - ".json".len()].parse::<u64>() {
+                        Ok(step) => step,
+                        Err(_) => continue
+                    }
+                },
+                _ => continue
+            };
+            let checkpoint = Checkpoint::load(&path)?;
+            checkpoints.push((step, checkpoint.val_accuracy, path));
+        }
+        checkpoints.sort_by_key(|c| c.0);
+        Ok(checkpoints)
+    }
+
+    pub fn latest(&self) -> io::Result<Option<Checkpoint>>{
+        match self.list()?.pop() {
+            Some((_, _, path)) => Ok(Some(Checkpoint::load(&path)?)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn best(&self) -> io::Result<Option<Checkpoint>>{
+        let mut best: Option<(f32, PathBuf)> = None;
+        for (_, acc, path) in self.list()?{
+            // a NaN accuracy is never the best
+            if let Some(acc) = acc.filter(|acc| !acc.is_nan()){
+                if best.as_ref().is_none_or(|b| acc > b.0){
+                    best = Some((acc, path));
+                }
+            }
+        }
+        match best {
+            Some((_, path)) => Ok(Some(Checkpoint::load(&path)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn prune(&self) -> io::Result<()>{
+        let checkpoints = self.list()?;
+        let mut keep = vec![false; checkpoints.len()];
+        for i in checkpoints.len().saturating_sub(self.keep_last)..checkpoints.len(){
+            keep[i] = true;
+        }
+
+        let mut by_accuracy: Vec<usize> = (0..checkpoints.len())
+            .filter(|&i| checkpoints[i].1.is_some_and(|acc| !acc.is_nan()))
+            .collect();
+        by_accuracy.sort_by(|&a, &b| checkpoints[b].1.unwrap().total_cmp(&checkpoints[a].1.unwrap()));
+        for &i in by_accuracy.iter().take(self.keep_best){
+            keep[i] = true;
+        }
+
+        for i in 0..checkpoints.len(){
+            if !keep[i]{
+                fs::remove_file(&checkpoints[i].2)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn sample(step: u64, val_accuracy: Option<f32>) -> Checkpoint{
+        let mut optimizer = Sgd::new(0.01, 0.9, 0.0);
+        let mut params = vec![0.25, -0.5];
+        optimizer.step("fc1.bias", params.iter_mut().collect(), &vec![0.1, 0.2]);
+        let mut rng = Rng::new(step + 1);
+        rng.next_u64();
+        Checkpoint{
+            epoch: step as u32 / 10,
+            step: step,
+            weights: json!({"fc1.weight": [[0.1, 0.2]], "fc1.bias": params}),
+            optimizer: optimizer,
+            scheduler: StepLR::new(0.01, 5, 0.1),
+            rng: rng,
+            val_accuracy: val_accuracy
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("draw-rust-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn checkpoint_round_trip_test(){
+        let dir = temp_dir("checkpoint-round-trip");
+        let manager = CheckpointManager::new(&dir, 3, 0).unwrap();
+        let checkpoint = sample(u64::MAX - 1, Some(0.75));
+        manager.save(&checkpoint).unwrap();
+        let restored = manager.latest().unwrap().unwrap();
+        assert_eq!(restored, checkpoint);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_retention_test(){
+        let dir = temp_dir("checkpoint-retention");
+        let manager = CheckpointManager::new(&dir, 2, 1).unwrap();
+        let accuracies = [0.5, 0.9, 0.6, 0.7, 0.8];
+        for i in 0..accuracies.len(){
+            manager.save(&sample(i as u64 * 100, Some(accuracies[i]))).unwrap();
+        }
+        let steps: Vec<u64> = manager.list().unwrap().iter().map(|c| c.0).collect();
+        assert_eq!(steps, vec![100, 300, 400], "Sample: {:?}", steps);
+        assert_eq!(manager.best().unwrap().unwrap().step, 100);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // NaN counts as the worst accuracy instead of panicking
+        let manager = CheckpointManager::new(&dir, 1, 1).unwrap();
+        for (i, &acc) in [f32::NAN, 0.4, f32::NAN, 0.3].iter().enumerate(){
+            manager.save(&sample(i as u64, Some(acc))).unwrap();
+        }
+        let steps: Vec<u64> = manager.list().unwrap().iter().map(|c| c.0).collect();
+        assert_eq!(steps, vec![1, 3], "Sample: {:?}", steps);
+        assert_eq!(manager.best().unwrap().unwrap().step, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use imagefile;
+use model;
+use preprocess;
+use CNN;
+
+// # Command line
+// `draw-rust predict [--format text|json|csv] [--model model.json] <files or dirs>`
+// classifies image files without opening a window. Every image goes through
+// the same MNIST preprocessing as the canvas, so a photo of a digit on paper
+// or a 28x28 MNIST sample both work.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format{
+    Text,
+    Json,
+    Csv
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prediction{
+    pub path: PathBuf,
+    pub class: usize,
+    pub probabilities: Vec<f32>
+}
+
+const EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "pgm", "pnm"];
+
+const USAGE: &str = "usage: draw-rust predict [--format text|json|csv] [--model model.json] <files or dirs>";
+
+impl Format{
+    pub fn parse(name: &str) -> Option<Format>{
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None
+        }
+    }
+}
+
+fn is_image(path: &Path) -> bool{
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+// files are taken as given, directories are searched recursively for images
+pub fn collect_files(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>>{
+    let mut files = Vec::new();
+    for path in paths.iter(){
+        if path.is_dir(){
+            let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .collect();
+            entries.sort();
+            for entry in entries{
+                if entry.is_dir(){
+                    files.extend(collect_files(&[entry])?);
+                }
+                else if is_image(&entry){
+                    files.push(entry);
+                }
+            }
+        }
+        else{
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+pub fn predict_file(cnn: &CNN, path: &Path) -> io::Result<Prediction>{
+    let image = preprocess::mnist(&imagefile::load(path)?);
+    let probabilities = cnn.probabilities(&preprocess::to_input(&image));
+    Ok(Prediction{
+        path: path.to_path_buf(),
+        class: model::argmax(&probabilities),
+        probabilities: probabilities
+    })
+}
+
+fn csv_field(value: &str) -> String{
+    if value.contains(',') || value.contains('"') || value.contains('\n'){
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }
+    else{
+        value.to_owned()
+    }
+}
+
+pub fn format(predictions: &Vec<Prediction>, format: Format) -> String{
+    match format {
+        Format::Text => {
+            let mut output = String::new();
+            for prediction in predictions.iter(){
+                let probabilities: Vec<String> = prediction.probabilities.iter().map(|p| format!("{:.4}", p)).collect();
+                output.push_str(&format!("{}: {} ({:.1}%) [{}]\n",
+                    prediction.path.display(), prediction.class,
+                    prediction.probabilities[prediction.class] * 100.0, probabilities.join(", ")));
+            }
+            output
+        },
+        Format::Json => {
+            let json: Vec<serde_json::Value> = predictions.iter().map(|prediction| json!({
+                "file": prediction.path.to_string_lossy(),
+                "class": prediction.class,
+                "probabilities": prediction.probabilities
+            })).collect();
+            format!("{}\n", serde_json::to_string_pretty(&json).unwrap())
+        },
+        Format::Csv => {
+            let mut output = String::from("file,class");
+            for i in 0..10{
+                output.push_str(&format!(",p{}", i));
+            }
+            output.push('\n');
+            for prediction in predictions.iter(){
+                output.push_str(&csv_field(&prediction.path.to_string_lossy()));
+                output.push_str(&format!(",{}", prediction.class));
+                for p in prediction.probabilities.iter(){
+                    output.push_str(&format!(",{}", p));
+                }
+                output.push('\n');
+            }
+            output
+        }
+    }
+}
+
+// arguments after `predict`, returns the exit code. Files that can not be
+// read are reported on stderr and the others are still predicted.
+pub fn run(args: &[String]) -> i32{
+    let mut output_format = Format::Text;
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut paths = Vec::new();
+    let mut i = 0;
+    while i < args.len(){
+        match args[i].as_str() {
+            "--format" | "--model" if i + 1 >= args.len() => {
+                eprintln!("{} needs a value\n{}", args[i], USAGE);
+                return 2;
+            },
+            "--format" => {
+                match Format::parse(&args[i + 1]) {
+                    Some(parsed) => output_format = parsed,
+                    None => {
+                        eprintln!("unknown format {}\n{}", args[i + 1], USAGE);
+                        return 2;
+                    }
+                }
+                i += 1;
+            },
+            "--model" => {
+                model_path = PathBuf::from(&args[i + 1]);
+                i += 1;
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            path => paths.push(PathBuf::from(path))
+        }
+        i += 1;
+    }
+    if paths.is_empty(){
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+
+    let cnn = match ::load_cnn(&model_path) {
+        Ok(cnn) => cnn,
+        Err(e) => {
+            eprintln!("Error: {}: {}", model_path.display(), e);
+            return 1;
+        }
+    };
+    let files = match collect_files(&paths) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    let mut predictions = Vec::new();
+    let mut failed = false;
+    for file in files.iter(){
+        match predict_file(&cnn, file) {
+            Ok(prediction) => predictions.push(prediction),
+            Err(e) => {
+                eprintln!("Error: {}: {}", file.display(), e);
+                failed = true;
+            }
+        }
+    }
+    print!("{}", format(&predictions, output_format));
+    if failed {1} else {0}
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    fn prediction(path: &str, class: usize) -> Prediction{
+        let mut probabilities = vec![0.0; 10];
+        probabilities[class] = 1.0;
+        Prediction{
+            path: PathBuf::from(path),
+            class: class,
+            probabilities: probabilities
+        }
+    }
+
+    #[test]
+    fn format_test(){
+        let predictions = vec![prediction("a.png", 3), prediction("b,c.png", 7)];
+        let text = format(&predictions, Format::Text);
+        assert!(text.starts_with("a.png: 3 (100.0%) [0.0000, "), "Sample: {}", text);
+
+        let csv = format(&predictions, Format::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "file,class,p0,p1,p2,p3,p4,p5,p6,p7,p8,p9");
+        assert_eq!(lines[2], "\"b,c.png\",7,0,0,0,0,0,0,0,1,0,0");
+
+        let json: serde_json::Value = serde_json::from_str(&format(&predictions, Format::Json)).unwrap();
+        assert_eq!(json[1]["file"], "b,c.png");
+        assert_eq!(json[1]["class"], 7);
+        assert_eq!(json[0]["probabilities"].as_array().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn predict_files_test(){
+        let dir = std::env::temp_dir().join(format!("draw-rust-cli-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // a dark 1 on white paper, in a nested directory next to a non-image
+        let mut pixels = vec![vec![1.0; 60]; 80];
+        for i in 10..70{
+            for j in 27..33{
+                pixels[i][j] = 0.0;
+            }
+        }
+        imagefile::save_png(&pixels, &dir.join("nested").join("one.png")).unwrap();
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+        // binary PGM, white on black
+        let mut pgm = b"P5 4 4 255\n".to_vec();
+        pgm.extend_from_slice(&[0, 255, 255, 0, 0, 255, 255, 0, 0, 255, 255, 0, 0, 255, 255, 0]);
+        fs::write(dir.join("small.pgm"), pgm).unwrap();
+
+        let files = collect_files(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(files, vec![dir.join("nested").join("one.png"), dir.join("small.pgm")]);
+        assert_eq!(imagefile::load(&files[1]).unwrap()[0], vec![0.0, 1.0, 1.0, 0.0]);
+        let cnn = testutil::model();
+        let prediction = predict_file(&cnn, &files[0]).unwrap();
+        assert_eq!(prediction.class, 1, "Sample: {:?}", prediction.probabilities);
+        assert!(predict_file(&cnn, &dir.join("notes.txt")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use idx;
+use imagefile;
+
+// # Dataset
+// Labelled drawings collected in the app, kept as a directory of 28x28 PNGs
+// (preprocessed, white on black like MNIST) and a manifest.json with the
+// label of each file. Unlike an IDX file this makes relabelling or deleting
+// one sample cheap, and `export_idx` writes the usual pair of IDX files when
+// they are needed for training.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample{
+    pub file: String,
+    pub label: u8,
+    // what the model said when the sample was labelled
+    pub predicted: Option<u8>
+}
+
+#[derive(Debug, Clone)]
+pub struct Dataset{
+    dir: PathBuf,
+    samples: Vec<Sample>,
+    // number for the next file name, never reused after a delete
+    next_id: u64
+}
+
+// 28x28 images and their labels
+pub type Labelled = (Vec<Vec<Vec<f32>>>, Vec<u8>);
+
+const MANIFEST: &str = "manifest.json";
+
+fn invalid_data(message: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// the manifest only names files directly inside the dataset directory,
+// anything else could make `image` or `remove` reach outside of it
+fn plain_file_name(file: &str) -> bool{
+    let path = Path::new(file);
+    path.file_name() == Some(path.as_os_str()) && !file.contains('\\')
+}
+
+impl Sample{
+    pub fn to_json(&self) -> serde_json::Value{
+        json!({
+            "file": self.file,
+            "label": self.label,
+            "predicted": self.predicted
+        })
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Option<Sample>{
+        Some(Sample{
+            file: json["file"].as_str()?.to_owned(),
+            label: json["label"].as_u64()? as u8,
+            predicted: json["predicted"].as_u64().map(|p| p as u8)
+        })
+    }
+}
+
+impl Dataset{
+    // opens the dataset in `dir`, an empty one if there is no manifest yet
+    pub fn open(dir: &Path) -> io::Result<Dataset>{
+        let mut dataset = Dataset{
+            dir: dir.to_path_buf(),
+            samples: Vec::new(),
+            next_id: 0
+        };
+        let manifest = dir.join(MANIFEST);
+        if !manifest.exists(){
+            return Ok(dataset);
+        }
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&manifest)?)
+            .map_err(|e| invalid_data(e.to_string()))?;
+        let samples = json["samples"].as_array()
+            .ok_or_else(|| invalid_data(format!("{} has no samples", manifest.display())))?;
+        for sample in samples{
+            let sample = Sample::from_json(sample)
+                .ok_or_else(|| invalid_data(format!("bad sample in {}: {}", manifest.display(), sample)))?;
+            if sample.label > 9{
+                return Err(invalid_data(format!("{} has label {}", sample.file, sample.label)));
+            }
+            if !plain_file_name(&sample.file){
+                return Err(invalid_data(format!("{} names {:?}, not a file in the dataset", manifest.display(), sample.file)));
+            }
+            dataset.samples.push(sample);
+        }
+        dataset.next_id = json["next_id"].as_u64().unwrap_or(dataset.samples.len() as u64);
+        Ok(dataset)
+    }
+
+    pub fn dir(&self) -> &Path{
+        &self.dir
+    }
+
+    pub fn samples(&self) -> &Vec<Sample>{
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize{
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.samples.is_empty()
+    }
+
+    // number of samples of every digit
+    pub fn counts(&self) -> Vec<usize>{
+        let mut counts = vec![0; 10];
+        for sample in self.samples.iter(){
+            counts[sample.label as usize] += 1;
+        }
+        counts
+    }
+
+    fn save_manifest(&self) -> io::Result<()>{
+        let json = json!({
+            "next_id": self.next_id,
+            "samples": self.samples.iter().map(|sample| sample.to_json()).collect::<Vec<_>>()
+        });
+        // written next to it first so a crash never leaves half a manifest
+        let path = self.dir.join(MANIFEST);
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST));
+        fs::write(&tmp, serde_json::to_string_pretty(&json).unwrap())?;
+        fs::rename(&tmp, &path)
+    }
+
+    // stores a 28x28 image, returns its index
+    pub fn add(&mut self, image: &Vec<Vec<f32>>, label: u8, predicted: Option<u8>) -> io::Result<usize>{
+        if label > 9{
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("label {} is not a digit", label)));
+        }
+        fs::create_dir_all(&self.dir)?;
+        let file = format!("sample-{:06}.png", self.next_id);
+        imagefile::save_png(image, &self.dir.join(&file))?;
+        self.next_id += 1;
+        self.samples.push(Sample{
+            file: file,
+            label: label,
+            predicted: predicted
+        });
+        self.save_manifest()?;
+        Ok(self.samples.len() - 1)
+    }
+
+    pub fn image(&self, index: usize) -> io::Result<Vec<Vec<f32>>>{
+        imagefile::load(&self.dir.join(&self.samples[index].file))
+    }
+
+    pub fn relabel(&mut self, index: usize, label: u8) -> io::Result<()>{
+        if label > 9{
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("label {} is not a digit", label)));
+        }
+        self.samples[index].label = label;
+        self.save_manifest()
+    }
+
+    pub fn remove(&mut self, index: usize) -> io::Result<()>{
+        let sample = self.samples.remove(index);
+        self.save_manifest()?;
+        fs::remove_file(self.dir.join(&sample.file))
+    }
+
+    // every image with its label, in the order they were added
+    pub fn load_all(&self) -> io::Result<Labelled>{
+        let mut images = Vec::with_capacity(self.samples.len());
+        for i in 0..self.samples.len(){
+            images.push(self.image(i)?);
+        }
+        Ok((images, self.samples.iter().map(|sample| sample.label).collect()))
+    }
+
+    // writes images-idx3-ubyte and labels-idx1-ubyte into `dir`
+    pub fn export_idx(&self, dir: &Path) -> io::Result<usize>{
+        let (images, labels) = self.load_all()?;
+        fs::create_dir_all(dir)?;
+        idx::write_images(&dir.join("images-idx3-ubyte"), &images)?;
+        idx::write_labels(&dir.join("labels-idx1-ubyte"), &labels)?;
+        Ok(images.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn digit(value: f32) -> Vec<Vec<f32>>{
+        let mut image = vec![vec![0.0; 28]; 28];
+        for i in 6..22{
+            image[i][14] = value;
+        }
+        image
+    }
+
+    #[test]
+    fn dataset_test(){
+        let dir = std::env::temp_dir().join(format!("draw-rust-dataset-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut dataset = Dataset::open(&dir).unwrap();
+        assert!(dataset.is_empty());
+        assert_eq!(dataset.add(&digit(1.0), 1, Some(1)).unwrap(), 0);
+        assert_eq!(dataset.add(&digit(0.6), 7, Some(1)).unwrap(), 1);
+        assert_eq!(dataset.add(&digit(0.8), 1, None).unwrap(), 2);
+        assert!(dataset.add(&digit(0.8), 10, None).is_err());
+        assert_eq!(dataset.counts(), vec![0, 2, 0, 0, 0, 0, 0, 1, 0, 0]);
+
+        dataset.relabel(1, 4).unwrap();
+        dataset.remove(0).unwrap();
+        assert!(!dir.join("sample-000000.png").exists());
+
+        // everything is in the manifest, and deleted numbers are not reused
+        let mut reopened = Dataset::open(&dir).unwrap();
+        assert_eq!(reopened.samples(), dataset.samples());
+        assert_eq!(reopened.counts(), vec![0, 1, 0, 0, 1, 0, 0, 0, 0, 0]);
+        reopened.add(&digit(1.0), 2, None).unwrap();
+        assert_eq!(reopened.samples()[2].file, "sample-000003.png");
+        assert!((reopened.image(0).unwrap()[10][14] - 0.6).abs() < 1.0 / 255.0);
+
+        let export = dir.join("idx");
+        assert_eq!(reopened.export_idx(&export).unwrap(), 3);
+        assert_eq!(idx::read_labels(&export.join("labels-idx1-ubyte")).unwrap(), vec![4, 1, 2]);
+        assert_eq!(idx::read_images(&export.join("images-idx3-ubyte")).unwrap().len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_paths_test(){
+        let dir = std::env::temp_dir().join(format!("draw-rust-dataset-paths-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for file in ["sample-000000.png", "1.png"].iter(){
+            fs::write(dir.join(MANIFEST), json!({"samples": [{"file": file, "label": 1}]}).to_string()).unwrap();
+            assert!(Dataset::open(&dir).is_ok(), "Sample: {:?}", file);
+        }
+        for file in ["../model.json", "/etc/passwd", "sub/sample.png", "sub\\sample.png", "..", ".", ""].iter(){
+            fs::write(dir.join(MANIFEST), json!({"samples": [{"file": file, "label": 1}]}).to_string()).unwrap();
+            let error = Dataset::open(&dir).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "Sample: {:?}", file);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,360 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use finetune;
+use idx;
+use model;
+use preprocess;
+use quantize;
+use rng::Rng;
+use train::{self, Sgd};
+use CNN;
+
+// # Distillation
+// Trains a small student CNN (fewer conv1 and conv2 channels) on the soft
+// labels of a bigger teacher. Both are softened with a temperature T, the
+// loss is
+//   alpha * T^2 * KL(teacher || student) + (1 - alpha) * cross entropy
+// where T^2 keeps the soft gradients about as large as the hard ones.
+#[derive(Debug, Clone)]
+pub struct Distill{
+    pub temperature: f32,
+    // weight of the soft labels against the hard ones
+    pub alpha: f32,
+    pub epochs: u32,
+    pub batch_size: usize,
+    pub lr: f32,
+    pub momentum: f32,
+    pub holdout: f32,
+    pub seed: u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary{
+    pub conv1_channels: usize,
+    pub conv2_channels: usize,
+    pub parameters: usize,
+    pub bytes: usize,
+    pub accuracy: f32,
+    // microseconds per image
+    pub latency: f64
+}
+
+#[derive(Debug, Clone)]
+pub struct Report{
+    pub train_samples: usize,
+    pub holdout_samples: usize,
+    // mean loss of every epoch
+    pub losses: Vec<f32>,
+    pub teacher: Summary,
+    pub student: Summary
+}
+
+impl Default for Distill{
+    fn default() -> Distill{
+        Distill{
+            temperature: 4.0,
+            alpha: 0.7,
+            epochs: 5,
+            batch_size: 32,
+            lr: 0.01,
+            momentum: 0.9,
+            holdout: 0.1,
+            seed: 1
+        }
+    }
+}
+
+// a CNN with `conv1` and `conv2` channels and He initialized weights, for
+// 28x28 inputs
+pub fn initialize(conv1: usize, conv2: usize, rng: &mut Rng) -> CNN{
+    let mut he = |count: usize, fan_in: usize| -> Vec<f32> {
+        let std = (2.0 / fan_in as f32).sqrt();
+        (0..count).map(|_| rng.normal() * std).collect()
+    };
+    let conv1_filter = he(conv1 * 9, 9).chunks(9)
+        .map(|w| vec![w.chunks(3).map(|row| row.to_vec()).collect()]).collect();
+    let conv2_weights = he(conv2 * conv1 * 9, conv1 * 9);
+    let conv2_filter = conv2_weights.chunks(conv1 * 9)
+        .map(|w| w.chunks(9).map(|f| f.chunks(3).map(|row| row.to_vec()).collect()).collect()).collect();
+    // 28 -> conv 26 -> pool 13 -> conv 11 -> pool 5
+    let inputs = conv2 * 25;
+    let fc_weights = he(10 * inputs, inputs).chunks(inputs).map(|row| row.to_vec()).collect();
+    CNN::new(1, 10,
+        model::Conv2D::new(1, conv1 as u32, conv1_filter, vec![0.0; conv1]),
+        model::Conv2D::new(conv1 as u32, conv2 as u32, conv2_filter, vec![0.0; conv2]),
+        model::FullyConnected::new(inputs as u32, 10, fc_weights, vec![0.0; 10])
+    )
+}
+
+fn softened(logits: &Vec<f32>, temperature: f32) -> Vec<f32>{
+    // shifted by the largest logit, the teacher's can be large
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    model::softmax(&logits.iter().map(|&v| (v - max) / temperature).collect())
+}
+
+// the distillation loss of the student logits and its gradient with respect
+// to them
+pub fn distillation_loss(student: &Vec<f32>, teacher: &Vec<f32>, label: usize, temperature: f32, alpha: f32) -> (f32, Vec<f32>){
+    let (soft_student, soft_teacher) = (softened(student, temperature), softened(teacher, temperature));
+    let mut kl = 0.0;
+    for i in 0..student.len(){
+        if soft_teacher[i] > 0.0{
+            kl += soft_teacher[i] * (soft_teacher[i].ln() - soft_student[i].max(1e-12).ln());
+        }
+    }
+    let (hard_loss, hard_grad) = train::cross_entropy(student, label);
+    let t2 = temperature * temperature;
+    let loss = alpha * t2 * kl + (1.0 - alpha) * hard_loss;
+    // d KL / d student_i = (soft_student_i - soft_teacher_i) / T
+    let grad = (0..student.len())
+        .map(|i| alpha * temperature * (soft_student[i] - soft_teacher[i]) + (1.0 - alpha) * hard_grad[i])
+        .collect();
+    (loss, grad)
+}
+
+impl Distill{
+    // like train::train_step with the teacher's logits for every input
+    pub fn train_step(&self, student: &mut CNN, optimizer: &mut Sgd, inputs: &[Vec<Vec<Vec<f32>>>], teacher: &[Vec<f32>], labels: &[u8]) -> f32{
+        let mut total: Option<train::Gradients> = None;
+        let mut loss = 0.0;
+        for n in 0..inputs.len(){
+            let (sample_loss, grad_logits) = distillation_loss(&student.logits(&inputs[n]), &teacher[n],
+                labels[n] as usize, self.temperature, self.alpha);
+            loss += sample_loss;
+            let grads = student.backward(&inputs[n], &grad_logits);
+            match total {
+                Some(ref mut total) => total.add(&grads),
+                None => total = Some(grads)
+            }
+        }
+        if let Some(mut grads) = total{
+            grads.scale(1.0 / inputs.len() as f32);
+            train::apply_gradients(student, optimizer, &grads, &[]);
+        }
+        loss / inputs.len().max(1) as f32
+    }
+
+    // images are preprocessed 28x28 grids as read from IDX files, `progress`
+    // gets the epoch and its mean loss
+    pub fn run<F>(&self, teacher: &CNN, mut student: CNN, images: &Vec<Vec<Vec<f32>>>, labels: &Vec<u8>, mut progress: F) -> (CNN, Report)
+        where F: FnMut(u32, f32){
+        let mut rng = Rng::new(self.seed);
+        let (train_indices, holdout_indices) = finetune::split(images.len(), self.holdout, &mut rng);
+        let inputs: Vec<Vec<Vec<Vec<f32>>>> = images.iter().map(preprocess::to_input).collect();
+        // the teacher does not change, its logits are computed once
+        let soft: Vec<Vec<f32>> = inputs.iter().map(|input| teacher.logits(input)).collect();
+
+        let mut optimizer = Sgd::new(self.lr, self.momentum, 0.0);
+        let mut order = train_indices.clone();
+        let mut losses = Vec::new();
+        for epoch in 0..self.epochs{
+            rng.shuffle(&mut order);
+            let (mut loss, mut batches) = (0.0, 0);
+            for batch in order.chunks(self.batch_size.max(1)){
+                let batch_inputs: Vec<Vec<Vec<Vec<f32>>>> = batch.iter().map(|&i| inputs[i].clone()).collect();
+                let batch_soft: Vec<Vec<f32>> = batch.iter().map(|&i| soft[i].clone()).collect();
+                let batch_labels: Vec<u8> = batch.iter().map(|&i| labels[i]).collect();
+                loss += self.train_step(&mut student, &mut optimizer, &batch_inputs, &batch_soft, &batch_labels);
+                batches += 1;
+            }
+            let loss = loss / batches.max(1) as f32;
+            losses.push(loss);
+            progress(epoch + 1, loss);
+        }
+
+        let holdout_inputs: Vec<Vec<Vec<Vec<f32>>>> = holdout_indices.iter().map(|&i| inputs[i].clone()).collect();
+        let holdout_labels: Vec<u8> = holdout_indices.iter().map(|&i| labels[i]).collect();
+        let report = Report{
+            train_samples: train_indices.len(),
+            holdout_samples: holdout_indices.len(),
+            losses: losses,
+            teacher: summarize(teacher, &holdout_inputs, &holdout_labels),
+            student: summarize(&student, &holdout_inputs, &holdout_labels)
+        };
+        (student, report)
+    }
+}
+
+pub fn summarize(cnn: &CNN, inputs: &[Vec<Vec<Vec<f32>>>], labels: &[u8]) -> Summary{
+    let bytes = quantize::float_bytes(cnn);
+    let start = Instant::now();
+    let accuracy = train::accuracy(cnn, inputs, labels);
+    Summary{
+        conv1_channels: cnn.conv1.filter().len(),
+        conv2_channels: cnn.conv2.filter().len(),
+        parameters: bytes / 4,
+        bytes: bytes,
+        accuracy: accuracy,
+        latency: start.elapsed().as_secs_f64() * 1e6 / inputs.len().max(1) as f64
+    }
+}
+
+impl fmt::Display for Summary{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "conv1 {} / conv2 {} channels, {} parameters ({} bytes), accuracy {:.2}%, {:.0} us per image",
+            self.conv1_channels, self.conv2_channels, self.parameters, self.bytes, self.accuracy * 100.0, self.latency)
+    }
+}
+
+impl fmt::Display for Report{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        writeln!(f, "trained on {} images, evaluated on {}", self.train_samples, self.holdout_samples)?;
+        writeln!(f, "teacher {}", self.teacher)?;
+        writeln!(f, "student {}", self.student)?;
+        write!(f, "{:.1}x smaller, {:.1}x faster, accuracy {:+.2}%",
+            self.teacher.bytes as f32 / self.student.bytes.max(1) as f32,
+            self.teacher.latency / self.student.latency.max(1e-9),
+            (self.student.accuracy - self.teacher.accuracy) * 100.0)
+    }
+}
+
+const USAGE: &str = "usage: draw-rust distill --images <idx> --labels <idx> [--teacher model.json] [--conv1 2] [--conv2 4] [--temperature 4] [--alpha 0.7] [--epochs 5] [--lr 0.01] [--batch-size 32] [--output model-student.json]";
+
+// arguments after `distill`, returns the exit code
+pub fn run(args: &[String]) -> i32{
+    let mut config = Distill::default();
+    let mut teacher_path = PathBuf::from("./src/assets/model.json");
+    let mut images_path = None;
+    let mut labels_path = None;
+    let mut output = None;
+    let (mut conv1, mut conv2) = (2usize, 4usize);
+    let mut i = 0;
+    while i < args.len(){
+        if args[i] == "-h" || args[i] == "--help"{
+            println!("{}", USAGE);
+            return 0;
+        }
+        if i + 1 >= args.len(){
+            eprintln!("{} needs a value\n{}", args[i], USAGE);
+            return 2;
+        }
+        let value = &args[i + 1];
+        let parsed = match args[i].as_str() {
+            "--teacher" => {teacher_path = PathBuf::from(value); true},
+            "--images" => {images_path = Some(PathBuf::from(value)); true},
+            "--labels" => {labels_path = Some(PathBuf::from(value)); true},
+            "--output" => {output = Some(PathBuf::from(value)); true},
+            "--conv1" => value.parse().map(|v: usize| conv1 = v.max(1)).is_ok(),
+            "--conv2" => value.parse().map(|v: usize| conv2 = v.max(1)).is_ok(),
+            "--temperature" => value.parse().map(|v: f32| config.temperature = v.max(0.1)).is_ok(),
+            "--alpha" => value.parse().map(|v: f32| config.alpha = v.clamp(0.0, 1.0)).is_ok(),
+            "--epochs" => value.parse().map(|v| config.epochs = v).is_ok(),
+            "--lr" => value.parse().map(|v| config.lr = v).is_ok(),
+            "--batch-size" => value.parse().map(|v: usize| config.batch_size = v.max(1)).is_ok(),
+            other => {
+                eprintln!("unknown argument {}\n{}", other, USAGE);
+                return 2;
+            }
+        };
+        if !parsed{
+            eprintln!("{} needs a number\n{}", args[i], USAGE);
+            return 2;
+        }
+        i += 2;
+    }
+    let (images_path, labels_path) = match (images_path, labels_path) {
+        (Some(images), Some(labels)) => (images, labels),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let output = output.unwrap_or_else(|| teacher_path.with_file_name(format!("{}-student.json",
+        teacher_path.file_stem().map_or("model".into(), |stem| stem.to_string_lossy()))));
+
+    let loaded = ::load_cnn(&teacher_path)
+        .and_then(|cnn| Ok((cnn, idx::read_images(&images_path)?, idx::read_labels(&labels_path)?)));
+    let (teacher, images, labels) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    if images.len() < 2 || images.len() != labels.len(){
+        eprintln!("Error: {} images but {} labels", images.len(), labels.len());
+        return 1;
+    }
+
+    let student = initialize(conv1, conv2, &mut Rng::new(config.seed));
+    let epochs = config.epochs;
+    let (student, report) = config.run(&teacher, student, &images, &labels, |epoch, loss| {
+        println!("Epoch {}/{} loss {:.4}", epoch, epochs, loss);
+    });
+    println!("{}", report);
+    match fs::write(&output, student.to_json().to_string()) {
+        Ok(()) => {
+            println!("Saved {}", output.display());
+            0
+        },
+        Err(e) => {
+            eprintln!("Error: {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn loss_test(){
+        let student = vec![0.5, -1.0, 2.0, 0.0];
+        let teacher = vec![1.0, -2.0, 8.0, 0.5];
+        // without soft labels it is the plain cross entropy
+        let (loss, grad) = distillation_loss(&student, &teacher, 2, 4.0, 0.0);
+        assert_eq!((loss, grad), train::cross_entropy(&student, 2));
+        // a student that matches the teacher has no soft loss
+        let (loss, grad) = distillation_loss(&teacher, &teacher, 2, 4.0, 1.0);
+        assert!(loss.abs() < 1e-6 && grad.iter().all(|g| g.abs() < 1e-6), "Sample: {} {:?}", loss, grad);
+
+        // the gradient matches finite differences
+        for &(temperature, alpha) in [(1.0, 0.5), (4.0, 0.7), (10.0, 1.0)].iter(){
+            let (_, grad) = distillation_loss(&student, &teacher, 2, temperature, alpha);
+            for i in 0..student.len(){
+                let (mut plus, mut minus) = (student.clone(), student.clone());
+                plus[i] += 1e-2;
+                minus[i] -= 1e-2;
+                let numeric = (distillation_loss(&plus, &teacher, 2, temperature, alpha).0
+                    - distillation_loss(&minus, &teacher, 2, temperature, alpha).0) / 2e-2;
+                assert!((numeric - grad[i]).abs() < 2e-3, "Sample: T {} {} {} {}", temperature, i, numeric, grad[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn initialize_test(){
+        let cnn = initialize(2, 3, &mut Rng::new(1));
+        assert_eq!(cnn.conv1.filter().len(), 2);
+        assert_eq!(cnn.conv2.filter()[0].len(), 2);
+        assert_eq!(cnn.fc.weights()[0].len(), 75);
+        assert_eq!(cnn.logits(&vec![vec![vec![0.5; 28]; 28]]).len(), 10);
+        assert_eq!(quantize::float_bytes(&cnn), 4 * (20 + 57 + 760));
+    }
+
+    #[test]
+    fn distill_test(){
+        // bars and rings with the digits the teacher calls them
+        let teacher = testutil::model();
+        let (images, _) = testutil::bars_and_rings(60, 1, 0);
+        let labels: Vec<u8> = images.iter().map(|image| teacher.forward(&preprocess::to_input(image)) as u8).collect();
+
+        let config = Distill{epochs: 4, batch_size: 8, holdout: 0.2, ..Distill::default()};
+        let student = initialize(2, 3, &mut Rng::new(2));
+        let mut epochs = 0;
+        let (student, report) = config.run(&teacher, student, &images, &labels, |_, _| epochs += 1);
+        assert_eq!(epochs, 4);
+        assert!(report.losses[3] < report.losses[0], "Sample: {:?}", report.losses);
+        assert_eq!(report.teacher.accuracy, 1.0);
+        assert!(report.student.accuracy >= 0.9, "Sample: {}", report);
+        assert!(report.student.bytes * 2 < report.teacher.bytes);
+        assert_eq!(report.student.conv2_channels, 3);
+        assert_eq!(CNN::from_json(&student.to_json()).unwrap().fc.weights()[0].len(), 75);
+    }
+}
@@ -0,0 +1,721 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use hooks;
+use idx;
+use model;
+use preprocess;
+use rng::Rng;
+use CNN;
+
+// # Embeddings
+// The 200 values `flatten` hands to fc1 for every image of a dataset, and
+// two ways to look at them in 2D:
+// - PCA, the two directions with the most variance, from the eigenvectors
+//   of the covariance matrix (Jacobi rotations, the matrix is only 200x200)
+// - t-SNE, which keeps images that are close in the embedding close in the
+//   plane. Exact, O(n^2) per iteration, so meant for a few thousand images.
+// The projection is written as CSV or as a PNG scatter plot colored by
+// label, where misclassified images have a black ring.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method{
+    Pca,
+    Tsne
+}
+
+#[derive(Debug, Clone)]
+pub struct Tsne{
+    // about how many neighbours every point keeps close
+    pub perplexity: f32,
+    pub iterations: u32,
+    // None for n / exaggeration, steps that are too large for the number
+    // of points scatter the clusters
+    pub learning_rate: Option<f32>,
+    // P is multiplied by this for the first iterations, which pulls the
+    // clusters apart early on
+    pub exaggeration: f32,
+    pub exaggeration_iterations: u32,
+    // PCA to this many dimensions before the pairwise distances
+    pub dimensions: usize,
+    pub seed: u64
+}
+
+// principal components, largest variance first
+#[derive(Debug, Clone)]
+pub struct Pca{
+    pub mean: Vec<f32>,
+    pub components: Vec<Vec<f32>>,
+    pub variances: Vec<f32>,
+    pub total_variance: f32
+}
+
+impl Method{
+    pub fn parse(name: &str) -> Option<Method>{
+        match name {
+            "pca" => Some(Method::Pca),
+            "tsne" | "t-sne" => Some(Method::Tsne),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str{
+        match *self {
+            Method::Pca => "pca",
+            Method::Tsne => "tsne"
+        }
+    }
+}
+
+impl Default for Tsne{
+    fn default() -> Tsne{
+        Tsne{
+            perplexity: 30.0,
+            iterations: 1000,
+            learning_rate: None,
+            exaggeration: 12.0,
+            exaggeration_iterations: 250,
+            dimensions: 50,
+            seed: 1
+        }
+    }
+}
+
+// embeddings of 28x28 images, preprocessed like MNIST
+pub fn embeddings(cnn: &CNN, images: &[Vec<Vec<f32>>]) -> Vec<Vec<f32>>{
+    images.iter().map(|image| hooks::embedding(cnn, &preprocess::to_input(image))).collect()
+}
+
+// eigenvalues and eigenvectors (as rows) of a symmetric matrix, largest first
+fn symmetric_eigen(matrix: &Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>){
+    let n = matrix.len();
+    let mut a = matrix.clone();
+    let mut v = vec![vec![0.0; n]; n];
+    for i in 0..n{
+        v[i][i] = 1.0;
+    }
+    let scale: f64 = a.iter().map(|row| row.iter().map(|x| x * x).sum::<f64>()).sum();
+    for _sweep in 0..100{
+        let mut off = 0.0;
+        for p in 0..n{
+            for q in p + 1..n{
+                off += a[p][q] * a[p][q];
+            }
+        }
+        if off <= 1e-24 * scale{
+            break;
+        }
+        for p in 0..n{
+            for q in p + 1..n{
+                if a[p][q] == 0.0{
+                    continue;
+                }
+                // the rotation that zeroes a[p][q]
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n{
+                    let (kp, kq) = (a[k][p], a[k][q]);
+                    a[k][p] = c * kp - s * kq;
+                    a[k][q] = s * kp + c * kq;
+                }
+                for k in 0..n{
+                    let (pk, qk) = (a[p][k], a[q][k]);
+                    a[p][k] = c * pk - s * qk;
+                    a[q][k] = s * pk + c * qk;
+                }
+                for k in 0..n{
+                    let (kp, kq) = (v[k][p], v[k][q]);
+                    v[k][p] = c * kp - s * kq;
+                    v[k][q] = s * kp + c * kq;
+                }
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| a[j][j].total_cmp(&a[i][i]));
+    let values = order.iter().map(|&i| a[i][i]).collect();
+    let vectors = order.iter().map(|&i| (0..n).map(|k| v[k][i]).collect()).collect();
+    (values, vectors)
+}
+
+pub fn pca(data: &[Vec<f32>], components: usize) -> Pca{
+    let n = data.len();
+    let d = data[0].len();
+    let mut mean = vec![0.0f64; d];
+    for point in data{
+        for j in 0..d{
+            mean[j] += point[j] as f64 / n as f64;
+        }
+    }
+    let mut covariance = vec![vec![0.0f64; d]; d];
+    let mut centered = vec![0.0f64; d];
+    for point in data{
+        for j in 0..d{
+            centered[j] = point[j] as f64 - mean[j];
+        }
+        for j in 0..d{
+            if centered[j] == 0.0{
+                continue;
+            }
+            for k in j..d{
+                covariance[j][k] += centered[j] * centered[k];
+            }
+        }
+    }
+    for j in 0..d{
+        for k in j..d{
+            covariance[j][k] /= n as f64;
+            covariance[k][j] = covariance[j][k];
+        }
+    }
+    let (values, vectors) = symmetric_eigen(&covariance);
+    let components = components.min(d);
+    Pca{
+        mean: mean.iter().map(|&m| m as f32).collect(),
+        components: vectors[..components].iter().map(|v| v.iter().map(|&x| x as f32).collect()).collect(),
+        variances: values[..components].iter().map(|&x| x.max(0.0) as f32).collect(),
+        total_variance: values.iter().map(|&x| x.max(0.0)).sum::<f64>() as f32
+    }
+}
+
+impl Pca{
+    pub fn project(&self, point: &[f32]) -> Vec<f32>{
+        self.components.iter()
+            .map(|component| component.iter().zip(point.iter().zip(self.mean.iter())).map(|(c, (x, m))| c * (x - m)).sum())
+            .collect()
+    }
+
+    // share of the total variance every component explains
+    pub fn explained(&self) -> Vec<f32>{
+        self.variances.iter().map(|v| if self.total_variance > 0.0 {v / self.total_variance} else {0.0}).collect()
+    }
+}
+
+fn squared_distances(data: &[Vec<f32>]) -> Vec<Vec<f64>>{
+    let n = data.len();
+    let mut distances = vec![vec![0.0; n]; n];
+    for i in 0..n{
+        for j in i + 1..n{
+            let d: f64 = data[i].iter().zip(data[j].iter()).map(|(a, b)| ((a - b) as f64).powi(2)).sum();
+            distances[i][j] = d;
+            distances[j][i] = d;
+        }
+    }
+    distances
+}
+
+// symmetric joint probabilities p_ij, every row calibrated by a binary
+// search on the gaussian precision until its entropy is log(perplexity)
+fn affinities(data: &[Vec<f32>], perplexity: f32) -> Vec<Vec<f64>>{
+    let n = data.len();
+    let distances = squared_distances(data);
+    let target = (perplexity as f64).ln();
+    let mut p = vec![vec![0.0; n]; n];
+    for i in 0..n{
+        let (mut beta, mut low, mut high) = (1.0f64, 0.0f64, f64::INFINITY);
+        // distances relative to the nearest neighbour keep exp() in range
+        let nearest = (0..n).filter(|&j| j != i).map(|j| distances[i][j]).fold(f64::INFINITY, f64::min);
+        for _ in 0..64{
+            let mut sum = 0.0;
+            let mut weighted = 0.0;
+            for j in 0..n{
+                p[i][j] = if j == i {0.0} else {(-beta * (distances[i][j] - nearest)).exp()};
+                sum += p[i][j];
+                weighted += p[i][j] * (distances[i][j] - nearest);
+            }
+            let entropy = sum.ln() + beta * weighted / sum;
+            for j in 0..n{
+                p[i][j] /= sum;
+            }
+            if (entropy - target).abs() < 1e-5{
+                break;
+            }
+            // too many neighbours: a narrower gaussian
+            if entropy > target{
+                low = beta;
+                beta = if high.is_finite() {(beta + high) / 2.0} else {beta * 2.0};
+            }
+            else{
+                high = beta;
+                beta = (beta + low) / 2.0;
+            }
+        }
+    }
+    let mut joint = vec![vec![0.0; n]; n];
+    for i in 0..n{
+        for j in 0..n{
+            joint[i][j] = ((p[i][j] + p[j][i]) / (2.0 * n as f64)).max(1e-12);
+        }
+    }
+    joint
+}
+
+impl Tsne{
+    // 2D points for `data`, `progress` gets the iteration and the KL
+    // divergence every 50 iterations
+    pub fn run<F>(&self, data: &[Vec<f32>], mut progress: F) -> Vec<(f32, f32)>
+        where F: FnMut(u32, f32){
+        let n = data.len();
+        if n < 2{
+            return vec![(0.0, 0.0); n];
+        }
+        let reduced: Vec<Vec<f32>> = if data[0].len() > self.dimensions{
+            let pca = pca(data, self.dimensions);
+            data.iter().map(|point| pca.project(point)).collect()
+        }
+        else{
+            data.to_vec()
+        };
+        // the perplexity can not be more than the neighbours there are
+        let perplexity = self.perplexity.min((n - 1) as f32 / 3.0).max(1.0);
+        let p = affinities(&reduced, perplexity);
+
+        let learning_rate = self.learning_rate.unwrap_or(n as f32 / self.exaggeration.max(1.0)) as f64;
+        let mut rng = Rng::new(self.seed);
+        let mut y: Vec<[f64; 2]> = (0..n).map(|_| [1e-4 * rng.normal() as f64, 1e-4 * rng.normal() as f64]).collect();
+        let mut update = vec![[0.0f64; 2]; n];
+        let mut gains = vec![[1.0f64; 2]; n];
+        let mut q = vec![vec![0.0f64; n]; n];
+        for iteration in 0..self.iterations{
+            let early = iteration < self.exaggeration_iterations;
+            let exaggeration = if early {self.exaggeration as f64} else {1.0};
+            let momentum = if early {0.5} else {0.8};
+
+            // student-t similarities in the plane
+            let mut sum = 0.0;
+            for i in 0..n{
+                for j in i + 1..n{
+                    let dx = y[i][0] - y[j][0];
+                    let dy = y[i][1] - y[j][1];
+                    let similarity = 1.0 / (1.0 + dx * dx + dy * dy);
+                    q[i][j] = similarity;
+                    q[j][i] = similarity;
+                    sum += 2.0 * similarity;
+                }
+            }
+            for i in 0..n{
+                let mut gradient = [0.0f64; 2];
+                for j in 0..n{
+                    if j == i{
+                        continue;
+                    }
+                    let force = (exaggeration * p[i][j] - q[i][j] / sum) * q[i][j];
+                    gradient[0] += 4.0 * force * (y[i][0] - y[j][0]);
+                    gradient[1] += 4.0 * force * (y[i][1] - y[j][1]);
+                }
+                for k in 0..2{
+                    // faster where the gradient keeps its direction
+                    gains[i][k] = if (gradient[k] > 0.0) != (update[i][k] > 0.0) {gains[i][k] + 0.2} else {(gains[i][k] * 0.8).max(0.01)};
+                    update[i][k] = momentum * update[i][k] - learning_rate * gains[i][k] * gradient[k];
+                }
+            }
+            let mut center = [0.0f64; 2];
+            for i in 0..n{
+                for k in 0..2{
+                    y[i][k] += update[i][k];
+                    center[k] += y[i][k] / n as f64;
+                }
+            }
+            for point in y.iter_mut(){
+                point[0] -= center[0];
+                point[1] -= center[1];
+            }
+
+            if (iteration + 1) % 50 == 0 || iteration + 1 == self.iterations{
+                let mut kl = 0.0;
+                for i in 0..n{
+                    for j in 0..n{
+                        if j != i{
+                            kl += p[i][j] * (p[i][j] / (q[i][j] / sum).max(1e-12)).ln();
+                        }
+                    }
+                }
+                progress(iteration + 1, kl as f32);
+            }
+        }
+        y.iter().map(|point| (point[0] as f32, point[1] as f32)).collect()
+    }
+}
+
+// points whose `k` nearest neighbours in the plane all have another label:
+// images that look like a different digit to the network, or are mislabeled
+pub fn outliers(points: &[(f32, f32)], labels: &[u8], k: usize) -> Vec<usize>{
+    let mut outliers = Vec::new();
+    for i in 0..points.len(){
+        let mut neighbours: Vec<(f32, usize)> = (0..points.len()).filter(|&j| j != i)
+            .map(|j| ((points[i].0 - points[j].0).powi(2) + (points[i].1 - points[j].1).powi(2), j))
+            .collect();
+        neighbours.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if !neighbours.is_empty() && neighbours.iter().take(k).all(|&(_, j)| labels[j] != labels[i]){
+            outliers.push(i);
+        }
+    }
+    outliers
+}
+
+// `index,label,predicted,x,y`, the index is the one in the dataset
+pub fn projection_csv(indices: &[usize], labels: &[u8], predicted: &[u8], points: &[(f32, f32)]) -> String{
+    let mut csv = String::from("index,label,predicted,x,y\n");
+    for i in 0..points.len(){
+        csv.push_str(&format!("{},{},{},{},{}\n", indices[i], labels[i], predicted[i], points[i].0, points[i].1));
+    }
+    csv
+}
+
+// `index,label,predicted,e0,...,e199`
+pub fn embeddings_csv(indices: &[usize], labels: &[u8], predicted: &[u8], embeddings: &[Vec<f32>]) -> String{
+    let width = embeddings.first().map_or(0, |e| e.len());
+    let mut csv = String::from("index,label,predicted");
+    for j in 0..width{
+        csv.push_str(&format!(",e{}", j));
+    }
+    csv.push('\n');
+    for i in 0..embeddings.len(){
+        let values: Vec<String> = embeddings[i].iter().map(|v| v.to_string()).collect();
+        csv.push_str(&format!("{},{},{},{}\n", indices[i], labels[i], predicted[i], values.join(",")));
+    }
+    csv
+}
+
+// one color per digit
+pub const PALETTE: [[u8; 3]; 10] = [
+    [31, 119, 180], [255, 127, 14], [44, 160, 44], [214, 39, 40], [148, 103, 189],
+    [140, 86, 75], [227, 119, 194], [127, 127, 127], [188, 189, 34], [23, 190, 207]
+];
+
+// 3x5 pixel digits for the legend, one row of 3 bits per line
+const DIGITS: [[u8; 5]; 10] = [
+    [7, 5, 5, 5, 7], [2, 6, 2, 2, 7], [7, 1, 7, 4, 7], [7, 1, 7, 1, 7], [5, 5, 7, 1, 1],
+    [7, 4, 7, 1, 7], [7, 4, 7, 5, 7], [7, 1, 1, 1, 1], [7, 5, 7, 5, 7], [7, 5, 7, 1, 7]
+];
+
+const SIZE: u32 = 800;
+const MARGIN: u32 = 20;
+// room for the legend above the points
+const LEGEND: u32 = 24;
+const RADIUS: i32 = 3;
+
+fn disc(plot: &mut image::RgbImage, x: i32, y: i32, radius: i32, color: [u8; 3]){
+    for dy in -radius..=radius{
+        for dx in -radius..=radius{
+            let (px, py) = (x + dx, y + dy);
+            if dx * dx + dy * dy <= radius * radius && px >= 0 && py >= 0 && (px as u32) < plot.width() && (py as u32) < plot.height(){
+                plot.put_pixel(px as u32, py as u32, image::Rgb(color));
+            }
+        }
+    }
+}
+
+// where the points go in the plot, the same scale on both axes
+pub fn layout(points: &[(f32, f32)]) -> Vec<(i32, i32)>{
+    let (mut min_x, mut max_x, mut min_y, mut max_y) = (f32::INFINITY, f32::NEG_INFINITY, f32::INFINITY, f32::NEG_INFINITY);
+    for &(x, y) in points{
+        min_x = min_x.min(x);
+        max_x = max_x.max(x);
+        min_y = min_y.min(y);
+        max_y = max_y.max(y);
+    }
+    let width = (SIZE - 2 * MARGIN) as f32;
+    let height = (SIZE - 2 * MARGIN - LEGEND) as f32;
+    let scale = (width / (max_x - min_x).max(1e-6)).min(height / (max_y - min_y).max(1e-6));
+    // centered in the free space
+    let left = MARGIN as f32 + (width - scale * (max_x - min_x)) / 2.0;
+    let top = (MARGIN + LEGEND) as f32 + (height - scale * (max_y - min_y)) / 2.0;
+    points.iter().map(|&(x, y)| ((left + scale * (x - min_x)).round() as i32, (top + scale * (y - min_y)).round() as i32)).collect()
+}
+
+// white background, a disc per point in the color of its label, a black
+// ring when the prediction is wrong, and the palette with the digits on top
+pub fn scatter(points: &[(f32, f32)], labels: &[u8], predicted: &[u8]) -> image::RgbImage{
+    let mut plot = image::RgbImage::from_pixel(SIZE, SIZE, image::Rgb([255, 255, 255]));
+    let positions = layout(points);
+    for i in 0..points.len(){
+        let (x, y) = positions[i];
+        if predicted[i] != labels[i]{
+            disc(&mut plot, x, y, RADIUS + 1, [0, 0, 0]);
+        }
+        disc(&mut plot, x, y, RADIUS, PALETTE[labels[i] as usize % 10]);
+    }
+    for digit in 0..10{
+        let left = MARGIN + digit as u32 * 40;
+        for row in 0..5{
+            for col in 0..3{
+                if DIGITS[digit][row] & (4 >> col) != 0{
+                    for dy in 0..2{
+                        for dx in 0..2{
+                            plot.put_pixel(left + 2 * col as u32 + dx, MARGIN + 2 * row as u32 + dy, image::Rgb([0, 0, 0]));
+                        }
+                    }
+                }
+            }
+        }
+        for y in MARGIN..MARGIN + 10{
+            for x in left + 10..left + 24{
+                plot.put_pixel(x, y, image::Rgb(PALETTE[digit]));
+            }
+        }
+    }
+    plot
+}
+
+fn save_scatter(plot: &image::RgbImage, path: &Path) -> io::Result<()>{
+    plot.save_with_format(path, image::ImageFormat::Png)
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+// t-SNE keeps a few count x count f64 matrices, 10000 images already need
+// around 800 MB each
+const MAX_TSNE: usize = 10000;
+
+const USAGE: &str = "usage: draw-rust embed --images <idx> --labels <idx> [--model model.json] [--method pca|tsne] [--count 1000] [--perplexity 30] [--iterations 1000] [--output embedding.png|.csv] [--embeddings embeddings.csv]";
+
+// arguments after `embed`, returns the exit code
+pub fn run(args: &[String]) -> i32{
+    let mut config = Tsne::default();
+    let mut method = Method::Tsne;
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut images_path = None;
+    let mut labels_path = None;
+    let mut output = None;
+    let mut embeddings_path = None;
+    let mut count = 1000usize;
+    let mut i = 0;
+    while i < args.len(){
+        if args[i] == "-h" || args[i] == "--help"{
+            println!("{}", USAGE);
+            return 0;
+        }
+        if i + 1 >= args.len(){
+            eprintln!("{} needs a value\n{}", args[i], USAGE);
+            return 2;
+        }
+        let value = &args[i + 1];
+        match args[i].as_str() {
+            "--model" => model_path = PathBuf::from(value),
+            "--images" => images_path = Some(PathBuf::from(value)),
+            "--labels" => labels_path = Some(PathBuf::from(value)),
+            "--output" => output = Some(PathBuf::from(value)),
+            "--embeddings" => embeddings_path = Some(PathBuf::from(value)),
+            "--method" => match Method::parse(value) {
+                Some(parsed) => method = parsed,
+                None => {
+                    eprintln!("unknown method {}\n{}", value, USAGE);
+                    return 2;
+                }
+            },
+            "--count" | "--perplexity" | "--iterations" | "--seed" => {
+                let parsed = match args[i].as_str() {
+                    "--count" => value.parse().map(|v: usize| count = v.max(2)).is_ok(),
+                    "--perplexity" => value.parse().map(|v: f32| config.perplexity = v.max(1.0)).is_ok(),
+                    "--iterations" => value.parse().map(|v| config.iterations = v).is_ok(),
+                    _ => value.parse().map(|v| config.seed = v).is_ok()
+                };
+                if !parsed{
+                    eprintln!("{} needs a number\n{}", args[i], USAGE);
+                    return 2;
+                }
+            },
+            other => {
+                eprintln!("unknown argument {}\n{}", other, USAGE);
+                return 2;
+            }
+        }
+        i += 2;
+    }
+    let (images_path, labels_path) = match (images_path, labels_path) {
+        (Some(images), Some(labels)) => (images, labels),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    if method == Method::Tsne && count > MAX_TSNE{
+        eprintln!("Error: t-SNE is limited to --count {}, use --method pca for more images", MAX_TSNE);
+        return 2;
+    }
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("embedding-{}.png", method.name())));
+
+    let loaded = ::load_cnn(&model_path)
+        .and_then(|cnn| Ok((cnn, idx::read_images(&images_path)?, idx::read_labels(&labels_path)?)));
+    let (cnn, images, labels) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    if images.len() < 2 || images.len() != labels.len(){
+        eprintln!("Error: {} images but {} labels", images.len(), labels.len());
+        return 1;
+    }
+
+    // a random subset, t-SNE is quadratic in the number of images
+    let mut indices: Vec<usize> = (0..images.len()).collect();
+    if count < images.len(){
+        Rng::new(config.seed).shuffle(&mut indices);
+        indices.truncate(count);
+        indices.sort();
+    }
+    let subset: Vec<Vec<Vec<f32>>> = indices.iter().map(|&i| images[i].clone()).collect();
+    let labels: Vec<u8> = indices.iter().map(|&i| labels[i]).collect();
+    let embeddings = embeddings(&cnn, &subset);
+    let predicted: Vec<u8> = embeddings.iter().map(|e| model_argmax(&cnn, e)).collect();
+    let wrong = (0..labels.len()).filter(|&i| predicted[i] != labels[i]).count();
+    println!("{} images, {} embedding values each, {} misclassified", embeddings.len(), embeddings[0].len(), wrong);
+
+    if let Some(ref path) = embeddings_path{
+        if let Err(e) = fs::write(path, embeddings_csv(&indices, &labels, &predicted, &embeddings)){
+            eprintln!("Error: {}: {}", path.display(), e);
+            return 1;
+        }
+        println!("Saved {}", path.display());
+    }
+
+    let points: Vec<(f32, f32)> = match method {
+        Method::Pca => {
+            let pca = pca(&embeddings, 2);
+            let explained = pca.explained();
+            println!("PCA: the first two components explain {:.1}% and {:.1}% of the variance", 100.0 * explained[0], 100.0 * explained[1]);
+            embeddings.iter().map(|e| {
+                let projected = pca.project(e);
+                (projected[0], projected[1])
+            }).collect()
+        },
+        Method::Tsne => config.run(&embeddings, |iteration, kl| {
+            if iteration % 250 == 0 || iteration == config.iterations{
+                println!("t-SNE iteration {}/{} KL {:.4}", iteration, config.iterations, kl);
+            }
+        })
+    };
+
+    let outliers = outliers(&points, &labels, 5);
+    println!("{} outliers (all 5 nearest neighbours have another label)", outliers.len());
+    for &i in outliers.iter().take(20){
+        println!("  index {} label {} predicted {}", indices[i], labels[i], predicted[i]);
+    }
+
+    let png = output.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    let saved = if png{
+        save_scatter(&scatter(&points, &labels, &predicted), &output)
+    }
+    else{
+        fs::write(&output, projection_csv(&indices, &labels, &predicted, &points))
+    };
+    match saved {
+        Ok(()) => {
+            println!("Saved {}", output.display());
+            0
+        },
+        Err(e) => {
+            eprintln!("Error: {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+// the class fc1 picks for an embedding
+fn model_argmax(cnn: &CNN, embedding: &Vec<f32>) -> u8{
+    model::argmax(&cnn.fc.forward(embedding)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    fn clusters(rng: &mut Rng, count: usize, dimensions: usize) -> (Vec<Vec<f32>>, Vec<u8>){
+        let mut data = Vec::new();
+        let mut labels = Vec::new();
+        for i in 0..count{
+            let label = (i % 3) as u8;
+            let mut point: Vec<f32> = (0..dimensions).map(|_| 0.3 * rng.normal()).collect();
+            point[label as usize] += 5.0;
+            data.push(point);
+            labels.push(label);
+        }
+        (data, labels)
+    }
+
+    #[test]
+    fn pca_test(){
+        let mut rng = Rng::new(3);
+        // along (1, 2, 2) / 3 with a little noise
+        let data: Vec<Vec<f32>> = (0..200).map(|_| {
+            let t = 4.0 * rng.normal();
+            vec![t / 3.0 + 0.01 * rng.normal(), 2.0 * t / 3.0 + 0.01 * rng.normal(), 2.0 * t / 3.0 + 5.0]
+        }).collect();
+        let pca = pca(&data, 2);
+        let first = &pca.components[0];
+        let dot = (first[0] + 2.0 * first[1] + 2.0 * first[2]) / 3.0;
+        assert!(dot.abs() > 0.999, "Sample: {:?}", first);
+        assert!(pca.explained()[0] > 0.999, "Sample: {:?}", pca.explained());
+        assert!((pca.mean[2] - 5.0).abs() < 1e-3);
+
+        // all components of 2D data keep the distances
+        let data = vec![vec![0.0, 0.0], vec![3.0, 1.0], vec![1.0, -2.0], vec![-1.0, 4.0]];
+        let pca = super::pca(&data, 2);
+        let projected: Vec<Vec<f32>> = data.iter().map(|point| pca.project(point)).collect();
+        assert_eq!(squared_distances(&projected).len(), 4);
+        for (a, b) in squared_distances(&projected).iter().zip(squared_distances(&data).iter()){
+            for j in 0..4{
+                assert!((a[j] - b[j]).abs() < 1e-4, "Sample: {:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn tsne_test(){
+        let mut rng = Rng::new(5);
+        let (data, labels) = clusters(&mut rng, 60, 10);
+        let p = affinities(&data, 10.0);
+        let total: f64 = p.iter().map(|row| row.iter().sum::<f64>()).sum();
+        assert!((total - 1.0).abs() < 1e-6, "Sample: {}", total);
+        assert_eq!(p[3][17], p[17][3]);
+
+        let config = Tsne{perplexity: 10.0, iterations: 300, dimensions: 5, ..Tsne::default()};
+        let mut reports = Vec::new();
+        let points = config.run(&data, |iteration, kl| reports.push((iteration, kl)));
+        assert_eq!(points.len(), 60);
+        assert_eq!(reports.len(), 6);
+        assert!(reports[5].1 < reports[0].1, "Sample: {:?}", reports);
+        // the clusters stay apart in the plane
+        assert!(outliers(&points, &labels, 1).is_empty(), "Sample: {:?}", outliers(&points, &labels, 1));
+        assert_eq!(outliers(&[(0.0, 0.0), (0.1, 0.0), (0.0, 0.1), (5.0, 5.0)], &[1, 1, 2, 2], 2), vec![2]);
+    }
+
+    #[test]
+    fn embed_test(){
+        let cnn = testutil::model();
+        let mut image = vec![vec![0.0; 28]; 28];
+        for i in 4..24{
+            image[i][13] = 1.0;
+        }
+        let embeddings = embeddings(&cnn, &[image.clone(), vec![vec![0.0; 28]; 28]]);
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(embeddings[0], hooks::embedding(&cnn, &preprocess::to_input(&image)));
+        assert_eq!(model_argmax(&cnn, &embeddings[0]) as u32, cnn.forward(&preprocess::to_input(&image)));
+
+        let csv = embeddings_csv(&[4, 9], &[1, 0], &[1, 7], &embeddings);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].split(',').count(), 203);
+        assert!(lines[2].starts_with("9,0,7,"));
+        assert_eq!(projection_csv(&[4], &[1], &[1], &[(0.5, -2.0)]), "index,label,predicted,x,y\n4,1,1,0.5,-2\n");
+
+        let points = [(0.0, 0.0), (10.0, 5.0)];
+        let positions = layout(&points);
+        assert_eq!(positions[0].0, MARGIN as i32);
+        assert_eq!(positions[1].0, (SIZE - MARGIN) as i32);
+        let plot = scatter(&points, &[3, 8], &[3, 2]);
+        assert_eq!(plot.dimensions(), (SIZE, SIZE));
+        let (x, y) = positions[0];
+        assert_eq!(plot.get_pixel(x as u32, y as u32), &image::Rgb(PALETTE[3]));
+        // the wrong prediction has a ring
+        let (x, y) = positions[1];
+        assert_eq!(plot.get_pixel(x as u32 + 4, y as u32), &image::Rgb([0, 0, 0]));
+        assert_eq!(plot.get_pixel(x as u32, y as u32), &image::Rgb(PALETTE[8]));
+    }
+}
@@ -0,0 +1,418 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use imagefile;
+use model;
+use preprocess::{self, Placement};
+use CNN;
+
+// # Explanations
+// Which pixels made the network pick a class:
+// - saliency is the magnitude of the gradient of the class logit with
+//   respect to every input pixel
+// - integrated gradients sums those gradients along the way from a blank
+//   canvas to the drawing, the attributions add up to the change in the logit
+// - Grad-CAM weights the conv2 feature maps (after ReLU) by their mean
+//   gradient, which gives a coarse 11x11 map of where the evidence is
+// `explain` scales each to a 28x28 heatmap in [0, 1] on the network input,
+// `project` maps that back onto the canvas the digit was drawn on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method{
+    Saliency,
+    IntegratedGradients,
+    GradCam
+}
+
+// every activation of one forward pass, for the backward passes below
+struct Pass{
+    conv1: Vec<Vec<Vec<f32>>>,
+    relu1: Vec<Vec<Vec<f32>>>,
+    pool1: Vec<Vec<Vec<f32>>>,
+    conv2: Vec<Vec<Vec<f32>>>,
+    relu2: Vec<Vec<Vec<f32>>>,
+    pooled: Vec<Vec<Vec<f32>>>,
+    logits: Vec<f32>
+}
+
+// steps along the path for integrated gradients
+const STEPS: usize = 32;
+
+impl Method{
+    pub fn parse(name: &str) -> Option<Method>{
+        match name {
+            "saliency" => Some(Method::Saliency),
+            "integrated-gradients" => Some(Method::IntegratedGradients),
+            "grad-cam" => Some(Method::GradCam),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str{
+        match *self {
+            Method::Saliency => "saliency",
+            Method::IntegratedGradients => "integrated-gradients",
+            Method::GradCam => "grad-cam"
+        }
+    }
+
+    // the toggle key goes through all methods and then off
+    pub fn next(method: Option<Method>) -> Option<Method>{
+        match method {
+            None => Some(Method::GradCam),
+            Some(Method::GradCam) => Some(Method::Saliency),
+            Some(Method::Saliency) => Some(Method::IntegratedGradients),
+            Some(Method::IntegratedGradients) => None
+        }
+    }
+}
+
+impl Pass{
+    fn new(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>) -> Pass{
+        let pool = model::MaxPooling2D::new(2);
+        let conv1 = cnn.conv1.forward(input);
+        let relu1 = model::ReLU::forward(&conv1);
+        let pool1 = pool.forward(&relu1);
+        let conv2 = cnn.conv2.forward(&pool1);
+        let relu2 = model::ReLU::forward(&conv2);
+        let pooled = pool.forward(&relu2);
+        let logits = cnn.fc.forward(&model::Flatten::forward(&pooled));
+        Pass{
+            conv1: conv1,
+            relu1: relu1,
+            pool1: pool1,
+            conv2: conv2,
+            relu2: relu2,
+            pooled: pooled,
+            logits: logits
+        }
+    }
+
+    // gradient of the class logit with respect to the conv2 feature maps
+    fn relu2_gradient(&self, cnn: &CNN, class: usize) -> Vec<Vec<Vec<f32>>>{
+        let mut grad_logits = vec![0.0; self.logits.len()];
+        grad_logits[class] = 1.0;
+        let (grad, _, _) = cnn.fc.backward(&model::Flatten::forward(&self.pooled), &grad_logits);
+        let grad = model::Flatten::backward(&self.pooled, &grad);
+        model::MaxPooling2D::new(2).backward(&self.relu2, &grad)
+    }
+
+    fn input_gradient(&self, cnn: &CNN, input: &Vec<Vec<Vec<f32>>>, class: usize) -> Vec<Vec<Vec<f32>>>{
+        let pool = model::MaxPooling2D::new(2);
+        let grad = model::ReLU::backward(&self.conv2, &self.relu2_gradient(cnn, class));
+        let (grad, _, _) = cnn.conv2.backward(&self.pool1, &grad);
+        let grad = model::ReLU::backward(&self.conv1, &pool.backward(&self.relu1, &grad));
+        cnn.conv1.backward(input, &grad).0
+    }
+}
+
+// gradient of the logit of `class` with respect to the input
+pub fn gradient(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>, class: usize) -> Vec<Vec<Vec<f32>>>{
+    Pass::new(cnn, input).input_gradient(cnn, input, class)
+}
+
+pub fn saliency(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>, class: usize) -> Vec<Vec<f32>>{
+    let grad = gradient(cnn, input, class);
+    grad[0].iter().map(|row| row.iter().map(|g| g.abs()).collect()).collect()
+}
+
+// attributions from `baseline` to `input`, in logit units and signed
+pub fn integrated_gradients(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>, baseline: &Vec<Vec<Vec<f32>>>, class: usize, steps: usize) -> Vec<Vec<f32>>{
+    let (rows, cols) = (input[0].len(), input[0][0].len());
+    let mut total = vec![vec![0.0; cols]; rows];
+    for step in 0..steps{
+        // midpoints of the steps
+        let t = (step as f32 + 0.5) / steps as f32;
+        let point = vec![(0..rows).map(|i| (0..cols).map(|j| baseline[0][i][j] + t * (input[0][i][j] - baseline[0][i][j])).collect()).collect()];
+        let grad = gradient(cnn, &point, class);
+        for i in 0..rows{
+            for j in 0..cols{
+                total[i][j] += grad[0][i][j];
+            }
+        }
+    }
+    for i in 0..rows{
+        for j in 0..cols{
+            total[i][j] *= (input[0][i][j] - baseline[0][i][j]) / steps as f32;
+        }
+    }
+    total
+}
+
+// the 11x11 class activation map of conv2, not scaled
+pub fn grad_cam(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>, class: usize) -> Vec<Vec<f32>>{
+    let pass = Pass::new(cnn, input);
+    let grad = pass.relu2_gradient(cnn, class);
+    let (rows, cols) = (pass.relu2[0].len(), pass.relu2[0][0].len());
+    let mut cam = vec![vec![0.0; cols]; rows];
+    for k in 0..pass.relu2.len(){
+        let weight: f32 = grad[k].iter().flat_map(|row| row.iter()).sum::<f32>() / (rows * cols) as f32;
+        for i in 0..rows{
+            for j in 0..cols{
+                cam[i][j] += weight * pass.relu2[k][i][j];
+            }
+        }
+    }
+    cam.iter().map(|row| row.iter().map(|&v| v.max(0.0)).collect()).collect()
+}
+
+// bilinear sample at (row, col) in pixel centers, edges are repeated
+pub fn sample(map: &Vec<Vec<f32>>, row: f32, col: f32) -> f32{
+    let (rows, cols) = (map.len(), map[0].len());
+    let r = row.max(0.0).min((rows - 1) as f32);
+    let c = col.max(0.0).min((cols - 1) as f32);
+    let (r0, c0) = (r.floor() as usize, c.floor() as usize);
+    let (r1, c1) = ((r0 + 1).min(rows - 1), (c0 + 1).min(cols - 1));
+    let (fr, fc) = (r - r0 as f32, c - c0 as f32);
+    let top = map[r0][c0] * (1.0 - fc) + map[r0][c1] * fc;
+    let bottom = map[r1][c0] * (1.0 - fc) + map[r1][c1] * fc;
+    top * (1.0 - fr) + bottom * fr
+}
+
+fn normalize(map: Vec<Vec<f32>>) -> Vec<Vec<f32>>{
+    let max = map.iter().flat_map(|row| row.iter()).fold(0.0f32, |max, &v| max.max(v));
+    if max <= 0.0{
+        return map.iter().map(|row| vec![0.0; row.len()]).collect();
+    }
+    map.iter().map(|row| row.iter().map(|&v| v.max(0.0) / max).collect()).collect()
+}
+
+// 28x28 heatmap in [0, 1] of the evidence for `class`
+pub fn explain(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>, class: usize, method: Method) -> Vec<Vec<f32>>{
+    let (rows, cols) = (input[0].len(), input[0][0].len());
+    match method {
+        Method::Saliency => normalize(saliency(cnn, input, class)),
+        Method::IntegratedGradients => {
+            // a blank canvas, what preprocess::to_input makes of zeros
+            let baseline = vec![vec![vec![-1.0; cols]; rows]];
+            normalize(integrated_gradients(cnn, input, &baseline, class, STEPS))
+        },
+        Method::GradCam => {
+            let cam = grad_cam(cnn, input, class);
+            // conv2 output (x, y) sees input rows 2x..2x+7, centered on 2x+3.5
+            normalize((0..rows).map(|i| (0..cols).map(|j| {
+                sample(&cam, (i as f32 - 3.5) / 2.0, (j as f32 - 3.5) / 2.0)
+            }).collect()).collect())
+        }
+    }
+}
+
+// the 28x28 heatmap on a (rows, cols) view of the source image that
+// `placement` was found in, e.g. the canvas for a drawing that was
+// downsampled to (source_rows, source_cols) first. Zero where the network
+// input does not reach.
+pub fn project(heatmap: &Vec<Vec<f32>>, placement: &Placement, source_rows: usize, source_cols: usize, rows: usize, cols: usize) -> Vec<Vec<f32>>{
+    let (scale_row, scale_col) = (source_rows as f32 / rows as f32, source_cols as f32 / cols as f32);
+    let mut output = vec![vec![0.0; cols]; rows];
+    for y in 0..rows{
+        for x in 0..cols{
+            let (row, col) = placement.to_mnist((y as f32 + 0.5) * scale_row, (x as f32 + 0.5) * scale_col);
+            if row >= 0.0 && col >= 0.0 && row < heatmap.len() as f32 && col < heatmap[0].len() as f32{
+                output[y][x] = sample(heatmap, row - 0.5, col - 0.5);
+            }
+        }
+    }
+    output
+}
+
+// blue for nothing over cyan and yellow to red for the strongest evidence
+pub fn heat_color(value: f32) -> [u8; 3]{
+    let channel = |center: f32| ((1.5 - (4.0 * value - center).abs()).clamp(0.0, 1.0) * 255.0).round() as u8;
+    [channel(3.0), channel(2.0), channel(1.0)]
+}
+
+// the drawing (1 is ink) in black on white, `scale` times bigger, with the
+// heatmap blended over it
+pub fn overlay(image: &Vec<Vec<f32>>, heatmap: &Vec<Vec<f32>>, scale: usize) -> image::RgbImage{
+    let (rows, cols) = (image.len(), image[0].len());
+    image::RgbImage::from_fn((cols * scale) as u32, (rows * scale) as u32, |x, y| {
+        let (i, j) = (y as usize / scale, x as usize / scale);
+        let gray = 255.0 * (1.0 - image[i][j].clamp(0.0, 1.0));
+        let heat = sample(heatmap, (y as f32 + 0.5) / scale as f32 - 0.5, (x as f32 + 0.5) / scale as f32 - 0.5);
+        let color = heat_color(heat);
+        let alpha = 0.7 * heat;
+        let blend = |c: u8| (gray * (1.0 - alpha) + c as f32 * alpha).round() as u8;
+        image::Rgb([blend(color[0]), blend(color[1]), blend(color[2])])
+    })
+}
+
+// explains the prediction for an image file (or `class`) and writes the
+// overlay to `output`, returns (predicted, explained) classes
+pub fn explain_file(cnn: &CNN, path: &Path, method: Method, class: Option<usize>, scale: usize, output: &Path) -> io::Result<(usize, usize)>{
+    let image = preprocess::mnist(&imagefile::load(path)?);
+    let input = preprocess::to_input(&image);
+    let predicted = model::argmax(&cnn.probabilities(&input));
+    let class = class.unwrap_or(predicted);
+    let heatmap = explain(cnn, &input, class, method);
+    overlay(&image, &heatmap, scale).save_with_format(output, image::ImageFormat::Png)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok((predicted, class))
+}
+
+const USAGE: &str = "usage: draw-rust explain [--method grad-cam|saliency|integrated-gradients] [--class 0-9] [--model model.json] [--scale 10] [--output heatmap.png] <images>";
+
+// arguments after `explain`, returns the exit code. Every image gets a PNG
+// next to it named after the method, unless --output is given for one image.
+pub fn run(args: &[String]) -> i32{
+    let mut method = Method::GradCam;
+    let mut class = None;
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut scale = 10;
+    let mut output = None;
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < args.len(){
+        match args[i].as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            "--method" | "--class" | "--model" | "--scale" | "--output" => {
+                let value = match args.get(i + 1) {
+                    Some(value) => value,
+                    None => {
+                        eprintln!("{} needs a value\n{}", args[i], USAGE);
+                        return 2;
+                    }
+                };
+                let parsed = match args[i].as_str() {
+                    "--method" => Method::parse(value).map(|m| method = m).is_some(),
+                    "--class" => value.parse().ok().filter(|&c: &usize| c < 10).map(|c| class = Some(c)).is_some(),
+                    "--model" => {model_path = PathBuf::from(value); true},
+                    "--scale" => value.parse().ok().filter(|&s: &usize| (1..=100).contains(&s)).map(|s| scale = s).is_some(),
+                    _ => {output = Some(PathBuf::from(value)); true}
+                };
+                if !parsed{
+                    eprintln!("{} does not take {}\n{}", args[i], value, USAGE);
+                    return 2;
+                }
+                i += 1;
+            },
+            other if other.starts_with("--") => {
+                eprintln!("unknown option {}\n{}", other, USAGE);
+                return 2;
+            },
+            _ => files.push(PathBuf::from(&args[i]))
+        }
+        i += 1;
+    }
+    if files.is_empty() || (output.is_some() && files.len() > 1){
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    let cnn = match ::load_cnn(&model_path) {
+        Ok(cnn) => cnn,
+        Err(e) => {
+            eprintln!("Error: {}: {}", model_path.display(), e);
+            return 1;
+        }
+    };
+    let mut code = 0;
+    for file in files.iter(){
+        let target = output.clone().unwrap_or_else(|| file.with_file_name(format!("{}-{}.png",
+            file.file_stem().map_or("image".into(), |stem| stem.to_string_lossy()), method.name())));
+        match explain_file(&cnn, file, method, class, scale, &target) {
+            Ok((predicted, explained)) => println!("{}: predicted {}, {} of {} saved to {}",
+                file.display(), predicted, method.name(), explained, target.display()),
+            Err(e) => {
+                eprintln!("Error: {}: {}", file.display(), e);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    // a slanted stroke, preprocessed like the canvas
+    fn stroke() -> Vec<Vec<Vec<f32>>>{
+        let mut image = vec![vec![0.0; 28]; 28];
+        for i in 4..24{
+            image[i][10 + i / 3] = 1.0;
+            image[i][11 + i / 3] = 0.6;
+        }
+        preprocess::to_input(&image)
+    }
+
+    #[test]
+    fn gradient_test(){
+        let (cnn, input) = (testutil::model(), stroke());
+        let grad = gradient(&cnn, &input, 4);
+        for &(i, j) in [(10, 13), (14, 15), (20, 17), (5, 5)].iter(){
+            let (mut plus, mut minus) = (input.clone(), input.clone());
+            plus[0][i][j] += 1e-2;
+            minus[0][i][j] -= 1e-2;
+            let numeric = (cnn.logits(&plus)[4] - cnn.logits(&minus)[4]) / 2e-2;
+            assert!((numeric - grad[0][i][j]).abs() < 1e-2, "Sample: ({}, {}) {} {}", i, j, numeric, grad[0][i][j]);
+        }
+    }
+
+    #[test]
+    fn integrated_gradients_test(){
+        // the attributions add up to the difference of the logits
+        let (cnn, input) = (testutil::model(), stroke());
+        let baseline = vec![vec![vec![-1.0; 28]; 28]];
+        let class = cnn.forward(&input) as usize;
+        let attributions = integrated_gradients(&cnn, &input, &baseline, class, 128);
+        let total: f32 = attributions.iter().flat_map(|row| row.iter()).sum();
+        let difference = cnn.logits(&input)[class] - cnn.logits(&baseline)[class];
+        assert!((total - difference).abs() < 0.05 * difference.abs().max(1.0), "Sample: {} {}", total, difference);
+        // pixels that equal the baseline get nothing
+        assert_eq!(attributions[0][0], 0.0);
+    }
+
+    #[test]
+    fn explain_test(){
+        let (cnn, input) = (testutil::model(), stroke());
+        assert_eq!(grad_cam(&cnn, &input, 4).len(), 11);
+        for &method in [Method::Saliency, Method::IntegratedGradients, Method::GradCam].iter(){
+            let heatmap = explain(&cnn, &input, 4, method);
+            assert_eq!((heatmap.len(), heatmap[0].len()), (28, 28));
+            let values: Vec<f32> = heatmap.iter().flat_map(|row| row.iter().cloned()).collect();
+            assert!(values.iter().all(|&v| (0.0..=1.0).contains(&v)), "{}", method.name());
+            assert_eq!(values.iter().cloned().fold(0.0, f32::max), 1.0, "{}", method.name());
+            assert_eq!(Method::parse(method.name()), Some(method));
+        }
+        // the toggle goes through every method once
+        let mut method = Method::next(None);
+        let mut count = 0;
+        while method.is_some(){
+            count += 1;
+            method = Method::next(method);
+        }
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn project_test(){
+        // a 10x10 square in a 56x56 image fills the middle of the 28x28 input
+        let mut image = vec![vec![0.0; 56]; 56];
+        for i in 30..40{
+            for j in 6..16{
+                image[i][j] = 1.0;
+            }
+        }
+        let placement = preprocess::placement(&image).unwrap();
+        let mut heatmap = vec![vec![0.0; 28]; 28];
+        for i in 4..24{
+            for j in 4..24{
+                heatmap[i][j] = 1.0;
+            }
+        }
+        // twice the size of the image, like a canvas that was downsampled
+        let projected = project(&heatmap, &placement, 56, 56, 112, 112);
+        assert_eq!(projected[70][22], 1.0);
+        assert_eq!(projected[10][100], 0.0);
+        assert_eq!(heat_color(0.0), [0, 0, 128]);
+        assert_eq!(heat_color(1.0), [128, 0, 0]);
+
+        let png = overlay(&vec![vec![1.0, 0.0]], &vec![vec![0.0, 0.0]], 3);
+        assert_eq!(png.dimensions(), (6, 3));
+        assert_eq!(png.get_pixel(0, 0), &image::Rgb([0, 0, 0]));
+        assert_eq!(png.get_pixel(5, 2), &image::Rgb([255, 255, 255]));
+    }
+}
@@ -0,0 +1,284 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use imagefile;
+use preprocess;
+use CNN;
+
+// # Feature maps
+// A sheet with what the layers of the CNN look at: the conv1 kernels, the
+// conv2 kernels (one row per output channel, one column per input channel)
+// and, for a drawing, the input and the maps after relu1, pool1, relu2 and
+// pool2 as `CNN::trace` sees them. Every section is a row (or rows) of
+// tiles, from top to bottom in that order.
+//
+// Kernels are red where positive and blue where negative, activations white
+// on black. Each section is scaled by its own largest value, so the tiles
+// within a section can be compared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section{
+    pub name: &'static str,
+    // (rows, cols) maps, one per tile
+    pub maps: Vec<Vec<Vec<f32>>>,
+    pub columns: usize,
+    // kernels have a sign, activations after ReLU do not
+    pub signed: bool
+}
+
+const MARGIN: u32 = 8;
+const GAP: u32 = 4;
+// activation tiles are about this many pixels wide, kernels a third of it
+const TILE: u32 = 96;
+
+pub fn filter_sections(cnn: &CNN) -> Vec<Section>{
+    let conv2 = cnn.conv2.filter();
+    vec![
+        Section{
+            name: "conv1",
+            maps: cnn.conv1.filter().iter().map(|output| output[0].clone()).collect(),
+            columns: cnn.conv1.filter().len(),
+            signed: true
+        },
+        Section{
+            name: "conv2",
+            maps: conv2.iter().flat_map(|output| output.iter().cloned()).collect(),
+            columns: conv2[0].len(),
+            signed: true
+        }
+    ]
+}
+
+// the activations of a 28x28 image, preprocessed like MNIST
+pub fn activation_sections(cnn: &CNN, image: &Vec<Vec<f32>>) -> Vec<Section>{
+    let trace = cnn.trace(&preprocess::to_input(image));
+    let mut sections = vec![Section{name: "input", maps: vec![image.clone()], columns: 1, signed: false}];
+    for &(layer, channels) in [("relu1", cnn.conv1.filter().len()), ("pool1", cnn.conv1.filter().len()),
+        ("relu2", cnn.conv2.filter().len()), ("pool2", cnn.conv2.filter().len())].iter(){
+        let values = &trace.iter().find(|&&(name, _)| name == layer).unwrap().1;
+        // square maps, flattened one channel after another
+        let side = ((values.len() / channels) as f32).sqrt().round() as usize;
+        let maps = values.chunks(side * side).map(|map| map.chunks(side).map(|row| row.to_vec()).collect()).collect();
+        sections.push(Section{name: layer, maps: maps, columns: channels, signed: false});
+    }
+    sections
+}
+
+// kernels, and the activations of `image` when there is one
+pub fn sections(cnn: &CNN, image: Option<&Vec<Vec<f32>>>) -> Vec<Section>{
+    let mut sections = filter_sections(cnn);
+    if let Some(image) = image{
+        sections.extend(activation_sections(cnn, image));
+    }
+    sections
+}
+
+// red for positive, blue for negative, white for zero, `value` in [-1, 1]
+pub fn signed_color(value: f32) -> [u8; 3]{
+    let v = value.clamp(-1.0, 1.0);
+    let fade = (255.0 * (1.0 - v.abs())).round() as u8;
+    if v >= 0.0 {[255, fade, fade]} else {[fade, fade, 255]}
+}
+
+impl Section{
+    fn scale(&self) -> u32{
+        let size = self.maps.iter().map(|map| map.len().max(map[0].len())).max().unwrap_or(1) as u32;
+        let target = if self.signed {TILE / 3} else {TILE};
+        (target / size).max(1)
+    }
+
+    fn tile_size(&self) -> (u32, u32){
+        let scale = self.scale();
+        (self.maps[0][0].len() as u32 * scale, self.maps[0].len() as u32 * scale)
+    }
+
+    // (width, height) in the sheet
+    pub fn size(&self) -> (u32, u32){
+        let (width, height) = self.tile_size();
+        let rows = self.maps.len().div_ceil(self.columns) as u32;
+        (self.columns as u32 * (width + GAP) - GAP, rows * (height + GAP) - GAP)
+    }
+
+    fn largest(&self) -> f32{
+        self.maps.iter().flat_map(|map| map.iter().flat_map(|row| row.iter())).fold(0.0f32, |max, v| max.max(v.abs()))
+    }
+
+    fn draw(&self, sheet: &mut image::RgbImage, left: u32, top: u32){
+        let (width, height) = self.tile_size();
+        let scale = self.scale();
+        let largest = self.largest();
+        for (n, map) in self.maps.iter().enumerate(){
+            let x0 = left + (n % self.columns) as u32 * (width + GAP);
+            let y0 = top + (n / self.columns) as u32 * (height + GAP);
+            for y in 0..height{
+                for x in 0..width{
+                    let value = map[(y / scale) as usize][(x / scale) as usize];
+                    let value = if largest > 0.0 {value / largest} else {0.0};
+                    let color = if self.signed {signed_color(value)} else {
+                        let gray = (255.0 * value.clamp(0.0, 1.0)).round() as u8;
+                        [gray, gray, gray]
+                    };
+                    sheet.put_pixel(x0 + x, y0 + y, image::Rgb(color));
+                }
+            }
+        }
+    }
+}
+
+// the sections below each other on a light gray background, with a line
+// between them
+pub fn render(sections: &[Section]) -> image::RgbImage{
+    let width = sections.iter().map(|s| s.size().0).max().unwrap_or(0) + 2 * MARGIN;
+    let height = sections.iter().map(|s| s.size().1 + 2 * MARGIN).sum::<u32>().max(1);
+    let mut sheet = image::RgbImage::from_pixel(width, height, image::Rgb([230, 230, 230]));
+    let mut top = 0;
+    for (n, section) in sections.iter().enumerate(){
+        if n > 0{
+            for x in 0..width{
+                sheet.put_pixel(x, top, image::Rgb([150, 150, 150]));
+            }
+        }
+        section.draw(&mut sheet, MARGIN, top + MARGIN);
+        top += section.size().1 + 2 * MARGIN;
+    }
+    sheet
+}
+
+pub fn sheet(cnn: &CNN, image: Option<&Vec<Vec<f32>>>) -> image::RgbImage{
+    render(&sections(cnn, image))
+}
+
+pub fn save(sheet: &image::RgbImage, path: &Path) -> io::Result<()>{
+    if let Some(dir) = path.parent(){
+        std::fs::create_dir_all(dir)?;
+    }
+    sheet.save_with_format(path, image::ImageFormat::Png)
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+const USAGE: &str = "usage: draw-rust features [--model model.json] [--output features.png] [<image>]";
+
+// arguments after `features`, returns the exit code. Without an image the
+// sheet only has the kernels.
+pub fn run(args: &[String]) -> i32{
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut output = None;
+    let mut file = None;
+    let mut i = 0;
+    while i < args.len(){
+        match args[i].as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            "--model" | "--output" => {
+                let value = match args.get(i + 1) {
+                    Some(value) => PathBuf::from(value),
+                    None => {
+                        eprintln!("{} needs a value\n{}", args[i], USAGE);
+                        return 2;
+                    }
+                };
+                if args[i] == "--model" {model_path = value} else {output = Some(value)}
+                i += 1;
+            },
+            other if other.starts_with("--") || file.is_some() => {
+                eprintln!("unexpected argument {}\n{}", other, USAGE);
+                return 2;
+            },
+            _ => file = Some(PathBuf::from(&args[i]))
+        }
+        i += 1;
+    }
+    let output = output.unwrap_or_else(|| match file {
+        Some(ref file) => file.with_file_name(format!("{}-features.png",
+            file.file_stem().map_or("image".into(), |stem| stem.to_string_lossy()))),
+        None => PathBuf::from("features.png")
+    });
+    let cnn = match ::load_cnn(&model_path) {
+        Ok(cnn) => cnn,
+        Err(e) => {
+            eprintln!("Error: {}: {}", model_path.display(), e);
+            return 1;
+        }
+    };
+    let image = match file {
+        Some(ref file) => match imagefile::load(file) {
+            Ok(pixels) => Some(preprocess::mnist(&pixels)),
+            Err(e) => {
+                eprintln!("Error: {}: {}", file.display(), e);
+                return 1;
+            }
+        },
+        None => None
+    };
+    let sections = sections(&cnn, image.as_ref());
+    let names: Vec<&str> = sections.iter().map(|s| s.name).collect();
+    match save(&render(&sections), &output) {
+        Ok(()) => {
+            println!("Saved {} ({} from top to bottom)", output.display(), names.join(", "));
+            0
+        },
+        Err(e) => {
+            eprintln!("Error: {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn sections_test(){
+        let cnn = testutil::model();
+        let filters = filter_sections(&cnn);
+        assert_eq!(filters[0].maps.len(), 4);
+        assert_eq!(filters[1].maps.len(), 32);
+        assert_eq!(filters[1].columns, 4);
+        // output 2, input 3 of conv2 is the 12th tile
+        assert_eq!(filters[1].maps[2 * 4 + 3], cnn.conv2.filter()[2][3]);
+
+        let mut image = vec![vec![0.0; 28]; 28];
+        for i in 4..24{
+            image[i][13] = 1.0;
+            image[i][14] = 1.0;
+        }
+        let sections = sections(&cnn, Some(&image));
+        let shapes: Vec<(&str, usize, usize)> = sections.iter().map(|s| (s.name, s.maps.len(), s.maps[0].len())).collect();
+        assert_eq!(shapes, vec![("conv1", 4, 3), ("conv2", 32, 3), ("input", 1, 28),
+            ("relu1", 4, 26), ("pool1", 4, 13), ("relu2", 8, 11), ("pool2", 8, 5)]);
+        // the same values as the forward pass
+        let pool2: Vec<f32> = sections[6].maps.iter().flat_map(|map| map.iter().flat_map(|row| row.iter().cloned())).collect();
+        let trace = cnn.trace(&preprocess::to_input(&image));
+        assert_eq!(&pool2, &trace.iter().find(|&&(name, _)| name == "pool2").unwrap().1);
+        assert!(sections[3].maps.iter().all(|map| map.iter().all(|row| row.iter().all(|&v| v >= 0.0))));
+    }
+
+    #[test]
+    fn render_test(){
+        assert_eq!(signed_color(0.0), [255, 255, 255]);
+        assert_eq!(signed_color(1.0), [255, 0, 0]);
+        assert_eq!(signed_color(-2.0), [0, 0, 255]);
+
+        let section = Section{
+            name: "test",
+            maps: vec![vec![vec![0.0, 2.0]], vec![vec![1.0, 0.5]], vec![vec![0.0, 0.0]]],
+            columns: 2,
+            signed: false
+        };
+        // 48 pixels per value, two tiles per row
+        assert_eq!(section.size(), (2 * 96 + GAP, 2 * 48 + GAP));
+        let sheet = render(&[section]);
+        assert_eq!(sheet.dimensions(), (2 * 96 + GAP + 2 * MARGIN, 2 * 48 + GAP + 2 * MARGIN));
+        assert_eq!(sheet.get_pixel(MARGIN, MARGIN), &image::Rgb([0, 0, 0]));
+        assert_eq!(sheet.get_pixel(MARGIN + 48, MARGIN), &image::Rgb([255, 255, 255]));
+        assert_eq!(sheet.get_pixel(MARGIN + 96 + GAP, MARGIN), &image::Rgb([128, 128, 128]));
+        assert_eq!(sheet.get_pixel(MARGIN + 96, MARGIN), &image::Rgb([230, 230, 230]));
+
+        let kernels = super::sheet(&testutil::model(), None);
+        assert!(kernels.width() > 4 * 33 && kernels.height() > 8 * 33, "Sample: {:?}", kernels.dimensions());
+    }
+}
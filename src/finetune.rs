@@ -0,0 +1,306 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+
+use augment::Augment;
+use checkpoint::{Checkpoint, CheckpointManager};
+use preprocess;
+use rng::Rng;
+use train::{self, Sgd, StepLR};
+use CNN;
+
+// # Fine-tuning
+// Adapts a trained CNN to the user's own drawings. A part of the samples is
+// held out, the rest is trained on for a few epochs (with augmentation,
+// since there are usually only a few dozen), and the accuracy on the
+// held-out part is reported before and after.
+//
+// With `checkpoints` set, a checkpoint is written there after every epoch
+// and `run` can continue from any of them: the split is redone from the
+// seed, and weights, optimizer, scheduler and RNG come from the checkpoint,
+// so the result is the same as a run that was never stopped.
+#[derive(Debug, Clone)]
+pub struct FineTune{
+    pub epochs: u32,
+    pub batch_size: usize,
+    pub lr: f32,
+    // the learning rate is multiplied by this after every epoch
+    pub lr_decay: f32,
+    pub momentum: f32,
+    // only train fc1, conv1 and conv2 keep the MNIST features
+    pub freeze_conv: bool,
+    // fraction of the samples used for the before/after accuracy
+    pub holdout: f32,
+    pub augment: bool,
+    pub seed: u64,
+    pub checkpoints: Option<PathBuf>
+}
+
+#[derive(Debug, Clone)]
+pub struct Report{
+    pub train_samples: usize,
+    pub holdout_samples: usize,
+    pub before: f32,
+    pub after: f32,
+    // mean loss of every epoch
+    pub losses: Vec<f32>
+}
+
+pub enum Progress{
+    // (epoch, epochs, mean loss)
+    Epoch(u32, u32, f32),
+    Done(CNN, Report),
+    Failed(String)
+}
+
+impl Default for FineTune{
+    fn default() -> FineTune{
+        FineTune{
+            epochs: 5,
+            batch_size: 8,
+            lr: 0.001,
+            lr_decay: 1.0,
+            momentum: 0.9,
+            freeze_conv: false,
+            holdout: 0.2,
+            augment: true,
+            seed: 1,
+            checkpoints: None
+        }
+    }
+}
+
+// shuffles the samples and splits them into (train, holdout) indices, the
+// holdout gets at least one sample as soon as there are two
+pub fn split(count: usize, holdout: f32, rng: &mut Rng) -> (Vec<usize>, Vec<usize>){
+    let mut indices: Vec<usize> = (0..count).collect();
+    rng.shuffle(&mut indices);
+    let held = if count >= 2 {((count as f32 * holdout).round() as usize).max(1).min(count - 1)} else {0};
+    let train = indices.split_off(held);
+    (train, indices)
+}
+
+impl FineTune{
+    fn frozen(&self) -> Vec<&'static str>{
+        if self.freeze_conv {vec!["conv1", "conv2"]} else {Vec::new()}
+    }
+
+    // images are preprocessed 28x28 grids as stored in the dataset. `resume`
+    // is a checkpoint of an earlier run with the same config and samples.
+    pub fn run<F>(&self, mut cnn: CNN, images: &Vec<Vec<Vec<f32>>>, labels: &Vec<u8>, resume: Option<&Checkpoint>, mut progress: F) -> io::Result<(CNN, Report)>
+        where F: FnMut(u32, f32){
+        let mut rng = Rng::new(self.seed);
+        let (train_indices, holdout_indices) = split(images.len(), self.holdout, &mut rng);
+        let holdout_inputs: Vec<Vec<Vec<Vec<f32>>>> = holdout_indices.iter()
+            .map(|&i| preprocess::to_input(&images[i])).collect();
+        let holdout_labels: Vec<u8> = holdout_indices.iter().map(|&i| labels[i]).collect();
+        let before = train::accuracy(&cnn, &holdout_inputs, &holdout_labels);
+
+        let augment = Augment::default();
+        let frozen = self.frozen();
+        let mut optimizer = Sgd::new(self.lr, self.momentum, 0.0);
+        let mut scheduler = StepLR::new(self.lr, 1, self.lr_decay);
+        let mut order = Vec::new();
+        let mut losses = Vec::new();
+        let mut start = 0;
+        let mut step = 0;
+        if let Some(checkpoint) = resume{
+            if checkpoint.epoch > self.epochs{
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("the checkpoint is after epoch {}, the run only has {}", checkpoint.epoch, self.epochs)));
+            }
+            cnn = CNN::from_json(&checkpoint.weights)?;
+            optimizer = checkpoint.optimizer.clone();
+            scheduler = checkpoint.scheduler.clone();
+            rng = checkpoint.rng.clone();
+            start = checkpoint.epoch;
+            step = checkpoint.step;
+        }
+        let manager = match self.checkpoints {
+            Some(ref dir) => Some(CheckpointManager::new(dir, 3, 1)?),
+            None => None
+        };
+
+        for epoch in start..self.epochs{
+            // shuffled from the split order, so an epoch only depends on
+            // the rng state a checkpoint keeps
+            order.clone_from(&train_indices);
+            rng.shuffle(&mut order);
+            let mut loss = 0.0;
+            let mut batches = 0;
+            for batch in order.chunks(self.batch_size.max(1)){
+                let inputs: Vec<Vec<Vec<Vec<f32>>>> = batch.iter().map(|&i| {
+                    let image = if self.augment {augment.apply(&images[i], &mut rng)} else {images[i].clone()};
+                    preprocess::to_input(&image)
+                }).collect();
+                let batch_labels: Vec<u8> = batch.iter().map(|&i| labels[i]).collect();
+                loss += train::train_step(&mut cnn, &mut optimizer, &inputs, &batch_labels, &frozen);
+                batches += 1;
+                step += 1;
+            }
+            scheduler.step(&mut optimizer);
+            let loss = loss / batches.max(1) as f32;
+            losses.push(loss);
+            if let Some(ref manager) = manager{
+                manager.save(&Checkpoint{
+                    epoch: epoch + 1,
+                    step: step,
+                    weights: cnn.to_json(),
+                    optimizer: optimizer.clone(),
+                    scheduler: scheduler.clone(),
+                    rng: rng.clone(),
+                    val_accuracy: Some(train::accuracy(&cnn, &holdout_inputs, &holdout_labels))
+                })?;
+            }
+            progress(epoch + 1, loss);
+        }
+
+        let after = train::accuracy(&cnn, &holdout_inputs, &holdout_labels);
+        let report = Report{
+            train_samples: train_indices.len(),
+            holdout_samples: holdout_indices.len(),
+            before: before,
+            after: after,
+            losses: losses
+        };
+        Ok((cnn, report))
+    }
+
+    // runs on its own thread, the receiver gets one message per epoch and
+    // the tuned model at the end
+    pub fn spawn(&self, cnn: CNN, images: Vec<Vec<Vec<f32>>>, labels: Vec<u8>) -> mpsc::Receiver<Progress>{
+        let (sender, receiver) = mpsc::channel();
+        let config = self.clone();
+        thread::spawn(move || {
+            let epochs = config.epochs;
+            let result = config.run(cnn, &images, &labels, None, |epoch, loss| {
+                let _ = sender.send(Progress::Epoch(epoch, epochs, loss));
+            });
+            let _ = sender.send(match result {
+                Ok((cnn, report)) => Progress::Done(cnn, report),
+                Err(e) => Progress::Failed(e.to_string())
+            });
+        });
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn split_test(){
+        let mut rng = Rng::new(3);
+        let (train, holdout) = split(10, 0.2, &mut rng);
+        assert_eq!((train.len(), holdout.len()), (8, 2));
+        let mut all: Vec<usize> = train.iter().chain(holdout.iter()).cloned().collect();
+        all.sort();
+        assert_eq!(all, (0..10).collect::<Vec<_>>());
+        assert_eq!(split(2, 0.0, &mut rng).1.len(), 1);
+        assert_eq!(split(1, 0.5, &mut rng).1.len(), 0);
+    }
+
+    #[test]
+    fn fine_tune_test(){
+        let cnn = testutil::model();
+        let (images, labels) = testutil::bars_and_rings(12, 7, 2);
+        let config = FineTune{
+            epochs: 8,
+            augment: false,
+            freeze_conv: true,
+            ..FineTune::default()
+        };
+        let mut epochs = Vec::new();
+        let (tuned, report) = config.run(cnn.clone(), &images, &labels, None, |epoch, _| epochs.push(epoch)).unwrap();
+        assert_eq!(epochs, (1..9).collect::<Vec<_>>());
+        assert_eq!(report.train_samples + report.holdout_samples, 12);
+        assert!(report.losses[7] < report.losses[0], "Sample: {:?}", report.losses);
+        assert_eq!(tuned.conv1.filter(), cnn.conv1.filter());
+        assert_eq!(tuned.conv2.filter(), cnn.conv2.filter());
+        assert!(tuned.fc.weights() != cnn.fc.weights());
+    }
+
+    #[test]
+    fn checkpoint_resume_test(){
+        let cnn = testutil::model();
+        let (images, labels) = testutil::bars_and_rings(12, 7, 2);
+        let dir = |name: &str| {
+            let dir = std::env::temp_dir().join(format!("draw-rust-resume-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            dir
+        };
+        // augmentation and a decaying lr, so the rng and the scheduler matter
+        let config = FineTune{
+            epochs: 4,
+            lr_decay: 0.5,
+            checkpoints: Some(dir("full")),
+            ..FineTune::default()
+        };
+        let (full, _) = config.run(cnn.clone(), &images, &labels, None, |_, _| {}).unwrap();
+        let full_checkpoints = CheckpointManager::new(config.checkpoints.as_ref().unwrap(), 3, 1).unwrap();
+        let last = full_checkpoints.latest().unwrap().unwrap();
+        assert_eq!(last.epoch, 4);
+        // serde_json parses the f64 text to within the last digit, which is
+        // exact again once it is an f32
+        assert_eq!(CNN::from_json(&last.weights).unwrap().to_json(), full.to_json());
+
+        // stopped after two epochs, then continued
+        let stopped = FineTune{epochs: 2, checkpoints: Some(dir("stopped")), ..config.clone()};
+        stopped.run(cnn.clone(), &images, &labels, None, |_, _| {}).unwrap();
+        let checkpoint = CheckpointManager::new(stopped.checkpoints.as_ref().unwrap(), 3, 1).unwrap().latest().unwrap().unwrap();
+        assert_eq!(checkpoint.epoch, 2);
+        let resumed = FineTune{checkpoints: Some(dir("resumed")), ..config.clone()};
+        let mut epochs = Vec::new();
+        let (tuned, report) = resumed.run(cnn.clone(), &images, &labels, Some(&checkpoint), |epoch, _| epochs.push(epoch)).unwrap();
+        assert_eq!(epochs, vec![3, 4]);
+        assert_eq!(report.losses.len(), 2);
+        assert_eq!(tuned.to_json(), full.to_json());
+        // weights, optimizer, scheduler and rng, bit for bit
+        let resumed_last = CheckpointManager::new(resumed.checkpoints.as_ref().unwrap(), 3, 1).unwrap().latest().unwrap().unwrap();
+        assert_eq!(resumed_last, last);
+
+        // any of the kept checkpoints leads to the same end
+        for (_, _, path) in full_checkpoints.list().unwrap(){
+            let checkpoint = Checkpoint::load(&path).unwrap();
+            let again = FineTune{checkpoints: None, ..config.clone()};
+            let (tuned, _) = again.run(cnn.clone(), &images, &labels, Some(&checkpoint), |_, _| {}).unwrap();
+            assert_eq!(tuned.to_json(), full.to_json(), "Sample: {}", checkpoint.epoch);
+        }
+        assert!(FineTune{epochs: 3, ..config.clone()}.run(cnn.clone(), &images, &labels, Some(&last), |_, _| {}).is_err());
+
+        for config in [config, stopped, resumed].iter(){
+            std::fs::remove_dir_all(config.checkpoints.as_ref().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn spawn_test(){
+        let cnn = testutil::model();
+        let (images, labels) = testutil::bars_and_rings(12, 7, 2);
+        let config = FineTune{
+            epochs: 2,
+            ..FineTune::default()
+        };
+        let receiver = config.spawn(cnn, images, labels);
+        let mut epochs = 0;
+        loop {
+            match receiver.recv().unwrap() {
+                Progress::Epoch(_, total, loss) => {
+                    assert_eq!(total, 2);
+                    assert!(loss.is_finite());
+                    epochs += 1;
+                },
+                Progress::Done(_, report) => {
+                    assert_eq!(report.losses.len(), 2);
+                    break;
+                },
+                Progress::Failed(e) => panic!("Sample: {}", e)
+            }
+        }
+        assert_eq!(epochs, 2);
+    }
+}
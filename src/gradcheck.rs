@@ -0,0 +1,328 @@
+use std::fmt;
+
+use model::{self, Conv2D, MaxPooling2D, ReLU, Flatten, FullyConnected};
+use rng::Rng;
+
+// # Gradient checking
+// Compares the analytic backward pass of every layer against central finite
+// differences. Each check reduces the layer output to a scalar loss by taking
+// the dot product with a fixed random tensor, so the gradient that goes into
+// backward is exactly that tensor.
+
+const EPS: f32 = 1e-2;
+
+#[derive(Debug, Clone)]
+pub struct GradReport{
+    pub layer: String,
+    pub tensor: String,
+    pub shape: Vec<usize>,
+    pub checked: usize,
+    pub max_abs_error: f32,
+    pub max_rel_error: f32
+}
+
+impl GradReport{
+    pub fn new(layer: &str, tensor: &str, shape: Vec<usize>, analytic: &Vec<f32>, numeric: &Vec<f32>) -> GradReport{
+        let mut max_abs_error = 0.0;
+        let mut max_rel_error = 0.0;
+        for i in 0..analytic.len(){
+            let abs_error = (analytic[i] - numeric[i]).abs();
+            let rel_error = abs_error / analytic[i].abs().max(numeric[i].abs()).max(1e-3);
+            if abs_error > max_abs_error{
+                max_abs_error = abs_error;
+            }
+            if rel_error > max_rel_error{
+                max_rel_error = rel_error;
+            }
+        }
+        GradReport{
+            layer: layer.to_owned(),
+            tensor: tensor.to_owned(),
+            shape: shape,
+            checked: analytic.len(),
+            max_abs_error: max_abs_error,
+            max_rel_error: max_rel_error
+        }
+    }
+
+    pub fn passed(&self, tolerance: f32) -> bool{
+        self.max_rel_error <= tolerance
+    }
+}
+
+impl fmt::Display for GradReport{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{:<16} {:<8} shape {:?}: {} values, max abs error {:.2e}, max rel error {:.2e}",
+            self.layer, self.tensor, self.shape, self.checked, self.max_abs_error, self.max_rel_error)
+    }
+}
+
+fn flat3(t: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+    t.iter().flat_map(|a| a.iter().flat_map(|b| b.iter().cloned())).collect()
+}
+
+fn flat4(t: &Vec<Vec<Vec<Vec<f32>>>>) -> Vec<f32>{
+    t.iter().flat_map(|a| a.iter().flat_map(|b| b.iter().flat_map(|c| c.iter().cloned()))).collect()
+}
+
+fn nth3(t: &mut Vec<Vec<Vec<f32>>>, n: usize) -> &mut f32{
+    t.iter_mut().flat_map(|a| a.iter_mut().flat_map(|b| b.iter_mut())).nth(n).unwrap()
+}
+
+fn nth4(t: &mut Vec<Vec<Vec<Vec<f32>>>>, n: usize) -> &mut f32{
+    t.iter_mut().flat_map(|a| a.iter_mut().flat_map(|b| b.iter_mut().flat_map(|c| c.iter_mut()))).nth(n).unwrap()
+}
+
+fn shape3(t: &Vec<Vec<Vec<f32>>>) -> Vec<usize>{
+    vec![t.len(), t[0].len(), t[0][0].len()]
+}
+
+fn random3(rng: &mut Rng, layers: usize, rows: usize, cols: usize) -> Vec<Vec<Vec<f32>>>{
+    let mut t = vec![vec![vec![0.0; cols]; rows]; layers];
+    for i in 0..layers{
+        for j in 0..rows{
+            for k in 0..cols{
+                t[i][j][k] = rng.uniform(-1.0, 1.0);
+            }
+        }
+    }
+    t
+}
+
+fn random_vec(rng: &mut Rng, len: usize) -> Vec<f32>{
+    (0..len).map(|_| rng.uniform(-1.0, 1.0)).collect()
+}
+
+// values that are all at least 2 * EPS apart and away from zero, so that no
+// finite difference step crosses a ReLU kink or changes a max pooling winner
+fn separated3(rng: &mut Rng, layers: usize, rows: usize, cols: usize) -> Vec<Vec<Vec<f32>>>{
+    let count = layers * rows * cols;
+    let mut values: Vec<f32> = (0..count)
+        .map(|i| (i as f32 - count as f32 / 2.0 + 0.25) * 8.0 * EPS)
+        .collect();
+    rng.shuffle(&mut values);
+    let mut t = vec![vec![vec![0.0; cols]; rows]; layers];
+    for i in 0..count{
+        *nth3(&mut t, i) = values[i];
+    }
+    t
+}
+
+fn dot(a: &Vec<f32>, b: &Vec<f32>) -> f64{
+    let mut sum = 0.0;
+    for i in 0..a.len(){
+        sum += a[i] as f64 * b[i] as f64;
+    }
+    sum
+}
+
+// central differences of `loss` with respect to `count` values, where
+// loss(i, delta) evaluates the loss with `delta` added to value i
+fn numeric_gradient<F>(count: usize, mut loss: F) -> Vec<f32> where F: FnMut(usize, f32) -> f64{
+    let mut grad = vec![0.0; count];
+    for i in 0..count{
+        grad[i] = ((loss(i, EPS) - loss(i, -EPS)) / (2.0 * EPS as f64)) as f32;
+    }
+    grad
+}
+
+pub fn check_conv2d(rng: &mut Rng) -> Vec<GradReport>{
+    let input_size = 1 + rng.below(3);
+    let output_size = 1 + rng.below(4);
+    let kernel = 1 + rng.below(3);
+    let rows = kernel + rng.below(5);
+    let cols = kernel + rng.below(5);
+
+    let mut filter = vec![vec![vec![vec![0.0; kernel]; kernel]; input_size]; output_size];
+    for i in 0..output_size{
+        filter[i] = random3(rng, input_size, kernel, kernel);
+    }
+    let bias = random_vec(rng, output_size);
+    let conv2d = Conv2D::new(input_size as u32, output_size as u32, filter, bias);
+    let input = random3(rng, input_size, rows, cols);
+    let grad_output = random3(rng, output_size, rows - kernel + 1, cols - kernel + 1);
+    let projection = flat3(&grad_output);
+
+    let (grad_input, grad_filter, grad_bias) = conv2d.backward(&input, &grad_output);
+
+    let numeric_input = numeric_gradient(input_size * rows * cols, |i, delta| {
+        let mut input = input.clone();
+        *nth3(&mut input, i) += delta;
+        dot(&flat3(&conv2d.forward(&input)), &projection)
+    });
+    let numeric_filter = numeric_gradient(output_size * input_size * kernel * kernel, |i, delta| {
+        let mut layer = conv2d.clone();
+        *nth4(layer.filter_mut(), i) += delta;
+        dot(&flat3(&layer.forward(&input)), &projection)
+    });
+    let numeric_bias = numeric_gradient(output_size, |i, delta| {
+        let mut layer = conv2d.clone();
+        layer.bias_mut()[i] += delta;
+        dot(&flat3(&layer.forward(&input)), &projection)
+    });
+
+    vec![
+        GradReport::new("Conv2D", "input", shape3(&input), &flat3(&grad_input), &numeric_input),
+        GradReport::new("Conv2D", "filter", vec![output_size, input_size, kernel, kernel], &flat4(&grad_filter), &numeric_filter),
+        GradReport::new("Conv2D", "bias", vec![output_size], &grad_bias, &numeric_bias)
+    ]
+}
+
+pub fn check_max_pooling_2d(rng: &mut Rng) -> Vec<GradReport>{
+    let pool_size = 1 + rng.below(3);
+    let layers = 1 + rng.below(3);
+    let rows = pool_size + rng.below(6);
+    let cols = pool_size + rng.below(6);
+
+    let maxpooling2d = MaxPooling2D::new(pool_size as u32);
+    let input = separated3(rng, layers, rows, cols);
+    let grad_output = random3(rng, layers, rows / pool_size, cols / pool_size);
+    let projection = flat3(&grad_output);
+
+    let grad_input = maxpooling2d.backward(&input, &grad_output);
+    let numeric_input = numeric_gradient(layers * rows * cols, |i, delta| {
+        let mut input = input.clone();
+        *nth3(&mut input, i) += delta;
+        dot(&flat3(&maxpooling2d.forward(&input)), &projection)
+    });
+
+    vec![GradReport::new("MaxPooling2D", "input", shape3(&input), &flat3(&grad_input), &numeric_input)]
+}
+
+pub fn check_relu(rng: &mut Rng) -> Vec<GradReport>{
+    let layers = 1 + rng.below(4);
+    let rows = 1 + rng.below(6);
+    let cols = 1 + rng.below(6);
+
+    let input = separated3(rng, layers, rows, cols);
+    let grad_output = random3(rng, layers, rows, cols);
+    let projection = flat3(&grad_output);
+
+    let grad_input = ReLU::backward(&input, &grad_output);
+    let numeric_input = numeric_gradient(layers * rows * cols, |i, delta| {
+        let mut input = input.clone();
+        *nth3(&mut input, i) += delta;
+        dot(&flat3(&ReLU::forward(&input)), &projection)
+    });
+
+    vec![GradReport::new("ReLU", "input", shape3(&input), &flat3(&grad_input), &numeric_input)]
+}
+
+pub fn check_flatten(rng: &mut Rng) -> Vec<GradReport>{
+    let layers = 1 + rng.below(4);
+    let rows = 1 + rng.below(6);
+    let cols = 1 + rng.below(6);
+
+    let input = random3(rng, layers, rows, cols);
+    let projection = random_vec(rng, layers * rows * cols);
+
+    let grad_input = Flatten::backward(&input, &projection);
+    let numeric_input = numeric_gradient(layers * rows * cols, |i, delta| {
+        let mut input = input.clone();
+        *nth3(&mut input, i) += delta;
+        dot(&Flatten::forward(&input), &projection)
+    });
+
+    vec![GradReport::new("Flatten", "input", shape3(&input), &flat3(&grad_input), &numeric_input)]
+}
+
+pub fn check_fully_connected(rng: &mut Rng) -> Vec<GradReport>{
+    let input_size = 1 + rng.below(20);
+    let output_size = 1 + rng.below(10);
+
+    let weights: Vec<Vec<f32>> = (0..output_size).map(|_| random_vec(rng, input_size)).collect();
+    let bias = random_vec(rng, output_size);
+    let fully_connected = FullyConnected::new(input_size as u32, output_size as u32, weights, bias);
+    let input = random_vec(rng, input_size);
+    let projection = random_vec(rng, output_size);
+
+    let (grad_input, grad_weights, grad_bias) = fully_connected.backward(&input, &projection);
+
+    let numeric_input = numeric_gradient(input_size, |i, delta| {
+        let mut input = input.clone();
+        input[i] += delta;
+        dot(&fully_connected.forward(&input), &projection)
+    });
+    let numeric_weights = numeric_gradient(output_size * input_size, |i, delta| {
+        let mut layer = fully_connected.clone();
+        layer.weights_mut()[i / input_size][i % input_size] += delta;
+        dot(&layer.forward(&input), &projection)
+    });
+    let numeric_bias = numeric_gradient(output_size, |i, delta| {
+        let mut layer = fully_connected.clone();
+        layer.bias_mut()[i] += delta;
+        dot(&layer.forward(&input), &projection)
+    });
+
+    let flat_weights: Vec<f32> = grad_weights.iter().flat_map(|row| row.iter().cloned()).collect();
+    vec![
+        GradReport::new("FullyConnected", "input", vec![input_size], &grad_input, &numeric_input),
+        GradReport::new("FullyConnected", "weights", vec![output_size, input_size], &flat_weights, &numeric_weights),
+        GradReport::new("FullyConnected", "bias", vec![output_size], &grad_bias, &numeric_bias)
+    ]
+}
+
+pub fn check_softmax(rng: &mut Rng) -> Vec<GradReport>{
+    let size = 2 + rng.below(10);
+
+    let input: Vec<f32> = (0..size).map(|_| rng.uniform(-3.0, 3.0)).collect();
+    let projection = random_vec(rng, size);
+
+    let grad_input = model::softmax_backward(&model::softmax(&input), &projection);
+    let numeric_input = numeric_gradient(size, |i, delta| {
+        let mut input = input.clone();
+        input[i] += delta;
+        dot(&model::softmax(&input), &projection)
+    });
+
+    vec![GradReport::new("softmax", "input", vec![size], &grad_input, &numeric_input)]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const TRIALS: u64 = 10;
+    const TOLERANCE: f32 = 1e-2;
+
+    fn run(check: fn(&mut Rng) -> Vec<GradReport>){
+        for seed in 0..TRIALS{
+            let mut rng = Rng::new(seed + 1);
+            for report in check(&mut rng){
+                println!("{}", report);
+                assert!(report.passed(TOLERANCE), "gradient mismatch (seed {}): {}", seed + 1, report);
+            }
+        }
+    }
+
+    #[test]
+    fn conv2d_gradient_test(){
+        run(check_conv2d);
+    }
+
+    #[test]
+    fn max_pooling_2d_gradient_test(){
+        run(check_max_pooling_2d);
+    }
+
+    #[test]
+    fn relu_gradient_test(){
+        run(check_relu);
+    }
+
+    #[test]
+    fn flatten_gradient_test(){
+        run(check_flatten);
+    }
+
+    #[test]
+    fn fully_connected_gradient_test(){
+        run(check_fully_connected);
+    }
+
+    #[test]
+    fn softmax_gradient_test(){
+        run(check_softmax);
+    }
+}
@@ -0,0 +1,237 @@
+use canvas::Canvas;
+
+// # History
+// The drawing is kept as the list of edits that produced it instead of as
+// pixels. Undo and redo only move a cursor through the list and the canvas
+// is rendered again from the edits, so there is no limit on either, and a
+// drawing can be replayed one stroke at a time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stroke{
+    pub erase: bool,
+    pub radius: f32,
+    // window coordinates in the order the mouse visited them
+    pub points: Vec<(f32, f32)>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit{
+    Stroke(Stroke),
+    Clear,
+    // an image file loaded into the canvas, at canvas resolution
+    Image(Vec<Vec<f32>>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct History{
+    edits: Vec<Edit>,
+    // edits[..cursor] are applied, the rest can be redone
+    cursor: usize
+}
+
+impl Stroke{
+    pub fn new(erase: bool, radius: f32) -> Stroke{
+        Stroke{
+            erase: erase,
+            radius: radius,
+            points: Vec::new()
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value{
+        json!({
+            "erase": self.erase,
+            "radius": self.radius,
+            "points": self.points.iter().map(|p| vec![p.0, p.1]).collect::<Vec<_>>()
+        })
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Option<Stroke>{
+        let mut stroke = Stroke::new(json["erase"].as_bool()?, json["radius"].as_f64()? as f32);
+        for point in json["points"].as_array()?{
+            stroke.points.push((point[0].as_f64()? as f32, point[1].as_f64()? as f32));
+        }
+        Some(stroke)
+    }
+}
+
+impl Edit{
+    pub fn apply(&self, canvas: &mut Canvas){
+        match *self {
+            Edit::Stroke(ref stroke) => {
+                let radius = canvas.brush_radius;
+                canvas.brush_radius = stroke.radius;
+                for &(x, y) in stroke.points.iter(){
+                    canvas.stroke_to(x, y, stroke.erase);
+                }
+                canvas.end_stroke();
+                canvas.brush_radius = radius;
+            },
+            Edit::Clear => canvas.clear(),
+            Edit::Image(ref pixels) => canvas.set_pixels(pixels)
+        }
+    }
+}
+
+impl Default for History{
+    fn default() -> History{
+        History::new()
+    }
+}
+
+impl History{
+    pub fn new() -> History{
+        History{
+            edits: Vec::new(),
+            cursor: 0
+        }
+    }
+
+    // the edits that make up the current drawing
+    pub fn edits(&self) -> &[Edit]{
+        &self.edits[..self.cursor]
+    }
+
+    pub fn push(&mut self, edit: Edit){
+        self.edits.truncate(self.cursor);
+        self.edits.push(edit);
+        self.cursor += 1;
+    }
+
+    pub fn can_undo(&self) -> bool{
+        self.cursor > 0
+    }
+
+    pub fn can_redo(&self) -> bool{
+        self.cursor < self.edits.len()
+    }
+
+    pub fn undo(&mut self) -> bool{
+        if self.can_undo(){
+            self.cursor -= 1;
+            true
+        }
+        else{
+            false
+        }
+    }
+
+    pub fn redo(&mut self) -> bool{
+        if self.can_redo(){
+            self.cursor += 1;
+            true
+        }
+        else{
+            false
+        }
+    }
+
+    // applies the first `count` edits of the current drawing to a blank canvas
+    pub fn render(&self, canvas: &mut Canvas, count: usize){
+        canvas.clear();
+        for edit in self.edits().iter().take(count){
+            edit.apply(canvas);
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value{
+        let edits: Vec<serde_json::Value> = self.edits().iter().map(|edit| match *edit {
+            Edit::Stroke(ref stroke) => stroke.to_json(),
+            Edit::Clear => json!("clear"),
+            Edit::Image(ref pixels) => json!({"image": pixels})
+        }).collect();
+        json!({"edits": edits})
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Option<History>{
+        let mut history = History::new();
+        for edit in json["edits"].as_array()?{
+            if edit.as_str() == Some("clear"){
+                history.push(Edit::Clear);
+            }
+            else if let Some(rows) = edit["image"].as_array(){
+                let mut pixels = Vec::with_capacity(rows.len());
+                for row in rows{
+                    let mut values = Vec::new();
+                    for value in row.as_array()?{
+                        values.push(value.as_f64()? as f32);
+                    }
+                    pixels.push(values);
+                }
+                history.push(Edit::Image(pixels));
+            }
+            else{
+                history.push(Edit::Stroke(Stroke::from_json(edit)?));
+            }
+        }
+        Some(history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn line(erase: bool, x0: f32, y0: f32, x1: f32, y1: f32) -> Edit{
+        // the eraser is wider so it also removes the soft edge of the ink
+        let mut stroke = Stroke::new(erase, if erase {8.0} else {4.0});
+        stroke.points.push((x0, y0));
+        stroke.points.push((x1, y1));
+        Edit::Stroke(stroke)
+    }
+
+    fn rendered(history: &History) -> Vec<Vec<f32>>{
+        let mut canvas = Canvas::new(60, 60, 10.0);
+        history.render(&mut canvas, history.edits().len());
+        canvas.pixels().clone()
+    }
+
+    #[test]
+    fn undo_redo_test(){
+        let mut history = History::new();
+        let blank = rendered(&history);
+        history.push(line(false, 10.0, 30.0, 50.0, 30.0));
+        let first = rendered(&history);
+        history.push(line(false, 30.0, 10.0, 30.0, 50.0));
+        history.push(Edit::Clear);
+        assert_eq!(rendered(&history), blank);
+
+        assert!(history.undo());
+        assert!(history.undo());
+        assert_eq!(rendered(&history), first);
+        assert!(history.undo());
+        assert!(!history.undo());
+        assert!(history.redo());
+        assert_eq!(rendered(&history), first);
+
+        // a new edit drops everything that could have been redone
+        history.push(line(true, 10.0, 30.0, 50.0, 30.0));
+        assert!(!history.can_redo());
+        assert_eq!(history.edits().len(), 2);
+        assert_eq!(rendered(&history), blank);
+    }
+
+    #[test]
+    fn render_partial_test(){
+        let mut history = History::new();
+        history.push(line(false, 10.0, 30.0, 50.0, 30.0));
+        let first = rendered(&history);
+        history.push(line(false, 30.0, 10.0, 30.0, 50.0));
+        let mut canvas = Canvas::new(60, 60, 10.0);
+        history.render(&mut canvas, 1);
+        assert_eq!(canvas.pixels(), &first);
+        // replaying does not change the brush the user picked
+        assert_eq!(canvas.brush_radius, 10.0);
+    }
+
+    #[test]
+    fn json_test(){
+        let mut history = History::new();
+        history.push(line(false, 10.5, 30.0, 50.0, 30.25));
+        history.push(Edit::Clear);
+        history.push(line(true, 1.0, 2.0, 3.0, 4.0));
+        history.push(Edit::Image(vec![vec![0.0, 0.5], vec![1.0, 0.25]]));
+        let restored = History::from_json(&history.to_json()).unwrap();
+        assert_eq!(restored, history);
+    }
+}
@@ -0,0 +1,438 @@
+use std::io;
+use std::path::PathBuf;
+
+use cli;
+use idx;
+use imagefile;
+use preprocess;
+use CNN;
+
+// # Forward hooks
+// Callbacks that run before or after a layer of `CNN::forward_with_hooks`.
+// A before hook sees the input of the layer, an after hook its output, and
+// both may change the tensor in place: whatever the hook leaves behind is
+// what the rest of the network gets. Hooks run in the order they were added.
+//
+// `capture`, `embedding` and `Stats` are built on them: copies of chosen
+// activations, the 200 values of `flatten` that fc1 classifies, and the
+// range of every layer over a dataset.
+pub const LAYERS: [&str; 8] = ["conv1", "relu1", "pool1", "conv2", "relu2", "pool2", "flatten", "fc1"];
+
+// feature maps (layers, rows, cols) up to pool2, a vector after that
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tensor{
+    Maps(Vec<Vec<Vec<f32>>>),
+    Vector(Vec<f32>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum When{
+    Before,
+    After
+}
+
+// returned when a hook is added, to remove it again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handle(usize);
+
+type Callback<'a> = Box<dyn FnMut(&'static str, &mut Tensor) + 'a>;
+
+struct Hook<'a>{
+    handle: Handle,
+    // None for every layer
+    layer: Option<&'static str>,
+    when: When,
+    callback: Callback<'a>
+}
+
+pub struct Hooks<'a>{
+    hooks: Vec<Hook<'a>>,
+    next: usize
+}
+
+impl Tensor{
+    pub fn maps(&self) -> &Vec<Vec<Vec<f32>>>{
+        match *self {
+            Tensor::Maps(ref maps) => maps,
+            Tensor::Vector(_) => panic!("expected feature maps, got a vector")
+        }
+    }
+
+    pub fn vector(&self) -> &Vec<f32>{
+        match *self {
+            Tensor::Vector(ref vector) => vector,
+            Tensor::Maps(_) => panic!("expected a vector, got feature maps")
+        }
+    }
+
+    pub fn into_vector(self) -> Vec<f32>{
+        match self {
+            Tensor::Vector(vector) => vector,
+            Tensor::Maps(_) => panic!("expected a vector, got feature maps")
+        }
+    }
+
+    pub fn shape(&self) -> Vec<usize>{
+        match *self {
+            Tensor::Maps(ref maps) => vec![maps.len(), maps[0].len(), maps[0][0].len()],
+            Tensor::Vector(ref vector) => vec![vector.len()]
+        }
+    }
+
+    // flattened in the same order as `model::Flatten`
+    pub fn values(&self) -> Vec<f32>{
+        match *self {
+            Tensor::Maps(ref maps) => maps.iter().flat_map(|map| map.iter().flat_map(|row| row.iter().cloned())).collect(),
+            Tensor::Vector(ref vector) => vector.clone()
+        }
+    }
+
+    pub fn values_mut(&mut self) -> Vec<&mut f32>{
+        match *self {
+            Tensor::Maps(ref mut maps) => maps.iter_mut().flat_map(|map| map.iter_mut().flat_map(|row| row.iter_mut())).collect(),
+            Tensor::Vector(ref mut vector) => vector.iter_mut().collect()
+        }
+    }
+}
+
+impl<'a> Hooks<'a>{
+    pub fn new() -> Hooks<'a>{
+        Hooks{hooks: Vec::new(), next: 0}
+    }
+
+    fn add<F>(&mut self, layer: Option<&str>, when: When, callback: F) -> io::Result<Handle>
+        where F: FnMut(&'static str, &mut Tensor) + 'a{
+        let layer = match layer {
+            Some(name) => match LAYERS.iter().find(|&&known| known == name) {
+                Some(&known) => Some(known),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("unknown layer {}, expected one of {}", name, LAYERS.join(", "))))
+            },
+            None => None
+        };
+        let handle = Handle(self.next);
+        self.next += 1;
+        self.hooks.push(Hook{handle: handle, layer: layer, when: when, callback: Box::new(callback)});
+        Ok(handle)
+    }
+
+    // runs with the input of `layer`
+    pub fn before<F>(&mut self, layer: &str, callback: F) -> io::Result<Handle>
+        where F: FnMut(&'static str, &mut Tensor) + 'a{
+        self.add(Some(layer), When::Before, callback)
+    }
+
+    // runs with the output of `layer`
+    pub fn after<F>(&mut self, layer: &str, callback: F) -> io::Result<Handle>
+        where F: FnMut(&'static str, &mut Tensor) + 'a{
+        self.add(Some(layer), When::After, callback)
+    }
+
+    // runs before or after every layer, with the name of the layer
+    pub fn every<F>(&mut self, when: When, callback: F) -> Handle
+        where F: FnMut(&'static str, &mut Tensor) + 'a{
+        self.add(None, when, callback).unwrap()
+    }
+
+    // false when the hook was already removed
+    pub fn remove(&mut self, handle: Handle) -> bool{
+        let count = self.hooks.len();
+        self.hooks.retain(|hook| hook.handle != handle);
+        self.hooks.len() < count
+    }
+
+    pub fn len(&self) -> usize{
+        self.hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.hooks.is_empty()
+    }
+
+    fn call(&mut self, layer: &'static str, when: When, tensor: &mut Tensor){
+        for hook in self.hooks.iter_mut(){
+            if hook.when == when && hook.layer.is_none_or(|name| name == layer){
+                (hook.callback)(layer, tensor);
+            }
+        }
+    }
+
+    // one layer of the forward pass: before hooks, `forward`, after hooks
+    pub fn run<F>(&mut self, layer: &'static str, input: Tensor, forward: F) -> Tensor
+        where F: FnOnce(&Tensor) -> Tensor{
+        let mut input = input;
+        self.call(layer, When::Before, &mut input);
+        let mut output = forward(&input);
+        self.call(layer, When::After, &mut output);
+        output
+    }
+}
+
+impl<'a> Default for Hooks<'a>{
+    fn default() -> Hooks<'a>{
+        Hooks::new()
+    }
+}
+
+// copies of the outputs of `layers`, in the order of the network
+pub fn capture(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>, layers: &[&str]) -> io::Result<Vec<(&'static str, Tensor)>>{
+    if let Some(unknown) = layers.iter().find(|layer| !LAYERS.contains(layer)){
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("unknown layer {}, expected one of {}", unknown, LAYERS.join(", "))));
+    }
+    let mut captured = Vec::new();
+    {
+        let mut hooks = Hooks::new();
+        hooks.every(When::After, |name, tensor| if layers.contains(&name){
+            captured.push((name, tensor.clone()));
+        });
+        cnn.forward_with_hooks(input, &mut hooks);
+    }
+    Ok(captured)
+}
+
+// the flattened pool2 output that fc1 classifies, 200 values for model.json
+pub fn embedding(cnn: &CNN, input: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+    let mut embedding = Vec::new();
+    {
+        let mut hooks = Hooks::new();
+        hooks.after("flatten", |_, tensor| embedding = tensor.values()).unwrap();
+        cnn.forward_with_hooks(input, &mut hooks);
+    }
+    embedding
+}
+
+// the range of the output of one layer over any number of forward passes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats{
+    pub layer: &'static str,
+    pub shape: Vec<usize>,
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub sum: f64,
+    pub zeros: usize
+}
+
+impl Stats{
+    pub fn new(layer: &'static str) -> Stats{
+        Stats{layer: layer, shape: Vec::new(), count: 0, min: f32::INFINITY, max: f32::NEG_INFINITY, sum: 0.0, zeros: 0}
+    }
+
+    pub fn add(&mut self, tensor: &Tensor){
+        self.shape = tensor.shape();
+        for v in tensor.values(){
+            self.count += 1;
+            self.min = self.min.min(v);
+            self.max = self.max.max(v);
+            self.sum += v as f64;
+            if v == 0.0{
+                self.zeros += 1;
+            }
+        }
+    }
+
+    pub fn mean(&self) -> f32{
+        if self.count == 0 {0.0} else {(self.sum / self.count as f64) as f32}
+    }
+
+    // dead units after a ReLU show up here
+    pub fn zero_fraction(&self) -> f32{
+        if self.count == 0 {0.0} else {self.zeros as f32 / self.count as f32}
+    }
+}
+
+// statistics of every layer output over `inputs`
+pub fn statistics(cnn: &CNN, inputs: &[Vec<Vec<Vec<f32>>>]) -> Vec<Stats>{
+    let mut stats: Vec<Stats> = LAYERS.iter().map(|&layer| Stats::new(layer)).collect();
+    {
+        let mut hooks = Hooks::new();
+        hooks.every(When::After, |layer, tensor| {
+            let index = LAYERS.iter().position(|&name| name == layer).unwrap();
+            stats[index].add(tensor);
+        });
+        for input in inputs{
+            cnn.forward_with_hooks(input, &mut hooks);
+        }
+    }
+    stats
+}
+
+pub fn format_statistics(stats: &[Stats]) -> String{
+    let mut text = format!("{:<8} {:<12} {:>9} {:>9} {:>9} {:>7}\n", "layer", "shape", "min", "mean", "max", "zeros");
+    for s in stats{
+        let shape: Vec<String> = s.shape.iter().map(|n| n.to_string()).collect();
+        text.push_str(&format!("{:<8} {:<12} {:>9.4} {:>9.4} {:>9.4} {:>6.1}%\n",
+            s.layer, shape.join("x"), s.min, s.mean(), s.max, 100.0 * s.zero_fraction()));
+    }
+    text
+}
+
+const USAGE: &str = "usage: draw-rust activations [--model model.json] [--images <idx>] [<images or dirs>]";
+
+// arguments after `activations`, returns the exit code. Prints the range of
+// every layer over the images, preprocessed like `predict` does, and the
+// images of an IDX file.
+pub fn run(args: &[String]) -> i32{
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut images_path = None;
+    let mut paths = Vec::new();
+    let mut i = 0;
+    while i < args.len(){
+        match args[i].as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            "--model" | "--images" => {
+                let value = match args.get(i + 1) {
+                    Some(value) => PathBuf::from(value),
+                    None => {
+                        eprintln!("{} needs a value\n{}", args[i], USAGE);
+                        return 2;
+                    }
+                };
+                if args[i] == "--model" {model_path = value} else {images_path = Some(value)}
+                i += 1;
+            },
+            other if other.starts_with("--") => {
+                eprintln!("unexpected argument {}\n{}", other, USAGE);
+                return 2;
+            },
+            path => paths.push(PathBuf::from(path))
+        }
+        i += 1;
+    }
+    if paths.is_empty() && images_path.is_none(){
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+
+    let cnn = match ::load_cnn(&model_path) {
+        Ok(cnn) => cnn,
+        Err(e) => {
+            eprintln!("Error: {}: {}", model_path.display(), e);
+            return 1;
+        }
+    };
+    let mut images = Vec::new();
+    if let Some(ref path) = images_path{
+        match idx::read_images(path) {
+            Ok(read) => images.extend(read),
+            Err(e) => {
+                eprintln!("Error: {}: {}", path.display(), e);
+                return 1;
+            }
+        }
+    }
+    let files = match cli::collect_files(&paths) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    for file in files{
+        match imagefile::load(&file) {
+            Ok(pixels) => images.push(preprocess::mnist(&pixels)),
+            Err(e) => {
+                eprintln!("Error: {}: {}", file.display(), e);
+                return 1;
+            }
+        }
+    }
+    let inputs: Vec<Vec<Vec<Vec<f32>>>> = images.iter().map(preprocess::to_input).collect();
+    println!("{} images", inputs.len());
+    print!("{}", format_statistics(&statistics(&cnn, &inputs)));
+    0
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn capture_test(){
+        let cnn = testutil::model();
+        let input = preprocess::to_input(&testutil::bar(13));
+        // no hooks, the same as `logits`
+        assert_eq!(cnn.forward_with_hooks(&input, &mut Hooks::new()), cnn.logits(&input));
+
+        let captured = capture(&cnn, &input, &LAYERS).unwrap();
+        let trace = cnn.trace(&input);
+        assert_eq!(captured.len(), trace.len());
+        for ((layer, tensor), (_, values)) in captured.iter().zip(trace.iter()){
+            assert_eq!(&tensor.values(), values, "Sample: {}", layer);
+        }
+        assert_eq!(captured[4].1.shape(), vec![8, 11, 11]);
+
+        let embedding = embedding(&cnn, &input);
+        assert_eq!(embedding.len(), 200);
+        assert_eq!(&embedding, &trace[6].1);
+
+        assert!(Hooks::new().before("fc2", |_, _| {}).is_err());
+        assert!(capture(&cnn, &input, &["softmax"]).is_err());
+    }
+
+    #[test]
+    fn modify_test(){
+        let cnn = testutil::model();
+        let input = preprocess::to_input(&testutil::bar(13));
+        let mut seen = Vec::new();
+        {
+            let mut hooks = Hooks::new();
+            // fc1 of an empty embedding is its bias
+            hooks.before("fc1", |_, tensor| for v in tensor.values_mut(){
+                *v = 0.0;
+            }).unwrap();
+            hooks.every(When::Before, |layer, tensor| seen.push((layer, tensor.shape())));
+            assert_eq!(cnn.forward_with_hooks(&input, &mut hooks), cnn.fc.bias().clone());
+        }
+        assert_eq!(seen.len(), 8);
+        assert_eq!(seen[0], ("conv1", vec![1, 28, 28]));
+        // the hook that was added first ran first
+        assert_eq!(seen[7], ("fc1", vec![200]));
+
+        // switching off a conv2 channel is the same as zeroing its filter
+        let mut ablated = cnn.clone();
+        for map in ablated.conv2.filter_mut()[3].iter_mut(){
+            for row in map.iter_mut(){
+                for v in row.iter_mut(){
+                    *v = 0.0;
+                }
+            }
+        }
+        ablated.conv2.bias_mut()[3] = 0.0;
+        let mut hooks = Hooks::new();
+        let handle = hooks.after("conv2", |_, tensor| if let Tensor::Maps(ref mut maps) = *tensor{
+            maps[3] = vec![vec![0.0; maps[3][0].len()]; maps[3].len()];
+        }).unwrap();
+        let logits = cnn.forward_with_hooks(&input, &mut hooks);
+        let expected = ablated.logits(&input);
+        for i in 0..10{
+            assert!((logits[i] - expected[i]).abs() < 1e-5, "Sample: {:?} {:?}", logits, expected);
+        }
+        assert!(hooks.remove(handle));
+        assert!(!hooks.remove(handle));
+        assert!(hooks.is_empty());
+        assert_eq!(cnn.forward_with_hooks(&input, &mut hooks), cnn.logits(&input));
+    }
+
+    #[test]
+    fn statistics_test(){
+        let cnn = testutil::model();
+        let inputs = vec![preprocess::to_input(&testutil::bar(13)), preprocess::to_input(&vec![vec![0.0; 28]; 28])];
+        let stats = statistics(&cnn, &inputs);
+        assert_eq!(stats.len(), 8);
+        assert_eq!(stats[1].layer, "relu1");
+        assert_eq!(stats[1].count, 2 * 4 * 26 * 26);
+        assert!(stats[1].min >= 0.0 && stats[1].zero_fraction() > 0.0, "Sample: {:?}", stats[1]);
+        assert_eq!(stats[6].shape, vec![200]);
+        assert!(stats[0].min < 0.0 && stats[0].min <= stats[0].mean() && stats[0].mean() <= stats[0].max);
+        let text = format_statistics(&stats);
+        assert_eq!(text.lines().count(), 9);
+        assert!(text.contains("flatten  200"), "Sample: {}", text);
+    }
+}
@@ -0,0 +1,273 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// # IDX
+// Reader and writer for the IDX files MNIST is distributed in. Images come back as
+// (rows, cols) grids scaled to [0, 1], the same layout as the canvas after
+// it is transposed for prediction.
+
+const IMAGES_MAGIC: u32 = 0x0000_0803;
+const LABELS_MAGIC: u32 = 0x0000_0801;
+
+fn invalid_data(message: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> io::Result<u32>{
+    if bytes.len() < offset + 4{
+        return Err(invalid_data("idx header is truncated".to_owned()));
+    }
+    Ok(((bytes[offset] as u32) << 24) | ((bytes[offset + 1] as u32) << 16)
+        | ((bytes[offset + 2] as u32) << 8) | bytes[offset + 3] as u32)
+}
+
+pub fn parse_images(bytes: &[u8]) -> io::Result<Vec<Vec<Vec<f32>>>>{
+    let magic = read_u32(bytes, 0)?;
+    if magic != IMAGES_MAGIC{
+        return Err(invalid_data(format!("not an idx image file (magic {:#010x})", magic)));
+    }
+    let count = read_u32(bytes, 4)? as usize;
+    let rows = read_u32(bytes, 8)? as usize;
+    let cols = read_u32(bytes, 12)? as usize;
+    // the header is not trusted: a huge count must not overflow, and empty
+    // images would let any count pass the size check
+    if rows == 0 || cols == 0{
+        return Err(invalid_data(format!("idx image header is invalid ({} images of {}x{})", count, rows, cols)));
+    }
+    let size = count.checked_mul(rows).and_then(|n| n.checked_mul(cols)).and_then(|n| n.checked_add(16))
+        .ok_or_else(|| invalid_data(format!("idx image header is invalid ({} images of {}x{})", count, rows, cols)))?;
+    if bytes.len() < size{
+        return Err(invalid_data(format!("idx image file is truncated, expected {} images", count)));
+    }
+
+    let mut images = vec![vec![vec![0.0; cols]; rows]; count];
+    let mut index = 16;
+    for n in 0..count{
+        for i in 0..rows{
+            for j in 0..cols{
+                images[n][i][j] = bytes[index] as f32 / 255.0;
+                index += 1;
+            }
+        }
+    }
+    Ok(images)
+}
+
+pub fn parse_labels(bytes: &[u8]) -> io::Result<Vec<u8>>{
+    let magic = read_u32(bytes, 0)?;
+    if magic != LABELS_MAGIC{
+        return Err(invalid_data(format!("not an idx label file (magic {:#010x})", magic)));
+    }
+    let count = read_u32(bytes, 4)? as usize;
+    if count.checked_add(8).is_none_or(|size| bytes.len() < size){
+        return Err(invalid_data(format!("idx label file is truncated, expected {} labels", count)));
+    }
+    Ok(bytes[8..8 + count].to_vec())
+}
+
+fn u32_bytes(value: u32) -> [u8; 4]{
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn to_byte(value: f32) -> u8{
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn images_header(count: usize, rows: usize, cols: usize) -> Vec<u8>{
+    let mut bytes = Vec::with_capacity(16);
+    bytes.extend_from_slice(&u32_bytes(IMAGES_MAGIC));
+    bytes.extend_from_slice(&u32_bytes(count as u32));
+    bytes.extend_from_slice(&u32_bytes(rows as u32));
+    bytes.extend_from_slice(&u32_bytes(cols as u32));
+    bytes
+}
+
+fn labels_header(count: usize) -> Vec<u8>{
+    let mut bytes = Vec::with_capacity(8);
+    bytes.extend_from_slice(&u32_bytes(LABELS_MAGIC));
+    bytes.extend_from_slice(&u32_bytes(count as u32));
+    bytes
+}
+
+fn image_bytes(image: &Vec<Vec<f32>>) -> Vec<u8>{
+    image.iter().flat_map(|row| row.iter().map(|&value| to_byte(value))).collect()
+}
+
+pub fn encode_images(images: &Vec<Vec<Vec<f32>>>) -> Vec<u8>{
+    let rows = images.first().map_or(28, |image| image.len());
+    let cols = images.first().and_then(|image| image.first()).map_or(28, |row| row.len());
+    let mut bytes = images_header(images.len(), rows, cols);
+    bytes.reserve(images.len() * rows * cols);
+    for image in images.iter(){
+        bytes.extend(image_bytes(image));
+    }
+    bytes
+}
+
+pub fn encode_labels(labels: &Vec<u8>) -> Vec<u8>{
+    let mut bytes = labels_header(labels.len());
+    bytes.extend_from_slice(labels);
+    bytes
+}
+
+pub fn write_images(path: &Path, images: &Vec<Vec<Vec<f32>>>) -> io::Result<()>{
+    fs::write(path, encode_images(images))
+}
+
+pub fn write_labels(path: &Path, labels: &Vec<u8>) -> io::Result<()>{
+    fs::write(path, encode_labels(labels))
+}
+
+// opens an idx file for reading and writing, writes `header` when the file
+// is new, and returns it with the header it has
+fn open_for_append(path: &Path, header: Vec<u8>) -> io::Result<(fs::File, Vec<u8>)>{
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    if file.metadata()?.len() == 0{
+        file.write_all(&header)?;
+    }
+    let mut read = vec![0; header.len()];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut read)
+        .map_err(|_| invalid_data(format!("{} has no idx header", path.display())))?;
+    Ok((file, read))
+}
+
+// adds one labelled image to a pair of idx files, creating them if needed,
+// and returns the number of images now in the file. Only the new bytes and
+// the counts in the headers are written: the data first, then the label
+// count, then the image count. A crash in between leaves either the old
+// counts or one label more than images, both are taken as the old count and
+// the next append overwrites whatever came after them.
+pub fn append(images_path: &Path, labels_path: &Path, image: &Vec<Vec<f32>>, label: u8) -> io::Result<usize>{
+    let (rows, cols) = (image.len(), image.first().map_or(0, |row| row.len()));
+    if rows == 0 || cols == 0{
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the image is empty"));
+    }
+    let (mut images_file, header) = open_for_append(images_path, images_header(0, rows, cols))?;
+    if read_u32(&header, 0)? != IMAGES_MAGIC{
+        return Err(invalid_data(format!("{} is not an idx image file", images_path.display())));
+    }
+    let count = read_u32(&header, 4)? as usize;
+    let (file_rows, file_cols) = (read_u32(&header, 8)? as usize, read_u32(&header, 12)? as usize);
+    if (file_rows, file_cols) != (rows, cols){
+        return Err(invalid_data(format!("{} holds {}x{} images", images_path.display(), file_rows, file_cols)));
+    }
+    let (mut labels_file, header) = open_for_append(labels_path, labels_header(0))?;
+    if read_u32(&header, 0)? != LABELS_MAGIC{
+        return Err(invalid_data(format!("{} is not an idx label file", labels_path.display())));
+    }
+    let label_count = read_u32(&header, 4)? as usize;
+    if count != label_count && count + 1 != label_count{
+        return Err(invalid_data(format!("{} has {} images but {} has {} labels",
+            images_path.display(), count, labels_path.display(), label_count)));
+    }
+
+    images_file.seek(SeekFrom::Start((16 + count * rows * cols) as u64))?;
+    images_file.write_all(&image_bytes(image))?;
+    labels_file.seek(SeekFrom::Start((8 + count) as u64))?;
+    labels_file.write_all(&[label])?;
+    labels_file.seek(SeekFrom::Start(4))?;
+    labels_file.write_all(&u32_bytes(count as u32 + 1))?;
+    images_file.seek(SeekFrom::Start(4))?;
+    images_file.write_all(&u32_bytes(count as u32 + 1))?;
+    Ok(count + 1)
+}
+
+pub fn read_images(path: &Path) -> io::Result<Vec<Vec<Vec<f32>>>>{
+    parse_images(&fs::read(path)?)
+}
+
+pub fn read_labels(path: &Path) -> io::Result<Vec<u8>>{
+    parse_labels(&fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_images_test(){
+        let bytes = vec![
+            0, 0, 8, 3,
+            0, 0, 0, 2,
+            0, 0, 0, 2,
+            0, 0, 0, 2,
+            0, 255, 255, 0,
+            51, 102, 153, 204
+        ];
+        let images = parse_images(&bytes).unwrap();
+        assert_eq!(images, vec![
+            vec![vec![0.0, 1.0], vec![1.0, 0.0]],
+            vec![vec![0.2, 0.4], vec![0.6, 0.8]]
+        ], "Sample: {:?}", images);
+        assert!(parse_images(&bytes[..20]).is_err());
+
+        // 0xffffffff images of 0xffffffff x 0xffffffff pixels
+        let mut huge = vec![0, 0, 8, 3];
+        huge.extend_from_slice(&[255; 12]);
+        huge.extend_from_slice(&[0; 8]);
+        let error = parse_images(&huge).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // 0xffffffff images with 0 rows fit in the header alone
+        let empty = vec![0, 0, 8, 3, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 28];
+        let error = parse_images(&empty).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encode_round_trip_test(){
+        let images = vec![
+            vec![vec![0.0, 1.0, 0.2], vec![0.4, 0.6, 0.8]]
+        ];
+        assert_eq!(parse_images(&encode_images(&images)).unwrap(), images);
+        assert_eq!(parse_labels(&encode_labels(&vec![3, 9])).unwrap(), vec![3, 9]);
+    }
+
+    #[test]
+    fn append_test(){
+        let dir = std::env::temp_dir().join(format!("draw-rust-idx-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (images, labels) = (dir.join("images-idx3-ubyte"), dir.join("labels-idx1-ubyte"));
+
+        assert_eq!(append(&images, &labels, &vec![vec![1.0; 28]; 28], 4).unwrap(), 1);
+        assert_eq!(append(&images, &labels, &vec![vec![0.0; 28]; 28], 7).unwrap(), 2);
+        assert!(append(&images, &labels, &vec![vec![0.0; 20]; 20], 1).is_err());
+        assert_eq!(read_labels(&labels).unwrap(), vec![4, 7]);
+        // only the new image and label were added
+        assert_eq!(fs::metadata(&images).unwrap().len(), 16 + 2 * 784);
+        assert_eq!(fs::metadata(&labels).unwrap().len(), 8 + 2);
+
+        // bytes after the counted images, e.g. from a crash, are overwritten
+        let mut file = fs::OpenOptions::new().append(true).open(&images).unwrap();
+        file.write_all(&[9; 100]).unwrap();
+        assert_eq!(append(&images, &labels, &vec![vec![0.5; 28]; 28], 1).unwrap(), 3);
+        assert_eq!(read_images(&images).unwrap()[2][0][0], 128.0 / 255.0);
+        assert_eq!(read_labels(&labels).unwrap(), vec![4, 7, 1]);
+
+        // a crash after the label count was written, before the image count
+        let mut file = fs::OpenOptions::new().write(true).open(&labels).unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&u32_bytes(4)).unwrap();
+        assert_eq!(append(&images, &labels, &vec![vec![0.0; 28]; 28], 2).unwrap(), 4);
+        assert_eq!(read_labels(&labels).unwrap(), vec![4, 7, 1, 2]);
+        assert_eq!(append(&images, &labels, &Vec::new(), 1).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let mismatched = dir.join("other-labels-idx1-ubyte");
+        write_labels(&mismatched, &vec![1]).unwrap();
+        assert!(append(&images, &mismatched, &vec![vec![0.0; 28]; 28], 1).is_err());
+        let read = read_images(&images).unwrap();
+        assert_eq!(read.len(), 4);
+        assert_eq!(read[0][27][27], 1.0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_labels_test(){
+        let bytes = vec![0, 0, 8, 1, 0, 0, 0, 3, 7, 2, 1];
+        assert_eq!(parse_labels(&bytes).unwrap(), vec![7, 2, 1]);
+        assert!(parse_images(&bytes).is_err());
+    }
+}
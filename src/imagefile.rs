@@ -0,0 +1,139 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use preprocess;
+
+// # Image files
+// Conversion between pixel grids and image files. Grids are (rows, cols) in
+// [0, 1] with 1 as ink, and saved images use the MNIST colors, a white digit
+// on black, so a saved 28x28 image looks like the training data.
+
+fn image_error(error: image::ImageError) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+pub fn to_gray(pixels: &Vec<Vec<f32>>) -> image::GrayImage{
+    let rows = pixels.len() as u32;
+    let cols = if rows > 0 {pixels[0].len() as u32} else {0};
+    image::GrayImage::from_fn(cols, rows, |x, y| {
+        let value = pixels[y as usize][x as usize].clamp(0.0, 1.0);
+        image::Luma([(value * 255.0).round() as u8])
+    })
+}
+
+// intensities as stored, without deciding what is ink
+pub fn from_gray(image: &image::GrayImage) -> Vec<Vec<f32>>{
+    let mut pixels = vec![vec![0.0; image.width() as usize]; image.height() as usize];
+    for (x, y, pixel) in image.enumerate_pixels(){
+        pixels[y as usize][x as usize] = pixel[0] as f32 / 255.0;
+    }
+    pixels
+}
+
+// mean intensity along the edges, which is background in any sensible drawing
+fn border_mean(pixels: &Vec<Vec<f32>>) -> f32{
+    let (rows, cols) = (pixels.len(), pixels[0].len());
+    let mut sum = 0.0;
+    let mut count = 0;
+    for i in 0..rows{
+        for j in 0..cols{
+            if i == 0 || j == 0 || i == rows - 1 || j == cols - 1{
+                sum += pixels[i][j];
+                count += 1;
+            }
+        }
+    }
+    sum / count as f32
+}
+
+// flips dark-on-light scans and photos so the ink is 1
+pub fn normalize_polarity(pixels: &Vec<Vec<f32>>) -> Vec<Vec<f32>>{
+    if pixels.is_empty() || pixels[0].is_empty() || border_mean(pixels) <= 0.5{
+        return pixels.clone();
+    }
+    pixels.iter().map(|row| row.iter().map(|&v| 1.0 - v).collect()).collect()
+}
+
+// scales the image to fit into (rows, cols) keeping its aspect ratio and
+// centers it on a blank background
+pub fn fit(pixels: &Vec<Vec<f32>>, rows: usize, cols: usize) -> Vec<Vec<f32>>{
+    let mut output = vec![vec![0.0; cols]; rows];
+    if pixels.is_empty() || pixels[0].is_empty(){
+        return output;
+    }
+    let scale = (rows as f64 / pixels.len() as f64).min(cols as f64 / pixels[0].len() as f64);
+    let height = ((pixels.len() as f64 * scale).round() as usize).max(1).min(rows);
+    let width = ((pixels[0].len() as f64 * scale).round() as usize).max(1).min(cols);
+    let scaled = preprocess::resize_area(pixels, height, width);
+    let (top, left) = ((rows - height) / 2, (cols - width) / 2);
+    for i in 0..height{
+        for j in 0..width{
+            output[top + i][left + j] = scaled[i][j];
+        }
+    }
+    output
+}
+
+pub fn save_png(pixels: &Vec<Vec<f32>>, path: &Path) -> io::Result<()>{
+    if let Some(dir) = path.parent(){
+        fs::create_dir_all(dir)?;
+    }
+    to_gray(pixels).save_with_format(path, image::ImageFormat::Png).map_err(image_error)
+}
+
+// any format the image crate knows from the bytes, PNG and JPEG in practice
+pub fn decode(bytes: &[u8]) -> io::Result<Vec<Vec<f32>>>{
+    let image = image::load_from_memory(bytes).map_err(image_error)?;
+    Ok(normalize_polarity(&from_gray(&image.to_luma8())))
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<Vec<f32>>>{
+    decode(&fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn png_round_trip_test(){
+        let path = std::env::temp_dir().join(format!("draw-rust-image-{}.png", std::process::id()));
+        let mut pixels = vec![vec![0.0; 12]; 8];
+        pixels[3][4] = 1.0;
+        pixels[5][9] = 0.6;
+        save_png(&pixels, &path).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 8);
+        assert_eq!(loaded[0].len(), 12);
+        assert_eq!(loaded[3][4], 1.0);
+        assert!((loaded[5][9] - 0.6).abs() < 1.0 / 255.0, "Sample: {}", loaded[5][9]);
+    }
+
+    #[test]
+    fn normalize_polarity_test(){
+        // black digit on white paper
+        let mut pixels = vec![vec![1.0; 5]; 5];
+        pixels[2][2] = 0.0;
+        let normalized = normalize_polarity(&pixels);
+        assert_eq!(normalized[2][2], 1.0);
+        assert_eq!(normalized[0][0], 0.0);
+        assert_eq!(normalize_polarity(&normalized), normalized);
+    }
+
+    #[test]
+    fn fit_test(){
+        // a wide image keeps its aspect ratio and is centered vertically
+        let pixels = vec![vec![1.0; 20]; 10];
+        let fitted = fit(&pixels, 40, 40);
+        assert_eq!(fitted.len(), 40);
+        assert_eq!(fitted[0][20], 0.0);
+        assert_eq!(fitted[20][0], 1.0);
+        assert_eq!(fitted[9][20], 0.0);
+        assert_eq!(fitted[10][20], 1.0);
+        assert_eq!(fitted[29][20], 1.0);
+        assert_eq!(fitted[30][20], 0.0);
+    }
+}
@@ -0,0 +1,338 @@
+// the style of the original model code: `field: field` initializers, `&Vec`
+// parameters and index loops over the layers
+#![allow(clippy::redundant_field_names, clippy::ptr_arg, clippy::needless_range_loop)]
+
+#[macro_use]
+extern crate serde_json;
+extern crate image;
+
+pub mod model;
+pub mod rng;
+pub mod train;
+pub mod checkpoint;
+pub mod gradcheck;
+pub mod parity;
+pub mod idx;
+pub mod augment;
+pub mod preprocess;
+pub mod imagefile;
+pub mod dataset;
+pub mod finetune;
+pub mod cli;
+pub mod server;
+pub mod quantize;
+pub mod qat;
+pub mod precision;
+pub mod prune;
+pub mod distill;
+pub mod explain;
+pub mod features;
+pub mod hooks;
+pub mod embed;
+pub mod canvas;
+pub mod toolbar;
+pub mod history;
+pub mod app;
+#[cfg(test)]
+mod testutil;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// # Draw Rust
+// The CNN and everything around it that does not need a window: layers,
+// loading and saving model.json, preprocessing, training and the state of
+// the drawing app. The Piston window in main.rs is only built with the
+// `gui` feature.
+
+fn invalid_model(message: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// appends the numbers of a nested array with `depth` levels to `values`,
+// None unless it is rectangular: `shape` holds the length of every level
+fn flatten_json(value: &serde_json::Value, level: usize, depth: usize, shape: &mut Vec<usize>, values: &mut Vec<f32>) -> Option<()>{
+    if level == depth{
+        values.push(value.as_f64()? as f32);
+        return Some(());
+    }
+    let array = value.as_array()?;
+    if shape.len() == level{
+        if array.is_empty(){
+            return None;
+        }
+        shape.push(array.len());
+    }
+    else if shape[level] != array.len(){
+        return None;
+    }
+    for item in array{
+        flatten_json(item, level + 1, depth, shape, values)?;
+    }
+    Some(())
+}
+
+// (shape, values) of `json[key]`
+fn json_tensor(json: &serde_json::Value, key: &str, depth: usize) -> io::Result<(Vec<usize>, Vec<f32>)>{
+    let mut shape = Vec::new();
+    let mut values = Vec::new();
+    flatten_json(&json[key], 0, depth, &mut shape, &mut values)
+        .ok_or_else(|| invalid_model(format!("{} is missing or not a {}-dimensional array of numbers", key, depth)))?;
+    Ok((shape, values))
+}
+
+pub fn generate_conv2d(json: &serde_json::Value, name: &str) -> io::Result<model::Conv2D>{
+    let (shape, values) = json_tensor(json, &format!("{}.weight", name), 4)?;
+    let (bias_shape, bias) = json_tensor(json, &format!("{}.bias", name), 1)?;
+    if bias_shape[0] != shape[0]{
+        return Err(invalid_model(format!("{} has {} filters but {} biases", name, shape[0], bias_shape[0])));
+    }
+    let weights = values.chunks(shape[1] * shape[2] * shape[3])
+        .map(|filter| filter.chunks(shape[2] * shape[3])
+            .map(|map| map.chunks(shape[3]).map(|row| row.to_vec()).collect())
+            .collect())
+        .collect();
+    Ok(model::Conv2D::new(shape[1] as u32, shape[0] as u32, weights, bias))
+}
+
+pub fn generate_fully_connected(json: &serde_json::Value, name: &str) -> io::Result<model::FullyConnected>{
+    let (shape, values) = json_tensor(json, &format!("{}.weight", name), 2)?;
+    let (bias_shape, bias) = json_tensor(json, &format!("{}.bias", name), 1)?;
+    if bias_shape[0] != shape[0]{
+        return Err(invalid_model(format!("{} has {} outputs but {} biases", name, shape[0], bias_shape[0])));
+    }
+    let weights = values.chunks(shape[1]).map(|row| row.to_vec()).collect();
+    Ok(model::FullyConnected::new(shape[1] as u32, shape[0] as u32, weights, bias))
+}
+
+// checks that layers with these shapes fit together on a 1x28x28 input:
+// conv1 (outputs, 1, k, k), conv2 (outputs, conv1 outputs, k, k) and
+// fc1 (outputs, conv2 outputs * side * side) with the side left after
+// both convolutions and 2x2 poolings, 5 for 3x3 kernels
+pub fn check_layers(conv1: [usize; 4], conv2: [usize; 4], fc: [usize; 2]) -> io::Result<()>{
+    if conv1.iter().chain(conv2.iter()).chain(fc.iter()).any(|&n| n == 0){
+        return Err(invalid_model("a layer has a dimension of size 0".to_owned()));
+    }
+    if conv1[1] != 1{
+        return Err(invalid_model(format!("conv1 expects {} input layers, the image has 1", conv1[1])));
+    }
+    if conv2[1] != conv1[0]{
+        return Err(invalid_model(format!("conv2 expects {} input layers, conv1 has {}", conv2[1], conv1[0])));
+    }
+    let mut side = 28;
+    for &(name, shape) in [("conv1", conv1), ("conv2", conv2)].iter(){
+        if shape[2] != shape[3] || shape[2] > side{
+            return Err(invalid_model(format!("{} has {}x{} kernels for a {}x{} input", name, shape[2], shape[3], side, side)));
+        }
+        // a valid convolution, then 2x2 pooling
+        let convolved = side - shape[2] + 1;
+        side = convolved / 2;
+    }
+    if side == 0 || fc[1] != conv2[0] * side * side{
+        return Err(invalid_model(format!("fc1 expects {} inputs, conv2 gives {}x{}x{}", fc[1], conv2[0], side, side)));
+    }
+    Ok(())
+}
+
+pub fn export_conv2d(json: &mut serde_json::Value, name: &str, layer: &model::Conv2D){
+    let mut weight: String = name.to_owned();
+    weight.push_str(".weight");
+    let mut bias: String = name.to_owned();
+    bias.push_str(".bias");
+
+    json[weight] = json!(layer.filter());
+    json[bias] = json!(layer.bias());
+}
+
+pub fn export_fully_connected(json: &mut serde_json::Value, name: &str, layer: &model::FullyConnected){
+    let mut weight: String = name.to_owned();
+    weight.push_str(".weight");
+    let mut bias: String = name.to_owned();
+    bias.push_str(".bias");
+
+    json[weight] = json!(layer.weights());
+    json[bias] = json!(layer.bias());
+}
+
+#[derive(Debug, Clone)]
+pub struct CNN{
+    pub input_size: u32,
+    pub output_size: u32,
+    pub conv1: model::Conv2D,
+    pub conv2: model::Conv2D,
+    pub fc: model::FullyConnected
+}
+
+impl CNN{
+    pub fn new(input_size: u32, output_size: u32, conv1: model::Conv2D, conv2: model::Conv2D, fc: model::FullyConnected) -> CNN{
+        CNN{
+            input_size: input_size,
+            output_size: output_size,
+            conv1: conv1,
+            conv2: conv2,
+            fc: fc
+        }
+    }
+
+    // fails on missing layers and on layers that do not fit together
+    pub fn from_json(json: &serde_json::Value) -> io::Result<CNN>{
+        let conv1 = generate_conv2d(json, "conv1")?;
+        let conv2 = generate_conv2d(json, "conv2")?;
+        let fc = generate_fully_connected(json, "fc1")?;
+        let shape = |layer: &model::Conv2D| {
+            let f = layer.filter();
+            [f.len(), f[0].len(), f[0][0].len(), f[0][0][0].len()]
+        };
+        check_layers(shape(&conv1), shape(&conv2), [fc.weights().len(), fc.weights()[0].len()])?;
+        Ok(CNN::new(1, 10, conv1, conv2, fc))
+    }
+
+    // same layout and names as model.json, also used for the checkpoint weights
+    pub fn to_json(&self) -> serde_json::Value{
+        let mut json = json!({});
+        export_conv2d(&mut json, "conv1", &self.conv1);
+        export_conv2d(&mut json, "conv2", &self.conv2);
+        export_fully_connected(&mut json, "fc1", &self.fc);
+        json
+    }
+
+    // every intermediate activation, flattened, named like the PyTorch model
+    pub fn trace(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<(&'static str, Vec<f32>)>{
+        let mut trace = Vec::new();
+        let img = self.conv1.forward(img);
+        trace.push(("conv1", model::Flatten::forward(&img)));
+        let img = model::ReLU::forward(&img);
+        trace.push(("relu1", model::Flatten::forward(&img)));
+        let pool2 = model::MaxPooling2D::new(2);
+        let img = pool2.forward(&img);
+        trace.push(("pool1", model::Flatten::forward(&img)));
+
+        let img = self.conv2.forward(&img);
+        trace.push(("conv2", model::Flatten::forward(&img)));
+        let img = model::ReLU::forward(&img);
+        trace.push(("relu2", model::Flatten::forward(&img)));
+        let img = pool2.forward(&img);
+        trace.push(("pool2", model::Flatten::forward(&img)));
+
+        let img = model::Flatten::forward(&img);
+        trace.push(("flatten", img.clone()));
+        let img = self.fc.forward(&img);
+        trace.push(("logits", img));
+        trace
+    }
+
+    pub fn logits(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        let img = self.conv1.forward(img);
+        let img = model::ReLU::forward(&img);
+        let pool2 = model::MaxPooling2D::new(2);
+        let img = pool2.forward(&img);
+        
+        let img = self.conv2.forward(&img);
+        let img = model::ReLU::forward(&img);
+        let img = pool2.forward(&img);
+
+        let img = model::Flatten::forward(&img);
+        self.fc.forward(&img)
+    }
+
+    // gradients of every parameter for one image, given the gradient of
+    // the loss with respect to the logits
+    pub fn backward(&self, img: &Vec<Vec<Vec<f32>>>, grad_logits: &Vec<f32>) -> train::Gradients{
+        let pool2 = model::MaxPooling2D::new(2);
+        let conv1 = self.conv1.forward(img);
+        let relu1 = model::ReLU::forward(&conv1);
+        let pool1 = pool2.forward(&relu1);
+        let conv2 = self.conv2.forward(&pool1);
+        let relu2 = model::ReLU::forward(&conv2);
+        let pooled = pool2.forward(&relu2);
+        let flat = model::Flatten::forward(&pooled);
+
+        let (grad, fc_weights, fc_bias) = self.fc.backward(&flat, grad_logits);
+        let grad = model::Flatten::backward(&pooled, &grad);
+        let grad = pool2.backward(&relu2, &grad);
+        let grad = model::ReLU::backward(&conv2, &grad);
+        let (grad, conv2_filter, conv2_bias) = self.conv2.backward(&pool1, &grad);
+        let grad = pool2.backward(&relu1, &grad);
+        let grad = model::ReLU::backward(&conv1, &grad);
+        let (_, conv1_filter, conv1_bias) = self.conv1.backward(img, &grad);
+        train::Gradients{
+            conv1_filter: conv1_filter,
+            conv1_bias: conv1_bias,
+            conv2_filter: conv2_filter,
+            conv2_bias: conv2_bias,
+            fc_weights: fc_weights,
+            fc_bias: fc_bias
+        }
+    }
+
+    // `logits` with the hooks called around every layer, see hooks.rs
+    pub fn forward_with_hooks(&self, img: &Vec<Vec<Vec<f32>>>, hooks: &mut hooks::Hooks) -> Vec<f32>{
+        use hooks::Tensor;
+        let pool2 = model::MaxPooling2D::new(2);
+        let img = Tensor::Maps(img.clone());
+        let img = hooks.run("conv1", img, |img| Tensor::Maps(self.conv1.forward(img.maps())));
+        let img = hooks.run("relu1", img, |img| Tensor::Maps(model::ReLU::forward(img.maps())));
+        let img = hooks.run("pool1", img, |img| Tensor::Maps(pool2.forward(img.maps())));
+
+        let img = hooks.run("conv2", img, |img| Tensor::Maps(self.conv2.forward(img.maps())));
+        let img = hooks.run("relu2", img, |img| Tensor::Maps(model::ReLU::forward(img.maps())));
+        let img = hooks.run("pool2", img, |img| Tensor::Maps(pool2.forward(img.maps())));
+
+        let img = hooks.run("flatten", img, |img| Tensor::Vector(model::Flatten::forward(img.maps())));
+        hooks.run("fc1", img, |img| Tensor::Vector(self.fc.forward(img.vector()))).into_vector()
+    }
+
+    pub fn probabilities(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        model::softmax(&self.logits(img))
+    }
+
+    pub fn forward(&self, img: &Vec<Vec<Vec<f32>>>) -> u32{
+        let img = self.probabilities(img);
+        model::argmax(&img) as u32
+    }
+}
+
+pub fn load_cnn(path: &Path) -> io::Result<CNN>{
+    parse_model(&fs::read(path)?)
+}
+
+// model.json, or a half precision model file with its weights as f32
+pub fn parse_model(bytes: &[u8]) -> io::Result<CNN>{
+    if precision::is_half_model(bytes){
+        return Ok(precision::HalfCNN::from_bytes(bytes)?.to_cnn());
+    }
+    let json: serde_json::Value = serde_json::from_slice(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    CNN::from_json(&json)
+}
+
+// runs the command in `args[1]` with the arguments after it and returns
+// its exit code, or None when there is no such command. Both binaries use
+// it: `draw-rust-cli <command> ...` and `draw-rust <command> ...`.
+pub fn dispatch(args: &[String]) -> Option<i32>{
+    let run: fn(&[String]) -> i32 = match args.get(1).map(|command| command.as_str()) {
+        Some("predict") => cli::run,
+        Some("serve") => server::run,
+        Some("quantize") => quantize::run,
+        Some("qat") => qat::run,
+        Some("half") => precision::run,
+        Some("prune") => prune::run,
+        Some("distill") => distill::run,
+        Some("explain") => explain::run,
+        Some("features") => features::run,
+        Some("activations") => hooks::run,
+        Some("embed") => embed::run,
+        _ => return None
+    };
+    Some(run(&args[2..]))
+}
+
+// seconds since the epoch, used to name saved files
+pub fn timestamp() -> u64{
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
@@ -1,20 +1,52 @@
-extern crate piston_window;
-extern crate serde_json;
+// the style of the original model code: `field: field` initializers, `&Vec`
+// parameters and index loops over the layers
+#![allow(clippy::redundant_field_names, clippy::ptr_arg, clippy::needless_range_loop)]
 
-pub mod model;
+extern crate draw_rust;
+extern crate piston_window;
+extern crate image;
 
-use std::fs;
+use std::path::Path;
 use piston_window::*;
 
-fn draw_canvas(state: &Vec<Vec<f32>>, c: &Context, g: &mut G2d){
+use draw_rust::{app, canvas, dataset, explain, model};
+use draw_rust::{load_cnn, timestamp};
+use draw_rust::toolbar::{Control, Toolbar};
+
+const CANVAS_SIZE: usize = 540;
+const TOOLBAR_HEIGHT: f64 = 44.0;
+
+// white background with black ink, uploaded as the canvas texture
+fn canvas_image(canvas: &canvas::Canvas) -> image::RgbaImage{
+    image::RgbaImage::from_fn(canvas.width() as u32, canvas.height() as u32, |x, y| {
+        let value = (255.0 * (1.0 - canvas.pixels()[y as usize][x as usize])).round() as u8;
+        image::Rgba([value, value, value, 255])
+    })
+}
+
+// the explanation heatmap in color, transparent where there is no evidence
+fn heatmap_image(heatmap: &Vec<Vec<f32>>) -> image::RgbaImage{
+    image::RgbaImage::from_fn(heatmap[0].len() as u32, heatmap.len() as u32, |x, y| {
+        let value = heatmap[y as usize][x as usize];
+        let color = explain::heat_color(value);
+        image::Rgba([color[0], color[1], color[2], (180.0 * value).round() as u8])
+    })
+}
+
+// the network input after preprocessing, drawn small in the top right corner
+fn draw_preview(image: &Vec<Vec<f32>>, c: &Context, g: &mut G2d){
+    let (left, top, cell) = (CANVAS_SIZE as f64 - 28.0 * 4.0 - 10.0, TOOLBAR_HEIGHT + 10.0, 4.0);
+    rectangle([0.5, 0.5, 0.5, 1.0], [left - 2.0, top - 2.0, 28.0 * cell + 4.0, 28.0 * cell + 4.0], c.transform, g);
+    rectangle([1.0; 4], [left, top, 28.0 * cell, 28.0 * cell], c.transform, g);
     for i in 0..28{
         for j in 0..28{
+            // image is (rows, cols), so i is the column and j the row
             rectangle(
-                [0.0, 0.0, 0.0, state[i as usize][j as usize]],
+                [0.0, 0.0, 0.0, image[j][i]],
                 [
-                    20.0 * (i as f64),
-                    20.0 * (j as f64),
-                    20.0, 20.0
+                    left + cell * (i as f64),
+                    top + cell * (j as f64),
+                    cell, cell
                 ],
                 c.transform, g
             );
@@ -22,117 +54,149 @@ fn draw_canvas(state: &Vec<Vec<f32>>, c: &Context, g: &mut G2d){
     }
 }
 
-fn generate_conv2d(json: &serde_json::Value, name: &str) -> model::Conv2D{
-    let mut weight: String = name.to_owned();
-    weight.push_str(".weight");
-    let mut bias: String = name.to_owned();
-    bias.push_str(".bias");
-    
-    let weight = json[weight].clone();
-    let mut weight_values = vec![
-                                vec![
-                                    vec![
-                                        vec![0.0; weight[0][0][0].as_array().unwrap().len()]
-                                    ; weight[0][0].as_array().unwrap().len()]
-                                ; weight[0].as_array().unwrap().len()]
-                            ; weight.as_array().unwrap().len()
-                            ];
-        
-    for i in 0..weight.as_array().unwrap().len(){
-        for j in 0..weight[i].as_array().unwrap().len(){
-            for k in 0..weight[i][j].as_array().unwrap().len(){
-                for l in 0..weight[i][j][k].as_array().unwrap().len(){
-                    weight_values[i][j][k][l] = weight[i][j][k][l].as_f64().unwrap() as f32;
-                }
-            }
-        }
+fn confidence_color(confidence: f32) -> [f32; 4]{
+    if confidence >= 0.8{
+        [0.1, 0.6, 0.2, 1.0]
     }
-
-    let bias = json[bias].clone();
-    let mut bias_values = vec![0.0; bias.as_array().unwrap().len()];
-    for i in 0..bias.as_array().unwrap().len(){
-        bias_values[i] = bias[i].as_f64().unwrap() as f32;
+    else if confidence >= 0.5{
+        [0.9, 0.6, 0.1, 1.0]
     }
+    else{
+        [0.8, 0.1, 0.1, 1.0]
+    }
+}
+
+// predicted digit, confidence and a bar for every class along the bottom
+fn draw_prediction(probabilities: &Vec<f32>, c: &Context, g: &mut G2d, glyphs: &mut Glyphs){
+    let top = TOOLBAR_HEIGHT + CANVAS_SIZE as f64 - 110.0;
+    let predicted = model::argmax(probabilities);
+    let confidence = probabilities[predicted];
+    let color = confidence_color(confidence);
+
+    rectangle([1.0, 1.0, 1.0, 0.85], [0.0, top, CANVAS_SIZE as f64, 110.0], c.transform, g);
+    text::Text::new_color([0.0, 0.0, 0.0, 1.0], 72)
+        .draw(&predicted.to_string(), glyphs, &c.draw_state, c.transform.trans(25.0, top + 75.0), g)
+        .unwrap();
+    text::Text::new_color(color, 20)
+        .draw(&format!("{:.0}%", confidence * 100.0), glyphs, &c.draw_state, c.transform.trans(22.0, top + 100.0), g)
+        .unwrap();
 
-    model::Conv2D::new(
-        weight[0].as_array().unwrap().len() as u32,
-        weight.as_array().unwrap().len() as u32,
-        weight_values, 
-        bias_values
-    )
+    for i in 0..probabilities.len(){
+        let left = 90.0 + 44.0 * i as f64;
+        let height = 70.0 * probabilities[i] as f64;
+        rectangle([0.9, 0.9, 0.9, 1.0], [left + 6.0, top + 10.0, 32.0, 70.0], c.transform, g);
+        rectangle(
+            if i == predicted {color} else {[0.4, 0.4, 0.4, 1.0]},
+            [left + 6.0, top + 80.0 - height, 32.0, height],
+            c.transform, g
+        );
+        text::Text::new_color([0.0, 0.0, 0.0, 1.0], 18)
+            .draw(&i.to_string(), glyphs, &c.draw_state, c.transform.trans(left + 17.0, top + 100.0), g)
+            .unwrap();
+    }
 }
 
-fn generate_fully_connected(json: &serde_json::Value, name: &str) -> model::FullyConnected{
-    let mut weight: String = name.to_owned();
-    weight.push_str(".weight");
-    let mut bias: String = name.to_owned();
-    bias.push_str(".bias");
-    
-    let weight = json[weight].clone();
-    let mut weight_values = vec![
-                                vec![0.0; weight[0].as_array().unwrap().len()]
-                                ; weight.as_array().unwrap().len()
-                            ];
-    
-    for i in 0..weight.as_array().unwrap().len(){
-        for j in 0..weight[i].as_array().unwrap().len(){
-            weight_values[i][j] = weight[i][j].as_f64().unwrap() as f32;
+fn draw_toolbar(toolbar: &Toolbar, app: &app::App, c: &Context, g: &mut G2d, glyphs: &mut Glyphs){
+    rectangle([0.85, 0.85, 0.85, 1.0], [0.0, 0.0, CANVAS_SIZE as f64, toolbar.height], c.transform, g);
+    for &(control, rect) in toolbar.controls().iter(){
+        let active = match control {
+            Control::Draw => !app.erase,
+            Control::Erase => app.erase,
+            Control::Tune => app.fine_tuning(),
+            Control::Label => app.labeling,
+            Control::Review => app.reviewing().is_some(),
+            _ => false
+        };
+        let enabled = match control {
+            Control::Undo => app.can_undo(),
+            Control::Redo => app.can_redo(),
+            Control::Review => app.dataset().map_or(false, |dataset| !dataset.is_empty()),
+            _ => true
+        };
+        let background = if active {[0.25, 0.45, 0.8, 1.0]} else {[1.0; 4]};
+        let foreground = if active {[1.0; 4]} else if enabled {[0.0, 0.0, 0.0, 1.0]} else {[0.6, 0.6, 0.6, 1.0]};
+        rectangle(background, rect, c.transform, g);
+
+        let label = match control {
+            Control::Brush => {
+                // slider: the filled part is the brush radius
+                let fraction = toolbar.slider_fraction(app.canvas.brush_radius, canvas::MIN_BRUSH_RADIUS, canvas::MAX_BRUSH_RADIUS);
+                rectangle([0.7, 0.8, 0.95, 1.0], [rect[0], rect[1], rect[2] * fraction, rect[3]], c.transform, g);
+                format!("{} {:.0}", control.label(), app.canvas.brush_radius)
+            },
+            Control::Model => app.model_name(),
+            _ => control.label().to_owned()
+        };
+        text::Text::new_color(foreground, 16)
+            .draw(&label, glyphs, &c.draw_state, c.transform.trans(rect[0] + 5.0, rect[1] + 16.0), g)
+            .unwrap();
+        if let Some(shortcut) = control.shortcut(){
+            text::Text::new_color([0.5, 0.5, 0.5, 1.0], 11)
+                .draw(shortcut, glyphs, &c.draw_state, c.transform.trans(rect[0] + 5.0, rect[1] + rect[3] - 4.0), g)
+                .unwrap();
         }
     }
+}
 
-    let bias = json[bias].clone();
-    let mut bias_values = vec![0.0; bias.as_array().unwrap().len()];
-    for i in 0..bias.as_array().unwrap().len(){
-        bias_values[i] = bias[i].as_f64().unwrap() as f32;
+// samples per digit while labeling, and the sample shown while reviewing
+fn draw_dataset(dataset: &dataset::Dataset, review: Option<usize>, c: &Context, g: &mut G2d, glyphs: &mut Glyphs){
+    let top = TOOLBAR_HEIGHT + 6.0;
+    let counts = dataset.counts();
+    rectangle([1.0, 1.0, 1.0, 0.85], [6.0, top, 250.0, 56.0], c.transform, g);
+    for i in 0..counts.len(){
+        text::Text::new_color([0.0, 0.0, 0.0, 1.0], 14)
+            .draw(&format!("{}: {}", i, counts[i]), glyphs, &c.draw_state,
+                c.transform.trans(12.0 + 48.0 * (i % 5) as f64, top + 18.0 + 18.0 * (i / 5) as f64), g)
+            .unwrap();
     }
-
-    model::FullyConnected::new(
-        weight[0].as_array().unwrap().len() as u32,
-        weight.as_array().unwrap().len() as u32,
-        weight_values, 
-        bias_values
-    )
+    let status = match review {
+        Some(index) => {
+            let sample = &dataset.samples()[index];
+            let predicted = sample.predicted.map_or(String::new(), |p| format!(", predicted {}", p));
+            format!("Sample {} of {}: {}{}", index + 1, dataset.len(), sample.label, predicted)
+        },
+        None => format!("Labeling, {} samples", dataset.len())
+    };
+    text::Text::new_color([0.25, 0.45, 0.8, 1.0], 14)
+        .draw(&status, glyphs, &c.draw_state, c.transform.trans(12.0, top + 52.0), g)
+        .unwrap();
 }
 
-#[derive(Debug)]
-struct CNN{
-    input_size: u32,
-    output_size: u32,
-    conv1: model::Conv2D,
-    conv2: model::Conv2D,
-    fc: model::FullyConnected
+// fine-tuning progress, just above the prediction panel
+fn draw_status(status: &str, c: &Context, g: &mut G2d, glyphs: &mut Glyphs){
+    let top = TOOLBAR_HEIGHT + CANVAS_SIZE as f64 - 140.0;
+    rectangle([1.0, 1.0, 1.0, 0.85], [0.0, top, CANVAS_SIZE as f64, 26.0], c.transform, g);
+    text::Text::new_color([0.0, 0.0, 0.0, 1.0], 14)
+        .draw(status, glyphs, &c.draw_state, c.transform.trans(8.0, top + 18.0), g)
+        .unwrap();
 }
 
-impl CNN{
-    fn new(input_size: u32, output_size: u32, conv1: model::Conv2D, conv2: model::Conv2D, fc: model::FullyConnected) -> CNN{
-        CNN{
-            input_size: input_size,
-            output_size: output_size,
-            conv1: conv1,
-            conv2: conv2,
-            fc: fc
-        }
-    }
-
-    fn forward(&self, img: &Vec<Vec<Vec<f32>>>) -> u32{
-        let img = self.conv1.forward(img);
-        let img = model::ReLU::forward(&img);
-        let pool2 = model::MaxPooling2D::new(2);
-        let img = pool2.forward(&img);
-        
-        let img = self.conv2.forward(&img);
-        let img = model::ReLU::forward(&img);
-        let img = pool2.forward(&img);
+// label for the number keys, top row or keypad
+fn digit(key: Key) -> Option<u8>{
+    let keys = [
+        (Key::D0, Key::NumPad0), (Key::D1, Key::NumPad1), (Key::D2, Key::NumPad2),
+        (Key::D3, Key::NumPad3), (Key::D4, Key::NumPad4), (Key::D5, Key::NumPad5),
+        (Key::D6, Key::NumPad6), (Key::D7, Key::NumPad7), (Key::D8, Key::NumPad8),
+        (Key::D9, Key::NumPad9)
+    ];
+    keys.iter().position(|&(top, pad)| key == top || key == pad).map(|i| i as u8)
+}
 
-        let img = model::Flatten::forward(&img);
-        let img = self.fc.forward(&img);
-        
-        let img = model::softmax(&img);
-        model::argmax(&img) as u32
+// drawing histories are replayed, anything else is loaded as an image
+fn open_file(app: &mut app::App, path: &Path){
+    let history = path.extension().map_or(false, |ext| ext == "json");
+    let result = if history {app.load_history(path)} else {app.load_image(path)};
+    match result {
+        Ok(()) => {
+            if history{
+                app.start_replay();
+            }
+        },
+        Err(e) => println!("Error: {}: {}", path.display(), e)
     }
 }
 
-fn print_screen(erase: &bool){
+fn print_screen(erase: &bool, brush_radius: f32){
     println!("{}[2J", 27 as char);
 
     println!("Draw Rust!");
@@ -144,133 +208,270 @@ fn print_screen(erase: &bool){
             "Write"
         }
     });
-    println!("Press D to draw, E to erase, C to clear, P to predict");
+    println!("Brush Radius: {}", brush_radius);
+    println!("Press D to draw, E to erase, C to clear, V to toggle the preview");
+    println!("Press G to show why the digit was predicted: Grad-CAM, saliency, integrated gradients, off");
+    println!("The prediction updates while drawing, P predicts again right away");
+    println!("Press [ and ] to change the brush size, Z to undo, Y to redo, M to switch models");
+    println!("Press R to replay the drawing stroke by stroke, H to save its history");
+    println!("Press I to save the kernels and feature maps of the drawing as a PNG sheet");
+    println!("Press S to save the drawing as PNG, 0-9 to add it to ./drawings as that digit (IDX)");
+    println!("Drop a PNG or JPEG on the window to load it");
+    println!("Press L to label: 0-9 then stores the drawing in ./drawings/dataset, X exports it as IDX");
+    println!("Press Tab to review the dataset, Left and Right to browse, 0-9 to relabel, Delete to remove");
+    println!("Press T to fine-tune the model on the dataset, F to freeze conv1 and conv2 while tuning");
 
 }
 
 fn main() {
+    // `draw-rust predict ...`, `serve ...` and the other commands of draw-rust-cli run without a window
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(code) = draw_rust::dispatch(&args){
+        std::process::exit(code);
+    }
+
     let mut window: PistonWindow = 
-        WindowSettings::new("Draw Rust!", [540, 540])
+        WindowSettings::new("Draw Rust!", [CANVAS_SIZE as u32, CANVAS_SIZE as u32 + TOOLBAR_HEIGHT as u32])
         .exit_on_esc(true).build().unwrap();
-    let mut draw = false;
-    let mut erase = false;
-    let fill = 1;
+    let toolbar = Toolbar::new(CANVAS_SIZE as f64, TOOLBAR_HEIGHT);
+    let mut cursor = [0.0, 0.0];
+    // the brush slider keeps following the mouse until the button is released
+    let mut sliding = false;
 
-    let mut state: Vec<Vec<f32>> = vec![vec![0.0; 28]; 28];
+    let mut glyphs = match window.load_font("./src/assets/Bebas-Neue.ttf") {
+        Ok(glyphs) => glyphs,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
     
     //let model = predict::CNN::new();
 
-    let file_result = fs::File::open("./src/assets/model.json");
-    let file = match file_result {
-        Ok(file) => file,
+    let model_path = Path::new("./src/assets/model.json");
+    let cnn = match load_cnn(model_path) {
+        Ok(cnn) => cnn,
         Err(e) => {
             println!("Error: {}", e);
             return;
         }
     };
+    let models = app::list_models(Path::new("./src/assets"));
+    let model_index = models.iter().position(|path| path.as_path() == model_path).unwrap_or(0);
 
+    let canvas = canvas::Canvas::new(CANVAS_SIZE, CANVAS_SIZE, 22.0);
+    let mut texture_context = window.create_texture_context();
+    let mut texture: G2dTexture = Texture::from_image(
+        &mut texture_context,
+        &canvas_image(&canvas),
+        &TextureSettings::new()
+    ).unwrap();
+    let mut heatmap: G2dTexture = Texture::from_image(
+        &mut texture_context,
+        &heatmap_image(&vec![vec![0.0; CANVAS_SIZE]; CANVAS_SIZE]),
+        &TextureSettings::new()
+    ).unwrap();
+    let mut app = app::App::new(canvas, cnn, models, model_index);
+    if let Err(e) = app.open_dataset(Path::new("./drawings/dataset")){
+        println!("Error: {}", e);
+    }
+    print_screen(&app.erase, app.canvas.brush_radius);
 
-    let json: serde_json::Value = serde_json::from_reader(file)
-        .expect("file should be proper JSON");
-
-    let conv1 = generate_conv2d(&json, "conv1");
-    let conv2 = generate_conv2d(&json, "conv2");
-    let fc = generate_fully_connected(&json, "fc1");
-
-    let cnn = CNN::new(1, 10, conv1, conv2, fc);
-    
+    // a history saved with H or an image can be passed as the first argument
+    if let Some(path) = std::env::args().nth(1){
+        open_file(&mut app, Path::new(&path));
+    }
 
     while let Some(e) = window.next() {
         
-        window.draw_2d(&e, |c, g, _device| {
+        if let Some(args) = e.update_args(){
+            app.step_replay(args.dt);
+            if let Some(message) = app.poll_finetune(){
+                println!("{}", message);
+            }
+        }
+        if e.render_args().is_some(){
+            if app.dirty{
+                texture.update(&mut texture_context, &canvas_image(&app.canvas)).unwrap();
+                app.dirty = false;
+            }
+            app.update_prediction();
+            if app.heatmap_dirty{
+                if let Some(ref map) = app.heatmap{
+                    heatmap.update(&mut texture_context, &heatmap_image(map)).unwrap();
+                }
+                app.heatmap_dirty = false;
+            }
+        }
+
+        window.draw_2d(&e, |c, g, device| {
+            texture_context.encoder.flush(device);
             clear([1.0; 4], g);
             
-            draw_canvas(&state, &c, g);
-            //buttons.push(draw_button(&vec![0.0, 600.0, 100.0, 30.0], &state, &c, g, &mut glyphs));
+            image(&texture, c.transform.trans(0.0, TOOLBAR_HEIGHT), g);
+            if app.heatmap.is_some(){
+                image(&heatmap, c.transform.trans(0.0, TOOLBAR_HEIGHT), g);
+            }
+            if app.show_preview{
+                if let Some(ref image) = app.preview{
+                    draw_preview(image, &c, g);
+                }
+            }
+            if let Some(ref probabilities) = app.probabilities{
+                draw_prediction(probabilities, &c, g, &mut glyphs);
+            }
+            if app.labeling || app.reviewing().is_some(){
+                if let Some(dataset) = app.dataset(){
+                    draw_dataset(dataset, app.reviewing(), &c, g, &mut glyphs);
+                }
+            }
+            if let Some(ref status) = app.status{
+                draw_status(status, &c, g, &mut glyphs);
+            }
+            draw_toolbar(&toolbar, &app, &c, g, &mut glyphs);
+            glyphs.factory.encoder.flush(device);
         });
 
+        if let Event::Input(Input::FileDrag(FileDrag::Drop(ref path)), _) = e{
+            open_file(&mut app, path);
+        }
+
         if let Some(button) = e.press_args() {
-            if button == Button::Keyboard(Key::E) {
-                erase = true;
-                print_screen(&erase);
-                //println!("Erase Mode");
+            let control = match button {
+                Button::Keyboard(Key::D) => Some(Control::Draw),
+                Button::Keyboard(Key::E) => Some(Control::Erase),
+                Button::Keyboard(Key::C) => Some(Control::Clear),
+                Button::Keyboard(Key::P) => Some(Control::Predict),
+                Button::Keyboard(Key::Z) => Some(Control::Undo),
+                Button::Keyboard(Key::Y) => Some(Control::Redo),
+                Button::Keyboard(Key::M) => Some(Control::Model),
+                Button::Keyboard(Key::T) => Some(Control::Tune),
+                Button::Keyboard(Key::L) => Some(Control::Label),
+                Button::Keyboard(Key::Tab) => Some(Control::Review),
+                Button::Mouse(MouseButton::Left) => toolbar.hit(cursor[0], cursor[1]),
+                _ => None
+            };
+            match control {
+                Some(Control::Brush) => {
+                    sliding = true;
+                    let radius = toolbar.slider_value(cursor[0], canvas::MIN_BRUSH_RADIUS, canvas::MAX_BRUSH_RADIUS);
+                    app.set_brush_radius(radius);
+                },
+                Some(control) => {
+                    app.apply(control);
+                    print_screen(&app.erase, app.canvas.brush_radius);
+                },
+                None => {}
+            }
+
+            if button == Button::Keyboard(Key::LeftBracket){
+                let radius = app.canvas.brush_radius - 2.0;
+                app.set_brush_radius(radius);
+                print_screen(&app.erase, app.canvas.brush_radius);
+            }
+            else if button == Button::Keyboard(Key::RightBracket){
+                let radius = app.canvas.brush_radius + 2.0;
+                app.set_brush_radius(radius);
+                print_screen(&app.erase, app.canvas.brush_radius);
             }
-            else if button == Button::Keyboard(Key::D){
-                erase = false;
-                print_screen(&erase);
-                //println!("Write Mode");
+            else if button == Button::Keyboard(Key::V){
+                app.show_preview = !app.show_preview;
             }
-            else if button == Button::Keyboard(Key::C){
-                // clear everything, back to draw
-                erase = false;
-                state = vec![vec![0.0; 28]; 28];
-                print_screen(&erase);
-                //println!("Clear - Write Mode");
+            else if button == Button::Keyboard(Key::G){
+                match app.toggle_explain() {
+                    Some(method) => println!("Showing {} for the predicted digit", method.name()),
+                    None => println!("Heatmap off")
+                }
+            }
+            else if button == Button::Keyboard(Key::I){
+                match app.save_features(Path::new("./drawings"), &format!("features-{}", timestamp())) {
+                    Ok(path) => println!("Saved {}", path.display()),
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            else if button == Button::Keyboard(Key::R){
+                app.start_replay();
+            }
+            else if button == Button::Keyboard(Key::H){
+                let path = format!("./drawings/history-{}.json", timestamp());
+                match app.save_history(Path::new(&path)) {
+                    Ok(()) => println!("Saved {}", path),
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            else if button == Button::Keyboard(Key::S){
+                match app.save_png(Path::new("./drawings"), &format!("drawing-{}", timestamp())) {
+                    Ok(paths) => {
+                        for path in paths.iter(){
+                            println!("Saved {}", path.display());
+                        }
+                    },
+                    Err(e) => println!("Error: {}", e)
+                }
+            }
+            else if button == Button::Keyboard(Key::Left){
+                app.review_step(-1);
+            }
+            else if button == Button::Keyboard(Key::Right){
+                app.review_step(1);
+            }
+            else if button == Button::Keyboard(Key::Delete) || button == Button::Keyboard(Key::Backspace){
+                match app.delete_sample() {
+                    Ok(message) => println!("{}", message),
+                    Err(e) => println!("Error: {}", e)
+                }
             }
-            else if button == Button::Keyboard(Key::P){
-                //println!("{:?}", state);
-                let mut convert = vec![vec![0.0; 28]; 28];
-                for i in 0..28{
-                    for j in 0..28{
-                        convert[i][j] = (state[j][i]-0.5)/0.5;
+            else if button == Button::Keyboard(Key::F){
+                app.freeze_conv = !app.freeze_conv;
+                println!("Fine-tuning {}", if app.freeze_conv {"only trains fc1"} else {"trains every layer"});
+            }
+            else if button == Button::Keyboard(Key::X){
+                if let Some(dataset) = app.dataset(){
+                    let dir = dataset.dir().join("idx");
+                    match dataset.export_idx(&dir) {
+                        Ok(count) => println!("Exported {} samples to {}", count, dir.display()),
+                        Err(e) => println!("Error: {}", e)
                     }
                 }
-
-                let wrapper = vec![convert.clone()];
-                let result = cnn.forward(&wrapper);
-                print_screen(&erase);
-                println!("Predicted: {}", result);
             }
-        };
-
-        if let Some(button) = e.press_args() {
-            if button == Button::Mouse(MouseButton::Left) {
-                draw = true;
-                //println!("Mouse Press");
-
+            else if let Button::Keyboard(key) = button{
+                if let Some(label) = digit(key){
+                    if app.labeling || app.reviewing().is_some(){
+                        match app.label(label) {
+                            Ok(message) => println!("{}", message),
+                            Err(e) => println!("Error: {}", e)
+                        }
+                    }
+                    else{
+                        match app.append_idx(Path::new("./drawings"), label) {
+                            Ok(count) => println!("Added a {} to ./drawings, {} images", label, count),
+                            Err(e) => println!("Error: {}", e)
+                        }
+                    }
+                }
+            }
+            else if button == Button::Mouse(MouseButton::Left) && cursor[1] >= TOOLBAR_HEIGHT{
+                app.begin_stroke(cursor[0] as f32, (cursor[1] - TOOLBAR_HEIGHT) as f32);
             }
         };
+
         if let Some(button) = e.release_args() {
             if button == Button::Mouse(MouseButton::Left) {
-                draw = false;
-                //println!("Mouse up");
+                if sliding{
+                    sliding = false;
+                    print_screen(&app.erase, app.canvas.brush_radius);
+                }
+                app.end_stroke();
             }
         };
 
-        if draw {
-            if let Some(pos) = e.mouse_cursor_args() {
-                let (x, y) = (((pos[0] as f32)/20.0).floor(), ((pos[1] as f32)/20.0).floor());
-                if x < 28.0 && x >= 0.0 && y < 28.0 && y >= 0.0{
-                    if erase{
-                        state[x as usize][y as usize] = 0.0;
-                        if fill == 2{
-                            let add_x = vec![1.0, -1.0, 0.0, 0.0];
-                            let add_y = vec![0.0, 0.0, -1.0, 1.0];
-                            for i in 0..4{
-                                let new_x = x + add_x[i];
-                                let new_y = y + add_y[i];
-                                if new_x < 28.0 && new_x >= 0.0 && new_y < 28.0 && new_y >= 0.0{
-                                    state[new_x as usize][new_y as usize] = 0.0;
-                                }
-                            }
-                        }
-                    }
-                    else{
-                        state[x as usize][y as usize] = 1.0;
-                        if fill == 2{
-                            let add_x = vec![1.0, -1.0, 0.0, 0.0];
-                            let add_y = vec![0.0, 0.0, -1.0, 1.0];
-                            for i in 0..4{
-                                let new_x = x + add_x[i];
-                                let new_y = y + add_y[i];
-                                if new_x < 28.0 && new_x >= 0.0 && new_y < 28.0 && new_y >= 0.0{
-                                    state[new_x as usize][new_y as usize] = std::cmp::max((state[new_x as usize][new_y as usize]*100.0) as u32, 99_u32) as f32 / 100.0;
-                                }
-                            }
-                        }
-                    }//println!("{}, {}", (x/20.0).floor(),(y/20.0).floor());
-                }
-            };
-        }
+        if let Some(pos) = e.mouse_cursor_args() {
+            cursor = pos;
+            if sliding{
+                let radius = toolbar.slider_value(pos[0], canvas::MIN_BRUSH_RADIUS, canvas::MAX_BRUSH_RADIUS);
+                app.set_brush_radius(radius);
+            }
+            app.continue_stroke(pos[0] as f32, (pos[1] - TOOLBAR_HEIGHT) as f32);
+        };
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Conv2D{
     input_size: u32,//input layers
     output_size: u32,//output layers
@@ -6,24 +6,31 @@ pub struct Conv2D{
     bias: Vec<f32>
 }
 
-#[derive(Debug)]
+// gradients of a convolution with respect to (input, filter, bias)
+pub type ConvGradients = (Vec<Vec<Vec<f32>>>, Vec<Vec<Vec<Vec<f32>>>>, Vec<f32>);
+
+#[derive(Debug, Clone)]
 pub struct MaxPooling2D{
     pool_size: u32
 }
 
-#[derive(Debug)]
+// the sizes are only kept to describe the layer, forward and backward
+// work on any shape
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
 pub struct Flatten{
     input_size: u32,
     output_size: u32
 }
 
-#[derive(Debug)]
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
 pub struct ReLU{
     input_size: u32,
     output_size: u32
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FullyConnected{
     input_size: u32,
     output_size: u32,
@@ -41,6 +48,22 @@ impl Conv2D{
         }
     }
 
+    pub fn filter(&self) -> &Vec<Vec<Vec<Vec<f32>>>>{
+        &self.filter
+    }
+
+    pub fn filter_mut(&mut self) -> &mut Vec<Vec<Vec<Vec<f32>>>>{
+        &mut self.filter
+    }
+
+    pub fn bias(&self) -> &Vec<f32>{
+        &self.bias
+    }
+
+    pub fn bias_mut(&mut self) -> &mut Vec<f32>{
+        &mut self.bias
+    }
+
     pub fn forward(&self, input: &Vec<Vec<Vec<f32>>>) -> Vec<Vec<Vec<f32>>> {
         // the input is (layers, rows, cols));
         let mut output:  Vec<Vec<Vec<f32>>> 
@@ -59,22 +82,71 @@ impl Conv2D{
                 // i is the index of the output layer
                 // j is the index of the filter group/input
                 for x in 0..(input[j as usize].len() - self.filter[i as usize][j as usize][0].len() + 1){
-                    for y in 0..(input[j as usize][x as usize].len() - self.filter[i as usize][j as usize][0].len() + 1){
+                    for y in 0..(input[j as usize][x].len() - self.filter[i as usize][j as usize][0].len() + 1){
                         // x,y refers to the insert place of the filter to the output
                         for k in 0..self.filter[i as usize][j as usize].len(){
-                            for l in 0..self.filter[i as usize][j as usize][k as usize].len(){
-                                output[i as usize][x as usize][y as usize] += 
-                                    input[j as usize][x as usize + k as usize][y as usize + l as usize] * 
-                                    self.filter[i as usize][j as usize][k as usize][l as usize];
+                            for l in 0..self.filter[i as usize][j as usize][k].len(){
+                                output[i as usize][x][y] += 
+                                    input[j as usize][x + k][y + l] * 
+                                    self.filter[i as usize][j as usize][k][l];
                             }
                         }
-                        output[i as usize][x as usize][y as usize] += self.bias[i as usize];
                     }
                 }
             }
+            // the bias is added once per output position, not once per input layer
+            for x in 0..output[i as usize].len(){
+                for y in 0..output[i as usize][x].len(){
+                    output[i as usize][x][y] += self.bias[i as usize];
+                }
+            }
         }
         output
     }
+
+    pub fn backward(&self, input: &Vec<Vec<Vec<f32>>>, grad_output: &Vec<Vec<Vec<f32>>>) -> ConvGradients {
+        // returns the gradients of (input, filter, bias)
+        let mut grad_input: Vec<Vec<Vec<f32>>> = vec![
+            vec![
+                vec![0.0; input[0][0].len()]
+                ;input[0].len()
+            ]
+            ;input.len()
+        ];
+        let mut grad_filter: Vec<Vec<Vec<Vec<f32>>>> = vec![
+            vec![
+                vec![
+                    vec![0.0; self.filter[0][0][0].len()]
+                    ;self.filter[0][0].len()
+                ]
+                ;self.input_size as usize
+            ]
+            ;self.output_size as usize
+        ];
+        let mut grad_bias: Vec<f32> = vec![0.0; self.output_size as usize];
+
+        for i in 0..self.output_size as usize{
+            for j in 0..self.input_size as usize{
+                for x in 0..grad_output[i].len(){
+                    for y in 0..grad_output[i][x].len(){
+                        let grad = grad_output[i][x][y];
+                        for k in 0..self.filter[i][j].len(){
+                            for l in 0..self.filter[i][j][k].len(){
+                                grad_filter[i][j][k][l] += input[j][x + k][y + l] * grad;
+                                grad_input[j][x + k][y + l] += self.filter[i][j][k][l] * grad;
+                            }
+                        }
+                    }
+                }
+            }
+            for x in 0..grad_output[i].len(){
+                for y in 0..grad_output[i][x].len(){
+                    grad_bias[i] += grad_output[i][x][y];
+                }
+            }
+        }
+        (grad_input, grad_filter, grad_bias)
+    }
 }
 
 impl MaxPooling2D{
@@ -93,7 +165,7 @@ impl MaxPooling2D{
                 vec![0.0; input[0][0].len() / self.pool_size as usize]
                 ;input[0].len() / self.pool_size as usize
             ]
-            ;input.len() as usize
+            ;input.len()
         ];
 
         for layer in 0..input.len(){
@@ -115,6 +187,40 @@ impl MaxPooling2D{
         }
         output
     }
+
+    pub fn backward(&self, input: &Vec<Vec<Vec<f32>>>, grad_output: &Vec<Vec<Vec<f32>>>) -> Vec<Vec<Vec<f32>>>{
+        // the gradient only flows to the position that was picked by forward,
+        // nothing flows back when the whole window was below zero
+        let pool_size = self.pool_size as usize;
+        let mut grad_input: Vec<Vec<Vec<f32>>> = vec![
+            vec![
+                vec![0.0; input[0][0].len()]
+                ;input[0].len()
+            ]
+            ;input.len()
+        ];
+
+        for layer in 0..grad_output.len(){
+            for x in 0..grad_output[layer].len(){
+                for y in 0..grad_output[layer][x].len(){
+                    let mut max = 0.0;
+                    let mut index: Option<(usize, usize)> = None;
+                    for i in 0..pool_size{
+                        for j in 0..pool_size{
+                            if input[layer][x * pool_size + i][y * pool_size + j] > max{
+                                max = input[layer][x * pool_size + i][y * pool_size + j];
+                                index = Some((x * pool_size + i, y * pool_size + j));
+                            }
+                        }
+                    }
+                    if let Some((i, j)) = index{
+                        grad_input[layer][i][j] += grad_output[layer][x][y];
+                    }
+                }
+            }
+        }
+        grad_input
+    }
 }
 
 impl Flatten{
@@ -138,6 +244,26 @@ impl Flatten{
         }
         output
     }
+
+    pub fn backward(img: &Vec<Vec<Vec<f32>>>, grad_output: &Vec<f32>) -> Vec<Vec<Vec<f32>>>{
+        let mut grad_input: Vec<Vec<Vec<f32>>> = vec![
+            vec![
+                vec![0.0; img[0][0].len()]
+                ;img[0].len()
+            ]
+            ;img.len()
+        ];
+        let mut index = 0;
+        for i in 0..img.len(){
+            for j in 0..img[i].len(){
+                for k in 0..img[i][j].len(){
+                    grad_input[i][j][k] = grad_output[index];
+                    index += 1;
+                }
+            }
+        }
+        grad_input
+    }
 }
 
 impl ReLU{
@@ -165,6 +291,24 @@ impl ReLU{
         }
         output
     }
+
+    pub fn backward(input: &Vec<Vec<Vec<f32>>>, grad_output: &Vec<Vec<Vec<f32>>>) -> Vec<Vec<Vec<f32>>>{
+        let mut grad_input: Vec<Vec<Vec<f32>>> = vec![
+            vec![
+                vec![0.0; input[0][0].len()]
+                ;input[0].len()
+            ]
+            ;input.len()
+        ];
+        for i in 0..input.len(){
+            for j in 0..input[i].len(){
+                for k in 0..input[i][j].len(){
+                    grad_input[i][j][k] = if input[i][j][k] > 0.0 {grad_output[i][j][k]} else {0.0};
+                }
+            }
+        }
+        grad_input
+    }
 }
 
 impl FullyConnected{
@@ -176,6 +320,23 @@ impl FullyConnected{
             bias: bias
         }
     }
+
+    pub fn weights(&self) -> &Vec<Vec<f32>>{
+        &self.weights
+    }
+
+    pub fn weights_mut(&mut self) -> &mut Vec<Vec<f32>>{
+        &mut self.weights
+    }
+
+    pub fn bias(&self) -> &Vec<f32>{
+        &self.bias
+    }
+
+    pub fn bias_mut(&mut self) -> &mut Vec<f32>{
+        &mut self.bias
+    }
+
     pub fn forward(&self, input: &Vec<f32>) -> Vec<f32>{
         let mut output: Vec<f32> = vec![0.0; self.output_size as usize];
         for i in 0..self.output_size{
@@ -186,6 +347,21 @@ impl FullyConnected{
         }
         output
     }
+
+    pub fn backward(&self, input: &Vec<f32>, grad_output: &Vec<f32>) -> (Vec<f32>, Vec<Vec<f32>>, Vec<f32>){
+        // returns the gradients of (input, weights, bias)
+        let mut grad_input: Vec<f32> = vec![0.0; self.input_size as usize];
+        let mut grad_weights: Vec<Vec<f32>> = vec![vec![0.0; self.input_size as usize]; self.output_size as usize];
+        let mut grad_bias: Vec<f32> = vec![0.0; self.output_size as usize];
+        for i in 0..self.output_size as usize{
+            for j in 0..self.input_size as usize{
+                grad_input[j] += self.weights[i][j] * grad_output[i];
+                grad_weights[i][j] = input[j] * grad_output[i];
+            }
+            grad_bias[i] = grad_output[i];
+        }
+        (grad_input, grad_weights, grad_bias)
+    }
 }
 
 pub fn argmax(input: &Vec<f32>) -> usize{
@@ -212,6 +388,19 @@ pub fn softmax(input: &Vec<f32>) -> Vec<f32>{
     output
 }
 
+pub fn softmax_backward(output: &Vec<f32>, grad_output: &Vec<f32>) -> Vec<f32>{
+    // output is the result of softmax, not its input
+    let mut dot = 0.0;
+    for i in 0..output.len(){
+        dot += output[i] * grad_output[i];
+    }
+    let mut grad_input: Vec<f32> = vec![0.0; output.len()];
+    for i in 0..output.len(){
+        grad_input[i] = output[i] * (grad_output[i] - dot);
+    }
+    grad_input
+}
+
 #[cfg(test)]
 mod tests {
     
@@ -286,6 +475,26 @@ mod tests {
         ], "Sample: {:?}", output);
     }
 
+    #[test]
+    fn conv2d_multi_input_bias_test(){
+        // the bias has to be added once, no matter how many input layers there are
+        let filter = vec![
+            vec![
+                vec![vec![1.0]],
+                vec![vec![2.0]],
+                vec![vec![3.0]]
+            ]
+        ];
+        let conv2d = Conv2D::new(3, 1, filter, vec![0.5]);
+        let input = vec![
+            vec![vec![1.0, 2.0]],
+            vec![vec![1.0, 2.0]],
+            vec![vec![1.0, 2.0]]
+        ];
+        let output = conv2d.forward(&input);
+        assert_eq!(output, vec![vec![vec![6.5, 12.5]]], "Sample: {:?}", output);
+    }
+
     #[test]
     fn max_pooling_2d_test(){
         let pool_size = 2;
@@ -417,6 +626,15 @@ mod tests {
         let x = maxpooling2d1.forward(&x);
         println!("MaxPooling2D: {:?}", x);
         
-        assert!(1==2);
+        assert_eq!(x, vec![
+            vec![
+                vec![3.0, 3.0],
+                vec![3.0, 3.0]
+            ],
+            vec![
+                vec![4.0, 4.0],
+                vec![4.0, 4.0]
+            ]
+        ], "Sample: {:?}", x);
     }
 }
\ No newline at end of file
@@ -0,0 +1,186 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// # Golden parity
+// Golden files are written by scripts/export_golden.py from the PyTorch model
+// that model.json was exported from. Each file holds one normalized input and
+// every intermediate activation (conv1, relu1, pool1, conv2, relu2, pool2,
+// flatten, logits), which are compared layer by layer with CNN::trace.
+#[derive(Debug, Clone)]
+pub struct Golden{
+    pub name: String,
+    pub generator: String,
+    pub input: Vec<Vec<Vec<f32>>>,
+    pub activations: Vec<(String, Vec<f32>)>
+}
+
+#[derive(Debug, Clone)]
+pub struct LayerReport{
+    pub layer: String,
+    pub max_abs_error: f32,
+    pub worst_index: usize,
+    pub passed: bool
+}
+
+impl fmt::Display for LayerReport{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{:<8} {} max abs error {:.2e} at index {}",
+            self.layer, if self.passed {"ok  "} else {"FAIL"}, self.max_abs_error, self.worst_index)
+    }
+}
+
+fn invalid_data(message: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn flatten_json(json: &serde_json::Value, output: &mut Vec<f32>){
+    match json.as_array() {
+        Some(values) => {
+            for value in values{
+                flatten_json(value, output);
+            }
+        },
+        None => output.push(json.as_f64().unwrap_or(f64::NAN) as f32)
+    }
+}
+
+impl Golden{
+    pub fn load(path: &Path) -> io::Result<Golden>{
+        let file = fs::File::open(path)?;
+        let json: serde_json::Value = serde_json::from_reader(file)
+            .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+
+        let mut input = Vec::new();
+        for layer in json["input"].as_array().into_iter().flat_map(|a| a.iter()){
+            let mut rows = Vec::new();
+            for row in layer.as_array().into_iter().flat_map(|a| a.iter()){
+                let mut values = Vec::new();
+                flatten_json(row, &mut values);
+                rows.push(values);
+            }
+            input.push(rows);
+        }
+        if input.is_empty(){
+            return Err(invalid_data(format!("{}: missing input", path.display())));
+        }
+
+        let mut activations = Vec::new();
+        let layers = json["activations"].as_object()
+            .ok_or_else(|| invalid_data(format!("{}: missing activations", path.display())))?;
+        for (layer, values) in layers.iter(){
+            let mut flat = Vec::new();
+            flatten_json(values, &mut flat);
+            activations.push((layer.clone(), flat));
+        }
+
+        Ok(Golden{
+            name: json["name"].as_str().unwrap_or("").to_owned(),
+            generator: json["generator"].as_str().unwrap_or("unknown").to_owned(),
+            input: input,
+            activations: activations
+        })
+    }
+
+    pub fn load_dir(dir: &Path) -> io::Result<Vec<Golden>>{
+        let mut paths: Vec<_> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+        paths.iter().map(|path| Golden::load(path)).collect()
+    }
+
+    pub fn activation(&self, layer: &str) -> Option<&Vec<f32>>{
+        self.activations.iter().find(|a| a.0 == layer).map(|a| &a.1)
+    }
+
+    // compares a trace in forward order, so the first failing report is the
+    // layer where the two implementations start to diverge
+    pub fn compare(&self, trace: &Vec<(&str, Vec<f32>)>, tolerance: f32) -> Vec<LayerReport>{
+        let mut reports = Vec::new();
+        for (layer, actual) in trace.iter(){
+            let expected = match self.activation(layer) {
+                Some(expected) => expected,
+                None => continue
+            };
+            let mut passed = expected.len() == actual.len();
+            let mut max_abs_error = 0.0;
+            let mut worst_index = 0;
+            for i in 0..expected.len().min(actual.len()){
+                let error = (expected[i] - actual[i]).abs();
+                // written so that NaN fails as well
+                if error.is_nan() || error > tolerance * (1.0 + expected[i].abs()){
+                    passed = false;
+                }
+                if error > max_abs_error || error.is_nan(){
+                    max_abs_error = error;
+                    worst_index = i;
+                }
+            }
+            reports.push(LayerReport{
+                layer: layer.to_string(),
+                max_abs_error: max_abs_error,
+                worst_index: worst_index,
+                passed: passed
+            });
+        }
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use model;
+    use CNN;
+
+    const TOLERANCE: f32 = 1e-4;
+
+    fn load_cnn() -> CNN{
+        let file = fs::File::open("./src/assets/model.json").unwrap();
+        let json: serde_json::Value = serde_json::from_reader(file).unwrap();
+        CNN::from_json(&json).unwrap()
+    }
+
+    #[test]
+    fn golden_parity_test(){
+        let cnn = load_cnn();
+        let goldens = Golden::load_dir(Path::new("./src/assets/golden")).unwrap();
+        assert!(!goldens.is_empty(), "no golden files found");
+
+        for golden in goldens.iter(){
+            // the committed goldens predate the torch-only script, regenerate
+            // them with `python scripts/export_golden.py` to check against PyTorch
+            println!("{} (generated by {})", golden.name, golden.generator);
+            let reports = golden.compare(&cnn.trace(&golden.input), TOLERANCE);
+            assert_eq!(reports.len(), golden.activations.len(), "{}: not every golden layer was checked", golden.name);
+            for report in reports.iter(){
+                println!("  {}", report);
+            }
+            if let Some(report) = reports.iter().find(|r| !r.passed){
+                panic!("{}: diverges from the golden output starting at {}", golden.name, report);
+            }
+
+            let expected = model::argmax(&model::softmax(golden.activation("logits").unwrap()));
+            assert_eq!(cnn.forward(&golden.input), expected as u32, "{}: predicted class differs", golden.name);
+        }
+    }
+
+    #[test]
+    fn golden_compare_detects_divergence_test(){
+        let golden = Golden{
+            name: "sample".to_owned(),
+            generator: "test".to_owned(),
+            input: vec![vec![vec![0.0]]],
+            activations: vec![("conv1".to_owned(), vec![1.0, 2.0]), ("logits".to_owned(), vec![3.0])]
+        };
+        let trace = vec![("conv1", vec![1.0, 2.5]), ("logits", vec![3.0])];
+        let reports = golden.compare(&trace, TOLERANCE);
+        assert!(!reports[0].passed);
+        assert_eq!(reports[0].worst_index, 1);
+        assert!(reports[1].passed);
+    }
+}
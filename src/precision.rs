@@ -0,0 +1,711 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use idx;
+use model;
+use preprocess;
+use quantize;
+use rng::Rng;
+use train::{self, Sgd};
+use CNN;
+
+// # Half precision
+// f16 (IEEE binary16) and bf16 (the upper half of an f32) for the weights.
+// `HalfCNN` keeps the layers as 16 bit values, which halves the model file,
+// and can also compute in half precision: every layer reads half values and
+// rounds its output to half again, but the products and sums are f32 like
+// on hardware with f32 accumulation. A product of two halves is exact in
+// f32, so this gives the same results as such hardware would.
+//
+// `MixedPrecision` trains with f32 master weights that are rounded to half
+// for the forward and backward pass. With f16 the loss is scaled up before
+// the backward pass so small gradients do not flush to zero, and a step
+// whose gradients overflow is skipped with a smaller scale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Half{
+    F16,
+    Bf16
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct HalfLayer{
+    // (outputs, inputs, rows, cols) for conv, (outputs, inputs) for fc
+    shape: Vec<usize>,
+    weights: Vec<u16>,
+    bias: Vec<u16>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HalfCNN{
+    pub precision: Half,
+    conv1: HalfLayer,
+    conv2: HalfLayer,
+    fc: HalfLayer
+}
+
+#[derive(Debug, Clone)]
+pub struct MixedPrecision{
+    pub precision: Half,
+    pub loss_scale: f32,
+    // steps without overflow before the loss scale is doubled
+    pub growth_interval: u32,
+    good_steps: u32,
+    pub skipped_steps: u32
+}
+
+// # File format
+// Big endian like IDX: "DRHF", u32 version, u8 precision (0 f16, 1 bf16),
+// then conv1, conv2 and fc1, each as u32 rank, u32 dims, the 16 bit weights
+// and one 16 bit bias per output.
+const MAGIC: &[u8; 4] = b"DRHF";
+const VERSION: u32 = 1;
+
+fn invalid_data(message: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// round to nearest, ties to even, like a float cast on hardware
+pub fn f16_from_f32(value: f32) -> u16{
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exp == 0xff{
+        return sign | 0x7c00 | if mantissa != 0 {0x200} else {0};
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f{
+        return sign | 0x7c00;
+    }
+    if e <= 0{
+        // subnormal in f16, or too small for it
+        if e < -10{
+            return sign;
+        }
+        let m = mantissa | 0x80_0000;
+        let shift = (14 - e) as u32;
+        let half = 1 << (shift - 1);
+        let rest = m & ((1 << shift) - 1);
+        let mut h = m >> shift;
+        if rest > half || (rest == half && h & 1 == 1){
+            h += 1;
+        }
+        return sign | h as u16;
+    }
+    let mut h = ((e as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    // a carry into the exponent is still the right number, up to infinity
+    if rest > 0x1000 || (rest == 0x1000 && h & 1 == 1){
+        h += 1;
+    }
+    sign | h as u16
+}
+
+pub fn f16_to_f32(half: u16) -> f32{
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exp = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    if exp == 0{
+        let value = mantissa as f32 * (2.0f32).powi(-24);
+        return if sign != 0 {-value} else {value};
+    }
+    if exp == 0x1f{
+        return f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13));
+    }
+    f32::from_bits(sign | ((exp + 112) << 23) | (mantissa << 13))
+}
+
+pub fn bf16_from_f32(value: f32) -> u16{
+    let bits = value.to_bits();
+    if value.is_nan(){
+        return ((bits >> 16) | 0x40) as u16;
+    }
+    let rounding = 0x7fff + ((bits >> 16) & 1);
+    ((bits + rounding) >> 16) as u16
+}
+
+pub fn bf16_to_f32(half: u16) -> f32{
+    f32::from_bits((half as u32) << 16)
+}
+
+impl Half{
+    pub fn parse(name: &str) -> Option<Half>{
+        match name {
+            "f16" => Some(Half::F16),
+            "bf16" => Some(Half::Bf16),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str{
+        match *self {
+            Half::F16 => "f16",
+            Half::Bf16 => "bf16"
+        }
+    }
+
+    pub fn to_bits(&self, value: f32) -> u16{
+        match *self {
+            Half::F16 => f16_from_f32(value),
+            Half::Bf16 => bf16_from_f32(value)
+        }
+    }
+
+    pub fn from_bits(&self, bits: u16) -> f32{
+        match *self {
+            Half::F16 => f16_to_f32(bits),
+            Half::Bf16 => bf16_to_f32(bits)
+        }
+    }
+
+    // the nearest value this precision can hold
+    pub fn round(&self, value: f32) -> f32{
+        self.from_bits(self.to_bits(value))
+    }
+
+    pub fn round_tensor(&self, input: &Vec<Vec<Vec<f32>>>) -> Vec<Vec<Vec<f32>>>{
+        input.iter().map(|layer| layer.iter().map(|row| row.iter().map(|&v| self.round(v)).collect()).collect()).collect()
+    }
+
+    // the network with every weight rounded to this precision
+    pub fn round_cnn(&self, cnn: &CNN) -> CNN{
+        HalfCNN::new(cnn, *self).to_cnn()
+    }
+}
+
+impl HalfLayer{
+    fn conv(layer: &model::Conv2D, precision: Half) -> HalfLayer{
+        let f = layer.filter();
+        HalfLayer{
+            shape: vec![f.len(), f[0].len(), f[0][0].len(), f[0][0][0].len()],
+            weights: f.iter().flat_map(|a| a.iter().flat_map(|b| b.iter().flat_map(|c| c.iter())))
+                .map(|&w| precision.to_bits(w)).collect(),
+            bias: layer.bias().iter().map(|&b| precision.to_bits(b)).collect()
+        }
+    }
+
+    fn fc(layer: &model::FullyConnected, precision: Half) -> HalfLayer{
+        let w = layer.weights();
+        HalfLayer{
+            shape: vec![w.len(), w[0].len()],
+            weights: w.iter().flat_map(|row| row.iter()).map(|&v| precision.to_bits(v)).collect(),
+            bias: layer.bias().iter().map(|&b| precision.to_bits(b)).collect()
+        }
+    }
+
+    fn to_conv(&self, precision: Half) -> model::Conv2D{
+        let (outputs, inputs, rows, cols) = (self.shape[0], self.shape[1], self.shape[2], self.shape[3]);
+        let mut filter = vec![vec![vec![vec![0.0; cols]; rows]; inputs]; outputs];
+        let mut index = 0;
+        for i in 0..outputs{
+            for j in 0..inputs{
+                for k in 0..rows{
+                    for l in 0..cols{
+                        filter[i][j][k][l] = precision.from_bits(self.weights[index]);
+                        index += 1;
+                    }
+                }
+            }
+        }
+        let bias = self.bias.iter().map(|&b| precision.from_bits(b)).collect();
+        model::Conv2D::new(inputs as u32, outputs as u32, filter, bias)
+    }
+
+    fn to_fc(&self, precision: Half) -> model::FullyConnected{
+        let (outputs, inputs) = (self.shape[0], self.shape[1]);
+        let weights = self.weights.chunks(inputs).map(|row| row.iter().map(|&w| precision.from_bits(w)).collect()).collect();
+        let bias = self.bias.iter().map(|&b| precision.from_bits(b)).collect();
+        model::FullyConnected::new(inputs as u32, outputs as u32, weights, bias)
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>){
+        push_u32(bytes, self.shape.len() as u32);
+        for &dim in self.shape.iter(){
+            push_u32(bytes, dim as u32);
+        }
+        for &value in self.weights.iter().chain(self.bias.iter()){
+            bytes.push((value >> 8) as u8);
+            bytes.push(value as u8);
+        }
+    }
+
+    fn read(reader: &mut Reader, rank: usize) -> io::Result<HalfLayer>{
+        if reader.u32()? as usize != rank{
+            return Err(invalid_data(format!("expected a layer of rank {}", rank)));
+        }
+        let mut shape = Vec::new();
+        for _ in 0..rank{
+            let dim = reader.u32()? as usize;
+            if dim == 0 || dim > 4096{
+                return Err(invalid_data(format!("half precision model has a layer dimension of {}", dim)));
+            }
+            shape.push(dim);
+        }
+        let count: usize = shape.iter().product();
+        Ok(HalfLayer{
+            weights: reader.u16s(count)?,
+            bias: reader.u16s(shape[0])?,
+            shape: shape
+        })
+    }
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32){
+    bytes.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+struct Reader<'a>{
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Reader<'a>{
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]>{
+        if self.bytes.len() < self.offset + count{
+            return Err(invalid_data("half precision model file is truncated".to_owned()));
+        }
+        let bytes = &self.bytes[self.offset..self.offset + count];
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32>{
+        let b = self.take(4)?;
+        Ok(((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32)
+    }
+
+    fn u16s(&mut self, count: usize) -> io::Result<Vec<u16>>{
+        Ok(self.take(count * 2)?.chunks(2).map(|b| ((b[0] as u16) << 8) | b[1] as u16).collect())
+    }
+}
+
+// whether `bytes` is a half precision model rather than model.json
+pub fn is_half_model(bytes: &[u8]) -> bool{
+    bytes.starts_with(MAGIC)
+}
+
+impl HalfCNN{
+    pub fn new(cnn: &CNN, precision: Half) -> HalfCNN{
+        HalfCNN{
+            precision: precision,
+            conv1: HalfLayer::conv(&cnn.conv1, precision),
+            conv2: HalfLayer::conv(&cnn.conv2, precision),
+            fc: HalfLayer::fc(&cnn.fc, precision)
+        }
+    }
+
+    // the stored weights as f32, to run or train the model as usual
+    pub fn to_cnn(&self) -> CNN{
+        CNN::new(1, 10, self.conv1.to_conv(self.precision), self.conv2.to_conv(self.precision), self.fc.to_fc(self.precision))
+    }
+
+    // every layer rounds its output to half, products and sums are f32
+    pub fn logits(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        let p = self.precision;
+        let cnn = self.to_cnn();
+        let pool = model::MaxPooling2D::new(2);
+        let img = p.round_tensor(img);
+        // ReLU and max pooling only pick values, they need no rounding
+        let img = pool.forward(&model::ReLU::forward(&p.round_tensor(&cnn.conv1.forward(&img))));
+        let img = pool.forward(&model::ReLU::forward(&p.round_tensor(&cnn.conv2.forward(&img))));
+        cnn.fc.forward(&model::Flatten::forward(&img)).iter().map(|&v| p.round(v)).collect()
+    }
+
+    pub fn probabilities(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        model::softmax(&self.logits(img))
+    }
+
+    pub fn forward(&self, img: &Vec<Vec<Vec<f32>>>) -> u32{
+        model::argmax(&self.probabilities(img)) as u32
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut bytes = MAGIC.to_vec();
+        push_u32(&mut bytes, VERSION);
+        bytes.push(match self.precision {Half::F16 => 0, Half::Bf16 => 1});
+        self.conv1.write(&mut bytes);
+        self.conv2.write(&mut bytes);
+        self.fc.write(&mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<HalfCNN>{
+        let mut reader = Reader{bytes: bytes, offset: 0};
+        if reader.take(4)? != MAGIC{
+            return Err(invalid_data("not a half precision model file".to_owned()));
+        }
+        let version = reader.u32()?;
+        if version != VERSION{
+            return Err(invalid_data(format!("half precision model version {} is not supported", version)));
+        }
+        let precision = match reader.take(1)?[0] {
+            0 => Half::F16,
+            1 => Half::Bf16,
+            other => return Err(invalid_data(format!("unknown precision {}", other)))
+        };
+        let half = HalfCNN{
+            precision: precision,
+            conv1: HalfLayer::read(&mut reader, 4)?,
+            conv2: HalfLayer::read(&mut reader, 4)?,
+            fc: HalfLayer::read(&mut reader, 2)?
+        };
+        if reader.offset != bytes.len(){
+            return Err(invalid_data("half precision model file has trailing bytes".to_owned()));
+        }
+        let (conv1, conv2, fc) = (&half.conv1.shape, &half.conv2.shape, &half.fc.shape);
+        ::check_layers([conv1[0], conv1[1], conv1[2], conv1[3]], [conv2[0], conv2[1], conv2[2], conv2[3]], [fc[0], fc[1]])?;
+        Ok(half)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()>{
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> io::Result<HalfCNN>{
+        HalfCNN::from_bytes(&fs::read(path)?)
+    }
+}
+
+impl MixedPrecision{
+    pub fn new(precision: Half) -> MixedPrecision{
+        MixedPrecision{
+            precision: precision,
+            // bf16 has the exponent range of f32 and needs no scaling
+            loss_scale: match precision {Half::F16 => 1024.0, Half::Bf16 => 1.0},
+            growth_interval: 200,
+            good_steps: 0,
+            skipped_steps: 0
+        }
+    }
+
+    // like train::train_step on the half precision copy of the weights, the
+    // f32 weights are updated. Returns the mean loss, also for a skipped step.
+    pub fn train_step(&mut self, cnn: &mut CNN, optimizer: &mut Sgd, inputs: &[Vec<Vec<Vec<f32>>>], labels: &[u8], frozen: &[&str]) -> f32{
+        let p = self.precision;
+        let layers = p.round_cnn(cnn);
+        let mut total: Option<train::Gradients> = None;
+        let mut loss = 0.0;
+        for n in 0..inputs.len(){
+            let input = p.round_tensor(&inputs[n]);
+            let (sample_loss, grad_logits) = train::cross_entropy(&layers.logits(&input), labels[n] as usize);
+            loss += sample_loss;
+            let scaled: Vec<f32> = grad_logits.iter().map(|&g| p.round(g * self.loss_scale)).collect();
+            let mut grads = layers.backward(&input, &scaled);
+            // the gradients are half values too, which is where f16 underflows
+            for value in grads.values_mut(){
+                *value = p.round(*value);
+            }
+            match total {
+                Some(ref mut total) => total.add(&grads),
+                None => total = Some(grads)
+            }
+        }
+        let loss = loss / inputs.len().max(1) as f32;
+        let mut grads = match total {
+            Some(grads) => grads,
+            None => return loss
+        };
+        if grads.values_mut().iter().any(|value| !value.is_finite()){
+            self.loss_scale = (self.loss_scale / 2.0).max(1.0);
+            self.good_steps = 0;
+            self.skipped_steps += 1;
+            return loss;
+        }
+        grads.scale(1.0 / (self.loss_scale * inputs.len() as f32));
+        train::apply_gradients(cnn, optimizer, &grads, frozen);
+        self.good_steps += 1;
+        if self.precision == Half::F16 && self.good_steps >= self.growth_interval{
+            self.loss_scale *= 2.0;
+            self.good_steps = 0;
+        }
+        loss
+    }
+}
+
+const USAGE: &str = "usage: draw-rust half [--precision f16|bf16] [--model model.json] [--output model-f16.bin] [--images <idx> --labels <idx> [--epochs 0] [--lr 0.001] [--batch-size 32]]";
+
+// arguments after `half`, returns the exit code. Writes the half precision
+// model. Given IDX files it compares the predictions with f32 and, with
+// --epochs, first fine-tunes the model in mixed precision.
+pub fn run(args: &[String]) -> i32{
+    let mut precision = Half::F16;
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut output = None;
+    let mut images_path = None;
+    let mut labels_path = None;
+    let (mut epochs, mut lr, mut batch_size) = (0u32, 0.001f32, 32usize);
+    let mut i = 0;
+    while i < args.len(){
+        if args[i] == "-h" || args[i] == "--help"{
+            println!("{}", USAGE);
+            return 0;
+        }
+        if i + 1 >= args.len(){
+            eprintln!("{} needs a value\n{}", args[i], USAGE);
+            return 2;
+        }
+        let value = &args[i + 1];
+        let parsed = match args[i].as_str() {
+            "--precision" => Half::parse(value).map(|v| precision = v).is_some(),
+            "--model" => {model_path = PathBuf::from(value); true},
+            "--output" => {output = Some(PathBuf::from(value)); true},
+            "--images" => {images_path = Some(PathBuf::from(value)); true},
+            "--labels" => {labels_path = Some(PathBuf::from(value)); true},
+            "--epochs" => value.parse().map(|v| epochs = v).is_ok(),
+            "--lr" => value.parse().map(|v| lr = v).is_ok(),
+            "--batch-size" => value.parse().map(|v: usize| batch_size = v.max(1)).is_ok(),
+            other => {
+                eprintln!("unknown argument {}\n{}", other, USAGE);
+                return 2;
+            }
+        };
+        if !parsed{
+            eprintln!("{} does not take {}\n{}", args[i], value, USAGE);
+            return 2;
+        }
+        i += 2;
+    }
+    let output = output.unwrap_or_else(|| model_path.with_file_name(format!("{}-{}.bin",
+        model_path.file_stem().map_or("model".into(), |stem| stem.to_string_lossy()), precision.name())));
+    let mut cnn = match ::load_cnn(&model_path) {
+        Ok(cnn) => cnn,
+        Err(e) => {
+            eprintln!("Error: {}: {}", model_path.display(), e);
+            return 1;
+        }
+    };
+
+    match (images_path, labels_path) {
+        (Some(images_path), Some(labels_path)) => {
+            let (images, labels) = match idx::read_images(&images_path).and_then(|images| Ok((images, idx::read_labels(&labels_path)?))) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return 1;
+                }
+            };
+            if images.is_empty() || images.len() != labels.len(){
+                eprintln!("Error: {} images but {} labels", images.len(), labels.len());
+                return 1;
+            }
+            let inputs: Vec<Vec<Vec<Vec<f32>>>> = images.iter().map(preprocess::to_input).collect();
+            let mut training = MixedPrecision::new(precision);
+            let mut optimizer = Sgd::new(lr, 0.9, 0.0);
+            let mut rng = Rng::new(1);
+            let mut order: Vec<usize> = (0..inputs.len()).collect();
+            for epoch in 0..epochs{
+                rng.shuffle(&mut order);
+                let (mut loss, mut batches) = (0.0, 0);
+                for batch in order.chunks(batch_size){
+                    let batch_inputs: Vec<Vec<Vec<Vec<f32>>>> = batch.iter().map(|&i| inputs[i].clone()).collect();
+                    let batch_labels: Vec<u8> = batch.iter().map(|&i| labels[i]).collect();
+                    loss += training.train_step(&mut cnn, &mut optimizer, &batch_inputs, &batch_labels, &[]);
+                    batches += 1;
+                }
+                println!("Epoch {}/{} loss {:.4}, loss scale {}, {} steps skipped", epoch + 1, epochs,
+                    loss / batches.max(1) as f32, training.loss_scale, training.skipped_steps);
+            }
+            let half = HalfCNN::new(&cnn, precision);
+            let mut agree = 0;
+            for input in inputs.iter(){
+                agree += (cnn.forward(input) == half.forward(input)) as usize;
+            }
+            println!("f32 accuracy {:.2}%", train::accuracy(&cnn, &inputs, &labels) * 100.0);
+            println!("{} accuracy {:.2}%, same prediction for {:.2}%", precision.name(),
+                accuracy(&half, &inputs, &labels) * 100.0, agree as f32 / inputs.len() as f32 * 100.0);
+        },
+        (None, None) if epochs == 0 => {},
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    }
+
+    let bytes = HalfCNN::new(&cnn, precision).to_bytes();
+    println!("{} bytes as f32, {} bytes as {}", quantize::float_bytes(&cnn), bytes.len(), precision.name());
+    match fs::write(&output, bytes) {
+        Ok(()) => {
+            println!("Saved {}", output.display());
+            0
+        },
+        Err(e) => {
+            eprintln!("Error: {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+// fraction of the inputs the half precision network classifies as their label
+pub fn accuracy(half: &HalfCNN, inputs: &[Vec<Vec<Vec<f32>>>], labels: &[u8]) -> f32{
+    let mut correct = 0;
+    for n in 0..inputs.len(){
+        if half.forward(&inputs[n]) == labels[n] as u32{
+            correct += 1;
+        }
+    }
+    correct as f32 / inputs.len().max(1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+    use parity::Golden;
+
+    #[test]
+    fn f16_test(){
+        // zero, normal numbers, the smallest normal and the smallest subnormal
+        for &value in [0.0, 1.0, -2.5, 0.5, 65504.0, 2f32.powi(-14), 2f32.powi(-24)].iter(){
+            assert_eq!(f16_to_f32(f16_from_f32(value)), value, "Sample: {}", value);
+        }
+        // subnormals are exact to half of 2^-24
+        let rounded = f16_to_f32(f16_from_f32(-0.0000123));
+        assert!((rounded + 0.0000123).abs() <= 2f32.powi(-25), "Sample: {}", rounded);
+        assert_eq!(f16_from_f32(-0.0), 0x8000);
+        assert_eq!(f16_to_f32(f16_from_f32(70000.0)), f32::INFINITY);
+        assert_eq!(f16_to_f32(f16_from_f32(f32::NEG_INFINITY)), f32::NEG_INFINITY);
+        assert!(f16_to_f32(f16_from_f32(f32::NAN)).is_nan());
+        assert_eq!(f16_to_f32(f16_from_f32(1e-9)), 0.0);
+        // ties go to the even mantissa
+        assert_eq!(f16_to_f32(f16_from_f32(1.0 + 2f32.powi(-11))), 1.0);
+        assert_eq!(f16_to_f32(f16_from_f32(1.0 + 3.0 * 2f32.powi(-11))), 1.0 + 2f32.powi(-9));
+
+        // every f16 converts back to itself
+        for bits in 0..0x7c00u16{
+            assert_eq!(f16_from_f32(f16_to_f32(bits)), bits);
+        }
+        // the relative error of normal numbers is at most half an ulp, 2^-11
+        let mut rng = Rng::new(5);
+        for _ in 0..10000{
+            let value = rng.uniform(1.0, 2.0) * 2f32.powi(rng.below(29) as i32 - 14);
+            let error = (f16_to_f32(f16_from_f32(value)) - value).abs() / value.abs();
+            assert!(error <= 2f32.powi(-11), "Sample: {} {}", value, error);
+        }
+    }
+
+    #[test]
+    fn bf16_test(){
+        assert_eq!(bf16_to_f32(bf16_from_f32(1.0)), 1.0);
+        // the whole f32 exponent range
+        assert_eq!(bf16_to_f32(bf16_from_f32(-1.5 * 2f32.powi(100))), -1.5 * 2f32.powi(100));
+        assert_eq!(bf16_to_f32(bf16_from_f32(1.25 * 2f32.powi(-100))), 1.25 * 2f32.powi(-100));
+        assert_eq!(bf16_to_f32(bf16_from_f32(1.0 + 2f32.powi(-8))), 1.0);
+        assert_eq!(bf16_to_f32(bf16_from_f32(1.0 + 3.0 * 2f32.powi(-8))), 1.0 + 2f32.powi(-6));
+        assert!(bf16_to_f32(bf16_from_f32(f32::NAN)).is_nan());
+        assert_eq!(bf16_to_f32(bf16_from_f32(f32::MAX)), f32::INFINITY);
+        let mut rng = Rng::new(6);
+        for _ in 0..10000{
+            let value = -rng.uniform(1.0, 2.0) * 2f32.powi(rng.below(200) as i32 - 100);
+            let error = (bf16_to_f32(bf16_from_f32(value)) - value).abs() / value.abs();
+            assert!(error <= 2f32.powi(-8), "Sample: {} {}", value, error);
+        }
+    }
+
+    #[test]
+    fn half_cnn_test(){
+        let cnn = testutil::model();
+        let goldens = Golden::load_dir(Path::new("./src/assets/golden")).unwrap();
+        for &precision in [Half::F16, Half::Bf16].iter(){
+            let half = HalfCNN::new(&cnn, precision);
+            let tolerance = if precision == Half::F16 {0.01} else {0.05};
+            for golden in goldens.iter(){
+                let (float, logits) = (cnn.logits(&golden.input), half.logits(&golden.input));
+                let range = float.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+                for i in 0..10{
+                    assert!((float[i] - logits[i]).abs() <= tolerance * range, "Sample: {} {:?} {:?}", golden.name, float, logits);
+                }
+                assert_eq!(half.forward(&golden.input), cnn.forward(&golden.input), "{} {}", golden.name, precision.name());
+            }
+            // half the size of the f32 weights, plus a small header
+            let bytes = half.to_bytes();
+            assert!(bytes.len() <= quantize::float_bytes(&cnn) / 2 + 64, "Sample: {}", bytes.len());
+            let loaded = HalfCNN::from_bytes(&bytes).unwrap();
+            assert_eq!(loaded, half);
+            assert_eq!(loaded.to_cnn().conv1.filter(), precision.round_cnn(&cnn).conv1.filter());
+            assert!(HalfCNN::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        }
+    }
+
+    // the first 1000 images of the MNIST test set, run with
+    // MNIST_DIR=<dir with t10k-images-idx3-ubyte and t10k-labels-idx1-ubyte>
+    // cargo test --no-default-features mnist_parity -- --ignored
+    #[test]
+    #[ignore]
+    fn mnist_parity_test(){
+        let dir = PathBuf::from(std::env::var("MNIST_DIR").expect("MNIST_DIR is not set"));
+        let images = idx::read_images(&dir.join("t10k-images-idx3-ubyte")).unwrap();
+        let labels = idx::read_labels(&dir.join("t10k-labels-idx1-ubyte")).unwrap();
+        let count = images.len().min(labels.len()).min(1000);
+        let inputs = testutil::inputs(&images[..count]);
+        let cnn = testutil::model();
+        let float: Vec<u32> = inputs.iter().map(|input| cnn.forward(input)).collect();
+        let float_accuracy = train::accuracy(&cnn, &inputs, &labels[..count]);
+        for &precision in [Half::F16, Half::Bf16].iter(){
+            let half = HalfCNN::new(&cnn, precision);
+            let agree = inputs.iter().zip(float.iter()).filter(|&(input, &class)| half.forward(input) == class).count();
+            let agreement = agree as f32 / count as f32;
+            assert!(agreement >= 0.99, "Sample: {} agrees with f32 on {:.4}", precision.name(), agreement);
+            let accuracy = accuracy(&half, &inputs, &labels[..count]);
+            assert!((accuracy - float_accuracy).abs() <= 0.01, "Sample: {} {} vs f32 {}", precision.name(), accuracy, float_accuracy);
+        }
+    }
+
+    #[test]
+    fn half_cnn_shapes_test(){
+        let cnn = testutil::model();
+        // an fc1 that does not take the 8x5x5 outputs of conv2
+        let mut narrow = HalfCNN::new(&cnn, Half::F16);
+        narrow.fc.shape[1] -= 1;
+        narrow.fc.weights.truncate(narrow.fc.shape[0] * narrow.fc.shape[1]);
+        let error = HalfCNN::from_bytes(&narrow.to_bytes()).unwrap_err();
+        assert!(error.to_string().contains("fc1 expects 199 inputs"), "Sample: {}", error);
+
+        // conv2 kernels larger than the 13x13 output of conv1
+        let mut wide = HalfCNN::new(&cnn, Half::F16);
+        wide.conv2.shape[2] = 14;
+        wide.conv2.shape[3] = 14;
+        wide.conv2.weights = vec![0; wide.conv2.shape.iter().product()];
+        let error = HalfCNN::from_bytes(&wide.to_bytes()).unwrap_err();
+        assert!(error.to_string().contains("conv2 has 14x14 kernels"), "Sample: {}", error);
+    }
+
+    #[test]
+    fn load_half_model_test(){
+        let cnn = testutil::model();
+        let path = std::env::temp_dir().join(format!("draw-rust-bf16-{}.bin", std::process::id()));
+        HalfCNN::new(&cnn, Half::Bf16).save(&path).unwrap();
+        let loaded = ::load_cnn(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.fc.weights(), Half::Bf16.round_cnn(&cnn).fc.weights());
+    }
+
+    #[test]
+    fn mixed_precision_test(){
+        let inputs = testutil::inputs(&[testutil::bar(10), testutil::bar(17)]);
+        let labels = vec![3, 8];
+        for &precision in [Half::F16, Half::Bf16].iter(){
+            let mut cnn = testutil::model();
+            let mut training = MixedPrecision::new(precision);
+            let mut sgd = Sgd::new(0.01, 0.9, 0.0);
+            let first = training.train_step(&mut cnn, &mut sgd, &inputs, &labels, &["conv1"]);
+            let mut last = first;
+            for _ in 0..20{
+                last = training.train_step(&mut cnn, &mut sgd, &inputs, &labels, &["conv1"]);
+            }
+            assert!(last < first * 0.5, "{}: loss went from {} to {}", precision.name(), first, last);
+            assert_eq!(training.skipped_steps, 0);
+        }
+
+        // gradients that overflow f16 skip the step and lower the scale
+        let mut cnn = testutil::model();
+        let before = cnn.fc.weights().clone();
+        let mut training = MixedPrecision::new(Half::F16);
+        training.loss_scale = 1e30;
+        training.train_step(&mut cnn, &mut Sgd::new(0.01, 0.0, 0.0), &inputs, &labels, &[]);
+        assert_eq!(training.skipped_steps, 1);
+        assert_eq!(training.loss_scale, 5e29);
+        assert_eq!(cnn.fc.weights(), &before);
+    }
+}
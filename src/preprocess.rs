@@ -0,0 +1,210 @@
+// # MNIST preprocessing
+// MNIST digits were cropped to their bounding box, scaled so the longer side
+// is 20 pixels (keeping the aspect ratio, anti-aliased), and placed into a
+// 28x28 image so that their center of mass sits in the middle. Canvas
+// drawings go through the same steps before prediction.
+//
+// Images are (rows, cols) grids of intensities in [0, 1] with 0 as background.
+
+// pixels at or below this are treated as background when looking for the digit
+const THRESHOLD: f32 = 0.05;
+const BOX: usize = 20;
+const SIZE: usize = 28;
+
+// (top, left, bottom, right) of the pixels above THRESHOLD, bottom and right exclusive
+pub fn bounding_box(image: &Vec<Vec<f32>>) -> Option<(usize, usize, usize, usize)>{
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for i in 0..image.len(){
+        for j in 0..image[i].len(){
+            if image[i][j] > THRESHOLD{
+                bounds = Some(match bounds {
+                    Some((top, left, bottom, right)) => (top.min(i), left.min(j), bottom.max(i + 1), right.max(j + 1)),
+                    None => (i, j, i + 1, j + 1)
+                });
+            }
+        }
+    }
+    bounds
+}
+
+pub fn crop(image: &Vec<Vec<f32>>, top: usize, left: usize, bottom: usize, right: usize) -> Vec<Vec<f32>>{
+    image[top..bottom].iter().map(|row| row[left..right].to_vec()).collect()
+}
+
+// area averaging: every output pixel is the mean of the input area it covers,
+// which is what keeps thin strokes from disappearing when shrinking
+pub fn resize_area(image: &Vec<Vec<f32>>, rows: usize, cols: usize) -> Vec<Vec<f32>>{
+    let scale_row = image.len() as f64 / rows as f64;
+    let scale_col = image[0].len() as f64 / cols as f64;
+    let mut output = vec![vec![0.0; cols]; rows];
+    for i in 0..rows{
+        let (r0, r1) = (i as f64 * scale_row, (i + 1) as f64 * scale_row);
+        for j in 0..cols{
+            let (c0, c1) = (j as f64 * scale_col, (j + 1) as f64 * scale_col);
+            let mut sum = 0.0;
+            for r in r0.floor() as usize..(r1.ceil() as usize).min(image.len()){
+                let height = (r1.min(r as f64 + 1.0) - r0.max(r as f64)).max(0.0);
+                for c in c0.floor() as usize..(c1.ceil() as usize).min(image[0].len()){
+                    let width = (c1.min(c as f64 + 1.0) - c0.max(c as f64)).max(0.0);
+                    sum += image[r][c] as f64 * height * width;
+                }
+            }
+            output[i][j] = (sum / (scale_row * scale_col)) as f32;
+        }
+    }
+    output
+}
+
+// (row, col) of the intensity weighted center, None for an empty image
+pub fn center_of_mass(image: &Vec<Vec<f32>>) -> Option<(f32, f32)>{
+    let mut total = 0.0;
+    let mut row = 0.0;
+    let mut col = 0.0;
+    for i in 0..image.len(){
+        for j in 0..image[i].len(){
+            total += image[i][j];
+            row += image[i][j] * i as f32;
+            col += image[i][j] * j as f32;
+        }
+    }
+    if total > 0.0 {Some((row / total, col / total))} else {None}
+}
+
+// where `mnist` puts the digit: the box (top, left, height, width) of the
+// source image is scaled to (rows, cols) and moved to (offset_row, offset_col)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement{
+    pub top: usize,
+    pub left: usize,
+    pub height: usize,
+    pub width: usize,
+    pub rows: usize,
+    pub cols: usize,
+    pub offset_row: usize,
+    pub offset_col: usize
+}
+
+impl Placement{
+    // a point of the source image, in pixels from its top left corner, in
+    // the 28x28 image
+    pub fn to_mnist(&self, row: f32, col: f32) -> (f32, f32){
+        (self.offset_row as f32 + (row - self.top as f32) * self.rows as f32 / self.height as f32,
+            self.offset_col as f32 + (col - self.left as f32) * self.cols as f32 / self.width as f32)
+    }
+}
+
+pub fn placement(image: &Vec<Vec<f32>>) -> Option<Placement>{
+    let (top, left, bottom, right) = bounding_box(image)?;
+    let (height, width) = (bottom - top, right - left);
+    let (rows, cols) = if height >= width {
+        (BOX, ((width * BOX) as f32 / height as f32).round().max(1.0) as usize)
+    }
+    else{
+        (((height * BOX) as f32 / width as f32).round().max(1.0) as usize, BOX)
+    };
+    let digit = resize_area(&crop(image, top, left, bottom, right), rows, cols);
+
+    // start centered by the box, then shift so the center of mass lands on
+    // the middle of the image, without pushing any of the digit outside
+    let (com_row, com_col) = center_of_mass(&digit).unwrap_or((rows as f32 / 2.0, cols as f32 / 2.0));
+    Some(Placement{
+        top: top,
+        left: left,
+        height: height,
+        width: width,
+        rows: rows,
+        cols: cols,
+        offset_row: ((SIZE as f32 / 2.0 - com_row).round() as i32).max(0).min((SIZE - rows) as i32) as usize,
+        offset_col: ((SIZE as f32 / 2.0 - com_col).round() as i32).max(0).min((SIZE - cols) as i32) as usize
+    })
+}
+
+pub fn mnist(image: &Vec<Vec<f32>>) -> Vec<Vec<f32>>{
+    let mut output = vec![vec![0.0; SIZE]; SIZE];
+    let p = match placement(image) {
+        Some(p) => p,
+        None => return output
+    };
+    let digit = resize_area(&crop(image, p.top, p.left, p.top + p.height, p.left + p.width), p.rows, p.cols);
+    for i in 0..p.rows{
+        for j in 0..p.cols{
+            output[p.offset_row + i][p.offset_col + j] = digit[i][j].clamp(0.0, 1.0);
+        }
+    }
+    output
+}
+
+// (1, rows, cols) network input, normalized the same way as during training
+pub fn to_input(image: &Vec<Vec<f32>>) -> Vec<Vec<Vec<f32>>>{
+    vec![image.iter().map(|row| row.iter().map(|&v| (v - 0.5) / 0.5).collect()).collect()]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn square(size: usize, top: usize, left: usize, height: usize, width: usize) -> Vec<Vec<f32>>{
+        let mut image = vec![vec![0.0; size]; size];
+        for i in top..top + height{
+            for j in left..left + width{
+                image[i][j] = 1.0;
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn resize_area_test(){
+        let image = vec![
+            vec![1.0, 1.0, 0.0, 0.0],
+            vec![1.0, 1.0, 0.0, 0.0],
+            vec![0.0, 0.0, 0.0, 1.0],
+            vec![0.0, 0.0, 1.0, 1.0]
+        ];
+        let output = resize_area(&image, 2, 2);
+        assert_eq!(output, vec![vec![1.0, 0.0], vec![0.0, 0.75]], "Sample: {:?}", output);
+    }
+
+    #[test]
+    fn mnist_centers_digit_test(){
+        // a tall bar drawn in the top left corner
+        let image = square(28, 0, 0, 10, 2);
+        let output = mnist(&image);
+        let bounds = bounding_box(&output).unwrap();
+        assert_eq!(bounds.2 - bounds.0, 20, "Sample: {:?}", bounds);
+        let (row, col) = center_of_mass(&output).unwrap();
+        assert!((row - 14.0).abs() <= 1.0 && (col - 14.0).abs() <= 1.0, "Sample: {} {}", row, col);
+    }
+
+    #[test]
+    fn mnist_position_invariant_test(){
+        let a = mnist(&square(28, 2, 3, 12, 6));
+        let b = mnist(&square(28, 14, 20, 12, 6));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn placement_test(){
+        let image = square(56, 10, 30, 20, 10);
+        let p = placement(&image).unwrap();
+        assert_eq!((p.top, p.left, p.height, p.width, p.rows, p.cols), (10, 30, 20, 10, 20, 10));
+        // the corners of the box land on the corners of the digit
+        let output = mnist(&image);
+        let bounds = bounding_box(&output).unwrap();
+        assert_eq!(p.to_mnist(10.0, 30.0), (bounds.0 as f32, bounds.1 as f32));
+        assert_eq!(p.to_mnist(30.0, 40.0), (bounds.2 as f32, bounds.3 as f32));
+        assert_eq!(placement(&vec![vec![0.0; 28]; 28]), None);
+    }
+
+    #[test]
+    fn mnist_empty_test(){
+        assert_eq!(mnist(&vec![vec![0.0; 28]; 28]), vec![vec![0.0; 28]; 28]);
+    }
+
+    #[test]
+    fn to_input_test(){
+        let input = to_input(&vec![vec![0.0, 1.0]]);
+        assert_eq!(input, vec![vec![vec![-1.0, 1.0]]]);
+    }
+}
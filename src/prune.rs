@@ -0,0 +1,645 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use finetune;
+use idx;
+use model;
+use preprocess;
+use rng::Rng;
+use train::{self, Sgd};
+use CNN;
+
+// # Pruning
+// Two ways to shrink the CNN:
+// - magnitude pruning sets the smallest fc1 weights to zero, the shapes stay
+//   the same and `SparseCNN` skips the zeros at inference
+// - structured pruning removes whole output channels of conv1 or conv2 (the
+//   ones with the smallest L1 norm) and the matching inputs of the next
+//   layer, so the dense model itself gets smaller and faster
+// `Prune` does both in a few steps, with some fine-tuning after each one so
+// the remaining weights can make up for the removed ones.
+#[derive(Debug, Clone)]
+pub struct Prune{
+    // fraction of the fc1 weights that are zero at the end
+    pub fc_sparsity: f32,
+    // output channels removed from conv1 and conv2 in total
+    pub conv1_filters: usize,
+    pub conv2_filters: usize,
+    pub steps: u32,
+    // fine-tuning after every step
+    pub epochs: u32,
+    pub batch_size: usize,
+    pub lr: f32,
+    pub momentum: f32,
+    pub holdout: f32,
+    pub seed: u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step{
+    pub fc_sparsity: f32,
+    pub conv1_channels: usize,
+    pub conv2_channels: usize,
+    // weights and biases that are not zero
+    pub parameters: usize,
+    pub accuracy: f32
+}
+
+#[derive(Debug, Clone)]
+pub struct Report{
+    pub train_samples: usize,
+    pub holdout_samples: usize,
+    pub parameters: usize,
+    pub accuracy: f32,
+    pub steps: Vec<Step>
+}
+
+// the layers whose output channels can be removed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conv{
+    Conv1,
+    Conv2
+}
+
+// # Sparse kernels
+// Compressed sparse rows: the non-zero values of row `r` are
+// `values[row_start[r]..row_start[r + 1]]`, in the columns `columns[..]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Csr{
+    pub rows: usize,
+    pub cols: usize,
+    row_start: Vec<usize>,
+    columns: Vec<usize>,
+    values: Vec<f32>
+}
+
+// one CSR row per output channel, the columns are (input, row, col) of the
+// filter flattened
+#[derive(Debug, Clone)]
+pub struct SparseConv2D{
+    filter: Csr,
+    kernel: (usize, usize),
+    bias: Vec<f32>
+}
+
+#[derive(Debug, Clone)]
+pub struct SparseFullyConnected{
+    weights: Csr,
+    bias: Vec<f32>
+}
+
+#[derive(Debug, Clone)]
+pub struct SparseCNN{
+    pub conv1: SparseConv2D,
+    pub conv2: SparseConv2D,
+    pub fc: SparseFullyConnected
+}
+
+impl Csr{
+    pub fn from_dense(dense: &Vec<Vec<f32>>) -> Csr{
+        let mut row_start = vec![0];
+        let mut columns = Vec::new();
+        let mut values = Vec::new();
+        for row in dense.iter(){
+            for (j, &value) in row.iter().enumerate(){
+                if value != 0.0{
+                    columns.push(j);
+                    values.push(value);
+                }
+            }
+            row_start.push(values.len());
+        }
+        Csr{
+            rows: dense.len(),
+            cols: dense.first().map_or(0, |row| row.len()),
+            row_start: row_start,
+            columns: columns,
+            values: values
+        }
+    }
+
+    pub fn to_dense(&self) -> Vec<Vec<f32>>{
+        let mut dense = vec![vec![0.0; self.cols]; self.rows];
+        for r in 0..self.rows{
+            for n in self.row_start[r]..self.row_start[r + 1]{
+                dense[r][self.columns[n]] = self.values[n];
+            }
+        }
+        dense
+    }
+
+    pub fn nonzero(&self) -> usize{
+        self.values.len()
+    }
+
+    // (column, value) of the non-zero entries of a row
+    pub fn row(&self, r: usize) -> Vec<(usize, f32)>{
+        (self.row_start[r]..self.row_start[r + 1]).map(|n| (self.columns[n], self.values[n])).collect()
+    }
+
+    pub fn multiply(&self, input: &[f32]) -> Vec<f32>{
+        let mut output = vec![0.0; self.rows];
+        for r in 0..self.rows{
+            let mut sum = 0.0;
+            for n in self.row_start[r]..self.row_start[r + 1]{
+                sum += self.values[n] * input[self.columns[n]];
+            }
+            output[r] = sum;
+        }
+        output
+    }
+}
+
+impl SparseConv2D{
+    pub fn new(layer: &model::Conv2D) -> SparseConv2D{
+        let f = layer.filter();
+        let rows: Vec<Vec<f32>> = f.iter()
+            .map(|output| output.iter().flat_map(|input| input.iter().flat_map(|row| row.iter().cloned())).collect())
+            .collect();
+        SparseConv2D{
+            filter: Csr::from_dense(&rows),
+            kernel: (f[0][0].len(), f[0][0][0].len()),
+            bias: layer.bias().clone()
+        }
+    }
+
+    pub fn forward(&self, input: &Vec<Vec<Vec<f32>>>) -> Vec<Vec<Vec<f32>>>{
+        let (kh, kw) = self.kernel;
+        let (height, width) = (input[0].len() - kh + 1, input[0][0].len() - kw + 1);
+        let mut output = vec![vec![vec![0.0; width]; height]; self.filter.rows];
+        for i in 0..self.filter.rows{
+            for x in 0..height{
+                for y in 0..width{
+                    output[i][x][y] = self.bias[i];
+                }
+            }
+            // only the non-zero taps of the filter are visited
+            for (column, weight) in self.filter.row(i){
+                let (j, k, l) = (column / (kh * kw), column / kw % kh, column % kw);
+                for x in 0..height{
+                    let source = &input[j][x + k];
+                    let target = &mut output[i][x];
+                    for y in 0..width{
+                        target[y] += weight * source[y + l];
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+impl SparseFullyConnected{
+    pub fn new(layer: &model::FullyConnected) -> SparseFullyConnected{
+        SparseFullyConnected{
+            weights: Csr::from_dense(layer.weights()),
+            bias: layer.bias().clone()
+        }
+    }
+
+    pub fn forward(&self, input: &Vec<f32>) -> Vec<f32>{
+        let mut output = self.weights.multiply(input);
+        for i in 0..output.len(){
+            output[i] += self.bias[i];
+        }
+        output
+    }
+}
+
+impl SparseCNN{
+    pub fn new(cnn: &CNN) -> SparseCNN{
+        SparseCNN{
+            conv1: SparseConv2D::new(&cnn.conv1),
+            conv2: SparseConv2D::new(&cnn.conv2),
+            fc: SparseFullyConnected::new(&cnn.fc)
+        }
+    }
+
+    pub fn nonzero(&self) -> usize{
+        self.conv1.filter.nonzero() + self.conv2.filter.nonzero() + self.fc.weights.nonzero()
+    }
+
+    pub fn logits(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        let pool = model::MaxPooling2D::new(2);
+        let img = pool.forward(&model::ReLU::forward(&self.conv1.forward(img)));
+        let img = pool.forward(&model::ReLU::forward(&self.conv2.forward(&img)));
+        self.fc.forward(&model::Flatten::forward(&img))
+    }
+
+    pub fn probabilities(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        model::softmax(&self.logits(img))
+    }
+
+    pub fn forward(&self, img: &Vec<Vec<Vec<f32>>>) -> u32{
+        model::argmax(&self.probabilities(img)) as u32
+    }
+}
+
+// sort key of a weight magnitude or filter norm, NaN goes first so a
+// diverged weight is pruned before any finite one
+fn magnitude(value: f32) -> f32{
+    if value.is_nan() {-1.0} else {value.abs()}
+}
+
+// sets the `fraction` of the weights with the smallest magnitude to zero
+// and returns how many are zero now
+pub fn prune_fc(layer: &mut model::FullyConnected, fraction: f32) -> usize{
+    let weights = layer.weights_mut();
+    let mut order: Vec<(usize, usize)> = Vec::new();
+    for i in 0..weights.len(){
+        for j in 0..weights[i].len(){
+            order.push((i, j));
+        }
+    }
+    order.sort_by(|&(a, b), &(c, d)| magnitude(weights[a][b]).total_cmp(&magnitude(weights[c][d])));
+    let count = ((order.len() as f32 * fraction.clamp(0.0, 1.0)).round() as usize).min(order.len());
+    for &(i, j) in order[..count].iter(){
+        weights[i][j] = 0.0;
+    }
+    weights.iter().map(|row| row.iter().filter(|&&w| w == 0.0).count()).sum()
+}
+
+// L1 norm of every output channel's filter
+pub fn filter_norms(layer: &model::Conv2D) -> Vec<f32>{
+    layer.filter().iter()
+        .map(|output| output.iter().flat_map(|input| input.iter().flat_map(|row| row.iter())).map(|w| w.abs()).sum())
+        .collect()
+}
+
+// the `count` channels with the smallest norm, at least one channel is kept
+pub fn weakest_filters(layer: &model::Conv2D, count: usize) -> Vec<usize>{
+    let norms = filter_norms(layer);
+    let mut order: Vec<usize> = (0..norms.len()).collect();
+    order.sort_by(|&a, &b| magnitude(norms[a]).total_cmp(&magnitude(norms[b])));
+    order.truncate(count.min(norms.len().saturating_sub(1)));
+    order.sort();
+    order
+}
+
+fn keep_conv(layer: &model::Conv2D, outputs: &[usize], inputs: &[usize]) -> model::Conv2D{
+    let filter = outputs.iter().map(|&i| inputs.iter().map(|&j| layer.filter()[i][j].clone()).collect()).collect();
+    let bias = outputs.iter().map(|&i| layer.bias()[i]).collect();
+    model::Conv2D::new(inputs.len() as u32, outputs.len() as u32, filter, bias)
+}
+
+// removes output channels of conv1 or conv2 and the inputs of the next
+// layer that read them
+pub fn remove_filters(cnn: &mut CNN, layer: Conv, channels: &[usize]) -> io::Result<()>{
+    let conv = if layer == Conv::Conv1 {&cnn.conv1} else {&cnn.conv2};
+    let all = conv.filter().len();
+    let keep: Vec<usize> = (0..all).filter(|i| !channels.contains(i)).collect();
+    if keep.is_empty(){
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} needs at least one channel", layer)));
+    }
+    let conv1_channels: Vec<usize> = (0..cnn.conv1.filter().len()).collect();
+    let conv2_channels: Vec<usize> = (0..cnn.conv2.filter().len()).collect();
+    let conv1_inputs: Vec<usize> = (0..cnn.conv1.filter()[0].len()).collect();
+    if layer == Conv::Conv1{
+        cnn.conv1 = keep_conv(&cnn.conv1, &keep, &conv1_inputs);
+        cnn.conv2 = keep_conv(&cnn.conv2, &conv2_channels, &keep);
+        return Ok(());
+    }
+    // fc1 reads the pooled conv2 channels one after another
+    let per_channel = cnn.fc.weights()[0].len() / all;
+    let weights: Vec<Vec<f32>> = cnn.fc.weights().iter()
+        .map(|row| keep.iter().flat_map(|&c| row[c * per_channel..(c + 1) * per_channel].iter().cloned()).collect())
+        .collect();
+    cnn.conv2 = keep_conv(&cnn.conv2, &keep, &conv1_channels);
+    cnn.fc = model::FullyConnected::new((keep.len() * per_channel) as u32, cnn.fc.bias().len() as u32, weights, cnn.fc.bias().clone());
+    Ok(())
+}
+
+// weights and biases that are not zero
+pub fn nonzero(cnn: &CNN) -> usize{
+    let conv = |layer: &model::Conv2D| layer.filter().iter()
+        .flat_map(|a| a.iter().flat_map(|b| b.iter().flat_map(|c| c.iter())))
+        .chain(layer.bias().iter()).filter(|&&w| w != 0.0).count();
+    let fc = cnn.fc.weights().iter().flat_map(|row| row.iter()).chain(cnn.fc.bias().iter()).filter(|&&w| w != 0.0).count();
+    conv(&cnn.conv1) + conv(&cnn.conv2) + fc
+}
+
+// zero weights of fc1 stay zero while fine-tuning
+fn fc_mask(cnn: &CNN) -> Vec<Vec<bool>>{
+    cnn.fc.weights().iter().map(|row| row.iter().map(|&w| w != 0.0).collect()).collect()
+}
+
+fn apply_mask(cnn: &mut CNN, mask: &Vec<Vec<bool>>){
+    let weights = cnn.fc.weights_mut();
+    for i in 0..weights.len(){
+        for j in 0..weights[i].len(){
+            if !mask[i][j]{
+                weights[i][j] = 0.0;
+            }
+        }
+    }
+}
+
+impl Default for Prune{
+    fn default() -> Prune{
+        Prune{
+            fc_sparsity: 0.8,
+            conv1_filters: 1,
+            conv2_filters: 2,
+            steps: 4,
+            epochs: 1,
+            batch_size: 32,
+            lr: 0.001,
+            momentum: 0.9,
+            holdout: 0.1,
+            seed: 1
+        }
+    }
+}
+
+impl Prune{
+    // the amount reached after `step` of `steps`, pruning a lot at first
+    // when there are many small weights and less towards the end
+    fn schedule(&self, total: f32, step: u32) -> f32{
+        let left = 1.0 - step as f32 / self.steps.max(1) as f32;
+        total * (1.0 - left * left * left)
+    }
+
+    // images are preprocessed 28x28 grids as read from IDX files, `progress`
+    // gets every step when its fine-tuning is done. Removing every channel
+    // of conv1 or conv2 is an error.
+    pub fn run<F>(&self, mut cnn: CNN, images: &Vec<Vec<Vec<f32>>>, labels: &Vec<u8>, mut progress: F) -> io::Result<(CNN, Report)>
+        where F: FnMut(&Step){
+        let (conv1_channels, conv2_channels) = (cnn.conv1.filter().len(), cnn.conv2.filter().len());
+        if self.conv1_filters >= conv1_channels || self.conv2_filters >= conv2_channels{
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("conv1 has {} and conv2 {} channels, at least one has to stay", conv1_channels, conv2_channels)));
+        }
+        let mut rng = Rng::new(self.seed);
+        let (train_indices, holdout_indices) = finetune::split(images.len(), self.holdout, &mut rng);
+        let inputs: Vec<Vec<Vec<Vec<f32>>>> = images.iter().map(preprocess::to_input).collect();
+        let holdout_inputs: Vec<Vec<Vec<Vec<f32>>>> = holdout_indices.iter().map(|&i| inputs[i].clone()).collect();
+        let holdout_labels: Vec<u8> = holdout_indices.iter().map(|&i| labels[i]).collect();
+        let (parameters, accuracy) = (nonzero(&cnn), train::accuracy(&cnn, &holdout_inputs, &holdout_labels));
+
+        let mut order = train_indices.clone();
+        let mut steps = Vec::new();
+        for step in 1..self.steps.max(1) + 1{
+            let conv1_target = conv1_channels - self.schedule(self.conv1_filters as f32, step).round() as usize;
+            let remove = weakest_filters(&cnn.conv1, cnn.conv1.filter().len().saturating_sub(conv1_target));
+            remove_filters(&mut cnn, Conv::Conv1, &remove)?;
+            let conv2_target = conv2_channels - self.schedule(self.conv2_filters as f32, step).round() as usize;
+            let remove = weakest_filters(&cnn.conv2, cnn.conv2.filter().len().saturating_sub(conv2_target));
+            remove_filters(&mut cnn, Conv::Conv2, &remove)?;
+            let sparsity = self.schedule(self.fc_sparsity, step);
+            prune_fc(&mut cnn.fc, sparsity);
+
+            // the shapes may have changed, so the momentum starts over
+            let mask = fc_mask(&cnn);
+            let mut optimizer = Sgd::new(self.lr, self.momentum, 0.0);
+            for _ in 0..self.epochs{
+                rng.shuffle(&mut order);
+                for batch in order.chunks(self.batch_size.max(1)){
+                    let batch_inputs: Vec<Vec<Vec<Vec<f32>>>> = batch.iter().map(|&i| inputs[i].clone()).collect();
+                    let batch_labels: Vec<u8> = batch.iter().map(|&i| labels[i]).collect();
+                    train::train_step(&mut cnn, &mut optimizer, &batch_inputs, &batch_labels, &[]);
+                    apply_mask(&mut cnn, &mask);
+                }
+            }
+            let report = Step{
+                fc_sparsity: sparsity,
+                conv1_channels: cnn.conv1.filter().len(),
+                conv2_channels: cnn.conv2.filter().len(),
+                parameters: nonzero(&cnn),
+                accuracy: train::accuracy(&cnn, &holdout_inputs, &holdout_labels)
+            };
+            progress(&report);
+            steps.push(report);
+        }
+
+        let report = Report{
+            train_samples: train_indices.len(),
+            holdout_samples: holdout_indices.len(),
+            parameters: parameters,
+            accuracy: accuracy,
+            steps: steps
+        };
+        Ok((cnn, report))
+    }
+}
+
+const USAGE: &str = "usage: draw-rust prune --images <idx> --labels <idx> [--model model.json] [--fc-sparsity 0.8] [--conv1-filters 1] [--conv2-filters 2] [--steps 4] [--epochs 1] [--lr 0.001] [--batch-size 32] [--output model-pruned.json]";
+
+// arguments after `prune`, returns the exit code
+pub fn run(args: &[String]) -> i32{
+    let mut config = Prune::default();
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut images_path = None;
+    let mut labels_path = None;
+    let mut output = None;
+    let mut i = 0;
+    while i < args.len(){
+        if args[i] == "-h" || args[i] == "--help"{
+            println!("{}", USAGE);
+            return 0;
+        }
+        if i + 1 >= args.len(){
+            eprintln!("{} needs a value\n{}", args[i], USAGE);
+            return 2;
+        }
+        let value = &args[i + 1];
+        let parsed = match args[i].as_str() {
+            "--model" => {model_path = PathBuf::from(value); true},
+            "--images" => {images_path = Some(PathBuf::from(value)); true},
+            "--labels" => {labels_path = Some(PathBuf::from(value)); true},
+            "--output" => {output = Some(PathBuf::from(value)); true},
+            "--fc-sparsity" => value.parse().map(|v: f32| config.fc_sparsity = v.clamp(0.0, 1.0)).is_ok(),
+            "--conv1-filters" => value.parse().map(|v| config.conv1_filters = v).is_ok(),
+            "--conv2-filters" => value.parse().map(|v| config.conv2_filters = v).is_ok(),
+            "--steps" => value.parse().map(|v: u32| config.steps = v.max(1)).is_ok(),
+            "--epochs" => value.parse().map(|v| config.epochs = v).is_ok(),
+            "--lr" => value.parse().map(|v| config.lr = v).is_ok(),
+            "--batch-size" => value.parse().map(|v: usize| config.batch_size = v.max(1)).is_ok(),
+            other => {
+                eprintln!("unknown argument {}\n{}", other, USAGE);
+                return 2;
+            }
+        };
+        if !parsed{
+            eprintln!("{} needs a number\n{}", args[i], USAGE);
+            return 2;
+        }
+        i += 2;
+    }
+    let (images_path, labels_path) = match (images_path, labels_path) {
+        (Some(images), Some(labels)) => (images, labels),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let output = output.unwrap_or_else(|| model_path.with_file_name(format!("{}-pruned.json",
+        model_path.file_stem().map_or("model".into(), |stem| stem.to_string_lossy()))));
+
+    let loaded = ::load_cnn(&model_path)
+        .and_then(|cnn| Ok((cnn, idx::read_images(&images_path)?, idx::read_labels(&labels_path)?)));
+    let (cnn, images, labels) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    if images.len() < 2 || images.len() != labels.len(){
+        eprintln!("Error: {} images but {} labels", images.len(), labels.len());
+        return 1;
+    }
+
+    let original = cnn.clone();
+    let result = config.run(cnn, &images, &labels, |step| {
+        println!("fc1 sparsity {:.0}%, conv1 {} channels, conv2 {} channels: {} parameters, accuracy {:.2}%",
+            step.fc_sparsity * 100.0, step.conv1_channels, step.conv2_channels, step.parameters, step.accuracy * 100.0);
+    });
+    let (pruned, report) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 2;
+        }
+    };
+    let last = report.steps.last().unwrap();
+    println!("Accuracy {:.2}% -> {:.2}% on {} held out images, {} -> {} parameters",
+        report.accuracy * 100.0, last.accuracy * 100.0, report.holdout_samples, report.parameters, last.parameters);
+
+    // dense and sparse time per image on the original and the pruned model
+    let inputs: Vec<Vec<Vec<Vec<f32>>>> = images.iter().take(200).map(preprocess::to_input).collect();
+    let time = |classify: &dyn Fn(usize) -> u32| {
+        let start = Instant::now();
+        for i in 0..inputs.len(){
+            classify(i);
+        }
+        start.elapsed().as_secs_f64() * 1e6 / inputs.len() as f64
+    };
+    let sparse = SparseCNN::new(&pruned);
+    println!("Original dense {:.0} us, pruned dense {:.0} us, pruned sparse {:.0} us per image",
+        time(&|i| original.forward(&inputs[i])), time(&|i| pruned.forward(&inputs[i])), time(&|i| sparse.forward(&inputs[i])));
+
+    match fs::write(&output, pruned.to_json().to_string()) {
+        Ok(()) => {
+            println!("Saved {}", output.display());
+            0
+        },
+        Err(e) => {
+            eprintln!("Error: {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn prune_fc_test(){
+        let mut layer = model::FullyConnected::new(2, 2, vec![vec![0.5, -0.1], vec![-0.3, 0.2]], vec![1.0, 1.0]);
+        assert_eq!(prune_fc(&mut layer, 0.5), 2);
+        assert_eq!(layer.weights(), &vec![vec![0.5, 0.0], vec![-0.3, 0.0]]);
+        assert_eq!(layer.bias(), &vec![1.0, 1.0]);
+
+        // a NaN weight is pruned first instead of panicking the sort
+        let mut layer = model::FullyConnected::new(3, 1, vec![vec![0.5, f32::NAN, -0.1]], vec![0.0]);
+        assert_eq!(prune_fc(&mut layer, 0.34), 1);
+        assert_eq!(layer.weights(), &vec![vec![0.5, 0.0, -0.1]]);
+        let filter = vec![vec![vec![vec![1.0]]], vec![vec![vec![f32::NAN]]], vec![vec![vec![0.1]]]];
+        assert_eq!(weakest_filters(&model::Conv2D::new(1, 3, filter, vec![0.0; 3]), 1), vec![1]);
+
+        let mut cnn = testutil::model();
+        assert_eq!(prune_fc(&mut cnn.fc, 0.9), 1800);
+    }
+
+    #[test]
+    fn remove_filters_test(){
+        let input = preprocess::to_input(&testutil::bar(12));
+        // a channel that is never positive contributes nothing after ReLU,
+        // so removing it does not change the logits
+        let mut cnn = testutil::model();
+        for value in cnn.conv1.filter_mut()[2].iter_mut().flat_map(|a| a.iter_mut().flat_map(|b| b.iter_mut())){
+            *value = 0.0;
+        }
+        cnn.conv1.bias_mut()[2] = -1.0;
+        for row in cnn.conv2.filter_mut().iter_mut(){
+            for value in row[2].iter_mut().flat_map(|a| a.iter_mut()){
+                *value = 0.0;
+            }
+        }
+        cnn.conv2.bias_mut()[5] = -1e6;
+        assert_eq!(weakest_filters(&cnn.conv1, 1), vec![2]);
+        let logits = cnn.logits(&input);
+
+        remove_filters(&mut cnn, Conv::Conv1, &[2]).unwrap();
+        remove_filters(&mut cnn, Conv::Conv2, &[5]).unwrap();
+        assert_eq!(cnn.conv1.filter().len(), 3);
+        assert_eq!(cnn.conv2.filter().len(), 7);
+        assert_eq!(cnn.conv2.filter()[0].len(), 3);
+        assert_eq!(cnn.fc.weights()[0].len(), 175);
+        let pruned = cnn.logits(&input);
+        for i in 0..10{
+            assert!((logits[i] - pruned[i]).abs() < 1e-4, "Sample: {:?} {:?}", logits, pruned);
+        }
+        // still a valid model.json
+        assert_eq!(CNN::from_json(&cnn.to_json()).unwrap().logits(&input), pruned);
+        assert_eq!(weakest_filters(&cnn.conv1, 5).len(), 2);
+        assert!(remove_filters(&mut cnn, Conv::Conv2, &[0, 1, 2, 3, 4, 5, 6]).is_err());
+        assert_eq!(cnn.conv2.filter().len(), 7);
+    }
+
+    #[test]
+    fn sparse_test(){
+        let dense = vec![vec![0.0, 2.0, 0.0], vec![0.0, 0.0, 0.0], vec![1.0, 0.0, -3.0]];
+        let csr = Csr::from_dense(&dense);
+        assert_eq!(csr.nonzero(), 3);
+        assert_eq!(csr.row(2), vec![(0, 1.0), (2, -3.0)]);
+        assert_eq!(csr.to_dense(), dense);
+        assert_eq!(csr.multiply(&[1.0, 2.0, 3.0]), vec![4.0, 0.0, -8.0]);
+
+        let mut cnn = testutil::model();
+        prune_fc(&mut cnn.fc, 0.7);
+        cnn.conv2.filter_mut()[1][3][0][0] = 0.0;
+        let sparse = SparseCNN::new(&cnn);
+        assert_eq!(sparse.fc.weights.nonzero(), 600);
+        assert_eq!(sparse.nonzero(), 36 + 287 + 600);
+        for &column in [6, 13, 20].iter(){
+            let input = preprocess::to_input(&testutil::bar(column));
+            let (dense, logits) = (cnn.logits(&input), sparse.logits(&input));
+            for i in 0..10{
+                assert!((dense[i] - logits[i]).abs() < 1e-4, "Sample: {:?} {:?}", dense, logits);
+            }
+        }
+    }
+
+    #[test]
+    fn schedule_test(){
+        let (images, labels) = testutil::bars_and_rings(40, 1, 0);
+        let config = Prune{fc_sparsity: 0.9, conv1_filters: 1, conv2_filters: 3, steps: 3, epochs: 2, batch_size: 8, lr: 0.01, holdout: 0.25, ..Prune::default()};
+        let mut seen = 0;
+        let (cnn, report) = config.run(testutil::model(), &images, &labels, |_| seen += 1).unwrap();
+        assert_eq!(seen, 3);
+        assert_eq!(report.holdout_samples, 10);
+        let last = report.steps.last().unwrap();
+        assert_eq!((last.conv1_channels, last.conv2_channels), (3, 5));
+        assert!((last.fc_sparsity - 0.9).abs() < 1e-6);
+        // the pruned weights stayed zero while fine-tuning
+        let zeros = cnn.fc.weights().iter().flat_map(|row| row.iter()).filter(|&&w| w == 0.0).count();
+        assert_eq!(zeros, (10.0 * 125.0 * 0.9f32).round() as usize);
+        assert!(last.parameters < report.parameters / 3, "{} of {}", last.parameters, report.parameters);
+        assert!(last.accuracy >= 0.9, "Sample: {:?}", report.steps);
+        // sparsity grows fastest at the start
+        assert!(report.steps[0].fc_sparsity > 0.9 / 3.0);
+
+        // removing all channels of a layer would underflow the targets
+        for &(conv1_filters, conv2_filters) in [(4, 0), (0, 8), (9, 20)].iter(){
+            let config = Prune{conv1_filters: conv1_filters, conv2_filters: conv2_filters, ..config.clone()};
+            let error = config.run(testutil::model(), &images, &labels, |_| {}).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "Sample: {:?}", (conv1_filters, conv2_filters));
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use std::path::PathBuf;
+
+use finetune;
+use idx;
+use model;
+use preprocess;
+use quantize::{self, Calibration, QuantizedCNN};
+use rng::Rng;
+use train::{self, Gradients, Sgd};
+use CNN;
+
+// # Quantization-aware training
+// Trains the float CNN while it sees the rounding of the int8 path, for
+// when post-training quantization loses too much accuracy. Fake-quantize
+// nodes round a tensor to the int8 grid of its scale and back to f32:
+//
+//   weights      per output channel, after conv1, conv2 and fc1
+//   activations  the input and the conv1 and conv2 outputs after ReLU,
+//                with ranges kept as a moving average of the batch maxima
+//
+// In the backward pass the rounding is skipped (straight-through
+// estimator), only values that were clipped to the int8 range get no
+// gradient. The scales are computed exactly like in `quantize`, so
+// `export` gives the int8 model that behaves like the trained network.
+#[derive(Debug, Clone)]
+pub struct Qat{
+    // where fake-quantize nodes are inserted
+    pub weights: bool,
+    pub activations: bool,
+    // weight of a new batch in the moving average of the activation ranges
+    pub momentum: f32,
+    // keeps the ranges as they are, usually for the last epoch
+    pub freeze_ranges: bool,
+    ranges: Calibration
+}
+
+// every tensor of one forward pass that the backward pass needs
+struct Pass{
+    input: Vec<Vec<Vec<f32>>>,
+    conv1: Vec<Vec<Vec<f32>>>,
+    relu1: Vec<Vec<Vec<f32>>>,
+    act1: Vec<Vec<Vec<f32>>>,
+    pool1: Vec<Vec<Vec<f32>>>,
+    conv2: Vec<Vec<Vec<f32>>>,
+    relu2: Vec<Vec<Vec<f32>>>,
+    act2: Vec<Vec<Vec<f32>>>,
+    pooled: Vec<Vec<Vec<f32>>>,
+    flat: Vec<f32>,
+    logits: Vec<f32>
+}
+
+// rounds to the int8 grid of `scale` and back
+pub fn fake_quant(input: &Vec<Vec<Vec<f32>>>, scale: f32) -> Vec<Vec<Vec<f32>>>{
+    input.iter().map(|layer| layer.iter().map(|row| row.iter()
+        .map(|&v| quantize::quantize_value(v, scale) as f32 * scale).collect()).collect()).collect()
+}
+
+// straight-through estimator, the gradient passes unchanged where the input
+// was inside [-127 * scale, 127 * scale]
+pub fn fake_quant_backward(input: &Vec<Vec<Vec<f32>>>, scale: f32, grad_output: &Vec<Vec<Vec<f32>>>) -> Vec<Vec<Vec<f32>>>{
+    let limit = 127.0 * scale;
+    let mut grad_input = grad_output.clone();
+    for i in 0..input.len(){
+        for j in 0..input[i].len(){
+            for k in 0..input[i][j].len(){
+                if input[i][j][k].abs() > limit{
+                    grad_input[i][j][k] = 0.0;
+                }
+            }
+        }
+    }
+    grad_input
+}
+
+// the layer with per-channel int8 weights, the bias stays f32 since the
+// int8 path keeps it in i32 at a much finer scale
+pub fn fake_quant_conv(layer: &model::Conv2D) -> model::Conv2D{
+    let filter = layer.filter().iter().map(|channel| {
+        let values: Vec<f32> = channel.iter().flat_map(|input| input.iter().flat_map(|row| row.iter().cloned())).collect();
+        let scale = quantize::scale_for(quantize::max_abs(&values));
+        fake_quant(channel, scale)
+    }).collect::<Vec<_>>();
+    model::Conv2D::new(filter[0].len() as u32, filter.len() as u32, filter, layer.bias().clone())
+}
+
+pub fn fake_quant_fc(layer: &model::FullyConnected) -> model::FullyConnected{
+    let weights: Vec<Vec<f32>> = layer.weights().iter().map(|row| {
+        let scale = quantize::scale_for(quantize::max_abs(row));
+        row.iter().map(|&w| quantize::quantize_value(w, scale) as f32 * scale).collect()
+    }).collect();
+    model::FullyConnected::new(weights[0].len() as u32, weights.len() as u32, weights, layer.bias().clone())
+}
+
+impl Qat{
+    // starts from the activation ranges of a calibration run
+    pub fn new(ranges: Calibration) -> Qat{
+        Qat{
+            weights: true,
+            activations: true,
+            momentum: 0.01,
+            freeze_ranges: false,
+            ranges: ranges
+        }
+    }
+
+    pub fn calibrated(cnn: &CNN, inputs: &[Vec<Vec<Vec<f32>>>]) -> Qat{
+        Qat::new(quantize::calibrate(cnn, inputs))
+    }
+
+    pub fn ranges(&self) -> Calibration{
+        self.ranges
+    }
+
+    // the layers as the forward pass sees them
+    fn layers(&self, cnn: &CNN) -> CNN{
+        if self.weights{
+            CNN::new(cnn.input_size, cnn.output_size, fake_quant_conv(&cnn.conv1), fake_quant_conv(&cnn.conv2), fake_quant_fc(&cnn.fc))
+        }
+        else{
+            cnn.clone()
+        }
+    }
+
+    fn activation(&self, input: &Vec<Vec<Vec<f32>>>, range: f32) -> Vec<Vec<Vec<f32>>>{
+        if self.activations {fake_quant(input, quantize::scale_for(range))} else {input.clone()}
+    }
+
+    fn pass(&self, layers: &CNN, img: &Vec<Vec<Vec<f32>>>) -> Pass{
+        let pool = model::MaxPooling2D::new(2);
+        let input = self.activation(img, self.ranges.input);
+        let conv1 = layers.conv1.forward(&input);
+        let relu1 = model::ReLU::forward(&conv1);
+        let act1 = self.activation(&relu1, self.ranges.conv1);
+        let pool1 = pool.forward(&act1);
+        let conv2 = layers.conv2.forward(&pool1);
+        let relu2 = model::ReLU::forward(&conv2);
+        let act2 = self.activation(&relu2, self.ranges.conv2);
+        let pooled = pool.forward(&act2);
+        let flat = model::Flatten::forward(&pooled);
+        let logits = layers.fc.forward(&flat);
+        Pass{
+            input: input,
+            conv1: conv1,
+            relu1: relu1,
+            act1: act1,
+            pool1: pool1,
+            conv2: conv2,
+            relu2: relu2,
+            act2: act2,
+            pooled: pooled,
+            flat: flat,
+            logits: logits
+        }
+    }
+
+    fn backward(&self, layers: &CNN, pass: &Pass, grad_logits: &Vec<f32>) -> Gradients{
+        let pool = model::MaxPooling2D::new(2);
+        let (grad, fc_weights, fc_bias) = layers.fc.backward(&pass.flat, grad_logits);
+        let grad = model::Flatten::backward(&pass.pooled, &grad);
+        let mut grad = pool.backward(&pass.act2, &grad);
+        if self.activations{
+            grad = fake_quant_backward(&pass.relu2, quantize::scale_for(self.ranges.conv2), &grad);
+        }
+        let grad = model::ReLU::backward(&pass.conv2, &grad);
+        let (grad, conv2_filter, conv2_bias) = layers.conv2.backward(&pass.pool1, &grad);
+        let mut grad = pool.backward(&pass.act1, &grad);
+        if self.activations{
+            grad = fake_quant_backward(&pass.relu1, quantize::scale_for(self.ranges.conv1), &grad);
+        }
+        let grad = model::ReLU::backward(&pass.conv1, &grad);
+        let (_, conv1_filter, conv1_bias) = layers.conv1.backward(&pass.input, &grad);
+        Gradients{
+            conv1_filter: conv1_filter,
+            conv1_bias: conv1_bias,
+            conv2_filter: conv2_filter,
+            conv2_bias: conv2_bias,
+            fc_weights: fc_weights,
+            fc_bias: fc_bias
+        }
+    }
+
+    // logits with all fake-quantize nodes in place
+    pub fn logits(&self, cnn: &CNN, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        self.pass(&self.layers(cnn), img).logits
+    }
+
+    // like train::train_step, the gradients of the fake-quantized weights
+    // are applied to the float weights
+    pub fn train_step(&mut self, cnn: &mut CNN, optimizer: &mut Sgd, inputs: &[Vec<Vec<Vec<f32>>>], labels: &[u8], frozen: &[&str]) -> f32{
+        let layers = self.layers(cnn);
+        let mut total: Option<Gradients> = None;
+        let mut batch = Calibration{input: 0.0, conv1: 0.0, conv2: 0.0};
+        let mut loss = 0.0;
+        for n in 0..inputs.len(){
+            let pass = self.pass(&layers, &inputs[n]);
+            batch.input = batch.input.max(quantize::max_abs(&model::Flatten::forward(&inputs[n])));
+            batch.conv1 = batch.conv1.max(quantize::max_abs(&model::Flatten::forward(&pass.relu1)));
+            batch.conv2 = batch.conv2.max(quantize::max_abs(&model::Flatten::forward(&pass.relu2)));
+            let (sample_loss, grad_logits) = train::cross_entropy(&pass.logits, labels[n] as usize);
+            loss += sample_loss;
+            let grads = self.backward(&layers, &pass, &grad_logits);
+            match total {
+                Some(ref mut total) => total.add(&grads),
+                None => total = Some(grads)
+            }
+        }
+        if let Some(mut grads) = total{
+            grads.scale(1.0 / inputs.len() as f32);
+            train::apply_gradients(cnn, optimizer, &grads, frozen);
+        }
+        if !self.freeze_ranges && !inputs.is_empty(){
+            let m = self.momentum;
+            self.ranges = Calibration{
+                input: self.ranges.input * (1.0 - m) + batch.input * m,
+                conv1: self.ranges.conv1 * (1.0 - m) + batch.conv1 * m,
+                conv2: self.ranges.conv2 * (1.0 - m) + batch.conv2 * m
+            };
+        }
+        loss / inputs.len().max(1) as f32
+    }
+
+    // the int8 model for the integer kernels
+    pub fn export(&self, cnn: &CNN) -> QuantizedCNN{
+        QuantizedCNN::new(cnn, &self.ranges)
+    }
+}
+
+const USAGE: &str = "usage: draw-rust qat --images <idx> --labels <idx> [--model model.json] [--epochs 3] [--lr 0.001] [--batch-size 32] [--output model-int8.bin]";
+
+// arguments after `qat`, returns the exit code. A tenth of the images is
+// held out to compare post-training quantization with the QAT model.
+pub fn run(args: &[String]) -> i32{
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut images_path = None;
+    let mut labels_path = None;
+    let mut output = None;
+    let (mut epochs, mut lr, mut batch_size) = (3u32, 0.001f32, 32usize);
+    let mut i = 0;
+    while i < args.len(){
+        if args[i] == "-h" || args[i] == "--help"{
+            println!("{}", USAGE);
+            return 0;
+        }
+        if i + 1 >= args.len(){
+            eprintln!("{} needs a value\n{}", args[i], USAGE);
+            return 2;
+        }
+        let value = &args[i + 1];
+        let parsed = match args[i].as_str() {
+            "--model" => {model_path = PathBuf::from(value); true},
+            "--images" => {images_path = Some(PathBuf::from(value)); true},
+            "--labels" => {labels_path = Some(PathBuf::from(value)); true},
+            "--output" => {output = Some(PathBuf::from(value)); true},
+            "--epochs" => value.parse().map(|v| epochs = v).is_ok(),
+            "--lr" => value.parse().map(|v| lr = v).is_ok(),
+            "--batch-size" => value.parse().map(|v: usize| batch_size = v.max(1)).is_ok(),
+            other => {
+                eprintln!("unknown argument {}\n{}", other, USAGE);
+                return 2;
+            }
+        };
+        if !parsed{
+            eprintln!("{} needs a number\n{}", args[i], USAGE);
+            return 2;
+        }
+        i += 2;
+    }
+    let (images_path, labels_path) = match (images_path, labels_path) {
+        (Some(images), Some(labels)) => (images, labels),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let output = output.unwrap_or_else(|| model_path.with_file_name(format!("{}-qat-int8.bin",
+        model_path.file_stem().map_or("model".into(), |stem| stem.to_string_lossy()))));
+
+    let loaded = ::load_cnn(&model_path)
+        .and_then(|cnn| Ok((cnn, idx::read_images(&images_path)?, idx::read_labels(&labels_path)?)));
+    let (mut cnn, images, labels) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    if images.len() < 2 || images.len() != labels.len(){
+        eprintln!("Error: {} images but {} labels", images.len(), labels.len());
+        return 1;
+    }
+    let inputs: Vec<Vec<Vec<Vec<f32>>>> = images.iter().map(preprocess::to_input).collect();
+    let mut rng = Rng::new(1);
+    let (mut train_indices, holdout_indices) = finetune::split(inputs.len(), 0.1, &mut rng);
+    let holdout_inputs: Vec<Vec<Vec<Vec<f32>>>> = holdout_indices.iter().map(|&i| inputs[i].clone()).collect();
+    let holdout_labels: Vec<u8> = holdout_indices.iter().map(|&i| labels[i]).collect();
+    let calibration: Vec<Vec<Vec<Vec<f32>>>> = train_indices.iter().take(500).map(|&i| inputs[i].clone()).collect();
+
+    let post_training = evaluate_report(&cnn, &QuantizedCNN::calibrated(&cnn, &calibration), &holdout_inputs, &holdout_labels, calibration.len());
+    println!("Post-training quantization:\n{}", post_training);
+
+    let mut qat = Qat::calibrated(&cnn, &calibration);
+    let mut optimizer = Sgd::new(lr, 0.9, 0.0);
+    for epoch in 0..epochs{
+        // the ranges settle before the last epoch, which trains against fixed scales
+        qat.freeze_ranges = epochs > 1 && epoch + 1 == epochs;
+        rng.shuffle(&mut train_indices);
+        let (mut loss, mut batches) = (0.0, 0);
+        for batch in train_indices.chunks(batch_size){
+            let batch_inputs: Vec<Vec<Vec<Vec<f32>>>> = batch.iter().map(|&i| inputs[i].clone()).collect();
+            let batch_labels: Vec<u8> = batch.iter().map(|&i| labels[i]).collect();
+            loss += qat.train_step(&mut cnn, &mut optimizer, &batch_inputs, &batch_labels, &[]);
+            batches += 1;
+        }
+        println!("Epoch {}/{} loss {:.4}", epoch + 1, epochs, loss / batches.max(1) as f32);
+    }
+
+    let quantized = qat.export(&cnn);
+    println!("Quantization-aware training:\n{}", evaluate_report(&cnn, &quantized, &holdout_inputs, &holdout_labels, calibration.len()));
+    match quantized.save(&output) {
+        Ok(()) => {
+            println!("Saved {}", output.display());
+            0
+        },
+        Err(e) => {
+            eprintln!("Error: {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+fn evaluate_report(cnn: &CNN, quantized: &QuantizedCNN, inputs: &[Vec<Vec<Vec<f32>>>], labels: &[u8], calibration: usize) -> quantize::Report{
+    let mut report = quantize::evaluate(cnn, quantized, inputs, labels);
+    report.calibration_samples = calibration;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn fake_quant_test(){
+        let input = vec![vec![vec![0.0, 0.26, -0.74, 2.0]]];
+        let scale = 0.5 / 127.0;
+        let output = fake_quant(&input, scale);
+        assert!((output[0][0][1] - 0.26).abs() <= scale / 2.0);
+        assert_eq!(output[0][0][3], 127.0 * scale);
+        assert_eq!(output[0][0][2], -127.0 * scale);
+        // clipped values get no gradient, the others all of it
+        let grad = fake_quant_backward(&input, scale, &vec![vec![vec![1.0, 2.0, 3.0, 4.0]]]);
+        assert_eq!(grad, vec![vec![vec![1.0, 2.0, 0.0, 0.0]]]);
+    }
+
+    #[test]
+    fn qat_test(){
+        let mut cnn = testutil::model();
+        let (images, labels) = testutil::bars_and_rings(8, 7, 2);
+        let inputs = testutil::inputs(&images);
+        let mut qat = Qat::calibrated(&cnn, &inputs);
+        let mut optimizer = Sgd::new(0.005, 0.9, 0.0);
+        let first = qat.train_step(&mut cnn, &mut optimizer, &inputs, &labels, &[]);
+        let mut last = first;
+        for _ in 0..15{
+            last = qat.train_step(&mut cnn, &mut optimizer, &inputs, &labels, &[]);
+        }
+        assert!(last < first * 0.5, "loss went from {} to {}", first, last);
+
+        // the exported int8 model computes what training saw
+        let quantized = qat.export(&cnn);
+        for input in inputs.iter(){
+            let (trained, int8) = (qat.logits(&cnn, input), quantized.logits(input));
+            let range = quantize::max_abs(&trained);
+            for i in 0..10{
+                assert!((trained[i] - int8[i]).abs() < 0.02 * range, "Sample: {:?} {:?}", trained, int8);
+            }
+        }
+        assert_eq!(quantize::evaluate(&cnn, &quantized, &inputs, &labels).int8_accuracy, 1.0);
+    }
+
+    #[test]
+    fn frozen_ranges_test(){
+        let mut cnn = testutil::model();
+        let (images, labels) = testutil::bars_and_rings(8, 7, 2);
+        let inputs = testutil::inputs(&images);
+        let mut qat = Qat::new(Calibration{input: 1.0, conv1: 1.0, conv2: 1.0});
+        qat.freeze_ranges = true;
+        let mut optimizer = Sgd::new(0.001, 0.0, 0.0);
+        qat.train_step(&mut cnn, &mut optimizer, &inputs, &labels, &["conv1"]);
+        assert_eq!(qat.ranges(), Calibration{input: 1.0, conv1: 1.0, conv2: 1.0});
+        qat.freeze_ranges = false;
+        qat.train_step(&mut cnn, &mut optimizer, &inputs, &labels, &["conv1"]);
+        assert!(qat.ranges().conv2 != 1.0);
+    }
+}
@@ -0,0 +1,738 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use idx;
+use model;
+use preprocess;
+use CNN;
+
+// # Int8 quantization
+// Post-training quantization of the CNN for devices without a fast FPU.
+// Weights are stored as int8 with one scale per output channel (conv filter
+// or fc row), activations as int8 with one scale per tensor, taken from the
+// largest value seen while running the float model on calibration images.
+// All zero points are 0, so a real value is `scale * q`.
+//
+// The kernels accumulate in i32. Conv outputs are brought back to int8 with
+// a fixed point multiplier (a Q31 number and a right shift) and ReLU is
+// folded into that clamp, max pooling works on the int8 values directly,
+// and only the logits of fc1 are converted to f32 again for the softmax.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration{
+    // largest absolute value of the network input, conv1 (after ReLU) and
+    // conv2 (after ReLU), which are also the inputs of conv2 and fc1
+    pub input: f32,
+    pub conv1: f32,
+    pub conv2: f32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedConv2D{
+    // (output layers, input layers, rows, cols)
+    weights: Vec<Vec<Vec<Vec<i8>>>>,
+    // weight scale per output layer
+    scales: Vec<f32>,
+    // in units of input scale * weight scale
+    bias: Vec<i32>,
+    input_scale: f32,
+    output_scale: f32,
+    // (Q31 multiplier, right shift) for input scale * weight scale / output scale
+    multipliers: Vec<(i32, i32)>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedFullyConnected{
+    weights: Vec<Vec<i8>>,
+    scales: Vec<f32>,
+    bias: Vec<i32>,
+    input_scale: f32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedCNN{
+    pub input_scale: f32,
+    pub conv1: QuantizedConv2D,
+    pub conv2: QuantizedConv2D,
+    pub fc: QuantizedFullyConnected
+}
+
+#[derive(Debug, Clone)]
+pub struct Report{
+    pub calibration_samples: usize,
+    pub samples: usize,
+    pub float_accuracy: f32,
+    pub int8_accuracy: f32,
+    // fraction of the samples where both models predict the same digit
+    pub agreement: f32,
+    pub float_bytes: usize,
+    pub int8_bytes: usize
+}
+
+// # File format
+// Big endian like IDX:
+//   "DRQ8", u32 version
+//   f32 input scale
+//   conv1, conv2: u32 output layers, input layers, rows, cols, f32 output
+//                 scale, then per output layer an f32 weight scale and an i32
+//                 bias, then the int8 weights in (output, input, row, col) order
+//   fc1:          u32 outputs, inputs, per output an f32 weight scale and an
+//                 i32 bias, then the int8 weights row by row
+const MAGIC: &[u8; 4] = b"DRQ8";
+const VERSION: u32 = 1;
+
+fn invalid_data(message: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// scale that maps [-max, max] onto [-127, 127]
+pub fn scale_for(max: f32) -> f32{
+    if max > 0.0 && max.is_finite() {max / 127.0} else {1.0 / 127.0}
+}
+
+pub fn quantize_value(value: f32, scale: f32) -> i8{
+    (value / scale).round().clamp(-127.0, 127.0) as i8
+}
+
+// the real multiplier as (Q31 value in [2^30, 2^31), right shift), so that
+// x * real == (x * value) >> (31 + shift)
+pub fn fixed_point(real: f64) -> (i32, i32){
+    if real <= 0.0{
+        return (0, 0);
+    }
+    let mut value = real;
+    let mut shift = 0;
+    while value < 0.5{
+        value *= 2.0;
+        shift += 1;
+    }
+    while value >= 1.0{
+        value /= 2.0;
+        shift -= 1;
+    }
+    let mut q = (value * (1i64 << 31) as f64).round() as i64;
+    if q == 1i64 << 31{
+        q /= 2;
+        shift -= 1;
+    }
+    (q as i32, shift)
+}
+
+// rounds to nearest, half away from zero
+pub fn requantize(acc: i32, multiplier: (i32, i32)) -> i32{
+    let total = 31 + multiplier.1;
+    if total <= 0{
+        return acc.saturating_mul(1 << (-total).min(30));
+    }
+    let product = acc as i64 * multiplier.0 as i64;
+    let half = 1i64 << (total - 1);
+    let rounded = if product >= 0 {(product + half) >> total} else {-((-product + half) >> total)};
+    rounded.max(i32::MIN as i64).min(i32::MAX as i64) as i32
+}
+
+pub fn quantize_tensor(input: &Vec<Vec<Vec<f32>>>, scale: f32) -> Vec<Vec<Vec<i8>>>{
+    input.iter().map(|layer| layer.iter().map(|row| row.iter().map(|&v| quantize_value(v, scale)).collect()).collect()).collect()
+}
+
+pub fn max_abs(values: &[f32]) -> f32{
+    values.iter().fold(0.0f32, |max, v| max.max(v.abs()))
+}
+
+fn quantize_bias(bias: f32, scale: f32) -> i32{
+    (bias as f64 / scale as f64).round().max(i32::MIN as f64).min(i32::MAX as f64) as i32
+}
+
+// largest activations of the float model over the calibration inputs
+pub fn calibrate(cnn: &CNN, inputs: &[Vec<Vec<Vec<f32>>>]) -> Calibration{
+    let mut calibration = Calibration{input: 0.0, conv1: 0.0, conv2: 0.0};
+    for input in inputs.iter(){
+        calibration.input = calibration.input.max(max_abs(&model::Flatten::forward(input)));
+        for (name, values) in cnn.trace(input){
+            match name {
+                "relu1" => calibration.conv1 = calibration.conv1.max(max_abs(&values)),
+                "relu2" => calibration.conv2 = calibration.conv2.max(max_abs(&values)),
+                _ => {}
+            }
+        }
+    }
+    calibration
+}
+
+impl QuantizedConv2D{
+    pub fn new(layer: &model::Conv2D, input_scale: f32, output_scale: f32) -> QuantizedConv2D{
+        let mut weights = Vec::new();
+        let mut scales = Vec::new();
+        let mut bias = Vec::new();
+        for i in 0..layer.filter().len(){
+            let channel: Vec<f32> = layer.filter()[i].iter()
+                .flat_map(|input| input.iter().flat_map(|row| row.iter().cloned()))
+                .collect();
+            let scale = scale_for(max_abs(&channel));
+            weights.push(layer.filter()[i].iter().map(|input| input.iter()
+                .map(|row| row.iter().map(|&w| quantize_value(w, scale)).collect()).collect()).collect());
+            bias.push(quantize_bias(layer.bias()[i], input_scale * scale));
+            scales.push(scale);
+        }
+        QuantizedConv2D::from_parts(weights, scales, bias, input_scale, output_scale)
+    }
+
+    fn from_parts(weights: Vec<Vec<Vec<Vec<i8>>>>, scales: Vec<f32>, bias: Vec<i32>, input_scale: f32, output_scale: f32) -> QuantizedConv2D{
+        let multipliers = scales.iter()
+            .map(|&scale| fixed_point(input_scale as f64 * scale as f64 / output_scale as f64))
+            .collect();
+        QuantizedConv2D{
+            weights: weights,
+            scales: scales,
+            bias: bias,
+            input_scale: input_scale,
+            output_scale: output_scale,
+            multipliers: multipliers
+        }
+    }
+
+    pub fn weights(&self) -> &Vec<Vec<Vec<Vec<i8>>>>{
+        &self.weights
+    }
+
+    pub fn scales(&self) -> &Vec<f32>{
+        &self.scales
+    }
+
+    pub fn output_scale(&self) -> f32{
+        self.output_scale
+    }
+
+    // the float layer these weights stand for
+    pub fn dequantize(&self) -> model::Conv2D{
+        let filter: Vec<Vec<Vec<Vec<f32>>>> = (0..self.weights.len()).map(|i| self.weights[i].iter()
+            .map(|input| input.iter().map(|row| row.iter().map(|&q| q as f32 * self.scales[i]).collect()).collect())
+            .collect()).collect();
+        let bias = (0..self.bias.len()).map(|i| self.bias[i] as f32 * self.input_scale * self.scales[i]).collect();
+        model::Conv2D::new(self.weights[0].len() as u32, self.weights.len() as u32, filter, bias)
+    }
+
+    // convolution followed by ReLU, int8 in and out
+    pub fn forward(&self, input: &Vec<Vec<Vec<i8>>>) -> Vec<Vec<Vec<i8>>>{
+        let (rows, cols) = (self.weights[0][0].len(), self.weights[0][0][0].len());
+        let out_rows = input[0].len() - rows + 1;
+        let out_cols = input[0][0].len() - cols + 1;
+        let mut output = vec![vec![vec![0i8; out_cols]; out_rows]; self.weights.len()];
+        for i in 0..self.weights.len(){
+            for x in 0..out_rows{
+                for y in 0..out_cols{
+                    let mut acc = self.bias[i];
+                    for j in 0..self.weights[i].len(){
+                        for k in 0..rows{
+                            for l in 0..cols{
+                                acc += input[j][x + k][y + l] as i32 * self.weights[i][j][k][l] as i32;
+                            }
+                        }
+                    }
+                    output[i][x][y] = requantize(acc, self.multipliers[i]).clamp(0, 127) as i8;
+                }
+            }
+        }
+        output
+    }
+}
+
+impl QuantizedFullyConnected{
+    pub fn new(layer: &model::FullyConnected, input_scale: f32) -> QuantizedFullyConnected{
+        let mut weights = Vec::new();
+        let mut scales = Vec::new();
+        let mut bias = Vec::new();
+        for i in 0..layer.weights().len(){
+            let scale = scale_for(max_abs(&layer.weights()[i]));
+            weights.push(layer.weights()[i].iter().map(|&w| quantize_value(w, scale)).collect());
+            bias.push(quantize_bias(layer.bias()[i], input_scale * scale));
+            scales.push(scale);
+        }
+        QuantizedFullyConnected{
+            weights: weights,
+            scales: scales,
+            bias: bias,
+            input_scale: input_scale
+        }
+    }
+
+    pub fn weights(&self) -> &Vec<Vec<i8>>{
+        &self.weights
+    }
+
+    pub fn scales(&self) -> &Vec<f32>{
+        &self.scales
+    }
+
+    pub fn dequantize(&self) -> model::FullyConnected{
+        let weights: Vec<Vec<f32>> = (0..self.weights.len())
+            .map(|i| self.weights[i].iter().map(|&q| q as f32 * self.scales[i]).collect())
+            .collect();
+        let bias = (0..self.bias.len()).map(|i| self.bias[i] as f32 * self.input_scale * self.scales[i]).collect();
+        model::FullyConnected::new(self.weights[0].len() as u32, self.weights.len() as u32, weights, bias)
+    }
+
+    // int8 in, float logits out
+    pub fn forward(&self, input: &Vec<i8>) -> Vec<f32>{
+        let mut output = vec![0.0; self.weights.len()];
+        for i in 0..self.weights.len(){
+            let mut acc = self.bias[i];
+            for j in 0..self.weights[i].len(){
+                acc += input[j] as i32 * self.weights[i][j] as i32;
+            }
+            output[i] = acc as f32 * self.input_scale * self.scales[i];
+        }
+        output
+    }
+}
+
+// max pooling on int8 values, which keeps their order
+fn max_pool(input: &Vec<Vec<Vec<i8>>>, size: usize) -> Vec<Vec<Vec<i8>>>{
+    let mut output = vec![vec![vec![0i8; input[0][0].len() / size]; input[0].len() / size]; input.len()];
+    for layer in 0..input.len(){
+        for x in 0..output[layer].len(){
+            for y in 0..output[layer][x].len(){
+                let mut max = 0;
+                for i in 0..size{
+                    for j in 0..size{
+                        max = max.max(input[layer][x * size + i][y * size + j]);
+                    }
+                }
+                output[layer][x][y] = max;
+            }
+        }
+    }
+    output
+}
+
+fn flatten(input: &Vec<Vec<Vec<i8>>>) -> Vec<i8>{
+    input.iter().flat_map(|layer| layer.iter().flat_map(|row| row.iter().cloned())).collect()
+}
+
+struct Reader<'a>{
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Reader<'a>{
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]>{
+        if self.bytes.len() - self.offset < count{
+            return Err(invalid_data("quantized model file is truncated".to_owned()));
+        }
+        let bytes = &self.bytes[self.offset..self.offset + count];
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32>{
+        let b = self.take(4)?;
+        Ok(((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32)
+    }
+
+    fn i32(&mut self) -> io::Result<i32>{
+        Ok(self.u32()? as i32)
+    }
+
+    fn f32(&mut self) -> io::Result<f32>{
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn i8s(&mut self, count: usize) -> io::Result<Vec<i8>>{
+        Ok(self.take(count)?.iter().map(|&b| b as i8).collect())
+    }
+
+    // a layer dimension, the limit keeps the product of four from
+    // overflowing, the weights are only allocated once their bytes are there
+    fn dim(&mut self) -> io::Result<usize>{
+        let value = self.u32()? as usize;
+        if value == 0 || value > 4096{
+            return Err(invalid_data(format!("quantized model has a layer dimension of {}", value)));
+        }
+        Ok(value)
+    }
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32){
+    bytes.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+fn push_f32(bytes: &mut Vec<u8>, value: f32){
+    push_u32(bytes, value.to_bits());
+}
+
+fn write_conv(bytes: &mut Vec<u8>, layer: &QuantizedConv2D){
+    let w = &layer.weights;
+    for &dim in [w.len(), w[0].len(), w[0][0].len(), w[0][0][0].len()].iter(){
+        push_u32(bytes, dim as u32);
+    }
+    push_f32(bytes, layer.output_scale);
+    for i in 0..w.len(){
+        push_f32(bytes, layer.scales[i]);
+        push_u32(bytes, layer.bias[i] as u32);
+    }
+    for output in w.iter(){
+        for input in output.iter(){
+            for row in input.iter(){
+                bytes.extend(row.iter().map(|&q| q as u8));
+            }
+        }
+    }
+}
+
+fn read_conv(reader: &mut Reader, input_scale: f32) -> io::Result<QuantizedConv2D>{
+    let (outputs, inputs, rows, cols) = (reader.dim()?, reader.dim()?, reader.dim()?, reader.dim()?);
+    let output_scale = reader.f32()?;
+    let mut scales = Vec::new();
+    let mut bias = Vec::new();
+    for _ in 0..outputs{
+        scales.push(reader.f32()?);
+        bias.push(reader.i32()?);
+    }
+    let values = reader.i8s(outputs * inputs * rows * cols)?;
+    let weights = values.chunks(inputs * rows * cols)
+        .map(|output| output.chunks(rows * cols).map(|input| input.chunks(cols).map(|row| row.to_vec()).collect()).collect())
+        .collect();
+    Ok(QuantizedConv2D::from_parts(weights, scales, bias, input_scale, output_scale))
+}
+
+impl QuantizedCNN{
+    pub fn new(cnn: &CNN, calibration: &Calibration) -> QuantizedCNN{
+        let input_scale = scale_for(calibration.input);
+        let conv1_scale = scale_for(calibration.conv1);
+        let conv2_scale = scale_for(calibration.conv2);
+        QuantizedCNN{
+            input_scale: input_scale,
+            conv1: QuantizedConv2D::new(&cnn.conv1, input_scale, conv1_scale),
+            conv2: QuantizedConv2D::new(&cnn.conv2, conv1_scale, conv2_scale),
+            fc: QuantizedFullyConnected::new(&cnn.fc, conv2_scale)
+        }
+    }
+
+    // calibrates on `inputs` and quantizes
+    pub fn calibrated(cnn: &CNN, inputs: &[Vec<Vec<Vec<f32>>>]) -> QuantizedCNN{
+        QuantizedCNN::new(cnn, &calibrate(cnn, inputs))
+    }
+
+    // the float network with the rounded weights, e.g. to keep training it
+    pub fn dequantize(&self) -> CNN{
+        CNN::new(1, 10, self.conv1.dequantize(), self.conv2.dequantize(), self.fc.dequantize())
+    }
+
+    pub fn logits(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        let img = quantize_tensor(img, self.input_scale);
+        let img = max_pool(&self.conv1.forward(&img), 2);
+        let img = max_pool(&self.conv2.forward(&img), 2);
+        self.fc.forward(&flatten(&img))
+    }
+
+    pub fn probabilities(&self, img: &Vec<Vec<Vec<f32>>>) -> Vec<f32>{
+        model::softmax(&self.logits(img))
+    }
+
+    pub fn forward(&self, img: &Vec<Vec<Vec<f32>>>) -> u32{
+        model::argmax(&self.probabilities(img)) as u32
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut bytes = MAGIC.to_vec();
+        push_u32(&mut bytes, VERSION);
+        push_f32(&mut bytes, self.input_scale);
+        write_conv(&mut bytes, &self.conv1);
+        write_conv(&mut bytes, &self.conv2);
+        push_u32(&mut bytes, self.fc.weights.len() as u32);
+        push_u32(&mut bytes, self.fc.weights[0].len() as u32);
+        for i in 0..self.fc.weights.len(){
+            push_f32(&mut bytes, self.fc.scales[i]);
+            push_u32(&mut bytes, self.fc.bias[i] as u32);
+        }
+        for row in self.fc.weights.iter(){
+            bytes.extend(row.iter().map(|&q| q as u8));
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<QuantizedCNN>{
+        let mut reader = Reader{bytes: bytes, offset: 0};
+        if reader.take(4)? != MAGIC{
+            return Err(invalid_data("not a quantized model file".to_owned()));
+        }
+        let version = reader.u32()?;
+        if version != VERSION{
+            return Err(invalid_data(format!("quantized model version {} is not supported", version)));
+        }
+        let input_scale = reader.f32()?;
+        let conv1 = read_conv(&mut reader, input_scale)?;
+        let conv2 = read_conv(&mut reader, conv1.output_scale)?;
+        let (outputs, inputs) = (reader.dim()?, reader.dim()?);
+        let shape = |layer: &QuantizedConv2D| {
+            let w = &layer.weights;
+            [w.len(), w[0].len(), w[0][0].len(), w[0][0][0].len()]
+        };
+        ::check_layers(shape(&conv1), shape(&conv2), [outputs, inputs])?;
+        let mut scales = Vec::new();
+        let mut bias = Vec::new();
+        for _ in 0..outputs{
+            scales.push(reader.f32()?);
+            bias.push(reader.i32()?);
+        }
+        let mut weights = Vec::new();
+        for _ in 0..outputs{
+            weights.push(reader.i8s(inputs)?);
+        }
+        if reader.offset != bytes.len(){
+            return Err(invalid_data("quantized model file has trailing bytes".to_owned()));
+        }
+        Ok(QuantizedCNN{
+            input_scale: input_scale,
+            fc: QuantizedFullyConnected{
+                weights: weights,
+                scales: scales,
+                bias: bias,
+                input_scale: conv2.output_scale
+            },
+            conv1: conv1,
+            conv2: conv2
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()>{
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> io::Result<QuantizedCNN>{
+        QuantizedCNN::from_bytes(&fs::read(path)?)
+    }
+}
+
+// bytes of the f32 parameters, what the quantized file is compared against
+pub fn float_bytes(cnn: &CNN) -> usize{
+    let conv = |layer: &model::Conv2D| {
+        let f = layer.filter();
+        f.len() * f[0].len() * f[0][0].len() * f[0][0][0].len() + layer.bias().len()
+    };
+    let fc = cnn.fc.weights().len() * cnn.fc.weights()[0].len() + cnn.fc.bias().len();
+    4 * (conv(&cnn.conv1) + conv(&cnn.conv2) + fc)
+}
+
+pub fn evaluate(cnn: &CNN, quantized: &QuantizedCNN, inputs: &[Vec<Vec<Vec<f32>>>], labels: &[u8]) -> Report{
+    let (mut float_correct, mut int8_correct, mut agree) = (0, 0, 0);
+    for n in 0..inputs.len(){
+        let (float_class, int8_class) = (cnn.forward(&inputs[n]), quantized.forward(&inputs[n]));
+        if float_class == labels[n] as u32{
+            float_correct += 1;
+        }
+        if int8_class == labels[n] as u32{
+            int8_correct += 1;
+        }
+        if float_class == int8_class{
+            agree += 1;
+        }
+    }
+    let fraction = |count: usize| if inputs.is_empty() {0.0} else {count as f32 / inputs.len() as f32};
+    Report{
+        calibration_samples: 0,
+        samples: inputs.len(),
+        float_accuracy: fraction(float_correct),
+        int8_accuracy: fraction(int8_correct),
+        agreement: fraction(agree),
+        float_bytes: float_bytes(cnn),
+        int8_bytes: quantized.to_bytes().len()
+    }
+}
+
+impl fmt::Display for Report{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        writeln!(f, "calibrated on {} images, evaluated on {}", self.calibration_samples, self.samples)?;
+        writeln!(f, "f32  accuracy {:.2}%  {} bytes", self.float_accuracy * 100.0, self.float_bytes)?;
+        writeln!(f, "int8 accuracy {:.2}%  {} bytes", self.int8_accuracy * 100.0, self.int8_bytes)?;
+        write!(f, "delta {:+.2}%, same prediction for {:.2}%",
+            (self.int8_accuracy - self.float_accuracy) * 100.0, self.agreement * 100.0)
+    }
+}
+
+const USAGE: &str = "usage: draw-rust quantize --images <idx> --labels <idx> [--model model.json] [--calibration 500] [--output model-int8.bin]";
+
+// arguments after `quantize`, returns the exit code. The first
+// `--calibration` images are used for the activation ranges and the rest
+// for the accuracy, or all of them when there are no more.
+pub fn run(args: &[String]) -> i32{
+    let mut model_path = PathBuf::from("./src/assets/model.json");
+    let mut images_path = None;
+    let mut labels_path = None;
+    let mut output = None;
+    let mut calibration_count = 500;
+    let mut i = 0;
+    while i < args.len(){
+        if args[i] == "-h" || args[i] == "--help"{
+            println!("{}", USAGE);
+            return 0;
+        }
+        if i + 1 >= args.len(){
+            eprintln!("{} needs a value\n{}", args[i], USAGE);
+            return 2;
+        }
+        let value = &args[i + 1];
+        match args[i].as_str() {
+            "--model" => model_path = PathBuf::from(value),
+            "--images" => images_path = Some(PathBuf::from(value)),
+            "--labels" => labels_path = Some(PathBuf::from(value)),
+            "--output" => output = Some(PathBuf::from(value)),
+            "--calibration" => match value.parse::<usize>() {
+                Ok(count) if count > 0 => calibration_count = count,
+                _ => {
+                    eprintln!("--calibration needs a positive number\n{}", USAGE);
+                    return 2;
+                }
+            },
+            other => {
+                eprintln!("unknown argument {}\n{}", other, USAGE);
+                return 2;
+            }
+        }
+        i += 2;
+    }
+    let (images_path, labels_path) = match (images_path, labels_path) {
+        (Some(images), Some(labels)) => (images, labels),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let output = output.unwrap_or_else(|| model_path.with_file_name(format!("{}-int8.bin",
+        model_path.file_stem().map_or("model".into(), |stem| stem.to_string_lossy()))));
+
+    let loaded = ::load_cnn(&model_path)
+        .and_then(|cnn| Ok((cnn, idx::read_images(&images_path)?, idx::read_labels(&labels_path)?)));
+    let (cnn, images, labels) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    if images.is_empty() || images.len() != labels.len(){
+        eprintln!("Error: {} images but {} labels", images.len(), labels.len());
+        return 1;
+    }
+    let inputs: Vec<Vec<Vec<Vec<f32>>>> = images.iter().map(preprocess::to_input).collect();
+    let split = calibration_count.min(inputs.len());
+    let quantized = QuantizedCNN::calibrated(&cnn, &inputs[..split]);
+    let (eval_inputs, eval_labels) = if split < inputs.len() {(&inputs[split..], &labels[split..])} else {(&inputs[..], &labels[..])};
+    let mut report = evaluate(&cnn, &quantized, eval_inputs, eval_labels);
+    report.calibration_samples = split;
+    println!("{}", report);
+    match quantized.save(&output) {
+        Ok(()) => {
+            println!("Saved {}", output.display());
+            0
+        },
+        Err(e) => {
+            eprintln!("Error: {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn fixed_point_test(){
+        for &real in [0.3, 0.0123, 0.75, 1.5, 0.5, 0.999999999].iter(){
+            let multiplier = fixed_point(real);
+            assert!(multiplier.0 >= 1 << 30, "Sample: {} {:?}", real, multiplier);
+            for &acc in [0, 1, -1, 1000, -1000, 123456, -98765].iter(){
+                let expected = (acc as f64 * real).round() as i32;
+                assert!((requantize(acc, multiplier) - expected).abs() <= 1,
+                    "Sample: {} * {} = {}, expected {}", acc, real, requantize(acc, multiplier), expected);
+            }
+        }
+        assert_eq!(requantize(5, fixed_point(0.5)), 3);
+        assert_eq!(requantize(-5, fixed_point(0.5)), -3);
+    }
+
+    #[test]
+    fn per_channel_test(){
+        // channels with very different ranges keep their precision
+        let filter = vec![vec![vec![vec![0.015, -0.02], vec![0.005, 0.0]]], vec![vec![vec![4.0, -1.0], vec![1.0, 0.5]]]];
+        let layer = model::Conv2D::new(1, 2, filter.clone(), vec![0.1, -0.3]);
+        let quantized = QuantizedConv2D::new(&layer, 1.0 / 127.0, 0.05);
+        assert_eq!(quantized.weights()[0][0][0], vec![95, -127]);
+        assert_eq!(quantized.weights()[1][0][0], vec![127, -32]);
+        let restored = quantized.dequantize();
+        for i in 0..2{
+            let step = quantized.scales()[i];
+            for k in 0..2{
+                for l in 0..2{
+                    assert!((restored.filter()[i][0][k][l] - filter[i][0][k][l]).abs() <= step / 2.0 + 1e-7);
+                }
+            }
+        }
+        assert!((restored.bias()[1] + 0.3).abs() < 1e-3, "Sample: {:?}", restored.bias());
+    }
+
+    #[test]
+    fn quantized_cnn_test(){
+        let cnn = testutil::model();
+        let (images, labels) = testutil::bars_and_rings(8, 1, 0);
+        let inputs = testutil::inputs(&images);
+        let quantized = QuantizedCNN::calibrated(&cnn, &inputs);
+        let calibration = calibrate(&cnn, &inputs);
+        assert!((calibration.input - 1.0).abs() < 1e-6);
+        assert!(calibration.conv1 > 0.0 && calibration.conv2 > 0.0);
+
+        // logits stay close to the float ones, relative to their range
+        for input in inputs.iter(){
+            let (float, int8) = (cnn.logits(input), quantized.logits(input));
+            let range = max_abs(&float);
+            for i in 0..10{
+                assert!((float[i] - int8[i]).abs() < 0.1 * range, "Sample: {:?} {:?}", float, int8);
+            }
+        }
+        let report = evaluate(&cnn, &quantized, &inputs, &labels);
+        assert!(report.agreement >= 0.875, "Sample: {:?}", report);
+        assert!(report.int8_bytes * 3 < report.float_bytes, "Sample: {:?}", report);
+    }
+
+    #[test]
+    fn file_round_trip_test(){
+        let cnn = testutil::model();
+        let inputs = testutil::inputs(&testutil::bars_and_rings(8, 1, 0).0);
+        let quantized = QuantizedCNN::calibrated(&cnn, &inputs[..4]);
+        let path = std::env::temp_dir().join(format!("draw-rust-int8-{}.bin", std::process::id()));
+        quantized.save(&path).unwrap();
+        let loaded = QuantizedCNN::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, quantized);
+        assert_eq!(loaded.logits(&inputs[5]), quantized.logits(&inputs[5]));
+
+        let bytes = quantized.to_bytes();
+        assert!(QuantizedCNN::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(QuantizedCNN::from_bytes(b"DRQ9").is_err());
+
+        // a header that asks for 4096^3 weights without the bytes for them
+        let mut huge = bytes[..12].to_vec();
+        for &dim in [1, 4096, 4096, 4096].iter(){
+            push_u32(&mut huge, dim);
+        }
+        push_f32(&mut huge, 1.0);
+        push_f32(&mut huge, 1.0);
+        push_u32(&mut huge, 0);
+        let error = QuantizedCNN::from_bytes(&huge).unwrap_err();
+        assert!(error.to_string().contains("truncated"), "Sample: {}", error);
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(QuantizedCNN::from_bytes(&longer).is_err());
+
+        // an fc1 that does not take the 8x5x5 outputs of conv2
+        let mut narrow = quantized.clone();
+        for row in narrow.fc.weights.iter_mut(){
+            row.pop();
+        }
+        let error = QuantizedCNN::from_bytes(&narrow.to_bytes()).unwrap_err();
+        assert!(error.to_string().contains("fc1 expects 199 inputs"), "Sample: {}", error);
+    }
+}
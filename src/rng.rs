@@ -0,0 +1,88 @@
+// # Rng
+// Small xorshift64* generator. The whole state is a single u64 so it can be
+// stored in a checkpoint and restored to continue the exact same sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rng{
+    state: u64
+}
+
+impl Rng{
+    pub fn new(seed: u64) -> Rng{
+        // a zero state would only ever produce zeros
+        Rng{
+            state: if seed == 0 {0x9E37_79B9_7F4A_7C15} else {seed}
+        }
+    }
+
+    pub fn from_state(state: u64) -> Rng{
+        Rng::new(state)
+    }
+
+    pub fn state(&self) -> u64{
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64{
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_f32(&mut self) -> f32{
+        // uniform in [0, 1)
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn uniform(&mut self, low: f32, high: f32) -> f32{
+        low + (high - low) * self.next_f32()
+    }
+
+    pub fn below(&mut self, n: usize) -> usize{
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn normal(&mut self) -> f32{
+        // Box-Muller, the second value is thrown away to keep the state simple
+        let u1 = (self.next_f32() as f64).max(1e-12);
+        let u2 = self.next_f32() as f64;
+        ((-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()) as f32
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut Vec<T>){
+        for i in (1..items.len()).rev(){
+            let j = self.below(i + 1);
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn rng_resume_test(){
+        let mut a = Rng::new(42);
+        for _ in 0..10{
+            a.next_u64();
+        }
+        let mut b = Rng::from_state(a.state());
+        for _ in 0..10{
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn rng_range_test(){
+        let mut rng = Rng::new(7);
+        for _ in 0..1000{
+            let x = rng.next_f32();
+            assert!((0.0..1.0).contains(&x), "Sample: {}", x);
+            assert!(rng.below(10) < 10);
+        }
+    }
+}
@@ -0,0 +1,776 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use imagefile;
+use model;
+use preprocess;
+use CNN;
+
+// # HTTP server
+// `draw-rust-cli serve` answers prediction requests over plain HTTP/1.1,
+// one request per connection, using only the standard library:
+//
+//   POST /predict  PNG or JPEG bytes (image/png, image/jpeg,
+//                  application/octet-stream), base64 text (text/plain), or
+//                  JSON: a 784 number array, {"pixels": [...]},
+//                  {"image": "<base64>"} or {"inputs": [...]} for several
+//   GET  /health   {"status": "ok"}
+//   GET  /model    layer shapes, the FNV-1a hash of the model file and how
+//                  often it was reloaded
+//
+// Images get the same MNIST preprocessing as the canvas. A 784 array is
+// taken as an already preprocessed 28x28 image (row major, [0, 1], 1 is ink).
+// Requests from all connections go through one batching thread, and a
+// watcher thread reloads the model when the file changes.
+#[derive(Debug, Clone)]
+pub struct Config{
+    pub addr: String,
+    pub model: PathBuf,
+    // largest number of images predicted in one batch
+    pub max_batch: usize,
+    // how long the first request of a batch waits for others
+    pub batch_wait: Duration,
+    // how often the model file is checked for changes
+    pub reload_interval: Duration,
+    // largest accepted request body in bytes
+    pub max_body: usize
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request{
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response{
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo{
+    pub hash: String,
+    // number of times the model was loaded again after the start
+    pub reloads: usize
+}
+
+// the current model, replaced as a whole when the file changes so a batch
+// always runs on one version
+pub struct ModelStore{
+    path: PathBuf,
+    state: RwLock<(Arc<CNN>, ModelInfo)>,
+    // hash of the last contents that did not parse, they are not tried again
+    failed: Mutex<Option<String>>
+}
+
+struct Job{
+    inputs: Vec<Vec<Vec<Vec<f32>>>>,
+    reply: mpsc::Sender<Vec<Vec<f32>>>
+}
+
+pub struct Server{
+    listener: TcpListener,
+    config: Config,
+    store: Arc<ModelStore>,
+    stop: Arc<AtomicBool>
+}
+
+// stops a server started with `spawn` when dropped or on `stop`
+pub struct ServerHandle{
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+const USAGE: &str = "usage: draw-rust serve [--addr 127.0.0.1:8080] [--model model.json]";
+
+// the request line and the headers together, a client that never sends a
+// newline must not grow a line without bound
+const MAX_HEAD: usize = 16 * 1024;
+
+// reads one line of the request head, counting it against `remaining`
+fn read_head_line<R: BufRead>(reader: &mut R, line: &mut String, remaining: &mut usize) -> io::Result<usize>{
+    line.clear();
+    let read = reader.by_ref().take(*remaining as u64).read_line(line)?;
+    if read == *remaining && !line.ends_with('\n'){
+        return Err(invalid_data(format!("request headers are longer than {} bytes", MAX_HEAD)));
+    }
+    *remaining -= read;
+    Ok(read)
+}
+
+fn invalid_data(message: String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Default for Config{
+    fn default() -> Config{
+        Config{
+            addr: "127.0.0.1:8080".to_owned(),
+            model: PathBuf::from("./src/assets/model.json"),
+            max_batch: 32,
+            batch_wait: Duration::from_millis(2),
+            reload_interval: Duration::from_millis(500),
+            max_body: 8 * 1024 * 1024
+        }
+    }
+}
+
+// 64 bit FNV-1a, enough to tell model files apart
+pub fn fnv1a(bytes: &[u8]) -> String{
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes.iter(){
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+// standard base64 with or without padding, whitespace and a data URL
+// prefix are ignored
+pub fn decode_base64(text: &str) -> Option<Vec<u8>>{
+    let text = match text.find(";base64,") {
+        Some(index) => &text[index + 8..],
+        None => text
+    };
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.chars(){
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            '=' => break,
+            c if c.is_whitespace() => continue,
+            _ => return None
+        };
+        buffer = (buffer << 6) | value;
+        bits += 6;
+        if bits >= 8{
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+fn read_model(path: &Path) -> io::Result<(CNN, String)>{
+    let bytes = fs::read(path)?;
+    Ok((::parse_model(&bytes)?, fnv1a(&bytes)))
+}
+
+impl ModelStore{
+    pub fn open(path: &Path) -> io::Result<ModelStore>{
+        let (cnn, hash) = read_model(path)?;
+        Ok(ModelStore{
+            path: path.to_path_buf(),
+            state: RwLock::new((Arc::new(cnn), ModelInfo{hash: hash, reloads: 0})),
+            failed: Mutex::new(None)
+        })
+    }
+
+    pub fn path(&self) -> &Path{
+        &self.path
+    }
+
+    pub fn current(&self) -> Arc<CNN>{
+        self.state.read().unwrap().0.clone()
+    }
+
+    pub fn info(&self) -> ModelInfo{
+        self.state.read().unwrap().1.clone()
+    }
+
+    // loads the file again if its contents changed, a file that is being
+    // written (and does not parse yet) keeps the old model. Invalid contents
+    // are reported once, not on every check until the file changes again.
+    pub fn reload_if_changed(&self) -> io::Result<bool>{
+        let bytes = fs::read(&self.path)?;
+        let hash = fnv1a(&bytes);
+        let mut failed = self.failed.lock().unwrap();
+        if hash == self.info().hash || failed.as_ref() == Some(&hash){
+            return Ok(false);
+        }
+        let cnn = match ::parse_model(&bytes) {
+            Ok(cnn) => cnn,
+            Err(e) => {
+                *failed = Some(hash);
+                return Err(e);
+            }
+        };
+        *failed = None;
+        let mut state = self.state.write().unwrap();
+        let reloads = state.1.reloads + 1;
+        *state = (Arc::new(cnn), ModelInfo{hash: hash, reloads: reloads});
+        Ok(true)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value{
+        let cnn = self.current();
+        let info = self.info();
+        let filter_shape = |filter: &Vec<Vec<Vec<Vec<f32>>>>| vec![filter.len(), filter[0].len(), filter[0][0].len(), filter[0][0][0].len()];
+        json!({
+            "path": self.path.to_string_lossy(),
+            "hash": info.hash,
+            "reloads": info.reloads,
+            "input": [1, 28, 28],
+            "output": [cnn.output_size],
+            "layers": [
+                {"name": "conv1", "type": "Conv2D", "weight": filter_shape(cnn.conv1.filter()), "bias": [cnn.conv1.bias().len()]},
+                {"name": "relu1", "type": "ReLU"},
+                {"name": "pool1", "type": "MaxPooling2D", "size": 2},
+                {"name": "conv2", "type": "Conv2D", "weight": filter_shape(cnn.conv2.filter()), "bias": [cnn.conv2.bias().len()]},
+                {"name": "relu2", "type": "ReLU"},
+                {"name": "pool2", "type": "MaxPooling2D", "size": 2},
+                {"name": "flatten", "type": "Flatten"},
+                {"name": "fc1", "type": "FullyConnected",
+                    "weight": [cnn.fc.weights().len(), cnn.fc.weights()[0].len()], "bias": [cnn.fc.bias().len()]},
+                {"name": "softmax", "type": "Softmax"}
+            ]
+        })
+    }
+}
+
+impl Request{
+    pub fn header(&self, name: &str) -> Option<&str>{
+        self.headers.iter()
+            .find(|header| header.0.eq_ignore_ascii_case(name))
+            .map(|header| header.1.as_str())
+    }
+
+    pub fn read<R: BufRead>(reader: &mut R, max_body: usize) -> io::Result<Request>{
+        let mut line = String::new();
+        let mut remaining = MAX_HEAD;
+        read_head_line(reader, &mut line, &mut remaining)?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next().ok_or_else(|| invalid_data("empty request".to_owned()))?.to_owned();
+        let path = parts.next().ok_or_else(|| invalid_data("request line has no path".to_owned()))?.to_owned();
+
+        let mut headers = Vec::new();
+        loop {
+            if read_head_line(reader, &mut line, &mut remaining)? == 0{
+                return Err(invalid_data("connection closed inside the headers".to_owned()));
+            }
+            let header = line.trim_end();
+            if header.is_empty(){
+                break;
+            }
+            match header.find(':') {
+                Some(index) => headers.push((header[..index].trim().to_owned(), header[index + 1..].trim().to_owned())),
+                None => return Err(invalid_data(format!("bad header line {:?}", header)))
+            }
+        }
+
+        let mut request = Request{
+            method: method,
+            path: path,
+            headers: headers,
+            body: Vec::new()
+        };
+        let length = match request.header("Content-Length") {
+            Some(value) => value.parse::<usize>().map_err(|_| invalid_data(format!("bad Content-Length {}", value)))?,
+            None => 0
+        };
+        if length > max_body{
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("body of {} bytes is larger than {}", length, max_body)));
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body)?;
+        Ok(request)
+    }
+}
+
+impl Response{
+    pub fn json(status: u16, json: &serde_json::Value) -> Response{
+        Response{
+            status: status,
+            content_type: "application/json".to_owned(),
+            body: json.to_string().into_bytes()
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Response{
+        Response::json(status, &json!({"error": message}))
+    }
+
+    fn reason(&self) -> &'static str{
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error"
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()>{
+        write!(writer, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status, self.reason(), self.content_type, self.body.len())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn pixels_input(values: &Vec<serde_json::Value>) -> Result<Vec<Vec<Vec<f32>>>, String>{
+    if values.len() != 784{
+        return Err(format!("expected 784 pixels, got {}", values.len()));
+    }
+    let mut image = vec![vec![0.0; 28]; 28];
+    for i in 0..784{
+        image[i / 28][i % 28] = values[i].as_f64().ok_or_else(|| format!("pixel {} is not a number", i))? as f32;
+    }
+    Ok(preprocess::to_input(&image))
+}
+
+fn image_input(bytes: &[u8]) -> Result<Vec<Vec<Vec<f32>>>, String>{
+    let image = imagefile::decode(bytes).map_err(|e| e.to_string())?;
+    Ok(preprocess::to_input(&preprocess::mnist(&image)))
+}
+
+fn base64_input(text: &str) -> Result<Vec<Vec<Vec<f32>>>, String>{
+    image_input(&decode_base64(text).ok_or_else(|| "invalid base64".to_owned())?)
+}
+
+// one JSON input: a pixel array, a base64 string or an object holding either
+fn json_input(json: &serde_json::Value) -> Result<Vec<Vec<Vec<f32>>>, String>{
+    if let Some(values) = json.as_array(){
+        return pixels_input(values);
+    }
+    if let Some(text) = json.as_str(){
+        return base64_input(text);
+    }
+    if let Some(values) = json["pixels"].as_array(){
+        return pixels_input(values);
+    }
+    if let Some(text) = json["image"].as_str(){
+        return base64_input(text);
+    }
+    Err("expected a 784 number array, a base64 image, {\"pixels\": [...]} or {\"image\": \"...\"}".to_owned())
+}
+
+// the network inputs of a /predict body, and whether it was a batch
+pub type Inputs = (Vec<Vec<Vec<Vec<f32>>>>, bool);
+
+pub fn parse_inputs(request: &Request) -> Result<Inputs, String>{
+    let content_type = request.header("Content-Type").unwrap_or("application/octet-stream");
+    let content_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    match content_type.as_str() {
+        "application/json" => {
+            let json: serde_json::Value = serde_json::from_slice(&request.body).map_err(|e| e.to_string())?;
+            if let Some(inputs) = json["inputs"].as_array(){
+                let mut parsed = Vec::with_capacity(inputs.len());
+                for input in inputs.iter(){
+                    parsed.push(json_input(input)?);
+                }
+                return Ok((parsed, true));
+            }
+            Ok((vec![json_input(&json)?], false))
+        },
+        "text/plain" => {
+            let text = String::from_utf8(request.body.clone()).map_err(|e| e.to_string())?;
+            Ok((vec![base64_input(&text)?], false))
+        },
+        _ => Ok((vec![image_input(&request.body)?], false))
+    }
+}
+
+fn prediction_json(probabilities: &Vec<f32>) -> serde_json::Value{
+    json!({
+        "class": model::argmax(probabilities),
+        "probabilities": probabilities
+    })
+}
+
+// collects the jobs that arrive within `batch_wait` of each other and runs
+// them on the same model
+fn run_batches(store: Arc<ModelStore>, jobs: mpsc::Receiver<Job>, max_batch: usize, batch_wait: Duration){
+    while let Ok(first) = jobs.recv(){
+        let mut count = first.inputs.len();
+        let mut batch = vec![first];
+        let deadline = Instant::now() + batch_wait;
+        while count < max_batch{
+            let now = Instant::now();
+            if now >= deadline{
+                break;
+            }
+            match jobs.recv_timeout(deadline - now) {
+                Ok(job) => {
+                    count += job.inputs.len();
+                    batch.push(job);
+                },
+                Err(_) => break
+            }
+        }
+        let cnn = store.current();
+        for job in batch{
+            let outputs = job.inputs.iter().map(|input| cnn.probabilities(input)).collect();
+            let _ = job.reply.send(outputs);
+        }
+    }
+}
+
+fn handle(request: &Request, store: &ModelStore, jobs: &mpsc::Sender<Job>) -> Response{
+    let path = request.path.split('?').next().unwrap_or("");
+    match (request.method.as_str(), path) {
+        ("GET", "/health") => Response::json(200, &json!({"status": "ok"})),
+        ("GET", "/model") => Response::json(200, &store.to_json()),
+        ("POST", "/predict") => {
+            let (inputs, batch) = match parse_inputs(request) {
+                Ok(parsed) => parsed,
+                Err(message) => return Response::error(400, &message)
+            };
+            let (reply, outputs) = mpsc::channel();
+            if jobs.send(Job{inputs: inputs, reply: reply}).is_err(){
+                return Response::error(500, "the prediction thread stopped");
+            }
+            let outputs = match outputs.recv() {
+                Ok(outputs) => outputs,
+                Err(_) => return Response::error(500, "the prediction thread stopped")
+            };
+            if batch{
+                let predictions: Vec<serde_json::Value> = outputs.iter().map(prediction_json).collect();
+                Response::json(200, &json!({"predictions": predictions}))
+            }
+            else{
+                Response::json(200, &prediction_json(&outputs[0]))
+            }
+        },
+        (_, "/health") | (_, "/model") | (_, "/predict") => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found")
+    }
+}
+
+fn serve_connection(stream: TcpStream, store: &ModelStore, jobs: &mpsc::Sender<Job>, max_body: usize) -> io::Result<()>{
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match Request::read(&mut reader, max_body) {
+        Ok(request) => handle(&request, store, jobs),
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => Response::error(413, &e.to_string()),
+        Err(e) => Response::error(400, &e.to_string())
+    };
+    let mut stream = stream;
+    response.write_to(&mut stream)
+}
+
+impl Server{
+    pub fn bind(config: Config) -> io::Result<Server>{
+        let store = Arc::new(ModelStore::open(&config.model)?);
+        Ok(Server{
+            listener: TcpListener::bind(&config.addr)?,
+            config: config,
+            store: store,
+            stop: Arc::new(AtomicBool::new(false))
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr>{
+        self.listener.local_addr()
+    }
+
+    pub fn store(&self) -> Arc<ModelStore>{
+        self.store.clone()
+    }
+
+    // serves until stopped through a handle, every connection gets a thread
+    pub fn run(self){
+        let (sender, receiver) = mpsc::channel();
+        let batch_store = self.store.clone();
+        let (max_batch, batch_wait) = (self.config.max_batch.max(1), self.config.batch_wait);
+        thread::spawn(move || run_batches(batch_store, receiver, max_batch, batch_wait));
+
+        let watch_store = self.store.clone();
+        let watch_stop = self.stop.clone();
+        let interval = self.config.reload_interval;
+        thread::spawn(move || {
+            while !watch_stop.load(Ordering::SeqCst){
+                thread::sleep(interval);
+                match watch_store.reload_if_changed() {
+                    Ok(true) => println!("Reloaded {}", watch_store.path.display()),
+                    Ok(false) => {},
+                    Err(e) => eprintln!("Error: {}: {}", watch_store.path.display(), e)
+                }
+            }
+        });
+
+        for stream in self.listener.incoming(){
+            if self.stop.load(Ordering::SeqCst){
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue
+            };
+            let (store, jobs) = (self.store.clone(), sender.clone());
+            let max_body = self.config.max_body;
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(stream, &store, &jobs, max_body){
+                    eprintln!("Error: {}", e);
+                }
+            });
+        }
+    }
+
+    pub fn spawn(self) -> io::Result<ServerHandle>{
+        let addr = self.local_addr()?;
+        let stop = self.stop.clone();
+        Ok(ServerHandle{
+            addr: addr,
+            stop: stop,
+            thread: Some(thread::spawn(move || self.run()))
+        })
+    }
+}
+
+impl ServerHandle{
+    pub fn addr(&self) -> SocketAddr{
+        self.addr
+    }
+
+    pub fn stop(&mut self){
+        if let Some(thread) = self.thread.take(){
+            self.stop.store(true, Ordering::SeqCst);
+            // wakes up the accept loop so it sees the flag
+            let _ = TcpStream::connect(self.addr);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ServerHandle{
+    fn drop(&mut self){
+        self.stop();
+    }
+}
+
+// arguments after `serve`, returns the exit code
+pub fn run(args: &[String]) -> i32{
+    let mut config = Config::default();
+    let mut i = 0;
+    while i < args.len(){
+        match args[i].as_str() {
+            "--addr" | "--model" if i + 1 >= args.len() => {
+                eprintln!("{} needs a value\n{}", args[i], USAGE);
+                return 2;
+            },
+            "--addr" => config.addr = args[i + 1].clone(),
+            "--model" => config.model = PathBuf::from(&args[i + 1]),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            other => {
+                eprintln!("unknown argument {}\n{}", other, USAGE);
+                return 2;
+            }
+        }
+        i += 2;
+    }
+    let server = match Server::bind(config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    match server.local_addr() {
+        Ok(addr) => println!("Listening on http://{}", addr),
+        Err(e) => println!("Error: {}", e)
+    }
+    server.run();
+    0
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+    use std::io::Read;
+
+    fn request(addr: SocketAddr, method: &str, path: &str, content_type: &str, body: &[u8]) -> (u16, serde_json::Value){
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            method, path, content_type, body.len()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    fn encode_base64(bytes: &[u8]) -> String{
+        let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut text = String::new();
+        for chunk in bytes.chunks(3){
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+            for i in 0..4{
+                text.push(if i <= chunk.len() {alphabet[(n >> (18 - 6 * i) & 63) as usize] as char} else {'='});
+            }
+        }
+        text
+    }
+
+    fn temp_model(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("draw-rust-server-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.json");
+        fs::copy("./src/assets/model.json", &path).unwrap();
+        path
+    }
+
+    fn start(model: &Path) -> ServerHandle{
+        Server::bind(Config{
+            addr: "127.0.0.1:0".to_owned(),
+            model: model.to_path_buf(),
+            reload_interval: Duration::from_millis(20),
+            ..Config::default()
+        }).unwrap().spawn().unwrap()
+    }
+
+    #[test]
+    fn base64_test(){
+        assert_eq!(decode_base64("aGVsbG8="), Some(b"hello".to_vec()));
+        assert_eq!(decode_base64("aGVsbG8"), Some(b"hello".to_vec()));
+        assert_eq!(decode_base64("data:image/png;base64,aGk=\n"), Some(b"hi".to_vec()));
+        assert_eq!(decode_base64("a*b"), None);
+        assert_eq!(decode_base64(&encode_base64(&[0, 255, 7, 1])), Some(vec![0, 255, 7, 1]));
+    }
+
+    #[test]
+    fn read_request_test(){
+        let raw = b"POST /predict HTTP/1.1\r\ncontent-length: 3\r\nContent-Type: text/plain\r\n\r\nabcdef";
+        let request = Request::read(&mut &raw[..], 100).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.header("Content-Length"), Some("3"));
+        assert_eq!(request.body, b"abc".to_vec());
+        assert!(Request::read(&mut &raw[..], 2).is_err());
+
+        // a header that never ends, or too many of them
+        let mut endless = b"GET /model HTTP/1.1\r\nX-Long: ".to_vec();
+        endless.extend(vec![b'a'; 2 * MAX_HEAD]);
+        let error = Request::read(&mut &endless[..], 100).unwrap_err();
+        assert!(error.to_string().contains("longer than"), "Sample: {}", error);
+        let many = format!("GET /model HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEAD / 4));
+        assert!(Request::read(&mut many.as_bytes(), 100).is_err());
+    }
+
+    #[test]
+    fn server_test(){
+        let model = temp_model("predict");
+        let handle = start(&model);
+        let addr = handle.addr();
+
+        assert_eq!(request(addr, "GET", "/health", "text/plain", b""), (200, json!({"status": "ok"})));
+        let (status, info) = request(addr, "GET", "/model", "text/plain", b"");
+        assert_eq!(status, 200);
+        assert_eq!(info["hash"], fnv1a(&fs::read(&model).unwrap()));
+        assert_eq!(info["layers"][0]["weight"], json!([4, 1, 3, 3]));
+        assert_eq!(info["layers"][7]["weight"], json!([10, 200]));
+        assert_eq!(request(addr, "GET", "/nothing", "text/plain", b"").0, 404);
+        assert_eq!(request(addr, "GET", "/predict", "text/plain", b"").0, 405);
+
+        // the same digit as raw pixels, PNG bytes and base64 in JSON
+        let pixels: Vec<f32> = testutil::bar(13).iter().flat_map(|row| row.iter().cloned()).collect();
+        let (status, raw) = request(addr, "POST", "/predict", "application/json", json!(pixels).to_string().as_bytes());
+        assert_eq!(status, 200);
+        assert_eq!(raw["class"], 1, "Sample: {}", raw);
+        let png_path = model.with_file_name("one.png");
+        imagefile::save_png(&testutil::bar(13), &png_path).unwrap();
+        let png = fs::read(&png_path).unwrap();
+        assert_eq!(request(addr, "POST", "/predict", "image/png", &png).1["class"], 1);
+        assert_eq!(request(addr, "POST", "/predict", "text/plain", encode_base64(&png).as_bytes()).1["class"], 1);
+
+        let batch = json!({"inputs": [pixels, {"image": encode_base64(&png)}, {"pixels": pixels}]});
+        let (status, predictions) = request(addr, "POST", "/predict", "application/json", batch.to_string().as_bytes());
+        assert_eq!(status, 200);
+        assert_eq!(predictions["predictions"].as_array().unwrap().len(), 3);
+        assert_eq!(predictions["predictions"][1]["class"], 1);
+
+        let (status, error) = request(addr, "POST", "/predict", "application/json", b"[1, 2, 3]");
+        assert_eq!(status, 400);
+        assert!(error["error"].as_str().unwrap().contains("784"));
+        assert_eq!(request(addr, "POST", "/predict", "image/png", b"not a png").0, 400);
+        drop(handle);
+        fs::remove_dir_all(model.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn concurrent_requests_test(){
+        let model = temp_model("concurrent");
+        let handle = start(&model);
+        let addr = handle.addr();
+        let pixels: Vec<f32> = testutil::bar(13).iter().flat_map(|row| row.iter().cloned()).collect();
+        let body = json!(pixels).to_string();
+        let threads: Vec<thread::JoinHandle<serde_json::Value>> = (0..8).map(|_| {
+            let body = body.clone();
+            thread::spawn(move || request(addr, "POST", "/predict", "application/json", body.as_bytes()).1)
+        }).collect();
+        for thread in threads{
+            assert_eq!(thread.join().unwrap()["class"], 1);
+        }
+        drop(handle);
+        fs::remove_dir_all(model.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn reload_test(){
+        let model = temp_model("reload");
+        let handle = start(&model);
+        let addr = handle.addr();
+        let before = request(addr, "GET", "/model", "text/plain", b"").1;
+        let pixels: Vec<f32> = testutil::bar(13).iter().flat_map(|row| row.iter().cloned()).collect();
+        let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&model).unwrap()).unwrap();
+
+        // valid JSON but not a model, and a model whose fc1 does not fit
+        // conv2: the old model keeps serving
+        let mut narrow = json.clone();
+        narrow["fc1.weight"] = json!(vec![vec![0.0; 199]; 10]);
+        let store = ModelStore::open(&model).unwrap();
+        for invalid in [json!({}), narrow].iter(){
+            fs::write(&model, invalid.to_string()).unwrap();
+            assert!(ModelStore::open(&model).is_err());
+            // reported once, then skipped until the file changes
+            assert!(store.reload_if_changed().is_err());
+            assert!(!store.reload_if_changed().unwrap());
+            thread::sleep(Duration::from_millis(200));
+            let during = request(addr, "GET", "/model", "text/plain", b"").1;
+            assert_eq!(during["reloads"], 0);
+            assert_eq!(during["hash"], before["hash"]);
+            assert_eq!(request(addr, "POST", "/predict", "application/json", json!(pixels).to_string().as_bytes()).1["class"], 1);
+        }
+
+        // a model that always answers 7
+        json["fc1.bias"][7] = json!(50.0);
+        fs::write(&model, json.to_string()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut after = before.clone();
+        while after["reloads"] == 0 && Instant::now() < deadline{
+            thread::sleep(Duration::from_millis(20));
+            after = request(addr, "GET", "/model", "text/plain", b"").1;
+        }
+        assert_eq!(after["reloads"], 1);
+        assert!(after["hash"] != before["hash"]);
+        assert_eq!(request(addr, "POST", "/predict", "application/json", json!(pixels).to_string().as_bytes()).1["class"], 7);
+        drop(handle);
+        fs::remove_dir_all(model.parent().unwrap()).unwrap();
+    }
+}
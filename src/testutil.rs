@@ -0,0 +1,45 @@
+use std::path::Path;
+
+use CNN;
+
+// drawings shared by the unit tests, as 28x28 images with ink 1.0 on 0.0
+
+// a vertical bar two pixels wide starting at `column`, the model calls it a 1
+pub fn bar(column: usize) -> Vec<Vec<f32>>{
+    let mut image = vec![vec![0.0; 28]; 28];
+    for i in 4..24{
+        image[i][column] = 1.0;
+        image[i][column + 1] = 1.0;
+    }
+    image
+}
+
+// `count` images that alternate between a bar and a ring, shifted up to two
+// pixels sideways, labelled `bar_label` and `ring_label`
+pub fn bars_and_rings(count: usize, bar_label: u8, ring_label: u8) -> (Vec<Vec<Vec<f32>>>, Vec<u8>){
+    let mut images = Vec::new();
+    let mut labels = Vec::new();
+    for n in 0..count{
+        let mut image = vec![vec![0.0; 28]; 28];
+        for i in 0..28{
+            for j in 0..28{
+                let (y, x) = (i as f32 - 14.0, j as f32 - 14.0 + (n % 5) as f32 - 2.0);
+                let on = if n % 2 == 0 {x.abs() < 1.5 && y.abs() < 10.0} else {((x * x + y * y).sqrt() - 7.0).abs() < 1.5};
+                image[i][j] = if on {1.0} else {0.0};
+            }
+        }
+        images.push(image);
+        labels.push(if n % 2 == 0 {bar_label} else {ring_label});
+    }
+    (images, labels)
+}
+
+// the network inputs of `images`
+pub fn inputs(images: &[Vec<Vec<f32>>]) -> Vec<Vec<Vec<Vec<f32>>>>{
+    images.iter().map(::preprocess::to_input).collect()
+}
+
+// the trained model that ships in src/assets
+pub fn model() -> CNN{
+    ::load_cnn(Path::new("./src/assets/model.json")).unwrap()
+}
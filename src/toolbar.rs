@@ -0,0 +1,160 @@
+// # Toolbar
+// Layout and hit testing for the row of controls above the canvas. Drawing
+// is done by the window code, which knows the current state of each control.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control{
+    Draw,
+    Erase,
+    Clear,
+    Predict,
+    Undo,
+    Redo,
+    Brush,
+    Model,
+    Tune,
+    Label,
+    Review
+}
+
+#[derive(Debug, Clone)]
+pub struct Toolbar{
+    pub height: f64,
+    controls: Vec<(Control, [f64; 4])>
+}
+
+const MARGIN: f64 = 4.0;
+const GAP: f64 = 2.0;
+
+impl Control{
+    pub fn label(&self) -> &'static str{
+        match *self {
+            Control::Draw => "Draw",
+            Control::Erase => "Erase",
+            Control::Clear => "Clear",
+            Control::Predict => "Predict",
+            Control::Undo => "Undo",
+            Control::Redo => "Redo",
+            Control::Brush => "Brush",
+            Control::Model => "Model",
+            Control::Tune => "Tune",
+            Control::Label => "Label",
+            Control::Review => "Review"
+        }
+    }
+
+    // keyboard shortcut shown next to the label
+    pub fn shortcut(&self) -> Option<&'static str>{
+        match *self {
+            Control::Draw => Some("D"),
+            Control::Erase => Some("E"),
+            Control::Clear => Some("C"),
+            Control::Predict => Some("P"),
+            Control::Undo => Some("Z"),
+            Control::Redo => Some("Y"),
+            Control::Brush => Some("[ ]"),
+            Control::Model => Some("M"),
+            Control::Tune => Some("T"),
+            Control::Label => Some("L"),
+            Control::Review => Some("Tab")
+        }
+    }
+}
+
+impl Toolbar{
+    pub fn new(width: f64, height: f64) -> Toolbar{
+        // relative widths, the brush slider and model selector get more room
+        let layout = vec![
+            (Control::Draw, 1.0),
+            (Control::Erase, 1.0),
+            (Control::Clear, 1.0),
+            (Control::Predict, 1.2),
+            (Control::Undo, 1.0),
+            (Control::Redo, 1.0),
+            (Control::Brush, 1.8),
+            (Control::Model, 1.6),
+            (Control::Tune, 1.0),
+            (Control::Label, 1.0),
+            (Control::Review, 1.1)
+        ];
+        let total: f64 = layout.iter().map(|c| c.1).sum();
+        let space = width - 2.0 * MARGIN - GAP * (layout.len() - 1) as f64;
+
+        let mut controls = Vec::new();
+        let mut left = MARGIN;
+        for (control, weight) in layout{
+            let w = space * weight / total;
+            controls.push((control, [left, MARGIN, w, height - 2.0 * MARGIN]));
+            left += w + GAP;
+        }
+        Toolbar{
+            height: height,
+            controls: controls
+        }
+    }
+
+    pub fn controls(&self) -> &Vec<(Control, [f64; 4])>{
+        &self.controls
+    }
+
+    pub fn rect(&self, control: Control) -> [f64; 4]{
+        self.controls.iter().find(|c| c.0 == control).unwrap().1
+    }
+
+    pub fn hit(&self, x: f64, y: f64) -> Option<Control>{
+        self.controls.iter()
+            .find(|c| x >= c.1[0] && x < c.1[0] + c.1[2] && y >= c.1[1] && y < c.1[1] + c.1[3])
+            .map(|c| c.0)
+    }
+
+    // brush radius for a click or drag at x on the slider
+    pub fn slider_value(&self, x: f64, min: f32, max: f32) -> f32{
+        let rect = self.rect(Control::Brush);
+        let t = ((x - rect[0]) / rect[2]).clamp(0.0, 1.0) as f32;
+        min + (max - min) * t
+    }
+
+    // how far along the slider `value` is, in [0, 1]
+    pub fn slider_fraction(&self, value: f32, min: f32, max: f32) -> f64{
+        (((value - min) / (max - min)) as f64).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn layout_test(){
+        let toolbar = Toolbar::new(540.0, 44.0);
+        let controls = toolbar.controls();
+        assert_eq!(controls.len(), 11);
+        let last = controls[controls.len() - 1].1;
+        assert!((last[0] + last[2] - 536.0).abs() < 1e-6, "Sample: {:?}", last);
+        for i in 1..controls.len(){
+            let (a, b) = (controls[i - 1].1, controls[i].1);
+            assert!(a[0] + a[2] < b[0], "controls overlap: {:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn hit_test(){
+        let toolbar = Toolbar::new(540.0, 44.0);
+        let rect = toolbar.rect(Control::Undo);
+        assert_eq!(toolbar.hit(rect[0] + 1.0, rect[1] + 1.0), Some(Control::Undo));
+        assert_eq!(toolbar.hit(rect[0] + 1.0, 50.0), None);
+        assert_eq!(toolbar.hit(1.0, 20.0), None);
+    }
+
+    #[test]
+    fn slider_test(){
+        let toolbar = Toolbar::new(540.0, 44.0);
+        let rect = toolbar.rect(Control::Brush);
+        assert_eq!(toolbar.slider_value(rect[0] - 10.0, 2.0, 60.0), 2.0);
+        assert_eq!(toolbar.slider_value(rect[0] + rect[2], 2.0, 60.0), 60.0);
+        let middle = toolbar.slider_value(rect[0] + rect[2] / 2.0, 2.0, 60.0);
+        assert!((middle - 31.0).abs() < 1e-4, "Sample: {}", middle);
+        assert!((toolbar.slider_fraction(middle, 2.0, 60.0) - 0.5).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+
+use model;
+use CNN;
+
+// # Sgd
+// Plain SGD with momentum and weight decay. Velocities are kept per
+// parameter name ("conv1.weight", "fc1.bias", ...) so they line up with the
+// names used in model.json.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sgd{
+    pub lr: f32,
+    pub momentum: f32,
+    pub weight_decay: f32,
+    velocity: BTreeMap<String, Vec<f32>>
+}
+
+// # StepLR
+// Multiplies the learning rate by gamma every step_size epochs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepLR{
+    pub base_lr: f32,
+    pub step_size: u32,
+    pub gamma: f32,
+    last_epoch: u32
+}
+
+// # Gradients
+// Gradient of the loss for every parameter of the CNN, shaped like the
+// parameters themselves. Summed over a batch with `add` and averaged with
+// `scale` before the optimizer step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradients{
+    pub conv1_filter: Vec<Vec<Vec<Vec<f32>>>>,
+    pub conv1_bias: Vec<f32>,
+    pub conv2_filter: Vec<Vec<Vec<Vec<f32>>>>,
+    pub conv2_bias: Vec<f32>,
+    pub fc_weights: Vec<Vec<f32>>,
+    pub fc_bias: Vec<f32>
+}
+
+fn flat4(values: &Vec<Vec<Vec<Vec<f32>>>>) -> Vec<f32>{
+    values.iter().flat_map(|a| a.iter().flat_map(|b| b.iter().flat_map(|c| c.iter().cloned()))).collect()
+}
+
+fn flat4_mut(values: &mut Vec<Vec<Vec<Vec<f32>>>>) -> Vec<&mut f32>{
+    values.iter_mut().flat_map(|a| a.iter_mut().flat_map(|b| b.iter_mut().flat_map(|c| c.iter_mut()))).collect()
+}
+
+fn add4(sum: &mut Vec<Vec<Vec<Vec<f32>>>>, other: &Vec<Vec<Vec<Vec<f32>>>>){
+    for (a, b) in flat4_mut(sum).into_iter().zip(flat4(other)){
+        *a += b;
+    }
+}
+
+impl Gradients{
+    pub fn add(&mut self, other: &Gradients){
+        add4(&mut self.conv1_filter, &other.conv1_filter);
+        add4(&mut self.conv2_filter, &other.conv2_filter);
+        for i in 0..self.fc_weights.len(){
+            for j in 0..self.fc_weights[i].len(){
+                self.fc_weights[i][j] += other.fc_weights[i][j];
+            }
+        }
+        for (sum, other) in [
+            (&mut self.conv1_bias, &other.conv1_bias),
+            (&mut self.conv2_bias, &other.conv2_bias),
+            (&mut self.fc_bias, &other.fc_bias)
+        ].iter_mut(){
+            for i in 0..sum.len(){
+                sum[i] += other[i];
+            }
+        }
+    }
+
+    pub fn scale(&mut self, factor: f32){
+        for value in self.values_mut(){
+            *value *= factor;
+        }
+    }
+
+    // every gradient, in no particular order
+    pub fn values_mut(&mut self) -> Vec<&mut f32>{
+        flat4_mut(&mut self.conv1_filter).into_iter()
+            .chain(flat4_mut(&mut self.conv2_filter))
+            .chain(self.fc_weights.iter_mut().flat_map(|row| row.iter_mut()))
+            .chain(self.conv1_bias.iter_mut())
+            .chain(self.conv2_bias.iter_mut())
+            .chain(self.fc_bias.iter_mut())
+            .collect()
+    }
+}
+
+// cross entropy of the softmax of `logits` against `label`, and its
+// gradient with respect to the logits
+pub fn cross_entropy(logits: &Vec<f32>, label: usize) -> (f32, Vec<f32>){
+    let mut grad = model::softmax(logits);
+    let loss = -grad[label].max(1e-12).ln();
+    grad[label] -= 1.0;
+    (loss, grad)
+}
+
+// one optimizer step on a batch of inputs (1, 28, 28), returns the mean
+// loss. Layers named in `frozen` ("conv1", "conv2", "fc1") keep their weights.
+pub fn train_step(cnn: &mut CNN, optimizer: &mut Sgd, inputs: &[Vec<Vec<Vec<f32>>>], labels: &[u8], frozen: &[&str]) -> f32{
+    let mut total: Option<Gradients> = None;
+    let mut loss = 0.0;
+    for n in 0..inputs.len(){
+        let (sample_loss, grad_logits) = cross_entropy(&cnn.logits(&inputs[n]), labels[n] as usize);
+        loss += sample_loss;
+        let grads = cnn.backward(&inputs[n], &grad_logits);
+        match total {
+            Some(ref mut total) => total.add(&grads),
+            None => total = Some(grads)
+        }
+    }
+    if let Some(mut grads) = total{
+        grads.scale(1.0 / inputs.len() as f32);
+        apply_gradients(cnn, optimizer, &grads, frozen);
+    }
+    loss / inputs.len().max(1) as f32
+}
+
+pub fn apply_gradients(cnn: &mut CNN, optimizer: &mut Sgd, grads: &Gradients, frozen: &[&str]){
+    if !frozen.contains(&"conv1"){
+        optimizer.step("conv1.weight", flat4_mut(cnn.conv1.filter_mut()), &flat4(&grads.conv1_filter));
+        optimizer.step("conv1.bias", cnn.conv1.bias_mut().iter_mut().collect(), &grads.conv1_bias);
+    }
+    if !frozen.contains(&"conv2"){
+        optimizer.step("conv2.weight", flat4_mut(cnn.conv2.filter_mut()), &flat4(&grads.conv2_filter));
+        optimizer.step("conv2.bias", cnn.conv2.bias_mut().iter_mut().collect(), &grads.conv2_bias);
+    }
+    if !frozen.contains(&"fc1"){
+        let weights: Vec<f32> = grads.fc_weights.iter().flat_map(|row| row.iter().cloned()).collect();
+        optimizer.step("fc1.weight", cnn.fc.weights_mut().iter_mut().flat_map(|row| row.iter_mut()).collect(), &weights);
+        optimizer.step("fc1.bias", cnn.fc.bias_mut().iter_mut().collect(), &grads.fc_bias);
+    }
+}
+
+// fraction of the inputs the network classifies as their label
+pub fn accuracy(cnn: &CNN, inputs: &[Vec<Vec<Vec<f32>>>], labels: &[u8]) -> f32{
+    if inputs.is_empty(){
+        return 0.0;
+    }
+    let mut correct = 0;
+    for n in 0..inputs.len(){
+        if cnn.forward(&inputs[n]) == labels[n] as u32{
+            correct += 1;
+        }
+    }
+    correct as f32 / inputs.len() as f32
+}
+
+impl Sgd{
+    pub fn new(lr: f32, momentum: f32, weight_decay: f32) -> Sgd{
+        Sgd{
+            lr: lr,
+            momentum: momentum,
+            weight_decay: weight_decay,
+            velocity: BTreeMap::new()
+        }
+    }
+
+    pub fn step(&mut self, name: &str, params: Vec<&mut f32>, grads: &Vec<f32>){
+        let velocity = self.velocity.entry(name.to_owned())
+            .or_insert_with(|| vec![0.0; grads.len()]);
+        for (i, param) in params.into_iter().enumerate(){
+            let grad = grads[i] + self.weight_decay * *param;
+            velocity[i] = self.momentum * velocity[i] + grad;
+            *param -= self.lr * velocity[i];
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value{
+        let mut velocity = serde_json::Map::new();
+        for (name, values) in self.velocity.iter(){
+            velocity.insert(name.clone(), json!(values));
+        }
+        json!({
+            "lr": self.lr,
+            "momentum": self.momentum,
+            "weight_decay": self.weight_decay,
+            "velocity": velocity
+        })
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Option<Sgd>{
+        let mut sgd = Sgd::new(
+            json["lr"].as_f64()? as f32,
+            json["momentum"].as_f64()? as f32,
+            json["weight_decay"].as_f64()? as f32
+        );
+        for (name, values) in json["velocity"].as_object()?.iter(){
+            let mut velocity = vec![0.0; values.as_array()?.len()];
+            for i in 0..velocity.len(){
+                velocity[i] = values[i].as_f64()? as f32;
+            }
+            sgd.velocity.insert(name.clone(), velocity);
+        }
+        Some(sgd)
+    }
+}
+
+impl StepLR{
+    pub fn new(base_lr: f32, step_size: u32, gamma: f32) -> StepLR{
+        StepLR{
+            base_lr: base_lr,
+            step_size: step_size,
+            gamma: gamma,
+            last_epoch: 0
+        }
+    }
+
+    pub fn last_epoch(&self) -> u32{
+        self.last_epoch
+    }
+
+    pub fn get_lr(&self) -> f32{
+        self.base_lr * self.gamma.powi((self.last_epoch / self.step_size.max(1)) as i32)
+    }
+
+    // called once at the end of every epoch, updates the optimizer in place
+    pub fn step(&mut self, optimizer: &mut Sgd){
+        self.last_epoch += 1;
+        optimizer.lr = self.get_lr();
+    }
+
+    pub fn to_json(&self) -> serde_json::Value{
+        json!({
+            "base_lr": self.base_lr,
+            "step_size": self.step_size,
+            "gamma": self.gamma,
+            "last_epoch": self.last_epoch
+        })
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Option<StepLR>{
+        let mut scheduler = StepLR::new(
+            json["base_lr"].as_f64()? as f32,
+            json["step_size"].as_u64()? as u32,
+            json["gamma"].as_f64()? as f32
+        );
+        scheduler.last_epoch = json["last_epoch"].as_u64()? as u32;
+        Some(scheduler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testutil;
+
+    #[test]
+    fn sgd_momentum_test(){
+        let mut sgd = Sgd::new(0.1, 0.9, 0.0);
+        let mut params = vec![1.0, -1.0];
+        sgd.step("w", params.iter_mut().collect(), &vec![1.0, -2.0]);
+        assert_eq!(params, vec![0.9, -0.8], "Sample: {:?}", params);
+        sgd.step("w", params.iter_mut().collect(), &vec![1.0, -2.0]);
+        // velocity is now 1.9 and -3.8
+        assert!((params[0] - 0.71).abs() < 1e-6, "Sample: {:?}", params);
+        assert!((params[1] + 0.42).abs() < 1e-6, "Sample: {:?}", params);
+    }
+
+    #[test]
+    fn sgd_json_test(){
+        let mut sgd = Sgd::new(0.01, 0.9, 0.0005);
+        let mut params = [0.3, 0.7, -0.1];
+        sgd.step("fc1.bias", params.iter_mut().collect(), &vec![0.123, -0.456, 0.789]);
+        let restored = Sgd::from_json(&sgd.to_json()).unwrap();
+        assert_eq!(restored, sgd);
+    }
+
+    #[test]
+    fn cross_entropy_test(){
+        let (loss, grad) = cross_entropy(&vec![0.0, 0.0], 1);
+        assert!((loss - 2f32.ln()).abs() < 1e-6, "Sample: {}", loss);
+        assert_eq!(grad, vec![0.5, -0.5]);
+    }
+
+    #[test]
+    fn train_step_test(){
+        let mut cnn = testutil::model();
+        let conv1 = cnn.conv1.filter().clone();
+        let inputs = testutil::inputs(&[testutil::bar(10), testutil::bar(17)]);
+        let labels = vec![3, 8];
+        let mut sgd = Sgd::new(0.01, 0.9, 0.0);
+        let first = train_step(&mut cnn, &mut sgd, &inputs, &labels, &["conv1"]);
+        let mut last = first;
+        for _ in 0..20{
+            last = train_step(&mut cnn, &mut sgd, &inputs, &labels, &["conv1"]);
+        }
+        assert!(last < first * 0.5, "loss went from {} to {}", first, last);
+        assert_eq!(accuracy(&cnn, &inputs, &labels), 1.0);
+        assert_eq!(cnn.conv1.filter(), &conv1);
+    }
+
+    #[test]
+    fn step_lr_test(){
+        let mut sgd = Sgd::new(0.1, 0.0, 0.0);
+        let mut scheduler = StepLR::new(0.1, 2, 0.5);
+        scheduler.step(&mut sgd);
+        assert_eq!(sgd.lr, 0.1);
+        scheduler.step(&mut sgd);
+        assert_eq!(sgd.lr, 0.05);
+        let restored = StepLR::from_json(&scheduler.to_json()).unwrap();
+        assert_eq!(restored, scheduler);
+    }
+}